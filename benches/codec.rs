@@ -0,0 +1,330 @@
+// Where the time of a session goes, split the way the protocol splits it:
+//   ecies    the auth/ack exchange, from making the auth to both sides
+//            holding the frame secrets
+//   handshake
+//            whole handshakes against a responder in the same process, auth
+//            through Hello and Status, over an in-memory pipe
+//   framing  one payload through a frame, encrypted and MAC'd on one codec
+//            and decrypted and checked on the other, at a few frame sizes
+//   frame_decode
+//            a large frame arriving a segment at a time, decrypted as it
+//            comes in against decrypted once all of it is buffered
+//   frame_write
+//            a large frame built whole before it's written, against streamed
+//            to the writer a piece at a time
+//   aes      the frame cipher's keystream and the block cipher the MACs are
+//            seeded through, whichever backend the aes crate picked
+//   mac      the keccak MAC steps every frame takes twice, and the header-mac
+//            with and without the digest carried over from the last frame
+//
+// Everything runs in process, there are no sockets in the numbers. Framing
+// goes through the frame layer alone, so it measures the cipher and MACs
+// rather than snappy or message decoding.
+// Criterion does the warm up, sampling and outlier handling, compare runs
+// with `cargo bench -- --save-baseline <name>` and `--baseline <name>`.
+use aes::cipher::{generic_array::GenericArray, BlockEncrypt, KeyInit, KeyIvInit, StreamCipher};
+use alloy_rlp::BytesMut;
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use reth_handshake::{
+    config::RLPxConfig,
+    ecies::Aes256Ctr64BE,
+    keccak_mac::KeccakMac,
+    messages::{Capability, Les, RLPx_Message},
+    rplx::{mac_digest, RLPx},
+    session::Session,
+};
+use secp256k1::{PublicKey, SecretKey, SECP256K1};
+use std::{
+    io::{self, Write},
+    time::{Duration, Instant},
+};
+use tokio::{io::duplex, runtime};
+use tokio_util::codec::{Decoder, Encoder};
+
+// header-ciphertext || header-mac
+const FRAME_HEADER_SIZE: usize = 32;
+const FRAME_SIZES: [usize; 3] = [64, 1024, 16 * 1024];
+// Enough for a whole auth or ack, so neither side blocks on the other reading.
+const PIPE_CAPACITY: usize = 64 * 1024;
+
+fn codecs() -> (RLPx, RLPx) {
+    codecs_with(RLPxConfig::default(), RLPxConfig::default())
+}
+
+fn codecs_with(initiator_config: RLPxConfig, responder_config: RLPxConfig) -> (RLPx, RLPx) {
+    let initiator_key = SecretKey::new(&mut secp256k1::rand::thread_rng());
+    let responder_key = SecretKey::new(&mut secp256k1::rand::thread_rng());
+    let responder_id = PublicKey::from_secret_key(SECP256K1, &responder_key);
+    (
+        RLPx::new(initiator_key, responder_id, initiator_config),
+        RLPx::new_incoming(responder_key, responder_config),
+    )
+}
+
+// Auth one way and ack the other, both codecs end up with the frame secrets.
+fn auth_ack(initiator: &mut RLPx, responder: &mut RLPx) {
+    let mut wire = BytesMut::new();
+    initiator.encode(RLPx_Message::Auth, &mut wire).unwrap();
+    responder.decode(&mut wire).unwrap().unwrap();
+    responder.encode(RLPx_Message::AuthAck, &mut wire).unwrap();
+    initiator.decode(&mut wire).unwrap().unwrap();
+}
+
+// Past Hello with only les shared, whose Announce goes through as an opaque
+// body, then resumed without snappy so the frame is as big as the body.
+fn les_pair() -> (RLPx, RLPx) {
+    let config = || RLPxConfig {
+        capabilities: vec![Capability { name: Les::CAPABILITY.to_string(), version: 4 }],
+        eth_status: false,
+        ..RLPxConfig::default()
+    };
+    let (mut initiator, mut responder) = codecs_with(config(), config());
+    auth_ack(&mut initiator, &mut responder);
+    // Both send their Hello before reading the other's, as sessions do.
+    let mut to_responder = BytesMut::new();
+    let mut to_initiator = BytesMut::new();
+    initiator.encode(RLPx_Message::Hello, &mut to_responder).unwrap();
+    responder.encode(RLPx_Message::Hello, &mut to_initiator).unwrap();
+    responder.decode(&mut to_responder).unwrap().unwrap();
+    initiator.decode(&mut to_initiator).unwrap().unwrap();
+    let secrets = initiator.secrets().unwrap().clone();
+    initiator.resume(secrets, false);
+    let secrets = responder.secrets().unwrap().clone();
+    responder.resume(secrets, false);
+    (initiator, responder)
+}
+
+fn ecies(c: &mut Criterion) {
+    let mut group = c.benchmark_group("ecies");
+    group.bench_function("auth", |b| {
+        b.iter_batched(
+            codecs,
+            |(mut initiator, _)| {
+                let mut wire = BytesMut::new();
+                initiator.encode(RLPx_Message::Auth, &mut wire).unwrap();
+                wire
+            },
+            BatchSize::SmallInput,
+        )
+    });
+    group.bench_function("auth_ack", |b| {
+        b.iter_batched(
+            codecs,
+            |(mut initiator, mut responder)| {
+                auth_ack(&mut initiator, &mut responder);
+                (initiator, responder)
+            },
+            BatchSize::SmallInput,
+        )
+    });
+    group.finish();
+}
+
+// Both sides share one thread, so a handshake's time includes the responder's
+// work as well as the initiator's.
+fn handshake(c: &mut Criterion) {
+    let mut group = c.benchmark_group("handshake");
+    let runtime = runtime::Builder::new_current_thread().build().unwrap();
+    group.bench_function("full", |b| {
+        b.iter_batched(
+            codecs,
+            |(initiator_codec, responder_codec)| {
+                let (initiator_pipe, responder_pipe) = duplex(PIPE_CAPACITY);
+                let mut initiator = Session::new(initiator_pipe, initiator_codec);
+                let mut responder = Session::new(responder_pipe, responder_codec);
+                runtime.block_on(async {
+                    let (initiated, responded) = tokio::join!(initiator.handshake(), responder.handshake());
+                    initiated.unwrap();
+                    responded.unwrap();
+                });
+            },
+            BatchSize::SmallInput,
+        )
+    });
+    group.finish();
+}
+
+fn framing(c: &mut Criterion) {
+    let mut group = c.benchmark_group("framing");
+    for size in FRAME_SIZES {
+        let payload = vec![0xab; size];
+        let (mut sender, mut receiver) = codecs();
+        auth_ack(&mut sender, &mut receiver);
+        group.throughput(Throughput::Bytes(size as u64));
+        group.bench_function(format!("round_trip/{}", size), |b| {
+            b.iter(|| {
+                let mut frame = BytesMut::new();
+                sender.write_raw_frame(&payload, &mut frame).unwrap();
+                let (header, rest) = frame.split_first_chunk_mut::<FRAME_HEADER_SIZE>().unwrap();
+                receiver.decode_frame_header(header).unwrap();
+                black_box(receiver.decode_frame_ciphertext(rest).unwrap().len())
+            })
+        });
+    }
+    group.finish();
+}
+
+// A frame delivered the way TCP tends to, a segment at a time, each decode
+// call getting whatever arrived so far.
+const SEGMENT_SIZE: usize = 1460;
+// What Session::stream_frame_bytes encrypts and writes at a time.
+const STREAM_PIECE_SIZE: usize = 16 * 1024;
+const LARGE_FRAME_SIZES: [usize; 2] = [64 * 1024, 1024 * 1024];
+
+// Decoding one large frame, through the decoder as its segments arrive, and
+// through decode_frame_ciphertext once all of it is in, which is how every
+// frame was decoded before ciphertext got decrypted as it came in. Only the
+// decoding is timed, each frame is encoded fresh to keep the MACs in step.
+fn frame_decode(c: &mut Criterion) {
+    let mut group = c.benchmark_group("frame_decode");
+    group.sample_size(20);
+    for size in LARGE_FRAME_SIZES {
+        let body = vec![0xab; size];
+        group.throughput(Throughput::Bytes(size as u64));
+
+        let (mut sender, mut receiver) = les_pair();
+        group.bench_function(format!("segments/{}", size), |b| {
+            b.iter_custom(|iters| {
+                let mut elapsed = Duration::ZERO;
+                for _ in 0..iters {
+                    let mut frame = BytesMut::new();
+                    sender.encode(RLPx_Message::LesAnnounce(body.clone()), &mut frame).unwrap();
+                    let mut read_buf = BytesMut::new();
+                    let start = Instant::now();
+                    let mut message = None;
+                    for segment in frame.chunks(SEGMENT_SIZE) {
+                        read_buf.extend_from_slice(segment);
+                        message = receiver.decode(&mut read_buf).unwrap();
+                    }
+                    black_box(message.unwrap());
+                    elapsed += start.elapsed();
+                }
+                elapsed
+            })
+        });
+
+        let (mut sender, mut receiver) = les_pair();
+        group.bench_function(format!("whole/{}", size), |b| {
+            b.iter_custom(|iters| {
+                let mut elapsed = Duration::ZERO;
+                for _ in 0..iters {
+                    let mut frame = BytesMut::new();
+                    sender.write_raw_frame(&body, &mut frame).unwrap();
+                    let mut read_buf = BytesMut::new();
+                    let start = Instant::now();
+                    for segment in frame.chunks(SEGMENT_SIZE) {
+                        read_buf.extend_from_slice(segment);
+                    }
+                    let (header, rest) = read_buf.split_first_chunk_mut::<FRAME_HEADER_SIZE>().unwrap();
+                    receiver.decode_frame_header(header).unwrap();
+                    black_box(receiver.decode_frame_ciphertext(rest).unwrap());
+                    elapsed += start.elapsed();
+                }
+                elapsed
+            })
+        });
+    }
+    group.finish();
+}
+
+// Writing one large frame out, built whole in a buffer first as the encoder
+// does, and streamed to the writer a piece at a time. The writer throws the
+// bytes away, so only framing and copying count.
+fn frame_write(c: &mut Criterion) {
+    let mut group = c.benchmark_group("frame_write");
+    group.sample_size(20);
+    for size in LARGE_FRAME_SIZES {
+        let payload = vec![0xab; size];
+        group.throughput(Throughput::Bytes(size as u64));
+
+        let (mut sender, _) = les_pair();
+        let mut out = io::sink();
+        group.bench_function(format!("buffered/{}", size), |b| {
+            b.iter(|| {
+                let mut frame = BytesMut::new();
+                sender.write_raw_frame(&payload, &mut frame).unwrap();
+                out.write_all(&frame).unwrap();
+            })
+        });
+
+        let (mut sender, _) = les_pair();
+        let mut piece = Vec::with_capacity(STREAM_PIECE_SIZE);
+        group.bench_function(format!("streamed/{}", size), |b| {
+            b.iter(|| {
+                out.write_all(&sender.begin_frame_stream(payload.len()).unwrap()).unwrap();
+                for chunk in payload.chunks(STREAM_PIECE_SIZE) {
+                    piece.clear();
+                    piece.extend_from_slice(chunk);
+                    sender.encrypt_frame_stream(&mut piece).unwrap();
+                    out.write_all(&piece).unwrap();
+                }
+                out.write_all(&sender.finish_frame_stream().unwrap()).unwrap();
+            })
+        });
+    }
+    group.finish();
+}
+
+// A 16 KiB frame, big enough that the per-call cost drops out.
+const KEYSTREAM_SIZE: usize = 16 * 1024;
+
+// The AES work a frame takes on its own, without the MACs around it. Run once
+// as built and once with RUSTFLAGS="--cfg aes_force_soft" against a saved
+// baseline to compare the detected backend with the portable one.
+fn aes(c: &mut Criterion) {
+    let mut group = c.benchmark_group("aes");
+    let mut keystream = Aes256Ctr64BE::new(&[0x11; 32].into(), &[0; 16].into());
+    let mut frame = vec![0xab; KEYSTREAM_SIZE];
+    group.throughput(Throughput::Bytes(KEYSTREAM_SIZE as u64));
+    group.bench_function(format!("keystream/{}", KEYSTREAM_SIZE), |b| {
+        b.iter(|| keystream.apply_keystream(black_box(&mut frame)))
+    });
+
+    let mac_secret = aes::Aes256::new(&[0x22; 32].into());
+    let mut block = [0x33; 16];
+    group.throughput(Throughput::Bytes(block.len() as u64));
+    group.bench_function("mac_block", |b| {
+        b.iter(|| mac_secret.encrypt_block(GenericArray::from_mut_slice(black_box(&mut block))))
+    });
+    group.finish();
+}
+
+fn mac(c: &mut Criterion) {
+    let mut group = c.benchmark_group("mac");
+    let mut mac = KeccakMac::new();
+    // What a header or frame mac seed feeds in.
+    group.bench_function("update_seed", |b| b.iter(|| mac.update(black_box([0x5a; 16]))));
+    group.bench_function("digest", |b| b.iter(|| mac_digest(black_box(&mac))));
+
+    // The header-mac steps, starting from keccak256.digest(mac)[:16] kept from
+    // the last frame-mac as the codec does, and from recomputing it the way it
+    // was done before that.
+    let mac_secret = aes::Aes256::new(&[0x11; 32].into());
+    let header_ciphertext = [0x22; 16];
+    let mut cached = mac_digest(&mac);
+    let header_mac = |mac: &mut KeccakMac, digest: [u8; 16]| {
+        let mut seed = digest;
+        mac_secret.encrypt_block(GenericArray::from_mut_slice(&mut seed));
+        for (seed, ciphertext) in seed.iter_mut().zip(header_ciphertext) {
+            *seed ^= ciphertext;
+        }
+        mac.update(seed);
+        mac_digest(mac)
+    };
+    group.bench_function("header_mac/recomputed", |b| {
+        b.iter(|| {
+            let digest = mac_digest(&mac);
+            black_box(header_mac(&mut mac, digest))
+        })
+    });
+    group.bench_function("header_mac/cached", |b| {
+        b.iter(|| {
+            cached = header_mac(&mut mac, cached);
+            black_box(cached)
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, ecies, handshake, framing, frame_decode, frame_write, aes, mac);
+criterion_main!(benches);
@@ -0,0 +1,106 @@
+use std::{net::SocketAddr, time::Duration};
+
+use log::{error, info};
+use secp256k1::{rand::RngCore, PublicKey, SecretKey};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    time::timeout,
+};
+
+use crate::{
+    cli::Options,
+    ecies::EciesRng,
+    messages::{Disconnect, RLPx_Message},
+    replay::{Recorder, RecordingStream},
+    rplx::{HandshakeError, RLPx, RlpxState},
+    session::Session,
+};
+
+// How long `check` gives the handshake before calling it a timeout.
+const CHECK_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Process exit codes of `check`, for probes that only look at those.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CheckStatus {
+    Active = 0,
+    HandshakeFailed = 2,
+    DisconnectedByPeer = 3,
+    Timeout = 4,
+}
+
+// Dials the peer, sees the handshake through and hangs up again.
+#[tokio::main(flavor = "current_thread")]
+pub async fn run(options: Options, public_key: PublicKey, address: SocketAddr) -> CheckStatus {
+    let private_key = SecretKey::new(&mut secp256k1::rand::thread_rng());
+    let mut config = options.rlpx_config();
+
+    // A recording is only any use if our side of the handshake comes out the
+    // same again, so the randomness comes from a seed that goes in the file.
+    let recorder = match options.record.as_deref() {
+        Some(path) => {
+            let seed = secp256k1::rand::thread_rng().next_u64();
+            config.ecies_rng = EciesRng::seeded(seed);
+            match Recorder::create(path, &options.args, private_key, seed, public_key, address) {
+                Ok(recorder) => Some(recorder),
+                Err(err) => {
+                    error!("{}", err);
+                    return CheckStatus::HandshakeFailed;
+                }
+            }
+        }
+        None => None,
+    };
+    let codec = RLPx::new(private_key, public_key, config);
+
+    // The dial has timeouts of its own, only the handshake counts against ours.
+    let stream = match Session::connect(address, &options.dial).await {
+        Ok(stream) => stream,
+        Err(err @ HandshakeError::Timeout(_)) => {
+            eprintln!("{}", err);
+            return CheckStatus::Timeout;
+        }
+        Err(err) => {
+            eprintln!("Handshake failed: {}", err);
+            return CheckStatus::HandshakeFailed;
+        }
+    };
+
+    match recorder {
+        Some(recorder) => {
+            let session = Session::new(RecordingStream::new(stream, recorder.clone()), codec);
+            let (status, state) = check_session(session, address).await;
+            recorder.finish(state, status);
+            status
+        }
+        None => check_session(Session::new(stream, codec), address).await.0,
+    }
+}
+
+// Everything after the TCP connect, over whatever the session runs on. Also
+// returns the state the codec was left in, which is what a replay compares.
+pub async fn check_session<T: AsyncRead + AsyncWrite + Unpin>(
+    mut session: Session<T>,
+    address: SocketAddr,
+) -> (CheckStatus, RlpxState) {
+    let status = match timeout(CHECK_TIMEOUT, session.handshake()).await {
+        Ok(Ok(())) => {
+            info!("Handshake with {} complete", address);
+            // We're done with it, so let the peer know rather than just dropping it.
+            let _ = session.send(RLPx_Message::Disconnect(Disconnect::REQUESTED)).await;
+            CheckStatus::Active
+        }
+        Ok(Err(HandshakeError::Disconnected(reason))) => {
+            eprintln!("Disconnected by peer, reason {:#x}", reason);
+            CheckStatus::DisconnectedByPeer
+        }
+        Ok(Err(err)) => {
+            eprintln!("Handshake failed: {}", err);
+            CheckStatus::HandshakeFailed
+        }
+        Err(_) => {
+            eprintln!("Timed out during handshake after {:?}", CHECK_TIMEOUT);
+            CheckStatus::Timeout
+        }
+    };
+    (status, session.state())
+}
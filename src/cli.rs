@@ -0,0 +1,330 @@
+use alloy_primitives::B256;
+use log::error;
+use std::{
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+    time::Duration,
+};
+
+use crate::{
+    config::{ClientVersion, DialConfig, Network, RLPxConfig},
+    dnsdisc::ENRTREE_PREFIX,
+    ipfilter::IpFilter,
+    messages::Capability,
+};
+
+// How many peers we're willing to pull out of DNS trees, unless told otherwise.
+const DEFAULT_MAX_DISCOVERED: usize = 10;
+// How many peers we dial at once, unless told otherwise.
+const DEFAULT_CONCURRENCY: usize = 1;
+
+#[derive(Debug)]
+pub struct Options {
+    // Static enodes passed in as arguments.
+    pub enodes: Vec<String>,
+    // EIP-1459 tree URLs (enrtree://<key>@<domain>) we should crawl for more peers.
+    pub enr_trees: Vec<String>,
+    pub max_discovered: usize,
+    // Where to dump the raw frames, if anywhere.
+    pub frame_log: Option<String>,
+    // Where to append session state transitions, if anywhere.
+    pub state_log: Option<String>,
+    // Handshake over plain blocking sockets instead of the tokio codec.
+    pub blocking: bool,
+    pub concurrency: usize,
+    // Timeouts and retries for DNS lookups and TCP connects.
+    pub dial: DialConfig,
+    // Minimum time between two dials into the same /24 or /48, if limited at all.
+    pub subnet_dial_interval: Option<Duration>,
+    // Only stay connected to peers whose client version starts with this.
+    pub require_client_prefix: Option<String>,
+    // Client versions to advertise, one is picked at random per handshake.
+    pub client_versions: Vec<String>,
+    // Capabilities to advertise instead of the default eth/68.
+    pub capabilities: Vec<Capability>,
+    // Print the summary as one JSON object per peer on stdout.
+    pub json: bool,
+    // --allow-cidr / --deny-cidr blocks every peer address is checked against.
+    pub ip_filter: IpFilter,
+    // Also accept peers dialing us on this address.
+    pub listen: Option<SocketAddr>,
+    // The address others reach us on, for the enode we advertise. Behind a NAT
+    // it's none of our own interfaces.
+    pub external_ip: Option<IpAddr>,
+    // STUN server (host:port) to ask for the external address instead, with
+    // external_ip as the fallback when it can't tell.
+    pub stun_server: Option<String>,
+    // Inbound sessions we keep at once, further peers get Too many peers.
+    pub max_peers: Option<usize>,
+    // Bytes any one session may hold on to at once, buffers and queue together.
+    pub max_session_memory: Option<usize>,
+    // `check <enode>`: one handshake, result in the exit code.
+    pub check: bool,
+    // `check --record <file>`: also write the handshake's bytes both ways to
+    // the file, for `replay <file>` to play back.
+    pub record: Option<String>,
+    // `replay <file>`: the recorded peer fed back into a fresh codec.
+    pub replay: Option<String>,
+    // The command line as we got it, which is what a recording keeps.
+    pub args: Vec<String>,
+    // `crawl --out <file>`: record every reachable peer, asking them over
+    // discv4 for more with --neighbors.
+    pub crawl: bool,
+    pub crawl_out: Option<String>,
+    pub crawl_neighbors: bool,
+    // The chain our eth Status claims, with single fields overridable.
+    pub network: Network,
+    pub network_id: Option<u64>,
+    pub genesis_hash: Option<B256>,
+    // Stop after the p2p Hello instead of exchanging eth Status.
+    pub skip_eth_status: bool,
+    // Stay connected after the handshake instead of disconnecting: answer the
+    // peer's Pings and Ping it ourselves, so the session outlives its ping
+    // timeout and we can watch it until Ctrl-C.
+    pub keep_alive: bool,
+    // Longest we stay connected to any one peer, handshake included.
+    pub session_deadline: Option<Duration>,
+    // Hang up right after auth and ack, before any Hello.
+    pub ecies_only: bool,
+    // Also dial enodes read from standard input, one per line, as they come.
+    pub stdin: bool,
+    // Only dial addresses that answer a discv4 Ping with a Pong first.
+    pub discv4_ping: bool,
+    // Log node ids and enodes as short fingerprints rather than in full.
+    pub redact_node_ids: bool,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            enodes: Vec::new(),
+            enr_trees: Vec::new(),
+            max_discovered: DEFAULT_MAX_DISCOVERED,
+            frame_log: None,
+            state_log: None,
+            blocking: false,
+            concurrency: DEFAULT_CONCURRENCY,
+            dial: DialConfig::default(),
+            subnet_dial_interval: None,
+            require_client_prefix: None,
+            client_versions: Vec::new(),
+            capabilities: Vec::new(),
+            json: false,
+            ip_filter: IpFilter::default(),
+            listen: None,
+            external_ip: None,
+            stun_server: None,
+            max_peers: None,
+            max_session_memory: None,
+            check: false,
+            record: None,
+            replay: None,
+            args: Vec::new(),
+            crawl: false,
+            crawl_out: None,
+            crawl_neighbors: false,
+            network: Network::default(),
+            network_id: None,
+            genesis_hash: None,
+            skip_eth_status: false,
+            keep_alive: false,
+            session_deadline: None,
+            ecies_only: false,
+            stdin: false,
+            discv4_ping: false,
+            redact_node_ids: false,
+        }
+    }
+}
+
+impl Options {
+    pub fn from_args(args: impl Iterator<Item = String>) -> Result<Self, &'static str> {
+        let mut options = Options {
+            args: args.collect(),
+            ..Options::default()
+        };
+        let mut args = options.args.clone().into_iter().peekable();
+        if args.next_if(|arg| arg == "check").is_some() {
+            options.check = true;
+        } else if args.next_if(|arg| arg == "replay").is_some() {
+            options.replay = Some(Self::value(&mut args, "replay needs a recording file! ")?);
+        } else if args.next_if(|arg| arg == "crawl").is_some() {
+            options.crawl = true;
+        }
+
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--max-discovered" => {
+                    options.max_discovered = Self::value(&mut args, "Invalid --max-discovered value! ")?;
+                }
+                "--frame-log" => {
+                    options.frame_log = Some(Self::value(&mut args, "Invalid --frame-log path! ")?);
+                }
+                "--state-log" => {
+                    options.state_log = Some(Self::value(&mut args, "Invalid --state-log path! ")?);
+                }
+                "--blocking" => options.blocking = true,
+                "--json" => options.json = true,
+                "--concurrency" => {
+                    options.concurrency = Self::value(&mut args, "Invalid --concurrency value! ")?;
+                    if options.concurrency == 0 {
+                        return Err("Invalid --concurrency value! ");
+                    }
+                }
+                "--resolve-timeout-ms" => {
+                    let millis = Self::value(&mut args, "Invalid --resolve-timeout-ms value! ")?;
+                    options.dial.resolve_timeout = Duration::from_millis(millis);
+                }
+                "--connect-timeout-ms" => {
+                    let millis = Self::value(&mut args, "Invalid --connect-timeout-ms value! ")?;
+                    options.dial.connect_timeout = Duration::from_millis(millis);
+                }
+                "--connect-attempts" => {
+                    options.dial.connect_attempts = Self::value(&mut args, "Invalid --connect-attempts value! ")?;
+                    if options.dial.connect_attempts == 0 {
+                        return Err("Invalid --connect-attempts value! ");
+                    }
+                }
+                "--mapped-ipv4" => {
+                    options.dial.mapped_ipv4 = Self::value(&mut args, "Invalid --mapped-ipv4, expected v4 or v6! ")?;
+                }
+                "--subnet-dial-interval-ms" => {
+                    let millis = Self::value(&mut args, "Invalid --subnet-dial-interval-ms value! ")?;
+                    options.subnet_dial_interval = Some(Duration::from_millis(millis));
+                }
+                "--require-client-prefix" => {
+                    options.require_client_prefix =
+                        Some(Self::value(&mut args, "Invalid --require-client-prefix value! ")?);
+                }
+                "--client-version" => {
+                    options
+                        .client_versions
+                        .push(Self::value(&mut args, "Invalid --client-version value! ")?);
+                }
+                "--capability" => {
+                    options
+                        .capabilities
+                        .push(Self::value(&mut args, "Invalid --capability value, expected name/version! ")?);
+                }
+                "--listen" => {
+                    options.listen = Some(Self::value(&mut args, "Invalid --listen address! ")?);
+                }
+                "--external-ip" => {
+                    options.external_ip = Some(Self::value(&mut args, "Invalid --external-ip address! ")?);
+                }
+                "--stun" => {
+                    options.stun_server = Some(Self::value(&mut args, "Invalid --stun server! ")?);
+                }
+                "--max-peers" => {
+                    options.max_peers = Some(Self::value(&mut args, "Invalid --max-peers value! ")?);
+                }
+                "--max-session-memory" => {
+                    options.max_session_memory = Some(Self::value(&mut args, "Invalid --max-session-memory value! ")?);
+                }
+                "--allow-cidr" => {
+                    options
+                        .ip_filter
+                        .allow
+                        .push(Self::value(&mut args, "Invalid --allow-cidr block! ")?);
+                }
+                "--deny-cidr" => {
+                    options
+                        .ip_filter
+                        .deny
+                        .push(Self::value(&mut args, "Invalid --deny-cidr block! ")?);
+                }
+                "--network" => {
+                    options.network = Self::value(&mut args, "Invalid --network, expected mainnet, sepolia or holesky! ")?;
+                }
+                "--network-id" => {
+                    options.network_id = Some(Self::value(&mut args, "Invalid --network-id value! ")?);
+                }
+                "--genesis-hash" => {
+                    options.genesis_hash = Some(Self::value(&mut args, "Invalid --genesis-hash value! ")?);
+                }
+                "--no-eth-status" => options.skip_eth_status = true,
+                "--keep-alive" => options.keep_alive = true,
+                "--ecies-only" => options.ecies_only = true,
+                "--stdin" => options.stdin = true,
+                "--discv4-ping" => options.discv4_ping = true,
+                "--redact-node-ids" => options.redact_node_ids = true,
+                "--out" => {
+                    options.crawl_out = Some(Self::value(&mut args, "Invalid --out path! ")?);
+                }
+                "--neighbors" => options.crawl_neighbors = true,
+                "--record" => {
+                    options.record = Some(Self::value(&mut args, "Invalid --record path! ")?);
+                }
+                "--session-deadline-secs" => {
+                    let secs = Self::value(&mut args, "Invalid --session-deadline-secs value! ")?;
+                    options.session_deadline = Some(Duration::from_secs(secs));
+                }
+                _ if arg.starts_with(ENRTREE_PREFIX) => options.enr_trees.push(arg),
+                _ if arg.starts_with("--") => {
+                    error!("Unknown option {:?}", arg);
+                    return Err("Unknown option! ");
+                }
+                _ => options.enodes.push(arg),
+            }
+        }
+        if options.crawl && options.crawl_out.is_none() {
+            return Err("crawl needs an --out file! ");
+        }
+        if options.record.is_some() && !options.check {
+            return Err("--record only works with check! ");
+        }
+        Ok(options)
+    }
+
+    pub fn rlpx_config(&self) -> RLPxConfig {
+        let mut config = RLPxConfig::default().with_network(self.network);
+        match self.client_versions.as_slice() {
+            [] => {}
+            [version] => config.client_version = ClientVersion::Fixed(version.clone()),
+            versions => config.client_version = ClientVersion::Random(versions.to_vec()),
+        }
+        if let Some(listen) = self.listen {
+            config.listen_port = listen.port();
+        }
+        if !self.capabilities.is_empty() {
+            config.capabilities = self.capabilities.clone();
+        }
+        if let Some(network_id) = self.network_id {
+            config.network_id = network_id;
+        }
+        if let Some(genesis_hash) = self.genesis_hash {
+            config = config.with_genesis(genesis_hash.0);
+        }
+        config.eth_status = !self.skip_eth_status;
+        config.max_session_memory = self.max_session_memory;
+        config
+    }
+
+    fn value<T: FromStr>(
+        args: &mut impl Iterator<Item = String>,
+        err: &'static str,
+    ) -> Result<T, &'static str> {
+        args.next().ok_or(err)?.parse().map_err(|_| err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn options(args: &[&str]) -> Options {
+        Options::from_args(args.iter().map(|arg| arg.to_string())).unwrap()
+    }
+
+    #[test]
+    fn genesis_hash_override_brings_its_own_fork_id() {
+        let preset = options(&["--network", "sepolia"]).rlpx_config();
+        let genesis = "0x1111111111111111111111111111111111111111111111111111111111111111";
+        let custom = options(&["--network", "sepolia", "--genesis-hash", genesis]).rlpx_config();
+        assert_eq!(custom.genesis_hash, [0x11; 32]);
+        assert_eq!(custom.best_block_hash, [0x11; 32]);
+        assert_eq!(custom.network_id, preset.network_id);
+        assert_eq!(custom.fork_id, RLPxConfig::default().with_genesis([0x11; 32]).fork_id);
+        assert_ne!(custom.fork_id, preset.fork_id);
+    }
+}
@@ -0,0 +1,398 @@
+use std::{
+    fmt,
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use alloy_primitives::hex;
+use secp256k1::rand::{seq::SliceRandom, thread_rng};
+
+use crate::{
+    ecies::EciesRng,
+    messages::{Capability, ForkId, RLPx_Message},
+};
+
+pub const DEFAULT_CLIENT_VERSION: &str = "reth_hello";
+// Auth and ack are a few hundred bytes even with EIP-8 padding, this leaves
+// plenty of room while not letting a peer make us buffer up to 64 KiB.
+pub const DEFAULT_MAX_AUTH_MESSAGE_SIZE: usize = 2048;
+// Real clients advertise a handful, a Hello with more is up to no good.
+pub const DEFAULT_MAX_PEER_CAPABILITIES: usize = 64;
+// Real client versions run to a few dozen bytes, "Geth/v1.14.11-stable-
+// f3c696fa/linux-amd64/go1.23.2" say. Anything past this only takes up
+// memory and log space.
+pub const DEFAULT_MAX_CLIENT_VERSION_LEN: usize = 256;
+// Keepalives come every 15s or so, anything near this is a peer making us
+// burn CPU on Pongs.
+pub const DEFAULT_MAX_PINGS_PER_SECOND: u32 = 5;
+pub const DEFAULT_RESOLVE_TIMEOUT: Duration = Duration::from_secs(5);
+pub const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+// Refused connects are common enough with busy nodes to be worth a retry or two.
+pub const DEFAULT_CONNECT_ATTEMPTS: u32 = 3;
+pub const DEFAULT_CONNECT_RETRY_DELAY: Duration = Duration::from_millis(500);
+
+// Limits for what happens before the handshake, none of it counts against
+// any handshake timeout.
+#[derive(Clone, Copy, Debug)]
+pub struct DialConfig {
+    // Per DNS lookup.
+    pub resolve_timeout: Duration,
+    // Per TCP connect attempt.
+    pub connect_timeout: Duration,
+    pub connect_attempts: u32,
+    pub connect_retry_delay: Duration,
+    pub mapped_ipv4: MappedIpv4,
+}
+
+impl Default for DialConfig {
+    fn default() -> Self {
+        Self {
+            resolve_timeout: DEFAULT_RESOLVE_TIMEOUT,
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+            connect_attempts: DEFAULT_CONNECT_ATTEMPTS,
+            connect_retry_delay: DEFAULT_CONNECT_RETRY_DELAY,
+            mapped_ipv4: MappedIpv4::default(),
+        }
+    }
+}
+
+impl DialConfig {
+    // The address we actually dial for the one we were given. Only IPv4
+    // addresses written as ::ffff:a.b.c.d ever change.
+    pub fn normalize(&self, address: SocketAddr) -> SocketAddr {
+        match (self.mapped_ipv4, address.ip()) {
+            (MappedIpv4::V4, IpAddr::V6(ip)) => match ip.to_ipv4_mapped() {
+                Some(ip) => SocketAddr::new(IpAddr::V4(ip), address.port()),
+                None => address,
+            },
+            _ => address,
+        }
+    }
+}
+
+// How to dial an IPv4 address written as ::ffff:a.b.c.d. Over IPv4 always
+// works. Over IPv6 needs a dual-stack socket, which some systems turn off
+// (net.ipv6.bindv6only, or IPv6 disabled altogether).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum MappedIpv4 {
+    #[default]
+    V4,
+    V6,
+}
+
+impl FromStr for MappedIpv4 {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "v4" => Ok(MappedIpv4::V4),
+            "v6" => Ok(MappedIpv4::V6),
+            _ => Err("Unknown address family, expected v4 or v6 "),
+        }
+    }
+}
+
+// What we advertise as our client in Hello.
+#[derive(Clone, Debug)]
+pub enum ClientVersion {
+    Fixed(String),
+    // A fresh pick for every handshake, so we can't be tracked by a static string.
+    Random(Vec<String>),
+}
+
+impl ClientVersion {
+    pub fn pick(&self) -> String {
+        match self {
+            ClientVersion::Fixed(version) => version.clone(),
+            ClientVersion::Random(versions) => versions
+                .choose(&mut thread_rng())
+                .cloned()
+                .unwrap_or_else(|| DEFAULT_CLIENT_VERSION.to_string()),
+        }
+    }
+}
+
+// Knobs for a single RLPx session.
+#[derive(Clone, Debug)]
+pub struct RLPxConfig {
+    pub client_version: ClientVersion,
+    // What we advertise in Hello, in any order.
+    pub capabilities: Vec<Capability>,
+    // Largest auth-ack size prefix we'll wait on, prefix included.
+    pub max_auth_message_size: usize,
+    // Most capabilities we'll take from a peer's Hello before disconnecting it.
+    pub max_peer_capabilities: usize,
+    // Longest client version, in bytes, we'll take from a peer's Hello before
+    // disconnecting it.
+    pub max_client_version_len: usize,
+    // Most Pings we'll take from a peer within a second before disconnecting it.
+    pub max_pings_per_second: u32,
+    // Most bytes a session may hold on to at once: the frame being read, what
+    // waits to go out and the ids of requests still unanswered. None for no
+    // limit beyond the individual ones.
+    pub max_session_memory: Option<usize>,
+    // Advertised in Hello, 0 when we're not listening.
+    pub listen_port: u16,
+    // Whether to send our eth Status right after Hello when eth is
+    // negotiated, the handshake then waits on the peer's Status too.
+    pub eth_status: bool,
+    // What goes into that Status. Peers on another chain hang up on us with
+    // "Useless peer", so these have to match the network we dial into.
+    pub network_id: u64,
+    pub genesis_hash: [u8; 32],
+    pub total_difficulty: u128,
+    pub best_block_hash: [u8; 32],
+    pub fork_id: ForkId,
+    // Sent in this order once the handshake is done, e.g. a Ping or a probe.
+    pub initial_messages: Vec<RLPx_Message>,
+    // Randomness for the handshake. Every session built from a seeded config
+    // starts from the same seed, which is the point for tests only.
+    pub ecies_rng: EciesRng,
+    // Told about peers whose Hello shares no capability with ours.
+    pub on_capability_mismatch: Option<CapabilityMismatchHook>,
+    // DANGER: takes every frame as authentic, whoever sent or mangled it.
+    // Anyone on the path can then feed us whatever they like. Only for
+    // fuzzing and for testing decoding against deliberately broken frames.
+    #[cfg(any(test, feature = "unsafe-no-mac"))]
+    pub unsafe_skip_mac_verification: bool,
+}
+
+// Gets our capabilities and the peer's when they have none in common, which
+// otherwise gets the peer disconnected as useless. Returning true keeps the
+// session going anyway, at the p2p level only. Crawlers can also just use it
+// to see what peers advertise.
+type CapabilityMismatchFn = dyn Fn(&[Capability], &[Capability]) -> bool + Send + Sync;
+
+#[derive(Clone)]
+pub struct CapabilityMismatchHook(Arc<CapabilityMismatchFn>);
+
+impl CapabilityMismatchHook {
+    pub fn new(hook: impl Fn(&[Capability], &[Capability]) -> bool + Send + Sync + 'static) -> Self {
+        Self(Arc::new(hook))
+    }
+
+    pub fn call(&self, ours: &[Capability], theirs: &[Capability]) -> bool {
+        (self.0)(ours, theirs)
+    }
+}
+
+impl fmt::Debug for CapabilityMismatchHook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("CapabilityMismatchHook")
+    }
+}
+
+impl Default for RLPxConfig {
+    fn default() -> Self {
+        Self {
+            client_version: ClientVersion::Fixed(DEFAULT_CLIENT_VERSION.to_string()),
+            capabilities: vec![Capability {
+                name: "eth".to_string(),
+                version: 68,
+            }],
+            max_auth_message_size: DEFAULT_MAX_AUTH_MESSAGE_SIZE,
+            max_peer_capabilities: DEFAULT_MAX_PEER_CAPABILITIES,
+            max_client_version_len: DEFAULT_MAX_CLIENT_VERSION_LEN,
+            max_pings_per_second: DEFAULT_MAX_PINGS_PER_SECOND,
+            max_session_memory: None,
+            listen_port: 0,
+            eth_status: true,
+            network_id: 0,
+            genesis_hash: [0; 32],
+            total_difficulty: 0,
+            best_block_hash: [0; 32],
+            fork_id: ForkId { hash: [0; 4], next: 0 },
+            initial_messages: Vec::new(),
+            ecies_rng: EciesRng::default(),
+            on_capability_mismatch: None,
+            #[cfg(any(test, feature = "unsafe-no-mac"))]
+            unsafe_skip_mac_verification: false,
+        }
+        .with_network(Network::Mainnet)
+    }
+}
+
+impl RLPxConfig {
+    // Fills in the Status fields for a known network. We don't follow the
+    // chain, so the best block we claim is the genesis and the fork id is the
+    // one for a node at the tip right now.
+    pub fn with_network(mut self, network: Network) -> Self {
+        let genesis_hash = network.genesis_hash();
+        self.network_id = network.network_id();
+        self.genesis_hash = genesis_hash;
+        self.total_difficulty = network.total_difficulty();
+        self.best_block_hash = genesis_hash;
+        self.fork_id = fork_id(&genesis_hash, network.block_forks(), network.time_forks());
+        self
+    }
+
+    // A chain we have no preset for. Its forks are unknown, so the fork id is
+    // the one for the bare genesis, which is what its peers expect when every
+    // fork was active from block 0.
+    pub fn with_genesis(mut self, genesis_hash: [u8; 32]) -> Self {
+        self.genesis_hash = genesis_hash;
+        self.best_block_hash = genesis_hash;
+        self.fork_id = fork_id(&genesis_hash, &[], &[]);
+        self
+    }
+}
+
+// Networks we have Status presets for.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Network {
+    #[default]
+    Mainnet,
+    Sepolia,
+    Holesky,
+}
+
+impl Network {
+    pub fn network_id(&self) -> u64 {
+        match self {
+            Network::Mainnet => 1,
+            Network::Sepolia => 11155111,
+            Network::Holesky => 17000,
+        }
+    }
+
+    pub fn genesis_hash(&self) -> [u8; 32] {
+        match self {
+            Network::Mainnet => hex!("d4e56740f876aef8c010b86a40d5f56745a118d0906a34e69aec8c0db1cb8fa3"),
+            Network::Sepolia => hex!("25a5cc106eea7138acab33231d7160d69cb777ee0c2c553fcddf5138993e6dd9"),
+            Network::Holesky => hex!("b5f7f912443c940f21fd611f12828d75b534364ed9e95ca4e307729a4661bde4"),
+        }
+    }
+
+    // Frozen at the merge.
+    pub fn total_difficulty(&self) -> u128 {
+        match self {
+            Network::Mainnet => 58_750_003_716_598_352_816_469,
+            Network::Sepolia => 17_000_000_000_000_000,
+            Network::Holesky => 1,
+        }
+    }
+
+    // EIP-2124 fork points, forks active from genesis left out.
+    fn block_forks(&self) -> &'static [u64] {
+        match self {
+            Network::Mainnet => &[
+                1_150_000, 1_920_000, 2_463_000, 2_675_000, 4_370_000, 7_280_000, 9_069_000, 9_200_000,
+                12_244_000, 12_965_000, 13_773_000, 15_050_000,
+            ],
+            Network::Sepolia => &[1_735_371],
+            Network::Holesky => &[],
+        }
+    }
+
+    // Shanghai, Cancun, Prague, Osaka, BPO1 and BPO2.
+    fn time_forks(&self) -> &'static [u64] {
+        match self {
+            Network::Mainnet => &[1681338455, 1710338135, 1746612311, 1764798551, 1765290071, 1767747671],
+            Network::Sepolia => &[1677557088, 1706655072, 1741159776, 1760427360, 1761017184, 1761607008],
+            Network::Holesky => &[1696000704, 1707305664, 1740434112, 1759308480, 1759800000, 1760389824],
+        }
+    }
+}
+
+impl FromStr for Network {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "mainnet" => Ok(Network::Mainnet),
+            "sepolia" => Ok(Network::Sepolia),
+            "holesky" => Ok(Network::Holesky),
+            _ => Err("Unknown network, expected mainnet, sepolia or holesky "),
+        }
+    }
+}
+
+// EIP-2124: a CRC32 over the genesis hash and then every fork passed, as
+// 8 byte big endian numbers. Block forks are all behind any synced node, time
+// forks are passed once their timestamp is.
+fn fork_id(genesis_hash: &[u8; 32], block_forks: &[u64], time_forks: &[u64]) -> ForkId {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |now| now.as_secs());
+    let mut hash = crc32(0, genesis_hash);
+    for fork in block_forks {
+        hash = crc32(hash, &fork.to_be_bytes());
+    }
+    for &fork in time_forks {
+        if fork > now {
+            return ForkId {
+                hash: hash.to_be_bytes(),
+                next: fork,
+            };
+        }
+        hash = crc32(hash, &fork.to_be_bytes());
+    }
+    ForkId {
+        hash: hash.to_be_bytes(),
+        next: 0,
+    }
+}
+
+// IEEE CRC32, picking up from a previous checksum.
+fn crc32(crc: u32, data: &[u8]) -> u32 {
+    let mut crc = !crc;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 { (crc >> 1) ^ 0xedb8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::active_codecs;
+
+    const MAINNET_GENESIS: [u8; 32] = hex!("d4e56740f876aef8c010b86a40d5f56745a118d0906a34e69aec8c0db1cb8fa3");
+
+    #[test]
+    fn mainnet_status_carries_its_network_id_and_genesis() {
+        let (initiator, _) = active_codecs();
+        let status = initiator.eth_status().unwrap();
+        assert_eq!(status.version, 68);
+        assert_eq!(status.networkid, 1);
+        assert_eq!(status.genesis, MAINNET_GENESIS);
+        assert_eq!(status.blockhash, MAINNET_GENESIS);
+
+        let sepolia = RLPxConfig::default().with_network(Network::Sepolia);
+        assert_eq!(sepolia.network_id, 11155111);
+        assert_eq!(sepolia.genesis_hash, hex!("25a5cc106eea7138acab33231d7160d69cb777ee0c2c553fcddf5138993e6dd9"));
+    }
+
+    #[test]
+    fn mainnet_fork_ids_match_eip_2124() {
+        let mainnet = Network::Mainnet;
+        let unsynced = fork_id(&MAINNET_GENESIS, &[], &[]);
+        assert_eq!(unsynced.hash, hex!("fc64ec04"));
+        let gray_glacier = fork_id(&MAINNET_GENESIS, mainnet.block_forks(), &[]);
+        assert_eq!(gray_glacier, ForkId { hash: hex!("f0afd0e3"), next: 0 });
+        // Shanghai and Cancun passed.
+        let cancun = fork_id(&MAINNET_GENESIS, mainnet.block_forks(), &mainnet.time_forks()[..2]);
+        assert_eq!(cancun.hash, hex!("9f3d2254"));
+        // A fork still to come is announced as next, without going into the hash.
+        let ahead = fork_id(&MAINNET_GENESIS, mainnet.block_forks(), &[u64::MAX]);
+        assert_eq!(ahead, ForkId { hash: hex!("f0afd0e3"), next: u64::MAX });
+    }
+
+    #[test]
+    fn custom_genesis_replaces_the_preset_fork_id() {
+        let config = RLPxConfig::default().with_genesis(MAINNET_GENESIS);
+        assert_eq!(config.genesis_hash, MAINNET_GENESIS);
+        assert_eq!(config.best_block_hash, MAINNET_GENESIS);
+        // Mainnet's genesis with no forks passed is EIP-2124's unsynced id.
+        assert_eq!(config.fork_id, ForkId { hash: hex!("fc64ec04"), next: 0 });
+
+        let custom = RLPxConfig::default().with_genesis([0x11; 32]);
+        assert_eq!(custom.fork_id, fork_id(&[0x11; 32], &[], &[]));
+        assert_ne!(custom.fork_id, RLPxConfig::default().fork_id);
+    }
+}
@@ -0,0 +1,130 @@
+// `crawl`: dials the seed peers and, with --neighbors, whoever they say they
+// know of over discv4, and on from there, until --max-discovered nodes have
+// turned up or there's nobody left to dial. Each peer that makes it through
+// the handshake and listens for connections gets a line in the --out file:
+//   <enode> <client version, JSON quoted>
+//
+// The file is appended to across runs, and nodes already in it aren't dialed
+// again, so a crawl can be stopped and picked up later.
+use std::{
+    collections::{HashSet, VecDeque},
+    fs::{self, File, OpenOptions},
+    io::{ErrorKind, Write},
+    net::SocketAddr,
+};
+
+use futures::{stream::FuturesUnordered, StreamExt};
+use log::{info, warn};
+use secp256k1::PublicKey;
+use tokio::sync::watch;
+
+use crate::{
+    dial_peer, discv4, nodekey::split_enode, redact, report::PeerReport, sessions::SessionManager, DialContext,
+};
+
+pub struct CrawlFile {
+    file: File,
+    // Everyone the file has a line for already.
+    recorded: HashSet<PublicKey>,
+}
+
+impl CrawlFile {
+    pub fn open(path: &str) -> Result<Self, &'static str> {
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == ErrorKind::NotFound => String::new(),
+            Err(_) => return Err("Could not read the crawl file! "),
+        };
+        let recorded = contents
+            .lines()
+            .filter_map(|line| line.split_whitespace().next())
+            .filter_map(|enode| split_enode(enode).ok())
+            .map(|(public_key, _)| public_key)
+            .collect();
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|_| "Could not open the crawl file! ")?;
+        Ok(Self { file, recorded })
+    }
+
+    fn record(&mut self, public_key: PublicKey, enode: &str, client_version: &str) {
+        if !self.recorded.insert(public_key) {
+            return;
+        }
+        let line = format!("{} {}\n", enode, serde_json::Value::from(client_version));
+        if let Err(err) = self.file.write_all(line.as_bytes()) {
+            warn!("Could not write to the crawl file: {}", err);
+        }
+    }
+}
+
+pub async fn run(
+    dial: &DialContext<'_>,
+    sessions: &SessionManager,
+    shutdown: watch::Receiver<bool>,
+    seeds: Vec<(PublicKey, Vec<SocketAddr>)>,
+    mut out: CrawlFile,
+) -> Vec<PeerReport> {
+    let options = dial.options;
+    let mut seen: HashSet<PublicKey> = out.recorded.clone();
+    let mut frontier = VecDeque::new();
+    for (public_key, addresses) in seeds {
+        if seen.insert(public_key) {
+            frontier.push_back((public_key, addresses));
+        } else {
+            info!("Already crawled {}, not dialing it again", redact::public_key(&public_key));
+        }
+    }
+
+    let mut discovered = 0;
+    let mut dialing = FuturesUnordered::new();
+    let mut reports = Vec::new();
+    loop {
+        while dialing.len() < options.concurrency && !*shutdown.borrow() {
+            let Some((public_key, addresses)) = frontier.pop_front() else {
+                break;
+            };
+            let shutdown = shutdown.clone();
+            dialing.push(async move {
+                let report = dial_peer(dial, sessions, public_key, addresses, shutdown).await;
+                let neighbors = if options.crawl_neighbors && report.handshake_complete {
+                    discv4::neighbors(&dial.private_key, &public_key, report.address, discv4::DEFAULT_NEIGHBORS_TIMEOUT)
+                        .await
+                        .inspect_err(|err| info!("No neighbors from {}: {}", report.address, err))
+                        .unwrap_or_default()
+                } else {
+                    Vec::new()
+                };
+                (public_key, report, neighbors)
+            });
+        }
+        let Some((public_key, report, neighbors)) = dialing.next().await else {
+            break;
+        };
+
+        if let (true, Some(enode), Some(client_version)) =
+            (report.handshake_complete, &report.enode, &report.client_version)
+        {
+            out.record(public_key, enode, client_version);
+        }
+        let mut new = 0;
+        for (public_key, address) in neighbors {
+            if discovered >= options.max_discovered {
+                break;
+            }
+            if seen.insert(public_key) {
+                frontier.push_back((public_key, vec![address]));
+                discovered += 1;
+                new += 1;
+            }
+        }
+        if new > 0 {
+            info!("{} new peers from {}, {} waiting to be dialed", new, report.address, frontier.len());
+        }
+        reports.push(report);
+    }
+    info!("Crawl done, {} peers recorded in total", out.recorded.len());
+    reports
+}
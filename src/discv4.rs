@@ -0,0 +1,347 @@
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use alloy_primitives::{keccak256, Bytes, B256, B512};
+use alloy_rlp::{Decodable, Encodable, Header, RlpDecodable, RlpEncodable};
+use log::debug;
+use secp256k1::{
+    ecdsa::{RecoverableSignature, RecoveryId},
+    rand::{thread_rng, RngCore},
+    Message, PublicKey, SecretKey, SECP256K1,
+};
+use tokio::{
+    net::UdpSocket,
+    time::{timeout_at, Instant},
+};
+
+// discv4 packet = hash || signature || packet-type || packet-data
+//   hash = keccak256(signature || packet-type || packet-data)
+//   signature = sign(keccak256(packet-type || packet-data)), 64 bytes || recovery id
+const HASH_SIZE: usize = 32;
+const SIGNATURE_SIZE: usize = 65;
+const HEADER_SIZE: usize = HASH_SIZE + SIGNATURE_SIZE;
+const PING: u8 = 0x01;
+const PONG: u8 = 0x02;
+const FIND_NODE: u8 = 0x03;
+const NEIGHBORS: u8 = 0x04;
+const DISCOVERY_VERSION: u8 = 4;
+// The spec caps packets at 1280 bytes.
+const MAX_PACKET_SIZE: usize = 1280;
+// How far ahead our Ping expires, peers drop ones that already have.
+const PING_EXPIRATION: Duration = Duration::from_secs(20);
+pub const DEFAULT_PONG_TIMEOUT: Duration = Duration::from_secs(2);
+// Ping, Pong, the node's Ping back and FindNode, then the Neighbors.
+pub const DEFAULT_NEIGHBORS_TIMEOUT: Duration = Duration::from_secs(4);
+// A full Kademlia bucket, which is as many as a FindNode gets back. It comes
+// split over a couple of Neighbors packets.
+const MAX_NEIGHBORS: usize = 16;
+// How long to give the node to Ping us back once it answered our Ping. Nodes
+// only answer FindNode from endpoints that have answered a Ping of theirs.
+const PING_BACK_TIMEOUT: Duration = Duration::from_millis(500);
+const NO_ANSWER: &str = "No discv4 answer in time";
+
+#[derive(Debug, RlpEncodable, RlpDecodable)]
+struct Endpoint {
+    // 4 or 16 bytes.
+    ip: Bytes,
+    udp: u16,
+    tcp: u16,
+}
+
+impl Endpoint {
+    fn new(address: SocketAddr, tcp: u16) -> Self {
+        let ip = match address.ip() {
+            IpAddr::V4(ip) => Bytes::copy_from_slice(&ip.octets()),
+            IpAddr::V6(ip) => Bytes::copy_from_slice(&ip.octets()),
+        };
+        Self {
+            ip,
+            udp: address.port(),
+            tcp,
+        }
+    }
+}
+
+#[derive(RlpEncodable)]
+struct Ping {
+    version: u8,
+    from: Endpoint,
+    to: Endpoint,
+    expiration: u64,
+}
+
+// Pong = [to, ping-hash, expiration, enr-seq, ...], newer fields are left
+// unread the same way Hello and Status allow for them.
+#[derive(RlpEncodable)]
+struct Pong {
+    to: Endpoint,
+    ping_hash: B256,
+    expiration: u64,
+}
+
+impl Decodable for Pong {
+    fn decode(buf: &mut &[u8]) -> alloy_rlp::Result<Self> {
+        let mut payload = Header::decode_bytes(buf, true)?;
+        Ok(Self {
+            to: Decodable::decode(&mut payload)?,
+            ping_hash: Decodable::decode(&mut payload)?,
+            expiration: Decodable::decode(&mut payload)?,
+        })
+    }
+}
+
+#[derive(RlpEncodable)]
+struct FindNode {
+    target: B512,
+    expiration: u64,
+}
+
+#[derive(RlpDecodable)]
+struct Neighbor {
+    // 4 or 16 bytes.
+    ip: Bytes,
+    _udp: u16,
+    tcp: u16,
+    id: B512,
+}
+
+// Neighbors = [[neighbor, ...], expiration, ...]
+struct Neighbors {
+    nodes: Vec<Neighbor>,
+    expiration: u64,
+}
+
+impl Decodable for Neighbors {
+    fn decode(buf: &mut &[u8]) -> alloy_rlp::Result<Self> {
+        let mut payload = Header::decode_bytes(buf, true)?;
+        Ok(Self {
+            nodes: Decodable::decode(&mut payload)?,
+            expiration: Decodable::decode(&mut payload)?,
+        })
+    }
+}
+
+// A UDP socket of our own for talking discv4 with one node at one address.
+struct Exchange<'a> {
+    socket: UdpSocket,
+    private_key: &'a SecretKey,
+    peer: &'a PublicKey,
+    address: SocketAddr,
+    // Whether we've answered a Ping of the node's yet.
+    pinged_back: bool,
+}
+
+impl<'a> Exchange<'a> {
+    async fn open(private_key: &'a SecretKey, peer: &'a PublicKey, address: SocketAddr) -> Result<Self, &'static str> {
+        let local_address: SocketAddr = if address.is_ipv4() {
+            (Ipv4Addr::UNSPECIFIED, 0).into()
+        } else {
+            (Ipv6Addr::UNSPECIFIED, 0).into()
+        };
+        let socket = UdpSocket::bind(local_address)
+            .await
+            .map_err(|_| "Could not open a UDP socket for discv4")?;
+        Ok(Self {
+            socket,
+            private_key,
+            peer,
+            address,
+            pinged_back: false,
+        })
+    }
+
+    // Returns the packet's hash, which is what a Pong refers back to.
+    async fn send(&self, packet_type: u8, message: &impl Encodable) -> Result<B256, &'static str> {
+        let mut packet_data = Vec::new();
+        message.encode(&mut packet_data);
+        let packet = encode_packet(self.private_key, packet_type, &packet_data);
+        self.socket
+            .send_to(&packet, self.address)
+            .await
+            .map_err(|_| "Could not send to the discv4 socket")?;
+        Ok(B256::from_slice(&packet[..HASH_SIZE]))
+    }
+
+    // The next packet from the node, with its type. Its Pings are answered on
+    // the way, and still handed back so a caller can tell. Whatever else turns
+    // up on the socket is ignored.
+    async fn recv(&mut self, deadline: Instant) -> Result<(u8, Vec<u8>), &'static str> {
+        let mut buf = [0; MAX_PACKET_SIZE];
+        loop {
+            let (size, from) = timeout_at(deadline, self.socket.recv_from(&mut buf))
+                .await
+                .map_err(|_| NO_ANSWER)?
+                .map_err(|_| "Could not read from the discv4 socket")?;
+            if from != self.address {
+                debug!("Ignoring a datagram from {} on the discv4 socket", from);
+                continue;
+            }
+            let (signer, packet_type, packet_data) = match decode_packet(&buf[..size]) {
+                Ok(packet) => packet,
+                Err(err) => {
+                    debug!("Ignoring a bad discv4 packet from {}: {}", from, err);
+                    continue;
+                }
+            };
+            if signer != *self.peer {
+                return Err("discv4 packet signed by another node");
+            }
+            if packet_type == PING {
+                let pong = Pong {
+                    to: Endpoint::new(from, from.port()),
+                    ping_hash: B256::from_slice(&buf[..HASH_SIZE]),
+                    expiration: unix_time() + PING_EXPIRATION.as_secs(),
+                };
+                self.send(PONG, &pong).await?;
+                self.pinged_back = true;
+            }
+            return Ok((packet_type, packet_data.to_vec()));
+        }
+    }
+
+    // Ping and wait for the matching Pong.
+    async fn ping(&mut self, deadline: Instant) -> Result<(), &'static str> {
+        let from = self.socket.local_addr().map_err(|_| "Could not open a UDP socket for discv4")?;
+        let ping = Ping {
+            version: DISCOVERY_VERSION,
+            from: Endpoint::new(from, 0),
+            // Enodes only carry the one port, discovery runs on the same number.
+            to: Endpoint::new(self.address, self.address.port()),
+            expiration: unix_time() + PING_EXPIRATION.as_secs(),
+        };
+        let ping_hash = self.send(PING, &ping).await?;
+        loop {
+            let (packet_type, packet_data) = self
+                .recv(deadline)
+                .await
+                .map_err(|err| if err == NO_ANSWER { "No discv4 Pong in time" } else { err })?;
+            if packet_type != PONG {
+                debug!("Ignoring discv4 packet type {:#x} while waiting on a Pong", packet_type);
+                continue;
+            }
+            let pong = Pong::decode(&mut &packet_data[..]).map_err(|_| "discv4 Pong decode error")?;
+            if pong.ping_hash != ping_hash {
+                debug!("Ignoring a Pong to some other Ping");
+                continue;
+            }
+            if pong.expiration < unix_time() {
+                return Err("discv4 Pong already expired");
+            }
+            return Ok(());
+        }
+    }
+}
+
+// Sends the node a discv4 Ping at address and waits for its Pong, which has
+// to be signed by the node's key and echo our Ping's hash. A node that
+// answers is up and is who the enode says, at least as far as discovery
+// goes.
+pub async fn ping(
+    private_key: &SecretKey,
+    peer: &PublicKey,
+    address: SocketAddr,
+    pong_timeout: Duration,
+) -> Result<(), &'static str> {
+    let mut exchange = Exchange::open(private_key, peer, address).await?;
+    exchange.ping(Instant::now() + pong_timeout).await
+}
+
+// Asks the node which other nodes it knows of, with a FindNode for a random
+// target after the Ping/Pong it insists on. Returns them with their RLPx
+// address, up to a bucket's worth, however many came in time. Nodes that
+// don't listen for RLPx are left out.
+pub async fn neighbors(
+    private_key: &SecretKey,
+    peer: &PublicKey,
+    address: SocketAddr,
+    neighbors_timeout: Duration,
+) -> Result<Vec<(PublicKey, SocketAddr)>, &'static str> {
+    let deadline = Instant::now() + neighbors_timeout;
+    let mut exchange = Exchange::open(private_key, peer, address).await?;
+    exchange.ping(deadline).await?;
+    // A node that knows us from before won't Ping again, so only wait a bit.
+    let ping_back_deadline = deadline.min(Instant::now() + PING_BACK_TIMEOUT);
+    while !exchange.pinged_back && exchange.recv(ping_back_deadline).await.is_ok() {}
+
+    let mut target = [0; 64];
+    thread_rng().fill_bytes(&mut target);
+    let find_node = FindNode {
+        target: B512::from(target),
+        expiration: unix_time() + PING_EXPIRATION.as_secs(),
+    };
+    exchange.send(FIND_NODE, &find_node).await?;
+
+    let mut found = Vec::new();
+    while found.len() < MAX_NEIGHBORS {
+        let (packet_type, packet_data) = match exchange.recv(deadline).await {
+            Ok(packet) => packet,
+            Err(_) if !found.is_empty() => break,
+            Err(_) => return Err("No discv4 Neighbors in time"),
+        };
+        if packet_type != NEIGHBORS {
+            continue;
+        }
+        let neighbors = Neighbors::decode(&mut &packet_data[..]).map_err(|_| "discv4 Neighbors decode error")?;
+        if neighbors.expiration < unix_time() {
+            return Err("discv4 Neighbors already expired");
+        }
+        for neighbor in neighbors.nodes {
+            let mut key = [0x04; 65];
+            key[1..].copy_from_slice(neighbor.id.as_slice());
+            let ip = match neighbor.ip.len() {
+                4 => IpAddr::from(<[u8; 4]>::try_from(&neighbor.ip[..]).unwrap()),
+                16 => IpAddr::from(<[u8; 16]>::try_from(&neighbor.ip[..]).unwrap()),
+                _ => continue,
+            };
+            match PublicKey::from_slice(&key) {
+                Ok(public_key) if neighbor.tcp != 0 => found.push((public_key, SocketAddr::new(ip, neighbor.tcp))),
+                _ => debug!("Leaving out neighbor {:?}, no key or no RLPx port", neighbor.id),
+            }
+        }
+    }
+    Ok(found)
+}
+
+fn encode_packet(private_key: &SecretKey, packet_type: u8, packet_data: &[u8]) -> Vec<u8> {
+    let mut signed = Vec::with_capacity(1 + packet_data.len());
+    signed.push(packet_type);
+    signed.extend_from_slice(packet_data);
+    let (recovery_id, signature) = SECP256K1
+        .sign_ecdsa_recoverable(&Message::from_digest(keccak256(&signed).0), private_key)
+        .serialize_compact();
+
+    let mut packet = vec![0; HASH_SIZE];
+    packet.extend_from_slice(&signature);
+    packet.push(recovery_id.to_i32() as u8);
+    packet.extend_from_slice(&signed);
+    let hash = keccak256(&packet[HASH_SIZE..]);
+    packet[..HASH_SIZE].copy_from_slice(hash.as_slice());
+    packet
+}
+
+// Checks the hash and recovers who signed it, returns the signer, the
+// packet type and the packet data.
+fn decode_packet(packet: &[u8]) -> Result<(PublicKey, u8, &[u8]), &'static str> {
+    if packet.len() <= HEADER_SIZE {
+        return Err("Packet too short");
+    }
+    if keccak256(&packet[HASH_SIZE..]) != packet[..HASH_SIZE] {
+        return Err("Packet hash mismatch");
+    }
+    let signature = &packet[HASH_SIZE..HEADER_SIZE];
+    let recovery_id = RecoveryId::from_i32(signature[64] as i32).map_err(|_| "Invalid recovery id")?;
+    let signature = RecoverableSignature::from_compact(&signature[..64], recovery_id).map_err(|_| "Invalid signature")?;
+    let signed = &packet[HEADER_SIZE..];
+    let signer = SECP256K1
+        .recover_ecdsa(&Message::from_digest(keccak256(signed).0), &signature)
+        .map_err(|_| "Could not recover the signer")?;
+    Ok((signer, signed[0], &signed[1..]))
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |now| now.as_secs())
+}
@@ -0,0 +1,437 @@
+// EIP-1459 node discovery, see https://eips.ethereum.org/EIPS/eip-1459
+//
+// The tree lives in DNS TXT records:
+//   <domain>          -> enrtree-root:v1 e=<enr-root> l=<link-root> seq=<n> sig=<sig>
+//   <hash>.<domain>   -> enrtree-branch:<h1>,<h2>,...,<hn>
+//   <hash>.<domain>   -> enr:<node-record>
+//   <hash>.<domain>   -> enrtree://<key>@<fqdn>
+//
+// We only walk the ENR subtree (e=), links to other trees are logged and skipped.
+use std::{
+    collections::{HashSet, VecDeque},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::Duration,
+};
+
+use data_encoding::{BASE32_NOPAD, BASE64URL_NOPAD};
+use hickory_resolver::TokioAsyncResolver;
+use log::{debug, info, warn};
+use rlp::{Rlp, RlpStream};
+use secp256k1::{ecdsa::Signature, Message, PublicKey, SECP256K1};
+use sha3::{Digest, Keccak256};
+use tokio::time::timeout;
+
+use crate::nodekey::node_pubkey_from_bytes;
+
+pub const ENRTREE_PREFIX: &str = "enrtree://";
+const ROOT_PREFIX: &str = "enrtree-root:v1";
+const BRANCH_PREFIX: &str = "enrtree-branch:";
+const ENR_PREFIX: &str = "enr:";
+
+// EIP-778 caps a record at 300 bytes.
+const MAX_ENR_SIZE: usize = 300;
+// Hard stop so a broken (or hostile) tree can't keep us resolving forever.
+const MAX_TREE_LOOKUPS: usize = 1000;
+
+pub struct EnrTreeUrl {
+    public_key: PublicKey,
+    domain: String,
+}
+
+impl EnrTreeUrl {
+    pub fn parse(url: &str) -> Result<Self, &'static str> {
+        let (prefix, rest) = url
+            .split_once(ENRTREE_PREFIX)
+            .ok_or("Invalid enrtree prefix! ")?;
+
+        if !prefix.is_empty() {
+            return Err("Invalid enrtree prefix location! ");
+        }
+
+        let (key, domain) = rest.split_once('@').ok_or("Invalid enrtree domain delimiter! ")?;
+
+        if domain.is_empty() {
+            return Err("Empty enrtree domain! ");
+        }
+
+        // The key is a base32 encoded compressed secp256k1 public key.
+        let key = BASE32_NOPAD
+            .decode(key.trim_end_matches('=').as_bytes())
+            .map_err(|_| "Invalid enrtree key encoding! ")?;
+
+        let public_key = PublicKey::from_slice(&key).map_err(|_| "Invalid enrtree public key! ")?;
+
+        Ok(Self {
+            public_key,
+            domain: domain.to_string(),
+        })
+    }
+}
+
+struct TreeRoot {
+    enr_root: String,
+    // Bumped on every republish. We walk the tree once per run and keep
+    // nothing from the last walk, so there's no older root to compare it
+    // with, it only goes in the log.
+    seq: u64,
+}
+
+impl TreeRoot {
+    fn parse(record: &str, public_key: &PublicKey) -> Result<Self, &'static str> {
+        let (content, sig) = record
+            .rsplit_once(" sig=")
+            .ok_or("Tree root has no signature! ")?;
+
+        let mut fields = content.split(' ');
+        if fields.next() != Some(ROOT_PREFIX) {
+            return Err("Invalid tree root prefix! ");
+        }
+
+        let mut enr_root = None;
+        let mut seq = None;
+        for field in fields {
+            match field.split_once('=') {
+                Some(("e", hash)) => enr_root = Some(hash.to_string()),
+                Some(("l", _)) => {}
+                Some(("seq", n)) => seq = n.parse().ok(),
+                _ => return Err("Invalid tree root field! "),
+            }
+        }
+
+        // sig = r || s || v over keccak256(<record without sig>)
+        let sig = decode_base64(sig).ok_or("Invalid tree root signature encoding! ")?;
+        if sig.len() != 65 {
+            return Err("Invalid tree root signature length! ");
+        }
+        verify_signature(content.as_bytes(), &sig[..64], public_key)
+            .then_some(())
+            .ok_or("Tree root signature mismatch! ")?;
+
+        Ok(Self {
+            enr_root: enr_root.ok_or("Tree root has no enr root! ")?,
+            seq: seq.ok_or("Tree root has no sequence number! ")?,
+        })
+    }
+}
+
+// Walks the tree behind `url` and returns at most `max_nodes` dialable peers.
+pub async fn discover(
+    url: &str,
+    max_nodes: usize,
+    resolve_timeout: Duration,
+) -> Result<Vec<(PublicKey, SocketAddr)>, &'static str> {
+    let url = EnrTreeUrl::parse(url)?;
+
+    let resolver = TokioAsyncResolver::tokio_from_system_conf()
+        .inspect_err(|err| debug!("The resolver configuration error is {:?}.", err))
+        .map_err(|_| "Could not set up DNS resolver! ")?;
+
+    let root_record = lookup_txt(&resolver, &url.domain, resolve_timeout)
+        .await
+        .map_err(|err| match err {
+            LookupError::Timeout => "Timed out resolving tree root! ",
+            LookupError::Failed => "Could not resolve tree root! ",
+        })?;
+    let root = TreeRoot::parse(&root_record, &url.public_key)?;
+
+    info!(
+        "Walking ENR tree at {:?}, sequence number {}",
+        url.domain, root.seq
+    );
+
+    let mut nodes = Vec::new();
+    let mut visited = HashSet::new();
+    let mut pending = VecDeque::from([root.enr_root]);
+
+    while let Some(hash) = pending.pop_front() {
+        if nodes.len() >= max_nodes {
+            break;
+        }
+        if visited.len() >= MAX_TREE_LOOKUPS {
+            warn!("Giving up on tree {:?} after {} lookups", url.domain, MAX_TREE_LOOKUPS);
+            break;
+        }
+        if !visited.insert(hash.clone()) {
+            continue;
+        }
+
+        // Entries vanish when the tree gets republished under a new root, so a
+        // failed lookup just means a stale branch. Skip it and carry on.
+        let record = match lookup_txt(&resolver, &format!("{}.{}", hash, url.domain), resolve_timeout).await {
+            Ok(record) => record,
+            Err(err) => {
+                warn!("Tree entry {:?} could not be resolved ({:?}), skipping", hash, err);
+                continue;
+            }
+        };
+
+        if !entry_matches_hash(&record, &hash) {
+            warn!("Tree entry {:?} does not match its hash, skipping", hash);
+            continue;
+        }
+
+        if let Some(children) = record.strip_prefix(BRANCH_PREFIX) {
+            pending.extend(
+                children
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|child| !child.is_empty())
+                    .map(str::to_string),
+            );
+        } else if let Some(enr) = record.strip_prefix(ENR_PREFIX) {
+            match parse_enr(enr) {
+                Ok(node) => nodes.push(node),
+                Err(err) => warn!("Skipping malformed ENR in entry {:?}: {}", hash, err),
+            }
+        } else if record.starts_with(ENRTREE_PREFIX) {
+            debug!("Not following link to another tree: {:?}", record);
+        } else {
+            warn!("Unknown tree entry {:?}, skipping", hash);
+        }
+    }
+
+    info!("Discovered {} peers from {:?}", nodes.len(), url.domain);
+    Ok(nodes)
+}
+
+#[derive(Debug)]
+enum LookupError {
+    Timeout,
+    Failed,
+}
+
+async fn lookup_txt(resolver: &TokioAsyncResolver, name: &str, resolve_timeout: Duration) -> Result<String, LookupError> {
+    let lookup = timeout(resolve_timeout, resolver.txt_lookup(name))
+        .await
+        .map_err(|_| LookupError::Timeout)?
+        .inspect_err(|err| debug!("TXT lookup of {:?} failed: {:?}", name, err))
+        .map_err(|_| LookupError::Failed)?;
+
+    // Records longer than 255 bytes get split up in multiple character strings.
+    let txt = lookup.iter().next().ok_or(LookupError::Failed)?;
+    let record: Vec<u8> = txt.txt_data().iter().flat_map(|s| s.iter().copied()).collect();
+
+    String::from_utf8(record).map_err(|_| LookupError::Failed)
+}
+
+// Subdomain names are base32(keccak256(record)[:16]).
+fn entry_matches_hash(record: &str, hash: &str) -> bool {
+    let digest = Keccak256::digest(record.as_bytes());
+    BASE32_NOPAD.encode(&digest[..16]).eq_ignore_ascii_case(hash)
+}
+
+fn decode_base64(data: &str) -> Option<Vec<u8>> {
+    BASE64URL_NOPAD
+        .decode(data.trim_end_matches('=').as_bytes())
+        .ok()
+}
+
+fn verify_signature(content: &[u8], sig: &[u8], public_key: &PublicKey) -> bool {
+    let digest: [u8; 32] = Keccak256::digest(content).into();
+    let Ok(mut signature) = Signature::from_compact(sig) else {
+        return false;
+    };
+    signature.normalize_s();
+
+    SECP256K1
+        .verify_ecdsa(&Message::from_digest(digest), &signature, public_key)
+        .is_ok()
+}
+
+// ENR, see https://eips.ethereum.org/EIPS/eip-778
+// record = [signature, seq, k, v, ...]
+fn parse_enr(enr: &str) -> Result<(PublicKey, SocketAddr), &'static str> {
+    let raw = decode_base64(enr).ok_or("Invalid ENR encoding! ")?;
+    if raw.len() > MAX_ENR_SIZE {
+        return Err("ENR too large! ");
+    }
+
+    let rlp = Rlp::new(&raw);
+    let items = rlp.item_count().map_err(|_| "ENR is not a list! ")?;
+    // Signature, sequence number and key/value pairs.
+    if items < 2 || items % 2 != 0 {
+        return Err("Invalid ENR item count! ");
+    }
+
+    let mut public_key = None;
+    let mut ip4 = None;
+    let mut tcp4 = None;
+    let mut ip6 = None;
+    let mut tcp6 = None;
+    let mut scheme_v4 = false;
+
+    for i in (2..items).step_by(2) {
+        let key: Vec<u8> = rlp.val_at(i).map_err(|_| "Invalid ENR key! ")?;
+        let value = rlp.at(i + 1).map_err(|_| "Missing ENR value! ")?;
+        match key.as_slice() {
+            b"id" => scheme_v4 = value.data().map_err(|_| "Invalid ENR id! ")? == b"v4",
+            b"secp256k1" => {
+                let key = value.data().map_err(|_| "Invalid ENR public key! ")?;
+                public_key = Some(node_pubkey_from_bytes(key)?);
+            }
+            b"ip" => {
+                let ip: [u8; 4] = value
+                    .data()
+                    .ok()
+                    .and_then(|data| data.try_into().ok())
+                    .ok_or("Invalid ENR ip! ")?;
+                ip4 = Some(IpAddr::V4(Ipv4Addr::from(ip)));
+            }
+            b"ip6" => {
+                let ip: [u8; 16] = value
+                    .data()
+                    .ok()
+                    .and_then(|data| data.try_into().ok())
+                    .ok_or("Invalid ENR ip6! ")?;
+                ip6 = Some(IpAddr::V6(Ipv6Addr::from(ip)));
+            }
+            b"tcp" => tcp4 = Some(value.as_val::<u16>().map_err(|_| "Invalid ENR tcp port! ")?),
+            b"tcp6" => tcp6 = Some(value.as_val::<u16>().map_err(|_| "Invalid ENR tcp6 port! ")?),
+            _ => {}
+        }
+    }
+
+    if !scheme_v4 {
+        return Err("Unsupported ENR identity scheme! ");
+    }
+    let public_key = public_key.ok_or("ENR has no public key! ")?;
+
+    // The v4 scheme signs keccak256(rlp([seq, k, v, ...])).
+    let mut content = RlpStream::new_list(items - 1);
+    for i in 1..items {
+        content.append_raw(rlp.at(i).map_err(|_| "Invalid ENR! ")?.as_raw(), 1);
+    }
+    let signature: Vec<u8> = rlp.val_at(0).map_err(|_| "Invalid ENR signature! ")?;
+    if !verify_signature(&content.out(), &signature, &public_key) {
+        return Err("ENR signature mismatch! ");
+    }
+
+    let socket_address = match (ip4, tcp4, ip6, tcp6.or(tcp4)) {
+        (Some(ip), Some(port), _, _) => SocketAddr::new(ip, port),
+        (_, _, Some(ip), Some(port)) => SocketAddr::new(ip, port),
+        _ => return Err("ENR has no TCP endpoint! "),
+    };
+
+    Ok((public_key, socket_address))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloy_primitives::hex;
+    use secp256k1::SecretKey;
+
+    // From EIP-1459.
+    const EXAMPLE_URL: &str = "enrtree://AM5FCQLWIZX2QFPNJAP7VUERCCRNGRHWZG3YYHIUV7BVDQ5FDPRT2@nodes.example.org";
+    // EIP-1459's example root without its signature. The key behind the
+    // example tree isn't published, the tests sign it with the EIP-778 one.
+    const EXAMPLE_ROOT: &str = "enrtree-root:v1 e=JWXYDBPXYWG6FX3GMDIBFA6CJ4 l=C7HRFPF3BLGF3YR4DY5KX3SMBE seq=1";
+    const EXAMPLE_BRANCH: &str =
+        "enrtree-branch:2XS2367YHAXJFGLZHVAWLQD4ZY,H4FHT4B454P6UXFD7JCYQ5PWDY,MHTDO6TMUBRIA2XWG5LUDACK24";
+    const EXAMPLE_TREE_ENR: &str = "enr:-HW4QOFzoVLaFJnNhbgMoDXPnOvcdVuj7pDpqRvh6BRDO68aVi5ZcjB3vzQRZH2IcLBGHzo8uUN3snqmgTiE56CH3AMBgmlkgnY0iXNlY3AyNTZrMaECC2_24YYkYHEgdzxlSNKQEnHhuNAbNlMlWJxrJxbAFvA";
+    // From EIP-778, signed with this key. It has a UDP port but no TCP one.
+    const EXAMPLE_ENR: &str = "-IS4QHCYrYZbAKWCBRlAy5zzaDZXJBGkcnh4MHcBFZntXNFrdvJjX04jRzjzCBOonrkTfj499SZuOh8R33Ls8RRcy5wBgmlkgnY0gmlwhH8AAAGJc2VjcDI1NmsxoQPKY0yuDUmstAHYpMa2_oxVtw0RW_QAdpzBQA8yWM0xOIN1ZHCCdl8";
+    const EXAMPLE_ENR_KEY: [u8; 32] = hex!("b71c71a67e1177ad4e901695e1b4b9ee17ae16c6668d313eac2f96dbcda3f291");
+
+    fn example_key() -> SecretKey {
+        SecretKey::from_slice(&EXAMPLE_ENR_KEY).unwrap()
+    }
+
+    // sig = r || s || v, the way tree roots carry it.
+    fn signed_root(content: &str) -> String {
+        let digest: [u8; 32] = Keccak256::digest(content.as_bytes()).into();
+        let (recovery_id, signature) = SECP256K1
+            .sign_ecdsa_recoverable(&Message::from_digest(digest), &example_key())
+            .serialize_compact();
+        let mut sig = signature.to_vec();
+        sig.push(recovery_id.to_i32() as u8);
+        format!("{} sig={}", content, BASE64URL_NOPAD.encode(&sig))
+    }
+
+    // A v4 record with the given pairs, in key order, signed with the EIP-778 key.
+    fn signed_enr(pairs: &[(&[u8], &[u8])]) -> String {
+        let key = example_key();
+        let public_key = PublicKey::from_secret_key(SECP256K1, &key).serialize();
+        let mut all: Vec<(&[u8], &[u8])> = vec![(b"id", b"v4"), (b"secp256k1", &public_key)];
+        all.extend_from_slice(pairs);
+        all.sort();
+
+        let append_pairs = |stream: &mut RlpStream| {
+            stream.append(&1u64);
+            for (key, value) in &all {
+                stream.append(key).append(value);
+            }
+        };
+        let mut content = RlpStream::new_list(1 + 2 * all.len());
+        append_pairs(&mut content);
+        let digest: [u8; 32] = Keccak256::digest(content.out()).into();
+        let signature = SECP256K1.sign_ecdsa(&Message::from_digest(digest), &key).serialize_compact();
+
+        let mut record = RlpStream::new_list(2 + 2 * all.len());
+        record.append(&signature.as_slice());
+        append_pairs(&mut record);
+        BASE64URL_NOPAD.encode(&record.out())
+    }
+
+    #[test]
+    fn enrtree_urls_give_the_signing_key_and_domain() {
+        let url = EnrTreeUrl::parse(EXAMPLE_URL).unwrap();
+        assert_eq!(url.domain, "nodes.example.org");
+        assert_eq!(
+            url.public_key.serialize(),
+            hex!("033a514176466fa815ed481ffad09110a2d344f6c9b78c1d14afc351c3a51be33d")
+        );
+
+        assert!(EnrTreeUrl::parse("https://nodes.example.org").is_err());
+        assert!(EnrTreeUrl::parse(&format!("x{}", EXAMPLE_URL)).is_err());
+        assert!(EnrTreeUrl::parse("enrtree://AM5FCQLWIZX2QFPNJAP7VUERCCRNGRHWZG3YYHIUV7BVDQ5FDPRT2").is_err());
+        assert!(EnrTreeUrl::parse("enrtree://AM5FCQLWIZX2QFPNJAP7VUERCCRNGRHWZG3YYHIUV7BVDQ5FDPRT2@").is_err());
+        assert!(EnrTreeUrl::parse("enrtree://AAAA@nodes.example.org").is_err());
+    }
+
+    #[test]
+    fn tree_root_is_checked_against_the_url_key() {
+        let key = PublicKey::from_secret_key(SECP256K1, &example_key());
+        let root = TreeRoot::parse(&signed_root(EXAMPLE_ROOT), &key).unwrap();
+        assert_eq!(root.enr_root, "JWXYDBPXYWG6FX3GMDIBFA6CJ4");
+        assert_eq!(root.seq, 1);
+
+        // The signature covers the rest of the record.
+        let bumped = signed_root(EXAMPLE_ROOT).replace("seq=1", "seq=2");
+        assert_eq!(TreeRoot::parse(&bumped, &key).err(), Some("Tree root signature mismatch! "));
+        let url = EnrTreeUrl::parse(EXAMPLE_URL).unwrap();
+        assert_eq!(TreeRoot::parse(&signed_root(EXAMPLE_ROOT), &url.public_key).err(), Some("Tree root signature mismatch! "));
+        assert_eq!(TreeRoot::parse(EXAMPLE_ROOT, &key).err(), Some("Tree root has no signature! "));
+        let unsequenced = signed_root("enrtree-root:v1 e=JWXYDBPXYWG6FX3GMDIBFA6CJ4 l=C7HRFPF3BLGF3YR4DY5KX3SMBE");
+        assert_eq!(TreeRoot::parse(&unsequenced, &key).err(), Some("Tree root has no sequence number! "));
+    }
+
+    #[test]
+    fn tree_entries_have_to_match_their_subdomain() {
+        assert!(entry_matches_hash(EXAMPLE_BRANCH, "JWXYDBPXYWG6FX3GMDIBFA6CJ4"));
+        assert!(entry_matches_hash(EXAMPLE_TREE_ENR, "2XS2367YHAXJFGLZHVAWLQD4ZY"));
+        assert!(entry_matches_hash(EXAMPLE_TREE_ENR, "2xs2367yhaxjfglzhvawlqd4zy"));
+        assert!(!entry_matches_hash(EXAMPLE_TREE_ENR, "H4FHT4B454P6UXFD7JCYQ5PWDY"));
+        assert!(!entry_matches_hash(&EXAMPLE_BRANCH.replace("2XS2", "2XS3"), "JWXYDBPXYWG6FX3GMDIBFA6CJ4"));
+    }
+
+    #[test]
+    fn enrs_need_a_good_signature_and_a_tcp_endpoint() {
+        // The example's signature checks out, but there's nothing to dial.
+        assert_eq!(parse_enr(EXAMPLE_ENR).err(), Some("ENR has no TCP endpoint! "));
+        let mut raw = decode_base64(EXAMPLE_ENR).unwrap();
+        // Into the signature, right after the two list and one string headers.
+        raw[5] ^= 0x01;
+        assert_eq!(parse_enr(&BASE64URL_NOPAD.encode(&raw)).err(), Some("ENR signature mismatch! "));
+
+        let (public_key, address) = parse_enr(&signed_enr(&[(b"ip", &[127, 0, 0, 1]), (b"tcp", &[0x76, 0x5f])])).unwrap();
+        assert_eq!(public_key, PublicKey::from_secret_key(SECP256K1, &example_key()));
+        assert_eq!(address, "127.0.0.1:30303".parse().unwrap());
+        let ip6 = Ipv6Addr::LOCALHOST.octets();
+        let (_, address) = parse_enr(&signed_enr(&[(b"ip6", &ip6), (b"tcp", &[0x76, 0x5f])])).unwrap();
+        assert_eq!(address, "[::1]:30303".parse().unwrap());
+
+        let padding = [0; MAX_ENR_SIZE];
+        assert_eq!(parse_enr(&signed_enr(&[(b"z", &padding)])).err(), Some("ENR too large! "));
+    }
+}
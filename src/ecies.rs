@@ -1,34 +1,125 @@
-use crate::rplx::PROTOCOL_VERSION;
-use aes::cipher::{KeyIvInit, StreamCipher};
+use crate::{
+    keccak_mac::{KeccakMac, KECCAK_MAC_STATE_SIZE},
+    rplx::PROTOCOL_VERSION,
+};
+use aes::cipher::{IvSizeUser, KeyIvInit, KeySizeUser, StreamCipher, StreamCipherSeek};
 use ethereum_types::{H128, H256};
 use hmac::{Hmac, Mac};
-use log::{debug, info};
+use log::{info, warn};
 use rlp::{Rlp, RlpStream};
-use secp256k1::{PublicKey, SecretKey, SECP256K1};
-use sha2::{Digest, Sha256};
+use secp256k1::{
+    ecdsa::{RecoverableSignature, RecoveryId},
+    rand::{rngs::StdRng, thread_rng, CryptoRng, RngCore, SeedableRng},
+    PublicKey, SecretKey, SECP256K1,
+};
+use sha2::{
+    digest::{typenum::Unsigned, OutputSizeUser},
+    Digest, Sha256,
+};
 use sha3::Keccak256;
-use tokio_util::bytes::{Bytes, BytesMut};
+use tokio_util::bytes::BytesMut;
+use zeroize::Zeroize;
 
 pub type Aes128Ctr64BE = ctr::Ctr64BE<aes::Aes128>;
 pub type Aes256Ctr64BE = ctr::Ctr64BE<aes::Aes256>;
 
-#[derive(Clone, Copy, Debug)]
+// The aes crate picks its implementation on its own: AES-NI when the CPU has
+// it, the portable one otherwise. It can be pinned at build time with
+// RUSTFLAGS="--cfg aes_force_soft" or, on ARMv8, opted into the crypto
+// extensions with RUSTFLAGS="--cfg aes_armv8". Those are cfg flags rather
+// than cargo features, and a feature of ours can't set a cfg on a dependency,
+// so there's no selecting it from Cargo.toml. This only tells which one it
+// ended up with.
+fn aes_backend() -> &'static str {
+    if cfg!(aes_force_soft) {
+        return "software (forced)";
+    }
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    if std::arch::is_x86_feature_detected!("aes") {
+        return "AES-NI";
+    }
+    #[cfg(target_arch = "aarch64")]
+    if cfg!(aes_armv8) && std::arch::is_aarch64_feature_detected!("aes") {
+        return "ARMv8 crypto extensions";
+    }
+    "software"
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum ECIESDirection {
     Outgoing,
     Incoming,
 }
 
+// Where ECIES draws its ephemeral key, nonce and envelope keys and IVs from.
+// Seeded makes a handshake reproducible, for spec vectors and fuzzing, and
+// must never be used against real peers. The one exception is `check
+// --record`, which writes its seed down next to the session anyway.
+#[derive(Clone, Debug, Default)]
+pub enum EciesRng {
+    #[default]
+    Os,
+    Seeded(Box<StdRng>),
+}
+
+impl EciesRng {
+    pub fn seeded(seed: u64) -> Self {
+        EciesRng::Seeded(Box::new(StdRng::seed_from_u64(seed)))
+    }
+}
+
+impl RngCore for EciesRng {
+    fn next_u32(&mut self) -> u32 {
+        match self {
+            EciesRng::Os => thread_rng().next_u32(),
+            EciesRng::Seeded(rng) => rng.next_u32(),
+        }
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        match self {
+            EciesRng::Os => thread_rng().next_u64(),
+            EciesRng::Seeded(rng) => rng.next_u64(),
+        }
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        match self {
+            EciesRng::Os => thread_rng().fill_bytes(dest),
+            EciesRng::Seeded(rng) => rng.fill_bytes(dest),
+        }
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), secp256k1::rand::Error> {
+        match self {
+            EciesRng::Os => thread_rng().try_fill_bytes(dest),
+            EciesRng::Seeded(rng) => rng.try_fill_bytes(dest),
+        }
+    }
+}
+
+impl CryptoRng for EciesRng {}
+
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Clone, Debug)]
 pub struct ECIES {
     connection_direction: ECIESDirection,
     our_private_key: SecretKey,
-    peer_public_key: PublicKey,
+    // Only learned from the auth message when the peer dialed us.
+    peer_public_key: Option<PublicKey>,
     ephemeral_priv_key: SecretKey,
+    // Set once the ephemeral key went out in an auth or ack.
+    ephemeral_key_used: bool,
     ephemeral_remote_pub_key: Option<PublicKey>,
     init_nonce: H256,
     resp_nonce: H256,
     auth: BytesMut,
     ack: BytesMut,
+    rng: EciesRng,
+    // The peer's auth came in the pre-EIP-8 format, so our ack has to as well.
+    legacy: bool,
+    // Envelope key and IV for the next auth or ack, in place of random ones.
+    next_envelope: Option<(SecretKey, H128)>,
 }
 
 #[derive(Clone)]
@@ -36,38 +127,185 @@ pub struct HandshakeSecrets {
     pub aes_keystream_ingress: Aes256Ctr64BE,
     pub aes_keystream_egress: Aes256Ctr64BE,
     pub mac_secret: aes::Aes256,
-    pub ingress_mac: Keccak256,
-    pub egress_mac: Keccak256,
+    pub ingress_mac: KeccakMac,
+    pub egress_mac: KeccakMac,
+    // The raw keys the ciphers above were made from, only kept for export.
+    aes_secret_bytes: [u8; 32],
+    mac_secret_bytes: [u8; 32],
+}
+
+// Exported secrets, version 1, all in one fixed size blob:
+//   version (1 byte, 0x01)
+//   aes-secret (32)
+//   ingress keystream position, egress keystream position (8 each, big-endian)
+//   mac-secret (32)
+//   ingress-mac state, egress-mac state (KECCAK_MAC_STATE_SIZE each)
+// The keystreams are AES-256-CTR with a zero IV, so the key and how far along
+// they are is all there is to them.
+const SECRETS_EXPORT_VERSION: u8 = 1;
+pub const SECRETS_EXPORT_SIZE: usize = 1 + 32 + 8 + 8 + 32 + 2 * KECCAK_MAC_STATE_SIZE;
+
+impl HandshakeSecrets {
+    fn new(aes_secret: H256, mac_secret: H256, ingress_mac: KeccakMac, egress_mac: KeccakMac) -> Self {
+        // Apparently, the keystream has the IV initialized with 0. This, I did not see in the documentation.
+        let iv = H128::default();
+        Self {
+            aes_keystream_ingress: Aes256Ctr64BE::new(aes_secret.as_ref().into(), iv.as_ref().into()),
+            aes_keystream_egress: Aes256Ctr64BE::new(aes_secret.as_ref().into(), iv.as_ref().into()),
+            // The mac secret AES encryption is running in block mode, whereas the AES ingress/outgress is running in keystream mode.
+            mac_secret: <aes::Aes256 as aes::cipher::KeyInit>::new(mac_secret.as_ref().into()),
+            ingress_mac,
+            egress_mac,
+            aes_secret_bytes: aes_secret.0,
+            mac_secret_bytes: mac_secret.0,
+        }
+    }
+
+    // Everything the frame codec needs to carry on where this one is, so the
+    // handshake and the data transfer can run in different processes. This is
+    // the session's key material in the clear, treat it like a private key.
+    pub fn export(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(SECRETS_EXPORT_SIZE);
+        out.push(SECRETS_EXPORT_VERSION);
+        out.extend_from_slice(&self.aes_secret_bytes);
+        out.extend_from_slice(&self.aes_keystream_ingress.current_pos::<u64>().to_be_bytes());
+        out.extend_from_slice(&self.aes_keystream_egress.current_pos::<u64>().to_be_bytes());
+        out.extend_from_slice(&self.mac_secret_bytes);
+        self.ingress_mac.write_state(&mut out);
+        self.egress_mac.write_state(&mut out);
+        out
+    }
+
+    pub fn import(bytes: &[u8]) -> Result<Self, &'static str> {
+        match bytes.first() {
+            Some(&SECRETS_EXPORT_VERSION) => {}
+            Some(_) => return Err("Unknown exported secrets version"),
+            None => return Err("Exported secrets are empty"),
+        }
+        if bytes.len() < SECRETS_EXPORT_SIZE {
+            return Err("Exported secrets are cut short");
+        }
+        if bytes.len() > SECRETS_EXPORT_SIZE {
+            return Err("Exported secrets have trailing bytes");
+        }
+        let aes_secret = H256::from_slice(&bytes[1..33]);
+        let ingress_position = u64::from_be_bytes(bytes[33..41].try_into().unwrap());
+        let egress_position = u64::from_be_bytes(bytes[41..49].try_into().unwrap());
+        let mac_secret = H256::from_slice(&bytes[49..81]);
+        let ingress_mac = KeccakMac::read_state(&bytes[81..81 + KECCAK_MAC_STATE_SIZE])?;
+        let egress_mac = KeccakMac::read_state(&bytes[81 + KECCAK_MAC_STATE_SIZE..])?;
+
+        let mut secrets = Self::new(aes_secret, mac_secret, ingress_mac, egress_mac);
+        secrets
+            .aes_keystream_ingress
+            .try_seek(ingress_position)
+            .map_err(|_| "Ingress keystream position out of range")?;
+        secrets
+            .aes_keystream_egress
+            .try_seek(egress_position)
+            .map_err(|_| "Egress keystream position out of range")?;
+        Ok(secrets)
+    }
+}
+
+impl Drop for HandshakeSecrets {
+    fn drop(&mut self) {
+        self.aes_secret_bytes.zeroize();
+        self.mac_secret_bytes.zeroize();
+    }
 }
 
 const PUBLIC_KEY_SIZE: usize = 65;
 const IV_SIZE: usize = 16;
 const TAG_SIZE: usize = 32;
+// Pre-EIP-8 messages have fixed size bodies and no size prefix:
+// auth = sig || keccak256(ephemeral-pubk) || pubk || nonce || 0x0
+// ack = ephemeral-pubk || nonce || 0x0
+const LEGACY_AUTH_BODY_SIZE: usize = 65 + 32 + 64 + 32 + 1;
+const LEGACY_ACK_BODY_SIZE: usize = 64 + 32 + 1;
+const ECIES_OVERHEAD: usize = PUBLIC_KEY_SIZE + IV_SIZE + TAG_SIZE;
+
+// The spec's ECIES: NIST SP 800-56 concatenation KDF over SHA-256, giving
+// kE || kM, and HMAC-SHA-256 keyed with sha256(kM) for the tag. Everything
+// goes through these two, so there's one place that says which hash it is.
+type EciesHash = Sha256;
+type EciesMac = Hmac<EciesHash>;
+// kE is an AES-128 key, kM is hashed into the HMAC key.
+const ENCRYPTION_KEY_SIZE: usize = 16;
+const MAC_KEY_SIZE: usize = 16;
+const KDF_OUTPUT_SIZE: usize = ENCRYPTION_KEY_SIZE + MAC_KEY_SIZE;
+
+// A dependency bump that changes any of these sizes would quietly break
+// interop, so make it fail the build instead.
+const _: () = assert!(<EciesHash as OutputSizeUser>::OutputSize::USIZE == 32);
+const _: () = assert!(<EciesMac as OutputSizeUser>::OutputSize::USIZE == TAG_SIZE);
+const _: () = assert!(<aes::Aes128 as KeySizeUser>::KeySize::USIZE == ENCRYPTION_KEY_SIZE);
+const _: () = assert!(<Aes128Ctr64BE as IvSizeUser>::IvSize::USIZE == IV_SIZE);
 
 impl ECIES {
-    pub fn new(our_private_key: SecretKey, peer_public_key: PublicKey) -> Self {
+    pub fn new(our_private_key: SecretKey, peer_public_key: PublicKey, rng: EciesRng) -> Self {
+        let mut ecies = Self::new_incoming(our_private_key, rng);
+        ecies.connection_direction = ECIESDirection::Outgoing;
+        ecies.peer_public_key = Some(peer_public_key);
+        ecies
+    }
+
+    // For a connection the peer dialed, we wait for its auth to find out who it is.
+    pub fn new_incoming(our_private_key: SecretKey, mut rng: EciesRng) -> Self {
         Self {
-            connection_direction: ECIESDirection::Outgoing,
+            connection_direction: ECIESDirection::Incoming,
             our_private_key,
-            peer_public_key,
-            ephemeral_priv_key: Self::generate_random_secret_key(),
+            peer_public_key: None,
+            ephemeral_priv_key: SecretKey::new(&mut rng),
+            ephemeral_key_used: false,
             ephemeral_remote_pub_key: None,
-            init_nonce: H256::random(),
-            resp_nonce: H256::random(),
+            init_nonce: random_h256(&mut rng),
+            resp_nonce: random_h256(&mut rng),
             auth: BytesMut::new(),
             ack: BytesMut::new(),
+            rng,
+            legacy: false,
+            next_envelope: None,
         }
     }
 
-    pub fn generate_random_secret_key() -> SecretKey {
-        return SecretKey::new(&mut secp256k1::rand::thread_rng());
+    // Replaces our random ephemeral key and nonce, which is everything the
+    // handshake secrets take from our side.
+    #[cfg(any(test, feature = "test-vectors"))]
+    pub fn set_ephemeral(&mut self, ephemeral_priv_key: SecretKey, nonce: H256) {
+        self.ephemeral_priv_key = ephemeral_priv_key;
+        self.ephemeral_key_used = false;
+        match self.connection_direction {
+            ECIESDirection::Outgoing => self.init_nonce = nonce,
+            ECIESDirection::Incoming => self.resp_nonce = nonce,
+        }
+    }
+
+    // Replaces the random key and IV of the envelope the next auth or ack is
+    // sealed in. Along with set_ephemeral that fixes the message byte for byte.
+    #[cfg(any(test, feature = "test-vectors"))]
+    pub fn set_envelope(&mut self, envelope_key: SecretKey, iv: H128) {
+        self.next_envelope = Some((envelope_key, iv));
+    }
+
+    pub fn peer_public_key(&self) -> Option<PublicKey> {
+        self.peer_public_key
+    }
+
+    // keccak256(static-shared-secret)[..8], the same on both ends whenever
+    // their ECDH agrees. Comparing it out of band tells an ECDH problem apart
+    // from a KDF or MAC one without either side giving away the secret.
+    #[cfg(feature = "secret-fingerprint")]
+    pub fn shared_secret_fingerprint(&self) -> Option<[u8; 8]> {
+        let static_shared_secret = Self::agree(self.peer_public_key?, self.our_private_key);
+        let mut fingerprint = [0; 8];
+        fingerprint.copy_from_slice(&Self::keccak256_hash(&[static_shared_secret.as_bytes()])[..8]);
+        Some(fingerprint)
     }
 
     // ECIES agree actually creates a secret point using the a private key and a peer public key
     pub fn agree(public_key: PublicKey, private_key: SecretKey) -> H256 {
-        return H256::from_slice(
-            &secp256k1::ecdh::shared_secret_point(&public_key, &private_key)[..32],
-        );
+        H256::from_slice(&secp256k1::ecdh::shared_secret_point(&public_key, &private_key)[..32])
     }
 
     pub fn encrypt_data_aes(
@@ -82,36 +320,58 @@ impl ECIES {
     }
 
     fn derive_keys(shared_key: &H256) -> Result<(H128, H256), &'static str> {
-        let mut key = [0_u8; 32];
-        concat_kdf::derive_key_into::<Sha256>(shared_key.as_bytes(), &[], &mut key)
-            .map_err(|e| "Key derivation failed!")?;
+        let mut key = [0_u8; KDF_OUTPUT_SIZE];
+        concat_kdf::derive_key_into::<EciesHash>(shared_key.as_bytes(), &[], &mut key)
+            .map_err(|_| "Key derivation failed!")?;
 
-        let encryption_key = H128::from_slice(&key[..16]);
-        let mac_key = H256::from(Sha256::digest(&key[16..32]).as_ref());
+        let (encryption_key, mac_key) = key.split_at(ENCRYPTION_KEY_SIZE);
+        let encryption_key = H128::from_slice(encryption_key);
+        let mac_key = H256::from_slice(&EciesHash::digest(mac_key));
 
         Ok((encryption_key, mac_key))
     }
 
+    // EIP-8 messages authenticate their size prefix as shared-mac-data, legacy
+    // ones have none.
     fn calculate_remote_tag(
         mac_key: &[u8],
         iv: &H128,
         encrypted_data: &[u8],
-        payload_size: u16,
+        shared_mac_data: &[u8],
     ) -> H256 {
-        let mut hmac = Hmac::<Sha256>::new_from_slice(mac_key).expect("HMAC creation failed");
+        let mut hmac = EciesMac::new_from_slice(mac_key).expect("HMAC creation failed");
         hmac.update(iv.as_bytes());
         hmac.update(encrypted_data);
-        hmac.update(&payload_size.to_be_bytes());
+        hmac.update(shared_mac_data);
         H256::from_slice(&hmac.finalize().into_bytes())
     }
 
+    // An ephemeral key must only ever go into one handshake. Building a second
+    // auth or ack from the same instance would reuse it, so get_auth_request
+    // and get_auth_ack hand back the bytes already built instead. Only once
+    // those are released can it come to this.
+    fn use_ephemeral_key(&mut self, message: &str) {
+        if self.ephemeral_key_used {
+            warn!("Ephemeral key reused for a second {}, handshakes must not share one!", message);
+            debug_assert!(false, "ephemeral key reused for a second {}", message);
+        }
+        self.ephemeral_key_used = true;
+    }
+
     pub fn get_auth_request(&mut self) -> &BytesMut {
+        if self.ephemeral_key_used && !self.auth.is_empty() {
+            return &self.auth;
+        }
+        self.use_ephemeral_key("auth");
         // We create the public key from our private key
         let our_public_key = PublicKey::from_secret_key(SECP256K1, &self.our_private_key);
         // We derive the shared secret S = Px
         //   where (Px, Py) = r * KB
         // And then we handle it as a 256bit hash.
-        let derived_shared_key = ECIES::agree(self.peer_public_key, self.our_private_key);
+        let peer_public_key = self
+            .peer_public_key
+            .expect("An outgoing ECIES always knows the peer key");
+        let derived_shared_key = ECIES::agree(peer_public_key, self.our_private_key);
 
         let msg = derived_shared_key ^ self.init_nonce;
 
@@ -146,13 +406,26 @@ impl ECIES {
         &self.auth
     }
 
-    pub fn encrypt<'a>(&mut self, data_to_encrypt: BytesMut) -> Result<BytesMut, &'static str> {
+    pub fn encrypt(&mut self, data_to_encrypt: BytesMut) -> Result<BytesMut, &'static str> {
+        self.seal(data_to_encrypt, true)
+    }
+
+    // With eip8 the message gets its size prefix, which also goes into the tag.
+    fn seal(&mut self, data_to_encrypt: BytesMut, eip8: bool) -> Result<BytesMut, &'static str> {
         // R = r * G
-        let random_secret_key = Self::generate_random_secret_key();
-        // S = Px where (Px, Py) = r * KB
-        let shared_key = Self::agree(self.peer_public_key, random_secret_key);
         // Generate initialization vector, each package has a new, spanking fresh iv
-        let iv = H128::random();
+        let (random_secret_key, iv) = match self.next_envelope.take() {
+            Some(envelope) => envelope,
+            None => {
+                let random_secret_key = SecretKey::new(&mut self.rng);
+                let mut iv = H128::zero();
+                self.rng.fill_bytes(iv.as_bytes_mut());
+                (random_secret_key, iv)
+            }
+        };
+        // S = Px where (Px, Py) = r * KB
+        let peer_public_key = self.peer_public_key.ok_or("Peer public key not known yet!")?;
+        let shared_key = Self::agree(peer_public_key, random_secret_key);
 
         // kE || kM = KDF(S, 32)
         let (encryption_key, mac_key) = Self::derive_keys(&shared_key)?;
@@ -164,10 +437,11 @@ impl ECIES {
         let encrypted_data = self.encrypt_data_aes(data_to_encrypt, &iv, &encryption_key);
 
         // d = MAC(sha256(kM), iv || c)
-        let tag = Self::calculate_remote_tag(&mac_key.as_bytes(), &iv, &encrypted_data, total_size);
+        let shared_mac_data: &[u8] = if eip8 { &total_size.to_be_bytes() } else { &[] };
+        let tag = Self::calculate_remote_tag(mac_key.as_bytes(), &iv, &encrypted_data, shared_mac_data);
 
         let mut data_encrypted_out = BytesMut::new();
-        data_encrypted_out.extend_from_slice(&total_size.to_be_bytes());
+        data_encrypted_out.extend_from_slice(shared_mac_data);
         data_encrypted_out.extend_from_slice(
             &PublicKey::from_secret_key(SECP256K1, &random_secret_key).serialize_uncompressed(),
         );
@@ -187,20 +461,69 @@ impl ECIES {
             return Err("Too small payload size");
         }
 
+        self.remember_message(&data_in[..frame_size]);
+        let body = Self::open_eip8(self.our_private_key, &mut data_in[..frame_size])?;
+
+        match self.connection_direction {
+            ECIESDirection::Incoming => self.parse_auth(body)?,
+            ECIESDirection::Outgoing => self.parse_ack(body)?,
+        }
+
+        let body_start = 2 + PUBLIC_KEY_SIZE + IV_SIZE;
+        Ok((&mut data_in[body_start..frame_size - TAG_SIZE], frame_size))
+    }
+
+    // Size of a pre-EIP-8 auth or ack, whichever we're waiting on.
+    pub fn legacy_message_size(&self) -> usize {
+        ECIES_OVERHEAD
+            + match self.connection_direction {
+                ECIESDirection::Incoming => LEGACY_AUTH_BODY_SIZE,
+                ECIESDirection::Outgoing => LEGACY_ACK_BODY_SIZE,
+            }
+    }
+
+    // Same as decrypt for a pre-EIP-8 message, which data_in must be exactly.
+    // Nothing is touched unless the tag checks out, so it's safe to try before
+    // falling back to EIP-8.
+    pub fn decrypt_legacy(&mut self, data_in: &mut [u8]) -> Result<(), &'static str> {
+        if data_in.len() != self.legacy_message_size() {
+            return Err("Not a legacy message size!");
+        }
+        self.remember_message(data_in);
+        let body = Self::open(self.our_private_key, data_in, &[])?;
         match self.connection_direction {
             ECIESDirection::Incoming => {
-                self.auth.clear();
-                self.auth.extend_from_slice(&data_in[..frame_size]);
+                let (signature, rest) = body.split_at(65);
+                // The ephemeral key hash is left out, we recover the key itself.
+                let (initiator_pubk, rest) = rest[32..].split_at(64);
+                self.accept_auth(signature, initiator_pubk, &rest[..32])?;
+                self.legacy = true;
             }
             ECIESDirection::Outgoing => {
-                self.ack.clear();
-                self.ack.extend_from_slice(&data_in[..frame_size]);
+                let (ephemeral_pubk, rest) = body.split_at(64);
+                self.accept_ack(ephemeral_pubk, &rest[..32])?;
             }
         }
+        Ok(())
+    }
 
-        let (_, rest) = data_in.split_at_mut_checked(2).ok_or("No payload size!")?;
+    // size || pubk || iv || c || tag, an EIP-8 auth or ack exactly as it came
+    // in. size is the shared-mac-data, so the tag covers it along with iv and
+    // c, and a prefix that was tampered with fails like the rest would. Checks
+    // the tag and decrypts c in place, without parsing it any further, so the
+    // envelope can be checked against other implementations on its own.
+    // encrypt is the other way round.
+    pub fn open_eip8(our_private_key: SecretKey, message: &mut [u8]) -> Result<&mut [u8], &'static str> {
+        let (size_prefix, rest) = message.split_at_mut_checked(2).ok_or("No payload size!")?;
+        if u16::from_be_bytes([size_prefix[0], size_prefix[1]]) as usize != rest.len() {
+            return Err("Size prefix doesn't match the message");
+        }
+        Self::open(our_private_key, rest, size_prefix)
+    }
 
-        let (pub_data, rest) = rest
+    // pubk || iv || c || tag, checks the tag and decrypts c in place.
+    fn open<'a>(our_private_key: SecretKey, message: &'a mut [u8], shared_mac_data: &[u8]) -> Result<&'a mut [u8], &'static str> {
+        let (pub_data, rest) = message
             .split_at_mut_checked(PUBLIC_KEY_SIZE)
             .ok_or("No public key data!")?;
 
@@ -208,22 +531,21 @@ impl ECIES {
             .split_at_mut_checked(IV_SIZE)
             .ok_or("No IV (initialization vector)!")?;
 
-        let (encrypted_data, tag) = rest
-            .split_at_mut_checked(payload_size - (PUBLIC_KEY_SIZE + IV_SIZE + TAG_SIZE))
-            .ok_or("Invalid tag field size! ")?;
+        let encrypted_size = rest.len().checked_sub(TAG_SIZE).ok_or("Invalid tag field size! ")?;
+        let (encrypted_data, tag) = rest.split_at_mut(encrypted_size);
 
         let remote_pub_key =
             PublicKey::from_slice(pub_data).map_err(|_| "Key conversion failed ")?;
 
-        let tag = H256::from_slice(&tag[..32]);
+        let tag = H256::from_slice(tag);
 
-        let shared_key = Self::agree(remote_pub_key, self.our_private_key);
+        let shared_key = Self::agree(remote_pub_key, our_private_key);
 
         let (encryption_key, mac_key) = Self::derive_keys(&shared_key)?;
         let iv = H128::from_slice(iv);
 
         let remote_tag =
-            Self::calculate_remote_tag(mac_key.as_ref(), &iv, encrypted_data, payload_size as u16);
+            Self::calculate_remote_tag(mac_key.as_ref(), &iv, encrypted_data, shared_mac_data);
 
         if tag != remote_tag {
             return Err("Tag mismatch!");
@@ -233,17 +555,31 @@ impl ECIES {
         let mut decryptor = Aes128Ctr64BE::new(encrypted_key.as_ref().into(), iv.as_ref().into());
         decryptor.apply_keystream(encrypted_data);
 
-        // Decode Ack message.
-        let rlp = Rlp::new(encrypted_data);
+        Ok(encrypted_data)
+    }
+
+    // The MACs start from the auth and ack exactly as they went over the wire,
+    // so this has to happen before they're decrypted in place.
+    fn remember_message(&mut self, message: &[u8]) {
+        let kept = match self.connection_direction {
+            ECIESDirection::Incoming => &mut self.auth,
+            ECIESDirection::Outgoing => &mut self.ack,
+        };
+        kept.clear();
+        kept.extend_from_slice(message);
+    }
+
+    // ack-body = [recipient-ephemeral-pubk, recipient-nonce, ack-vsn, ...]
+    fn parse_ack(&mut self, body: &[u8]) -> Result<(), &'static str> {
+        let rlp = Rlp::new(body);
         let recipient_ephemeral_pubk_raw: Vec<_> = rlp
             .val_at(0)
             .map_err(|_| "RLP ack structure invalid, missing ephemeral pubk!")?;
-
-        let mut buf = [4_u8; 65];
-        buf[1..].copy_from_slice(&recipient_ephemeral_pubk_raw);
-        self.ephemeral_remote_pub_key =
-            Some(PublicKey::from_slice(&buf).map_err(|_| "RLP ephemeral pubk is invalid!")?);
-
+        // An auth is sealed to our key just like an ack, so it gets this far.
+        // Its signature in place of the key means the peer dialed us too.
+        if recipient_ephemeral_pubk_raw.len() == 65 {
+            return Err("Got an auth where the ack should be, the peer dialed us as well!");
+        }
         let recipient_nonce: Vec<_> = rlp
             .val_at(1)
             .map_err(|_| "RLP ack structure invalid, missing nonce!")?;
@@ -251,9 +587,106 @@ impl ECIES {
             .val_at(2)
             .map_err(|_| "RLP ack structure invalid, missing protocol version! ")?;
 
-        self.resp_nonce = H256::from_slice(&recipient_nonce);
+        if recipient_nonce.len() != 32 {
+            return Err("RLP ack nonce has the wrong size!");
+        }
+        self.accept_ack(&recipient_ephemeral_pubk_raw, &recipient_nonce)
+    }
+
+    fn accept_ack(&mut self, ephemeral_pubk: &[u8], nonce: &[u8]) -> Result<(), &'static str> {
+        self.ephemeral_remote_pub_key =
+            Some(Self::public_key_from_id(ephemeral_pubk).ok_or("Ack ephemeral pubk is invalid!")?);
+        self.resp_nonce = H256::from_slice(nonce);
+        Ok(())
+    }
+
+    // auth-body = [sig, initiator-pubk, initiator-nonce, auth-vsn, ...]
+    fn parse_auth(&mut self, body: &[u8]) -> Result<(), &'static str> {
+        let rlp = Rlp::new(body);
+        let signature: Vec<_> = rlp
+            .val_at(0)
+            .map_err(|_| "RLP auth structure invalid, missing signature!")?;
+        let initiator_pubk_raw: Vec<_> = rlp
+            .val_at(1)
+            .map_err(|_| "RLP auth structure invalid, missing initiator pubk!")?;
+        let initiator_nonce: Vec<_> = rlp
+            .val_at(2)
+            .map_err(|_| "RLP auth structure invalid, missing nonce!")?;
+
+        if signature.len() != 65 || initiator_nonce.len() != 32 {
+            return Err("RLP auth signature or nonce has the wrong size!");
+        }
+        self.accept_auth(&signature, &initiator_pubk_raw, &initiator_nonce)
+    }
+
+    fn accept_auth(&mut self, signature: &[u8], initiator_pubk: &[u8], nonce: &[u8]) -> Result<(), &'static str> {
+        let peer_public_key =
+            Self::public_key_from_id(initiator_pubk).ok_or("Auth initiator pubk is invalid!")?;
+        let init_nonce = H256::from_slice(nonce);
+
+        // The initiator signed static-shared-secret ^ initiator-nonce with its
+        // ephemeral key, which is how we get to that key.
+        let msg = Self::agree(peer_public_key, self.our_private_key) ^ init_nonce;
+        let recovery_id = RecoveryId::from_i32(signature[64] as i32)
+            .map_err(|_| "Auth signature recovery id is invalid!")?;
+        let signature = RecoverableSignature::from_compact(&signature[..64], recovery_id)
+            .map_err(|_| "Auth signature is invalid!")?;
+        let ephemeral_remote_pub_key = SECP256K1
+            .recover_ecdsa(
+                &secp256k1::Message::from_digest_slice(msg.as_bytes()).map_err(|_| "Invalid auth digest!")?,
+                &signature,
+            )
+            .map_err(|_| "Could not recover the initiator ephemeral key!")?;
 
-        Ok((encrypted_data, frame_size))
+        self.peer_public_key = Some(peer_public_key);
+        self.init_nonce = init_nonce;
+        self.ephemeral_remote_pub_key = Some(ephemeral_remote_pub_key);
+        Ok(())
+    }
+
+    // The 64 byte X || Y form keys are sent in, back to a key.
+    fn public_key_from_id(id: &[u8]) -> Option<PublicKey> {
+        if id.len() != 64 {
+            return None;
+        }
+        let mut buf = [4_u8; 65];
+        buf[1..].copy_from_slice(id);
+        PublicKey::from_slice(&buf).ok()
+    }
+
+    pub fn get_auth_ack(&mut self) -> Result<&BytesMut, &'static str> {
+        if self.ephemeral_key_used && !self.ack.is_empty() {
+            return Ok(&self.ack);
+        }
+        self.use_ephemeral_key("ack");
+        let ephemeral_public_key =
+            PublicKey::from_secret_key(SECP256K1, &self.ephemeral_priv_key).serialize_uncompressed();
+
+        // ack-body = [recipient-ephemeral-pubk, recipient-nonce, ack-vsn, ...]
+        let mut stream: RlpStream = RlpStream::new_list(3);
+        stream.append(&&ephemeral_public_key[1..]);
+        stream.append(&self.resp_nonce.as_bytes());
+        // ack-vsn = 4
+        stream.append(&PROTOCOL_VERSION);
+
+        let ack_encrypted = if self.legacy {
+            self.seal(self.legacy_ack_body(&ephemeral_public_key[1..]), false)?
+        } else {
+            self.encrypt(stream.out())?
+        };
+        self.ack.clear();
+        self.ack.extend_from_slice(&ack_encrypted);
+
+        Ok(&self.ack)
+    }
+
+    // ephemeral-pubk || nonce || 0x0, for peers that only speak pre-EIP-8.
+    fn legacy_ack_body(&self, ephemeral_pubk: &[u8]) -> BytesMut {
+        let mut body = BytesMut::with_capacity(LEGACY_ACK_BODY_SIZE);
+        body.extend_from_slice(ephemeral_pubk);
+        body.extend_from_slice(self.resp_nonce.as_bytes());
+        body.extend_from_slice(&[0]);
+        body
     }
 
     fn keccak256_hash(inputs: &[&[u8]]) -> H256 {
@@ -266,6 +699,40 @@ impl ECIES {
         H256::from(hasher.finalize().as_ref())
     }
 
+    // Auth and ack as they went over the wire, size prefix included.
+    #[cfg(feature = "handshake-bytes")]
+    pub fn last_auth_bytes(&self) -> &[u8] {
+        &self.auth
+    }
+
+    #[cfg(feature = "handshake-bytes")]
+    pub fn last_auth_ack_bytes(&self) -> &[u8] {
+        &self.ack
+    }
+
+    // Auth and ack only seed the MACs, once the secrets exist they're dead
+    // weight unless we're keeping them for inspection.
+    pub fn release_handshake_messages(&mut self) {
+        if cfg!(not(feature = "handshake-bytes")) {
+            self.auth = BytesMut::new();
+            self.ack = BytesMut::new();
+        }
+    }
+
+    // Wipes what's left of the handshake: our ephemeral key, both nonces and
+    // the auth and ack. Our static key is shared with every other session, so
+    // it stays.
+    pub fn wipe(&mut self) {
+        self.ephemeral_priv_key.non_secure_erase();
+        self.ephemeral_remote_pub_key = None;
+        self.init_nonce.as_bytes_mut().zeroize();
+        self.resp_nonce.as_bytes_mut().zeroize();
+        self.auth.as_mut().zeroize();
+        self.auth = BytesMut::new();
+        self.ack.as_mut().zeroize();
+        self.ack = BytesMut::new();
+    }
+
     pub fn get_secrets(&self) -> HandshakeSecrets {
         // Generate the secrets list obtained after the ECIES handshake took place,
         // Inputs:
@@ -308,8 +775,8 @@ impl ECIES {
 
         let mac_secret = Self::keccak256_hash(&[ephemeral_key.as_bytes(), aes_secret.as_bytes()]);
 
-        let mut ingress_mac = Keccak256::new();
-        let mut egress_mac = Keccak256::new();
+        let mut ingress_mac = KeccakMac::new();
+        let mut egress_mac = KeccakMac::new();
 
         match self.connection_direction {
             ECIESDirection::Incoming => {
@@ -326,26 +793,258 @@ impl ECIES {
             }
         }
 
-        // Apparently, the keystream has the IV initialized with 0. This, I did not see in the documentation.
-        let iv = H128::default();
+        info!(" Created ecies secrets, AES backend: {} ", aes_backend());
+        #[cfg(feature = "secret-fingerprint")]
+        if let Some(fingerprint) = self.shared_secret_fingerprint() {
+            info!(" Static shared secret fingerprint: {} ", hex::encode(fingerprint));
+        }
+
+        HandshakeSecrets::new(aes_secret, mac_secret, ingress_mac, egress_mac)
+    }
+}
+
+fn random_h256(rng: &mut EciesRng) -> H256 {
+    let mut value = H256::zero();
+    rng.fill_bytes(value.as_bytes_mut());
+    value
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::RLPxConfig,
+        messages::RLPx_Message,
+        rplx::RLPx,
+        testing::{active_codecs, deliver, random_key},
+    };
+    use alloy_primitives::hex;
+    use tokio_util::codec::{Decoder, Encoder};
+
+    // The keys and nonces of the EIP-8 handshake test vectors.
+    const KEY_A: [u8; 32] = hex!("49a7b37aa6f6645917e7b807e9d1c00d4fa71f18343b0d4122a4d2df64dd6fee");
+    const KEY_B: [u8; 32] = hex!("b71c71a67e1177ad4e901695e1b4b9ee17ae16c6668d313eac2f96dbcda3f291");
+    const EPHEMERAL_KEY_A: [u8; 32] = hex!("869d6ecf5211f1cc60418a13b9d870b22959d0c16f02bec714c960dd2298a32d");
+    const EPHEMERAL_KEY_B: [u8; 32] = hex!("e238eb8e04fee6511ab04c6dd3c89ce097b11f25d584863ac2b6d5b35b1847e4");
+    const NONCE_A: [u8; 32] = hex!("7e968bba13b6c50e2c4cd7f241cc0d64d1ac25c7f5952df231ac6a2bda8ee5d6");
+    const NONCE_B: [u8; 32] = hex!("559aead08264d5795d3909718cdd05abd49572e84fe55590eef31a88a08fdffd");
+
+    fn key(bytes: [u8; 32]) -> SecretKey {
+        SecretKey::from_slice(&bytes).unwrap()
+    }
+
+    // A with its EIP-8 ephemeral key and nonce dialing B with its own, and
+    // the envelopes fixed as well when given.
+    fn vector_pair(envelope: Option<(SecretKey, H128)>) -> (ECIES, ECIES) {
+        let mut initiator = ECIES::new(key(KEY_A), PublicKey::from_secret_key(SECP256K1, &key(KEY_B)), EciesRng::Os);
+        initiator.set_ephemeral(key(EPHEMERAL_KEY_A), H256(NONCE_A));
+        let mut responder = ECIES::new_incoming(key(KEY_B), EciesRng::Os);
+        responder.set_ephemeral(key(EPHEMERAL_KEY_B), H256(NONCE_B));
+        if let Some((envelope_key, iv)) = envelope {
+            initiator.set_envelope(envelope_key, iv);
+            responder.set_envelope(envelope_key, iv);
+        }
+        (initiator, responder)
+    }
+
+    // Auth and ack as they went over the wire.
+    fn handshake(initiator: &mut ECIES, responder: &mut ECIES) -> (Vec<u8>, Vec<u8>) {
+        let auth = initiator.get_auth_request().to_vec();
+        responder.decrypt(&mut auth.clone()).unwrap();
+        let ack = responder.get_auth_ack().unwrap().to_vec();
+        initiator.decrypt(&mut ack.clone()).unwrap();
+        (auth, ack)
+    }
+
+    // EIP-8's Auth₁, A's auth in the pre-EIP-8 format sealed for B.
+    const LEGACY_AUTH: [u8; 307] = hex!(
+        "048ca79ad18e4b0659fab4853fe5bc58eb83992980f4c9cc147d2aa31532efd29a3d3dc6a3d89eaf"
+        "913150cfc777ce0ce4af2758bf4810235f6e6ceccfee1acc6b22c005e9e3a49d6448610a58e98744"
+        "ba3ac0399e82692d67c1f58849050b3024e21a52c9d3b01d871ff5f210817912773e610443a9ef14"
+        "2e91cdba0bd77b5fdf0769b05671fc35f83d83e4d3b0b000c6b2a1b1bba89e0fc51bf4e460df3105"
+        "c444f14be226458940d6061c296350937ffd5e3acaceeaaefd3c6f74be8e23e0f45163cc7ebd7622"
+        "0f0128410fd05250273156d548a414444ae2f7dea4dfca2d43c057adb701a715bf59f6fb66b2d1d2"
+        "0f2c703f851cbf5ac47396d9ca65b6260bd141ac4d53e2de585a73d1750780db4c9ee4cd4d225173"
+        "a4592ee77e2bd94d0be3691f3b406f9bba9b591fc63facc016bfa8"
+    );
+    // And Ack₁, B's answer to it, sealed for A.
+    const LEGACY_ACK: [u8; 210] = hex!(
+        "049f8abcfa9c0dc65b982e98af921bc0ba6e4243169348a236abe9df5f93aa69d99cadddaa387662"
+        "b0ff2c08e9006d5a11a278b1b3331e5aaabf0a32f01281b6f4ede0e09a2d5f585b26513cb794d963"
+        "5a57563921c04a9090b4f14ee42be1a5461049af4ea7a7f49bf4c97a352d39c8d02ee4acc416388c"
+        "1c66cec761d2bc1c72da6ba143477f049c9d2dde846c252c111b904f630ac98e51609b3b1f58168d"
+        "dca6505b7196532e5f85b259a20c45e1979491683fee108e9660edbf38f3add489ae73e3dda2c71b"
+        "d1497113d5c755e942d1"
+    );
+
+    #[test]
+    fn legacy_auth_and_ack_give_both_sides_the_same_secrets() {
+        let (mut initiator, mut responder) = vector_pair(None);
+        assert_eq!(LEGACY_AUTH.len(), responder.legacy_message_size());
+        responder.decrypt_legacy(&mut LEGACY_AUTH.clone()).unwrap();
+        assert_eq!(responder.init_nonce, H256(NONCE_A));
+        assert_eq!(responder.ephemeral_remote_pub_key, Some(PublicKey::from_secret_key(SECP256K1, &key(EPHEMERAL_KEY_A))));
+        assert_eq!(responder.peer_public_key, Some(PublicKey::from_secret_key(SECP256K1, &key(KEY_A))));
+        assert!(responder.legacy);
 
-        // The mac secret AES encryption is running in block mode, whereas the AES ingress/outgress is running in keystream mode.
-        let mac_cypher = <aes::Aes256 as aes::cipher::KeyInit>::new(mac_secret.as_ref().into());
-
-        info!(" Created ecies secrets... ");
-        
-        HandshakeSecrets {
-            aes_keystream_ingress: Aes256Ctr64BE::new(
-                aes_secret.as_ref().into(),
-                iv.as_ref().into(),
-            ),
-            aes_keystream_egress: Aes256Ctr64BE::new(
-                aes_secret.as_ref().into(),
-                iv.as_ref().into(),
-            ),
-            mac_secret: mac_cypher,
-            ingress_mac: ingress_mac,
-            egress_mac: egress_mac,
+        assert_eq!(LEGACY_ACK.len(), initiator.legacy_message_size());
+        initiator.decrypt_legacy(&mut LEGACY_ACK.clone()).unwrap();
+        assert_eq!(initiator.resp_nonce, H256(NONCE_B));
+        assert_eq!(initiator.ephemeral_remote_pub_key, Some(PublicKey::from_secret_key(SECP256K1, &key(EPHEMERAL_KEY_B))));
+
+        // Each side's own message is the published one, not what it would
+        // seal itself with a random envelope.
+        initiator.auth = BytesMut::from(&LEGACY_AUTH[..]);
+        responder.ack = BytesMut::from(&LEGACY_ACK[..]);
+        let (initiator, responder) = (initiator.get_secrets(), responder.get_secrets());
+        for secrets in [&initiator, &responder] {
+            assert_eq!(secrets.aes_secret_bytes, hex!("80e8632c05fed6fc2a13b0f8d31a3cf645366239170ea067065aba8e28bac487"));
+            assert_eq!(secrets.mac_secret_bytes, hex!("2ea74ec5dae199227dff1af715362700e989d889d7a493cb0639691efb8e5f98"));
         }
+        assert_eq!(initiator.egress_mac.clone().finalize(), responder.ingress_mac.clone().finalize());
+        assert_eq!(initiator.ingress_mac.clone().finalize(), responder.egress_mac.clone().finalize());
+    }
+
+    #[test]
+    fn secrets_match_the_eip8_vectors() {
+        let (mut initiator, mut responder) = vector_pair(None);
+        handshake(&mut initiator, &mut responder);
+        for secrets in [initiator.get_secrets(), responder.get_secrets()] {
+            assert_eq!(secrets.aes_secret_bytes, hex!("80e8632c05fed6fc2a13b0f8d31a3cf645366239170ea067065aba8e28bac487"));
+            assert_eq!(secrets.mac_secret_bytes, hex!("2ea74ec5dae199227dff1af715362700e989d889d7a493cb0639691efb8e5f98"));
+        }
+    }
+
+    #[test]
+    fn fixed_envelope_makes_auth_and_ack_reproducible() {
+        let envelope = (key([0x11; 32]), H128([0x22; 16]));
+        let (mut initiator, mut responder) = vector_pair(Some(envelope));
+        let first = handshake(&mut initiator, &mut responder);
+        let (mut initiator, mut responder) = vector_pair(Some(envelope));
+        assert_eq!(handshake(&mut initiator, &mut responder), first);
+
+        // The envelope key is the one right after the size prefix.
+        let envelope_public_key = PublicKey::from_secret_key(SECP256K1, &envelope.0).serialize_uncompressed();
+        assert_eq!(first.0[2..2 + PUBLIC_KEY_SIZE], envelope_public_key);
+        assert_eq!(first.0[2 + PUBLIC_KEY_SIZE..2 + PUBLIC_KEY_SIZE + IV_SIZE], [0x22; 16]);
+
+        // Only the next message takes it, random ones come back after.
+        assert!(initiator.next_envelope.is_none() && responder.next_envelope.is_none());
+        let (mut initiator, mut responder) = vector_pair(None);
+        assert_ne!(handshake(&mut initiator, &mut responder).0, first.0);
+    }
+
+    #[test]
+    fn auth_and_ack_asked_for_again_come_back_the_same() {
+        let (mut initiator, mut responder) = vector_pair(None);
+        let (auth, ack) = handshake(&mut initiator, &mut responder);
+        // A rebuild would reuse the ephemeral key, which debug builds assert on.
+        assert_eq!(initiator.get_auth_request().to_vec(), auth);
+        assert_eq!(responder.get_auth_ack().unwrap().to_vec(), ack);
+    }
+
+    #[test]
+    fn open_eip8_checks_the_size_prefix() {
+        let (mut initiator, _) = vector_pair(None);
+        let auth = initiator.get_auth_request().to_vec();
+
+        let body = ECIES::open_eip8(key(KEY_B), &mut auth.clone()).unwrap().to_vec();
+        // auth-body is an RLP list, the signature first.
+        assert_eq!(Rlp::new(&body).val_at::<Vec<u8>>(0).unwrap().len(), 65);
+
+        // A prefix that doesn't match the length is refused before anything
+        // else, a flipped IV bit and the wrong key fail the tag.
+        let mut short = auth.clone();
+        short.pop();
+        assert_eq!(ECIES::open_eip8(key(KEY_B), &mut short), Err("Size prefix doesn't match the message"));
+        let mut tampered = auth.clone();
+        tampered[2 + PUBLIC_KEY_SIZE] ^= 1;
+        assert_eq!(ECIES::open_eip8(key(KEY_B), &mut tampered), Err("Tag mismatch!"));
+        assert_eq!(ECIES::open_eip8(key(KEY_A), &mut auth.clone()), Err("Tag mismatch!"));
+    }
+
+    #[test]
+    fn derived_keys_split_the_kdf_output_as_the_spec_does() {
+        let shared_key = H256::repeat_byte(0x5a);
+        let (encryption_key, mac_key) = ECIES::derive_keys(&shared_key).unwrap();
+
+        // One round of concat-KDF with no shared info is sha256(counter || z),
+        // kE its first 16 bytes and the MAC key sha256(kM) of the rest.
+        let mut round = EciesHash::new();
+        round.update(1_u32.to_be_bytes());
+        round.update(shared_key.as_bytes());
+        let round = round.finalize();
+        assert_eq!(encryption_key.as_bytes(), &round[..ENCRYPTION_KEY_SIZE]);
+        assert_eq!(mac_key.as_bytes(), EciesHash::digest(&round[ENCRYPTION_KEY_SIZE..]).as_slice());
+
+        // RFC 4231 test case 2, the tag is all of HMAC-SHA256.
+        let mut hmac = EciesMac::new_from_slice(b"Jefe").unwrap();
+        hmac.update(b"what do ya want for nothing?");
+        let tag = hmac.finalize().into_bytes();
+        assert_eq!(tag.len(), TAG_SIZE);
+        assert_eq!(tag[..], hex!("5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"));
+    }
+
+    #[test]
+    fn exported_secrets_carry_on_in_fresh_codecs() {
+        let (initiator, responder) = active_codecs();
+        let resumed = |codec: &RLPx| {
+            let exported = codec.secrets().unwrap().export();
+            assert_eq!(exported.len(), SECRETS_EXPORT_SIZE);
+            let mut resumed = RLPx::new_incoming(random_key(), RLPxConfig::default());
+            resumed.resume(HandshakeSecrets::import(&exported).unwrap(), cfg!(feature = "snappy"));
+            resumed
+        };
+        let (mut initiator, mut responder) = (resumed(&initiator), resumed(&responder));
+
+        // Both ways, and more than once, so the keystreams and MACs run on.
+        for _ in 0..3 {
+            assert!(matches!(deliver(&mut initiator, &mut responder, RLPx_Message::Ping)[..], [RLPx_Message::Ping]));
+            assert!(matches!(deliver(&mut responder, &mut initiator, RLPx_Message::Pong)[..], [RLPx_Message::Pong]));
+        }
+        let body = vec![0xab; 1000];
+        let p2p = RLPx_Message::P2p { id: 0x0f, body: body.clone() };
+        assert!(matches!(
+            &deliver(&mut responder, &mut initiator, p2p)[..],
+            [RLPx_Message::P2p { id: 0x0f, body: received }] if *received == body
+        ));
+    }
+
+    #[test]
+    fn import_refuses_what_export_would_not_write() {
+        let (initiator, _) = active_codecs();
+        let exported = initiator.secrets().unwrap().export();
+
+        let mut trailing = exported.clone();
+        trailing.push(0);
+        assert_eq!(HandshakeSecrets::import(&trailing).err(), Some("Exported secrets have trailing bytes"));
+        let short = &exported[..exported.len() - 1];
+        assert_eq!(HandshakeSecrets::import(short).err(), Some("Exported secrets are cut short"));
+        let mut version = exported.clone();
+        version[0] = SECRETS_EXPORT_VERSION + 1;
+        assert_eq!(HandshakeSecrets::import(&version).err(), Some("Unknown exported secrets version"));
+        assert_eq!(HandshakeSecrets::import(&[]).err(), Some("Exported secrets are empty"));
+        assert!(HandshakeSecrets::import(&exported).is_ok());
+    }
+
+    #[test]
+    fn mac_mismatch_leaves_nothing_of_the_handshake_behind() {
+        let (mut sender, mut receiver) = active_codecs();
+        let ephemeral_key = receiver.ecies().ephemeral_priv_key;
+        assert!(receiver.ecies().ephemeral_remote_pub_key.is_some());
+        assert!(!receiver.ecies().init_nonce.is_zero() && !receiver.ecies().resp_nonce.is_zero());
+
+        let mut wire = BytesMut::new();
+        sender.encode(RLPx_Message::Ping, &mut wire).unwrap();
+        let last = wire.len() - 1;
+        wire[last] ^= 0x01;
+        assert!(receiver.decode(&mut wire).is_err());
+
+        assert!(receiver.secrets().is_none());
+        let ecies = receiver.ecies();
+        assert_ne!(ecies.ephemeral_priv_key, ephemeral_key);
+        assert!(ecies.ephemeral_remote_pub_key.is_none());
+        assert!(ecies.init_nonce.is_zero() && ecies.resp_nonce.is_zero());
+        assert!(ecies.auth.is_empty() && ecies.ack.is_empty());
     }
 }
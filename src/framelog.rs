@@ -0,0 +1,89 @@
+// Raw wire logging, one line per frame:
+//   <unix time, µs precision> <peer> <in|out> <kind> <length> <hex bytes>
+//
+// The bytes are exactly what went over (or came off) the socket, so they can be
+// lined up against a reference client's capture.
+use std::{
+    fs::{File, OpenOptions},
+    io::Write,
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use log::warn;
+
+#[derive(Clone, Copy, Debug)]
+pub enum FrameDirection {
+    Inbound,
+    Outbound,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum FrameKind {
+    Auth,
+    AuthAck,
+    Frame,
+}
+
+#[derive(Clone)]
+pub struct FrameLog {
+    file: Arc<Mutex<File>>,
+    peer: String,
+}
+
+impl FrameLog {
+    pub fn create(path: &str) -> Result<Self, &'static str> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|_| "Could not open frame log file! ")?;
+
+        Ok(Self {
+            file: Arc::new(Mutex::new(file)),
+            peer: String::new(),
+        })
+    }
+
+    // Same file, tagged with the peer so concurrent sessions can be told apart.
+    pub fn for_peer(&self, peer: String) -> Self {
+        Self {
+            file: self.file.clone(),
+            peer,
+        }
+    }
+
+    pub fn record(&self, direction: FrameDirection, kind: FrameKind, data: &[u8]) {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+
+        let direction = match direction {
+            FrameDirection::Inbound => "in",
+            FrameDirection::Outbound => "out",
+        };
+        let kind = match kind {
+            FrameKind::Auth => "auth",
+            FrameKind::AuthAck => "ack",
+            FrameKind::Frame => "frame",
+        };
+
+        let line = format!(
+            "{}.{:06} {} {} {} {} {}\n",
+            timestamp.as_secs(),
+            timestamp.subsec_micros(),
+            self.peer,
+            direction,
+            kind,
+            data.len(),
+            hex::encode(data)
+        );
+
+        let Ok(mut file) = self.file.lock() else {
+            return;
+        };
+        if let Err(err) = file.write_all(line.as_bytes()) {
+            warn!("Writing the frame log failed: {:?}", err);
+        }
+    }
+}
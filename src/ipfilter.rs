@@ -0,0 +1,78 @@
+use std::{net::IpAddr, str::FromStr};
+
+// An address block in a.b.c.d/n or x:y::z/n notation.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Cidr {
+    network: IpAddr,
+    prefix_len: u8,
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.network, ip) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                Self::prefix_matches(&network.octets(), &ip.octets(), self.prefix_len)
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                Self::prefix_matches(&network.octets(), &ip.octets(), self.prefix_len)
+            }
+            // An IPv4 peer written as ::ffff:a.b.c.d still belongs to IPv4 blocks.
+            (IpAddr::V4(_), IpAddr::V6(ip)) => ip
+                .to_ipv4_mapped()
+                .is_some_and(|ip| self.contains(IpAddr::V4(ip))),
+            (IpAddr::V6(_), IpAddr::V4(_)) => false,
+        }
+    }
+
+    fn prefix_matches(network: &[u8], ip: &[u8], prefix_len: u8) -> bool {
+        let full_bytes = prefix_len as usize / 8;
+        let rest_bits = prefix_len % 8;
+        if network[..full_bytes] != ip[..full_bytes] {
+            return false;
+        }
+        if rest_bits == 0 {
+            return true;
+        }
+        let mask = 0xffu8 << (8 - rest_bits);
+        network[full_bytes] & mask == ip[full_bytes] & mask
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (network, prefix_len) = s.split_once('/').ok_or("CIDR block is missing the /prefix ")?;
+        let network: IpAddr = network.parse().map_err(|_| "Invalid CIDR address ")?;
+        let prefix_len: u8 = prefix_len.parse().map_err(|_| "Invalid CIDR prefix length ")?;
+        let max_len = if network.is_ipv4() { 32 } else { 128 };
+        if prefix_len > max_len {
+            return Err("CIDR prefix length too long for the address ");
+        }
+        Ok(Self {
+            network,
+            prefix_len,
+        })
+    }
+}
+
+// Which peers we're willing to talk to. A deny match always wins, and once
+// any allow block is given, peers outside all of them are refused too.
+#[derive(Clone, Debug, Default)]
+pub struct IpFilter {
+    pub allow: Vec<Cidr>,
+    pub deny: Vec<Cidr>,
+}
+
+impl IpFilter {
+    // Why the address is refused, if it is.
+    pub fn check(&self, ip: IpAddr) -> Result<(), &'static str> {
+        if self.deny.iter().any(|cidr| cidr.contains(ip)) {
+            return Err("Address is in a denied CIDR block");
+        }
+        if !self.allow.is_empty() && !self.allow.iter().any(|cidr| cidr.contains(ip)) {
+            return Err("Address is outside the allowed CIDR blocks");
+        }
+        Ok(())
+    }
+}
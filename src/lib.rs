@@ -0,0 +1,22 @@
+// The protocol side: ECIES, the RLPx codec and sessions, and the discovery
+// and network bits that feed them peers. The binary drives them from the
+// command line, the benches call into them directly.
+pub mod config;
+pub mod discv4;
+pub mod dnsdisc;
+pub mod ecies;
+pub mod framelog;
+pub mod ipfilter;
+pub mod keccak_mac;
+pub mod messages;
+pub mod nodekey;
+pub mod ratelimit;
+pub mod redact;
+pub mod report;
+pub mod rplx;
+pub mod session;
+pub mod sessions;
+pub mod statelog;
+pub mod stun;
+#[cfg(test)]
+mod testing;
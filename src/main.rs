@@ -1,189 +1,810 @@
-use ecies::ECIES;
-use futures::executor::block_on;
-use futures::SinkExt;
-use futures::StreamExt;
-use log::{debug, error, info};
-use messages::RLPx_Message;
-use rplx::RlpxState;
-use secp256k1::{PublicKey, SecretKey, SECP256K1};
-use std::process;
+use cli::Options;
+use config::DialConfig;
+use framelog::FrameLog;
+use futures::{stream::FuturesUnordered, StreamExt};
+use log::{debug, error, info, warn};
+use messages::{Disconnect, RLPx_Message};
+use nodekey::{dedup_peers, interleave_families, split_enode};
+use ratelimit::SubnetRateLimiter;
+use report::PeerReport;
+use reth_handshake::{
+    config, discv4, dnsdisc, ecies, framelog, ipfilter, messages, nodekey, ratelimit, redact, report, rplx, session,
+    sessions, statelog, stun,
+};
+use rplx::{mac_digest, HandshakeError, PeerInfo, RLPx};
+use secp256k1::{PublicKey, SecretKey};
+use session::Session;
+use sessions::{HandshakeGuard, Rejection, SessionCommand, SessionManager};
+use statelog::StateLog;
 use std::{
     env,
-    fmt::Error,
-    future::Future,
-    net::{SocketAddr, ToSocketAddrs},
-    str::FromStr,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs},
+    process,
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{
+    net::TcpListener,
+    signal,
+    sync::watch,
+    time::{self, Instant},
 };
-use tokio::net::TcpStream;
-use tokio_util::codec::Framed;
 
-use crate::rplx::RLPx;
+mod check;
+mod cli;
+mod crawl;
+mod replay;
+mod stdin;
 
-mod ecies;
-mod messages;
-mod rplx;
+// How long sessions get to send their Disconnect after Ctrl-C.
+const SHUTDOWN_GRACE_PERIOD: Duration = Duration::from_secs(2);
+// How often --keep-alive Pings the peer, geth drops peers it hasn't heard
+// from in 15s and Pings about that often itself.
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(15);
 
 fn main() {
     if env::var("RUST_LOG").is_err() {
         env::set_var("RUST_LOG", "trace")
     }
     env_logger::init();
-    let peers_eip = match get_peers() {
+    let options = match Options::from_args(env::args().skip(1)) {
+        Ok(x) => x,
+        Err(e) => {
+            error!("Error parsing arguments! {}", e);
+            return;
+        }
+    };
+    redact::set_redact_node_ids(options.redact_node_ids);
+    let peers_eip = match get_peers(&options.enodes, &options.dial) {
         Ok(x) => x,
         Err(e) => {
             error!("Error getting peers! {}", e);
             return;
         }
     };
-    multi_connection_runner(peers_eip);
+    if let Some(path) = &options.replay {
+        process::exit(replay::run(path) as i32);
+    }
+    if options.check {
+        let [(public_key, ref addresses)] = dedup_peers(peers_eip)[..] else {
+            error!("check takes exactly one enode! ");
+            process::exit(check::CheckStatus::HandshakeFailed as i32);
+        };
+        // A check is one handshake, so only the first address gets it.
+        process::exit(check::run(options, public_key, addresses[0]) as i32);
+    }
+    if options.blocking {
+        blocking_runner(options, peers_eip);
+    } else {
+        multi_connection_runner(options, peers_eip);
+    }
 }
 
-fn get_peers() -> Result<Vec<(PublicKey, SocketAddr)>, &'static str> {
-    const ENODE_PREFIX: &str = "enode://";
+fn get_peers(enodes: &[String], dial: &DialConfig) -> Result<Vec<(PublicKey, SocketAddr)>, &'static str> {
     const MAX_ENODES: usize = 10;
 
     let mut nodes: Vec<(PublicKey, SocketAddr)> = Vec::new();
 
-    for enode in env::args().skip(1) {
-        info!("Enode argument is: {:?}", enode);
-
-        let (enode_prefix, enode_data) = enode
-            .split_once(ENODE_PREFIX)
-            .ok_or("Invalid enode prefix! ")?;
-
-        if enode_prefix != "" {
-            return Err("Invalid enode prefix location! ");
-        }
+    for (index, enode) in enodes.iter().enumerate() {
+        info!("Enode argument is: {:?}", redact::enode(enode));
 
-        let (enode_key_string, socket_address_string) =
-            enode_data.rsplit_once("@").ok_or("Invalid ip delimiter")?;
+        let (enode_public_key, socket_address_string) = split_enode(enode)?;
 
-        let mut socket_address = socket_address_string
+        // A hostname can resolve to several addresses, the node gets dialed at
+        // each in turn until one completes the handshake. IPv4-mapped ones
+        // ([::ffff:a.b.c.d]) get their family sorted out before they're
+        // ordered, filtered or deduplicated.
+        let socket_addresses: Vec<SocketAddr> = socket_address_string
             .to_socket_addrs()
             .inspect_err(|err| debug!("The IpAddr conversion parse error is {:?}.", err))
-            .map_err(|_| " Invalid IP address format! ")?;
+            .map_err(|_| " Invalid IP address format! ")?
+            .map(|address| dial.normalize(address))
+            .collect();
 
-        if socket_address.len() != 1 {
-            return Err("Multiple IP/sockets specified for node, only one 1 supported! ");
+        if socket_addresses.is_empty() {
+            return Err("Invalid IP address! ");
         }
 
-        let socket_address = socket_address.next().ok_or("Invalid IP address! ")?;
+        for socket_address in interleave_families(socket_addresses) {
+            nodes.push((enode_public_key, socket_address));
+        }
 
-        let mut enode_key_string = enode_key_string.to_string();
-        enode_key_string.insert_str(0, "04");
+        if index + 1 > MAX_ENODES {
+            return Err("Too many peers in arguments! ");
+        }
+    }
+    Ok(nodes)
+}
 
-        let enode_public_key = PublicKey::from_str(&enode_key_string)
-            .inspect_err(|err| debug!("The publickey parse error is {:?}.", err))
-            .map_err(|_| "Invalid enode public key ")?;
+fn blocking_runner(options: Options, peers: Vec<(PublicKey, SocketAddr)>) {
+    let private_key = SecretKey::new(&mut secp256k1::rand::thread_rng());
+    let peers = dedup_peers(peers);
 
-        nodes.push((enode_public_key, socket_address));
+    if !options.enr_trees.is_empty() {
+        warn!("DNS discovery needs the async runner, ignoring ENR trees in blocking mode.");
+    }
+    if options.listen.is_some() {
+        warn!("Listening needs the async runner, ignoring --listen in blocking mode.");
+    }
+    if options.stdin {
+        warn!("Reading peers from stdin needs the async runner, ignoring --stdin in blocking mode.");
+    }
+    if options.crawl {
+        warn!("Crawling needs the async runner, ignoring crawl in blocking mode.");
+    }
 
-        if nodes.len() > MAX_ENODES {
-            return Err("Too many peers in arguments! ");
+    for (public_key, addresses) in peers {
+        info!("Peer public key is {}", redact::public_key(&public_key));
+        for ip_address in addresses {
+            if let Err(err) = options.ip_filter.check(ip_address.ip()) {
+                info!("Skipping {}: {}", ip_address, err);
+                continue;
+            }
+            match handle_session_blocking(private_key, public_key, ip_address, &options) {
+                Ok(peer_info) => {
+                    info!(
+                        "Handshake with {} complete, peer runs {:?}",
+                        ip_address, peer_info.client_version
+                    );
+                    break;
+                }
+                Err(err) => info!("Session error! {}", err),
+            }
         }
     }
-    Ok(nodes)
+}
+
+fn handle_session_blocking(
+    private_key: SecretKey,
+    peer_public_key: PublicKey,
+    socket_address: SocketAddr,
+    options: &Options,
+) -> Result<PeerInfo, HandshakeError> {
+    let mut stream = std::net::TcpStream::connect(socket_address)?;
+    info!(
+        "TCP connection to {:?} established! ",
+        socket_address.to_string()
+    );
+
+    let mut rplx_tp = RLPx::new(private_key, peer_public_key, options.rlpx_config());
+    rplx_tp.handshake_blocking(&mut stream)
 }
 
 // #[launch]
 #[tokio::main(flavor = "current_thread")]
 // #[tokio::main]
-async fn multi_connection_runner(peers: Vec<(PublicKey, SocketAddr)>) {
+async fn multi_connection_runner(options: Options, mut peers: Vec<(PublicKey, SocketAddr)>) {
     let private_key = SecretKey::new(&mut secp256k1::rand::thread_rng());
 
-    // let mut futures_list: Vec<impt> = Vec::new();
-    for (public_key, ip_address) in peers {
-        info!("Peer public key is {:?}", public_key);
-        match handle_session(private_key, public_key, ip_address).await {
-            Ok(())=> info!("Session cleanly terminated"),
-            Err(err) => info!("Session error! {:?}", err) };
+    let mut discovered = 0;
+    for tree in &options.enr_trees {
+        if discovered >= options.max_discovered {
+            break;
+        }
+        match dnsdisc::discover(tree, options.max_discovered - discovered, options.dial.resolve_timeout).await {
+            Ok(nodes) => {
+                discovered += nodes.len();
+                peers.extend(nodes);
+            }
+            Err(err) => warn!("DNS discovery of {:?} failed! {}", tree, err),
+        }
     }
 
+    let peers = dedup_peers(peers);
+
+    let frame_log = match options.frame_log.as_deref().map(FrameLog::create).transpose() {
+        Ok(frame_log) => frame_log,
+        Err(err) => {
+            error!("{}", err);
+            process::exit(1);
+        }
+    };
+
+    let state_log = match options.state_log.as_deref().map(StateLog::create).transpose() {
+        Ok(state_log) => state_log,
+        Err(err) => {
+            error!("{}", err);
+            process::exit(1);
+        }
+    };
+
+    let crawl_file = match options.crawl_out.as_deref().map(crawl::CrawlFile::open).transpose() {
+        Ok(crawl_file) => crawl_file,
+        Err(err) => {
+            error!("{}", err);
+            process::exit(1);
+        }
+    };
+
+    let rate_limiter = options.subnet_dial_interval.map(SubnetRateLimiter::new);
+    let rate_limiter = rate_limiter.as_ref();
+
+    let sessions = Arc::new(SessionManager::new(&private_key, options.max_peers));
+
+    // Flipped on Ctrl-C to stop dialing, accepting and any handshakes still
+    // going, the sessions past theirs are told through the manager.
+    let (shutdown_tx, shutdown) = watch::channel(false);
+    let active_sessions = sessions.clone();
+    tokio::spawn(async move {
+        if signal::ctrl_c().await.is_err() {
+            return;
+        }
+        info!("Ctrl-C received, disconnecting from all peers");
+        let _ = shutdown_tx.send(true);
+        active_sessions.close_all(Disconnect::CLIENT_QUITTING, "Client quitting");
+        // Sessions get this long to flush their Disconnect before we give up on them.
+        tokio::time::sleep(SHUTDOWN_GRACE_PERIOD).await;
+        warn!("Sessions did not close in time, exiting anyway");
+        process::exit(130);
+    });
+    let sessions = &*sessions;
+
+    let options = &options;
+    let dial = &DialContext {
+        private_key,
+        frame_log: frame_log.as_ref(),
+        state_log: state_log.as_ref(),
+        rate_limiter,
+        options,
+    };
+    let dialing = async {
+        if let Some(crawl_file) = crawl_file {
+            if options.stdin {
+                warn!("Reading peers from stdin doesn't go with crawl, ignoring --stdin.");
+            }
+            return crawl::run(dial, sessions, shutdown.clone(), peers, crawl_file).await;
+        }
+        // Peers from standard input queue up behind the ones we already know of.
+        futures::stream::iter(peers)
+            .chain(futures::stream::iter(options.stdin.then(|| stdin::peers(options.dial))).flatten())
+            .map(|(public_key, addresses)| dial_peer(dial, sessions, public_key, addresses, shutdown.clone()))
+            .buffer_unordered(options.concurrency)
+            .collect::<Vec<PeerReport>>()
+            .await
+    };
+    let listening = listen(private_key, frame_log.as_ref(), state_log.as_ref(), options, sessions, shutdown.clone());
+
+    let (mut reports, inbound_reports) = tokio::join!(dialing, listening);
+    reports.extend(inbound_reports);
+
+    report::print_summary(&reports, options.json);
+
     process::exit(0);
 }
 
-enum SessionState {
-    SendingAuth,
-    RecivingAuthAck,
-    RecievingHello,
-    ProtocolActive,
+// Dials the node unless there's a session with it already, and sees the
+// session through to the end.
+async fn dial_peer(
+    dial: &DialContext<'_>,
+    sessions: &SessionManager,
+    public_key: PublicKey,
+    addresses: Vec<SocketAddr>,
+    mut shutdown: watch::Receiver<bool>,
+) -> PeerReport {
+    let mut report = PeerReport::new(Some(public_key), addresses[0]);
+    let handshake = sessions.begin_handshake(&public_key).await;
+    if sessions.get(&public_key).is_some() {
+        info!("Already connected to {}, not dialing {}", redact::public_key(&public_key), addresses[0]);
+        report.rejected = Some("Already connected to this node");
+        return report;
+    }
+    info!("Peer public key is {}", redact::public_key(&public_key));
+    if let Some(mut session) = dial.first_to_handshake(public_key, &addresses, &mut shutdown, &mut report).await {
+        handle_session(&mut session, dial.options, sessions, Ok(handshake), shutdown, &mut report).await;
+    }
+    report
 }
 
-async fn handle_session(
+// What dialing out needs besides the peer itself.
+struct DialContext<'a> {
     private_key: SecretKey,
-    peer_public_key: PublicKey,
-    socket_address: SocketAddr,
-) -> Result<(), &'static str> {
-    let stream = match TcpStream::connect(&socket_address).await {
-        Ok(stream) => {
-            info!(
-                "TCP connection to {:?} established! ",
-                socket_address.to_string()
-            );
-            stream
+    frame_log: Option<&'a FrameLog>,
+    state_log: Option<&'a StateLog>,
+    rate_limiter: Option<&'a SubnetRateLimiter>,
+    options: &'a Options,
+}
+
+impl DialContext<'_> {
+    // Dials the node's addresses one after the other until one of them makes
+    // it through the handshake, and leaves that address in the report. If none
+    // does, the report keeps the last one tried and why it failed.
+    async fn first_to_handshake(
+        &self,
+        public_key: PublicKey,
+        addresses: &[SocketAddr],
+        shutdown: &mut watch::Receiver<bool>,
+        report: &mut PeerReport,
+    ) -> Option<Session> {
+        for (attempt, &ip_address) in addresses.iter().enumerate() {
+            report.address = ip_address;
+            if let Err(err) = self.options.ip_filter.check(ip_address.ip()) {
+                info!("Skipping {}: {}", ip_address, err);
+                report.rejected = Some(err);
+                continue;
+            }
+            report.rejected = None;
+            if let Some(rate_limiter) = self.rate_limiter {
+                rate_limiter.wait(ip_address.ip()).await;
+            }
+            if *shutdown.borrow() {
+                report.error = Some("Shutting down, not dialed".to_string());
+                return None;
+            }
+            if self.options.discv4_ping {
+                if let Err(err) = discv4::ping(&self.private_key, &public_key, ip_address, discv4::DEFAULT_PONG_TIMEOUT).await {
+                    info!("Skipping {}, not alive on discv4: {}", ip_address, err);
+                    report.error = Some(err.to_string());
+                    continue;
+                }
+                debug!("{} answered our discv4 Ping", ip_address);
+            }
+            if addresses.len() > 1 {
+                info!("Dialing {} ({}/{})", ip_address, attempt + 1, addresses.len());
+            }
+            let mut rplx_tp = RLPx::new(self.private_key, public_key, self.options.rlpx_config());
+            if let Some(frame_log) = self.frame_log {
+                rplx_tp.set_frame_log(frame_log.for_peer(ip_address.to_string()));
+            }
+            if let Some(state_log) = self.state_log {
+                rplx_tp.set_state_log(state_log.for_peer(ip_address.to_string()));
+            }
+            let mut session = match Session::dial(ip_address, rplx_tp, &self.options.dial).await {
+                Ok(session) => session,
+                Err(err) => {
+                    info!("Session error! {}", err);
+                    report.error = Some(err.to_string());
+                    continue;
+                }
+            };
+            start_deadline(&mut session, self.options);
+            let handshake = async {
+                if self.options.ecies_only {
+                    session.ecies_handshake().await.map(|_| ())
+                } else {
+                    session.handshake().await
+                }
+            };
+            let handshake = tokio::select! {
+                result = handshake => result.map_err(|err| err.to_string()),
+                _ = shutdown.wait_for(|quit| *quit) => Err("Interrupted during handshake".to_string()),
+            };
+            match handshake {
+                Ok(()) => {
+                    if addresses.len() > 1 {
+                        info!("Handshake with {} completed at {}", redact::public_key(&public_key), ip_address);
+                    }
+                    report.error = None;
+                    return Some(session);
+                }
+                Err(err) => {
+                    info!("Session error! {:?}", err);
+                    report.error = Some(err);
+                    report.byte_stats = session.byte_stats();
+                    if *shutdown.borrow() {
+                        return None;
+                    }
+                }
+            }
         }
-        Err(e) => {
-            info!(
-                "TCP connection to {:?} failed! Error {:?} ",
-                socket_address.to_string(),
-                e
-            );
-            return Err("TCP connection failed!");
+        None
+    }
+}
+
+// The address to put in the enode we hand out: what the STUN server sees if
+// we asked one and it answered, else --external-ip, else the listen address
+// unless that's a wildcard, else localhost.
+async fn advertised_ip(options: &Options, listen_ip: IpAddr) -> IpAddr {
+    if let Some(server) = &options.stun_server {
+        match stun::external_ip(server).await {
+            Ok(ip) => {
+                info!("STUN server {} sees us as {}", server, ip);
+                return ip;
+            }
+            Err(err) => warn!("Could not learn our external address from {}! {}", server, err),
+        }
+    }
+    if let Some(ip) = options.external_ip {
+        return ip;
+    }
+    if !listen_ip.is_unspecified() {
+        return listen_ip;
+    }
+    warn!("No external address known, advertising localhost");
+    if listen_ip.is_ipv6() {
+        Ipv6Addr::LOCALHOST.into()
+    } else {
+        Ipv4Addr::LOCALHOST.into()
+    }
+}
+
+// Accepts peers on --listen until Ctrl-C, then waits for the open sessions to
+// say their goodbyes.
+async fn listen(
+    private_key: SecretKey,
+    frame_log: Option<&FrameLog>,
+    state_log: Option<&StateLog>,
+    options: &Options,
+    sessions: &SessionManager,
+    shutdown: watch::Receiver<bool>,
+) -> Vec<PeerReport> {
+    let Some(address) = options.listen else {
+        return Vec::new();
+    };
+    let listener = match TcpListener::bind(address).await {
+        Ok(listener) => listener,
+        Err(err) => {
+            error!("Could not listen on {}! {}", address, err);
+            return Vec::new();
         }
     };
+    let node_id = PublicKey::from_secret_key(secp256k1::SECP256K1, &private_key).serialize_uncompressed();
+    let advertised = SocketAddr::new(advertised_ip(options, address.ip()).await, listener.local_addr().map_or(address.port(), |bound| bound.port()));
+    info!("Listening for peers on {} as enode://{}@{}", address, hex::encode(&node_id[1..]), advertised);
 
-    let rplx_tp = RLPx::new(private_key, peer_public_key);
+    let mut inbound = FuturesUnordered::new();
+    let mut reports = Vec::new();
+    let mut stop = shutdown.clone();
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => {
+                let (stream, peer_address) = match accepted {
+                    Ok(accepted) => accepted,
+                    Err(err) => {
+                        warn!("Accepting a connection failed! {}", err);
+                        continue;
+                    }
+                };
+                let mut report = PeerReport::new(None, peer_address);
+                report.inbound = true;
+                if let Err(err) = options.ip_filter.check(peer_address.ip()) {
+                    info!("Refusing {}: {}", peer_address, err);
+                    report.rejected = Some(err);
+                    reports.push(report);
+                    continue;
+                }
+                info!("Accepted connection from {}", peer_address);
+
+                let mut rplx_tp = RLPx::new_incoming(private_key, options.rlpx_config());
+                if let Some(frame_log) = frame_log {
+                    rplx_tp.set_frame_log(frame_log.for_peer(peer_address.to_string()));
+                }
+                if let Some(state_log) = state_log {
+                    rplx_tp.set_state_log(state_log.for_peer(peer_address.to_string()));
+                }
+                let handshake = sessions.begin_inbound();
+                let shutdown = shutdown.clone();
+                inbound.push(async move {
+                    let mut session = Session::new(stream, rplx_tp);
+                    start_deadline(&mut session, options);
+                    handle_session(&mut session, options, sessions, handshake, shutdown, &mut report).await;
+                    report
+                });
+            }
+            Some(report) = inbound.next(), if !inbound.is_empty() => reports.push(report),
+            _ = stop.wait_for(|quit| *quit) => break,
+        }
+    }
 
-    let mut framed: Framed<TcpStream, RLPx> = Framed::new(stream, rplx_tp);
+    reports.extend(inbound.collect::<Vec<_>>().await);
+    reports
+}
 
-    debug!("We're sending Auth!");
-    framed
-        .send(RLPx_Message::Auth)
-        .await
-        .map_err(|_| "Auth frame send Error ")?;
+async fn handle_session(
+    session: &mut Session,
+    options: &Options,
+    sessions: &SessionManager,
+    handshake: Result<HandshakeGuard<'_>, Rejection>,
+    shutdown: watch::Receiver<bool>,
+    report: &mut PeerReport,
+) {
+    match run_session(session, options, sessions, handshake, shutdown, report).await {
+        Ok(()) => info!("Session cleanly terminated"),
+        Err(err) => {
+            info!("Session error! {:?}", err);
+            report.error = Some(err);
+        }
+    }
+    report.public_key = session.peer_public_key();
+    report.byte_stats = session.byte_stats();
+}
 
-    debug!("We're recieving ack!");
-    match framed.next().await {
-        Some(Ok(RLPx_Message::AuthAck)) => {}
-        Some(Ok(_)) => return Err("Unexpected frame recieved"),
-        Some(Err(_)) => return Err("Codec Error"), 
-        None => return Err("Peer closed socket connection"),
+// --session-deadline-secs counts from when the connection is there.
+fn start_deadline(session: &mut Session, options: &Options) {
+    if let Some(session_deadline) = options.session_deadline {
+        session.set_deadline(Instant::now() + session_deadline);
     }
+}
 
-    debug!("We're sending Hello!");
-    framed
-        .send(RLPx_Message::Hello)
-        .await
-        .map_err(|_| "Frame send Error ")?;
+// --ecies-only: the peer gets no Hello, and with no framed connection there's
+// no Disconnect to send either, so it's just dropped. Both sides' MAC digests
+// are logged, the peer's ingress should match our egress and vice versa.
+async fn stop_after_ecies(
+    session: &mut Session,
+    mut shutdown: watch::Receiver<bool>,
+    report: &mut PeerReport,
+) -> Result<(), String> {
+    let secrets = tokio::select! {
+        result = session.ecies_handshake() => result.map_err(|err| err.to_string())?,
+        _ = shutdown.wait_for(|quit| *quit) => return Err("Interrupted during handshake".to_string()),
+    };
+    info!(
+        "ECIES handshake complete, egress MAC {} ingress MAC {}, stopping before Hello",
+        hex::encode(mac_digest(&secrets.egress_mac)),
+        hex::encode(mac_digest(&secrets.ingress_mac))
+    );
+    report.rejected = Some("Stopped after the ECIES handshake");
+    Ok(())
+}
 
-    debug!("We're waiting Hello!");
-    match framed.next().await {
-        Some(Ok(RLPx_Message::Hello)) => {}
-        Some(Ok(_)) => return Err("Unexpected frame recieved during Hello exchange"),
-        Some(Err(_)) => return Err("Codec Error during Hello exchange"), 
-        None => return Err("Peer closed socket connection"),
+async fn run_session(
+    session: &mut Session,
+    options: &Options,
+    sessions: &SessionManager,
+    // Released once the session is registered, or when we give up on it. A
+    // peer that dialed us with no inbound slot left is turned away with the
+    // rejection once the handshake is done.
+    handshake: Result<HandshakeGuard<'_>, Rejection>,
+    mut shutdown: watch::Receiver<bool>,
+    report: &mut PeerReport,
+) -> Result<(), String> {
+    if options.ecies_only {
+        return stop_after_ecies(session, shutdown, report).await;
     }
 
-    if framed.codec().get_state() != RlpxState::Active {return Err("Unexpected RLPx decoder state after handshake ")}
+    // Nothing to say goodbye to before the handshake is done, just drop it.
+    // Dialed sessions come here past it already.
+    tokio::select! {
+        result = session.handshake() => result.map_err(|err| err.to_string())?,
+        _ = shutdown.wait_for(|quit| *quit) => return Err("Interrupted during handshake".to_string()),
+    };
 
     info!("We've recieved Hello! Handshake (kinda') established. ");
+    report.handshake_complete = true;
+    let client_version = session
+        .peer_info()
+        .map(|peer_info| peer_info.client_version.clone())
+        .unwrap_or_default();
+    for shared in session.peer_info().map(|peer_info| &peer_info.shared_capabilities[..]).unwrap_or_default() {
+        info!(
+            "Negotiated {}/{} at offset {:#x}",
+            shared.capability.name, shared.capability.version, shared.offset
+        );
+    }
+    for (message_id, name) in session.supported_message_ids() {
+        debug!("Message id {:#04x} is {}", message_id, name);
+    }
+    info!("Peer runs {:?}", client_version);
+    report.client_version = Some(client_version.clone());
+    report.enode = session.peer_info().and_then(|peer_info| peer_info.enode(report.address.ip()));
+    match &report.enode {
+        Some(enode) => info!("Peer listens on {}", redact::enode(enode)),
+        None => info!("Peer does not accept inbound connections"),
+    }
+
+    if let Some(prefix) = &options.require_client_prefix {
+        if !client_version.starts_with(prefix.as_str()) {
+            info!("Peer client {:?} does not start with {:?}, disconnecting", client_version, prefix);
+            report.rejected = Some("Client version prefix mismatch");
+            session
+                .send(RLPx_Message::Disconnect(Disconnect::SUBPROTOCOL_REASON))
+                .await
+                .map_err(|err| err.to_string())?;
+            return Ok(());
+        }
+    }
+
+    // A crawl only wanted to know the peer is there and what it runs.
+    if options.crawl {
+        report.rejected = Some("Crawled");
+        session
+            .send(RLPx_Message::Disconnect(Disconnect::REQUESTED))
+            .await
+            .map_err(|err| err.to_string())?;
+        return Ok(());
+    }
+
+    let Some(peer_public_key) = session.peer_public_key() else {
+        return Err("Handshake finished without a peer key".to_string());
+    };
+    // The Ctrl-C handler only reaches registered sessions, so don't register
+    // one it has already gone past.
+    if *shutdown.borrow() {
+        report.rejected = Some("Client quitting");
+        session
+            .send(RLPx_Message::Disconnect(Disconnect::CLIENT_QUITTING))
+            .await
+            .map_err(|err| err.to_string())?;
+        return Ok(());
+    }
+    // The guard goes once the session is in the table, or has been refused.
+    let registration = handshake.and_then(|_handshake| sessions.add(&peer_public_key, session.direction()));
+    let mut registration = match registration {
+        Ok(registration) => registration,
+        Err(rejection) => {
+            info!("Not keeping the session: {}", rejection.why);
+            report.rejected = Some(rejection.why);
+            session
+                .send(RLPx_Message::Disconnect(rejection.reason))
+                .await
+                .map_err(|err| err.to_string())?;
+            return Ok(());
+        }
+    };
+
+    // Seeing the handshake through is all we're after, unless asked to stay.
+    // The session is registered by now, so a peer over the cap or already
+    // connected is turned away with the right reason either way.
+    if !options.keep_alive {
+        session
+            .send(RLPx_Message::Disconnect(Disconnect::REQUESTED))
+            .await
+            .map_err(|err| err.to_string())?;
+        return Ok(());
+    }
+
+    let mut keepalive = time::interval_at(Instant::now() + KEEPALIVE_INTERVAL, KEEPALIVE_INTERVAL);
+    let deadline = session.deadline();
+    let lifetime = time::sleep_until(deadline.unwrap_or_else(Instant::now));
+    tokio::pin!(lifetime);
     loop {
-        match framed.next().await {
+        let message = tokio::select! {
+            message = session.recv() => message,
+            _ = &mut lifetime, if deadline.is_some() => {
+                info!("Session deadline reached, disconnecting");
+                session
+                    .send(RLPx_Message::Disconnect(Disconnect::REQUESTED))
+                    .await
+                    .map_err(|err| err.to_string())?;
+                return Err(HandshakeError::SessionDeadlineExceeded.to_string());
+            }
+            _ = keepalive.tick() => {
+                session.queue(RLPx_Message::Ping).map_err(|err| err.to_string())?;
+                session.flush_queue().await.map_err(|err| err.to_string())?;
+                continue;
+            }
+            Some(command) = registration.commands.recv() => match command {
+                SessionCommand::Send(message) => {
+                    session.queue(message).map_err(|err| err.to_string())?;
+                    session.flush_queue().await.map_err(|err| err.to_string())?;
+                    continue;
+                }
+                SessionCommand::Close(reason, why) => {
+                    report.rejected = Some(why);
+                    session
+                        .send(RLPx_Message::Disconnect(reason))
+                        .await
+                        .map_err(|err| err.to_string())?;
+                    return Ok(());
+                }
+            },
+        };
+        match message {
             Some(Ok(message)) => match message {
-                RLPx_Message::Auth | RLPx_Message::AuthAck =>  return Err("Unexpected ack/auth frame recieved"),
-                RLPx_Message::Hello => return Err("Unexpected hello frame recieved"),
-                RLPx_Message::Ping => {}
-                RLPx_Message::Pong => {}
-                RLPx_Message::Disconnect(Reason) => {}
-                RLPx_Message::Status(Status) => {}
+                RLPx_Message::Auth | RLPx_Message::AuthAck =>  return Err("Unexpected ack/auth frame recieved".to_string()),
+                RLPx_Message::Hello => return Err("Unexpected hello frame recieved".to_string()),
+                RLPx_Message::Ping => {
+                    session.queue(RLPx_Message::Pong).map_err(|err| err.to_string())?;
+                    session.flush_queue().await.map_err(|err| err.to_string())?;
+                }
+                RLPx_Message::Pong => debug!("Peer answered our Ping"),
+                RLPx_Message::Disconnect(_) => return Ok(()),
+                RLPx_Message::Status(_) => {}
+                RLPx_Message::Transactions(hashes) => {
+                    for hash in hashes {
+                        info!("Transaction {}", hash);
+                    }
+                }
+                RLPx_Message::LesStatus(_) | RLPx_Message::LesAnnounce(_) => {}
+                // We keep no pool, so there's nothing to answer with.
+                RLPx_Message::GetPooledTransactions(_) => {}
+                RLPx_Message::PooledTransactions(response) => {
+                    for hash in response.hashes().map_err(|err| err.to_string())? {
+                        info!("Pooled transaction {} for request {}", hash, response.request_id);
+                    }
+                }
+                RLPx_Message::NewPooledTransactionHashes(announcement) => {
+                    for hash in announcement.hashes {
+                        info!("Pooled transaction announced {}", hash);
+                    }
+                }
+                RLPx_Message::NewBlockHashes(announced) => {
+                    for block in announced {
+                        info!("Block {} announced {}", block.number, block.hash);
+                    }
+                }
+                RLPx_Message::NewBlock(block) => {
+                    info!(
+                        "Block {} received {} with {} transactions, made at {}",
+                        block.number,
+                        block.hash,
+                        block.transactions.len(),
+                        block.timestamp
+                    );
+                }
+                RLPx_Message::P2p { id, body } => info!("p2p message {:#x} received, {}", id, hex::encode(body)),
+                RLPx_Message::Unknown { capability, code, body } => {
+                    info!("{} message {:#x} received, {} bytes", capability, code, body.len())
+                }
             }
-                
-            Some(Err(_)) => {
-                return Err("Codec Error");
-            } 
-            None => return Err("Peer closed socket connection"),
+            Some(Err(err)) => return Err(err.to_string()),
+            None => return Err("Peer closed socket connection".to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::RLPxConfig;
+    use tokio::net::TcpStream;
+
+    fn options(args: &[&str]) -> Options {
+        Options::from_args(args.iter().map(|arg| arg.to_string())).unwrap()
+    }
+
+    // Takes the next peer the way listen does, and runs its session.
+    async fn serve(
+        listener: &TcpListener,
+        private_key: SecretKey,
+        options: &Options,
+        sessions: &SessionManager,
+        shutdown: watch::Receiver<bool>,
+    ) -> PeerReport {
+        let (stream, peer_address) = listener.accept().await.unwrap();
+        let mut report = PeerReport::new(None, peer_address);
+        report.inbound = true;
+        let handshake = sessions.begin_inbound();
+        let mut session = Session::new(stream, RLPx::new_incoming(private_key, options.rlpx_config()));
+        handle_session(&mut session, options, sessions, handshake, shutdown, &mut report).await;
+        report
+    }
+
+    async fn dial(address: SocketAddr, listener_key: &SecretKey) -> Session {
+        let stream = TcpStream::connect(address).await.unwrap();
+        let public_key = PublicKey::from_secret_key(secp256k1::SECP256K1, listener_key);
+        let our_key = SecretKey::new(&mut secp256k1::rand::thread_rng());
+        Session::new(stream, RLPx::new(our_key, public_key, RLPxConfig::default()))
+    }
+
+    #[tokio::test]
+    async fn inbound_peer_over_max_peers_is_told_too_many_peers() {
+        let options = options(&["--max-peers", "1", "--keep-alive"]);
+        let private_key = SecretKey::new(&mut secp256k1::rand::thread_rng());
+        let sessions = SessionManager::new(&private_key, options.max_peers);
+        let (quit, shutdown) = watch::channel(false);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+
+        // The first peer takes the only slot and stays.
+        let first = serve(&listener, private_key, &options, &sessions, shutdown.clone());
+        tokio::pin!(first);
+        let mut first_peer = dial(address, &private_key).await;
+        tokio::select! {
+            _ = &mut first => panic!("The first session ended early"),
+            result = first_peer.handshake() => result.unwrap(),
         }
-    }  
 
+        // The second gets through the handshake, then is turned away.
+        let mut second_peer = dial(address, &private_key).await;
+        let second = async {
+            tokio::join!(serve(&listener, private_key, &options, &sessions, shutdown.clone()), async {
+                second_peer.handshake().await.unwrap();
+                second_peer.recv().await
+            })
+        };
+        let (report, message) = tokio::select! {
+            _ = &mut first => panic!("The first session ended early"),
+            second = second => second,
+        };
+        assert_eq!(report.rejected, Some("Too many peers"));
+        assert!(report.handshake_complete);
+        assert!(matches!(message, Some(Ok(RLPx_Message::Disconnect(Disconnect::TOO_MANY_PEERS)))));
 
+        // The first is still up, and leaves on Ctrl-C.
+        quit.send(true).unwrap();
+        sessions.close_all(Disconnect::CLIENT_QUITTING, "Client quitting");
+        let report = first.await;
+        assert_eq!(report.rejected, Some("Client quitting"));
+        assert!(matches!(first_peer.recv().await, Some(Ok(RLPx_Message::Disconnect(Disconnect::CLIENT_QUITTING)))));
+    }
 }
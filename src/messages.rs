@@ -1,7 +1,14 @@
-use alloy_rlp::{RlpDecodable, RlpEncodable};
+use alloy_primitives::{keccak256, Bytes, TxHash, B256};
+use alloy_rlp::{Decodable, Header, PayloadView, RlpDecodable, RlpEncodable};
+use std::str::FromStr;
+
 pub type Reason = usize;
 
-#[derive(Debug)]
+// Message ids below this belong to the p2p base protocol.
+pub const BASE_PROTOCOL_IDS: u8 = 0x10;
+
+#[allow(non_camel_case_types, dead_code)]
+#[derive(Clone, Debug)]
 pub enum RLPx_Message {
     Auth,
     AuthAck,
@@ -10,9 +17,60 @@ pub enum RLPx_Message {
     Pong,
     Disconnect(Reason),
     Status(Status),
+    Transactions(Vec<TxHash>),
+    NewPooledTransactionHashes(PooledTransactionHashes),
+    GetPooledTransactions(GetPooledTransactions),
+    PooledTransactions(PooledTransactions),
+    NewBlockHashes(Vec<BlockHashNumber>),
+    NewBlock(NewBlock),
+    // les messages, left as their undecoded RLP bodies for now.
+    LesStatus(Vec<u8>),
+    LesAnnounce(Vec<u8>),
+    // Any other p2p message (id below BASE_PROTOCOL_IDS), with its RLP body
+    // as is, past snappy. New base protocol messages come through here
+    // instead of failing the session.
+    P2p { id: u8, body: Vec<u8> },
+    // A message of a negotiated capability we route but don't decode (wit
+    // for now), by its code within the capability and with its RLP body past
+    // snappy.
+    Unknown { capability: String, code: u8, body: Vec<u8> },
 }
 
-#[derive(Debug, RlpEncodable, RlpDecodable, PartialEq, Eq)]
+impl RLPx_Message {
+    // p2p housekeeping rather than subprotocol data, it's small and waiting on
+    // it holds the session up.
+    pub fn is_control(&self) -> bool {
+        matches!(
+            self,
+            RLPx_Message::Ping | RLPx_Message::Pong | RLPx_Message::Disconnect(_) | RLPx_Message::P2p { .. }
+        )
+    }
+
+    // Roughly what the message takes up while it waits to be sent, for the
+    // session memory budget.
+    pub fn approx_size(&self) -> usize {
+        let heap = match self {
+            RLPx_Message::Transactions(hashes) => hashes.len() * size_of::<TxHash>(),
+            RLPx_Message::NewPooledTransactionHashes(announcement) => {
+                announcement.types.len()
+                    + announcement.sizes.len() * size_of::<u64>()
+                    + announcement.hashes.len() * size_of::<TxHash>()
+            }
+            RLPx_Message::GetPooledTransactions(request) => request.hashes.len() * size_of::<TxHash>(),
+            RLPx_Message::PooledTransactions(response) => response.transactions.iter().map(|transaction| transaction.len()).sum(),
+            RLPx_Message::NewBlockHashes(announced) => announced.len() * size_of::<BlockHashNumber>(),
+            RLPx_Message::NewBlock(block) => block.transactions.len() * size_of::<TxHash>(),
+            RLPx_Message::LesStatus(body)
+            | RLPx_Message::LesAnnounce(body)
+            | RLPx_Message::P2p { body, .. }
+            | RLPx_Message::Unknown { body, .. } => body.len(),
+            _ => 0,
+        };
+        size_of::<Self>() + heap
+    }
+}
+
+#[derive(Debug, RlpEncodable, PartialEq, Eq)]
 pub struct Hello {
     pub protocol_version: usize,
     pub client_version: String,
@@ -23,21 +81,238 @@ pub struct Hello {
 
 impl Hello {
     pub const ID: u8 = 0x00;
+    pub const TOO_MANY_CAPABILITIES: &'static str = "Hello advertises too many capabilities";
+    pub const EMPTY_CAPABILITY_NAME: &'static str = "Hello advertises a capability without a name";
+    pub const CLIENT_VERSION_TOO_LONG: &'static str = "Hello client version is too long";
+
+    // Same as decode, but gives up on a capability list longer than
+    // max_capabilities before decoding any more of it, and on a client version
+    // longer than max_client_version_len before it's copied out.
+    pub fn decode_limited(
+        buf: &mut &[u8],
+        max_capabilities: usize,
+        max_client_version_len: usize,
+    ) -> alloy_rlp::Result<Self> {
+        let mut payload = Header::decode_bytes(buf, true)?;
+        Ok(Self {
+            protocol_version: Decodable::decode(&mut payload)?,
+            client_version: decode_client_version(&mut payload, max_client_version_len)?,
+            capabilities: decode_capabilities(&mut payload, max_capabilities)?,
+            port: Decodable::decode(&mut payload)?,
+            id: Decodable::decode(&mut payload)?,
+        })
+    }
 }
 
-#[derive(Debug, RlpEncodable, RlpDecodable, PartialEq, Eq)]
+fn decode_client_version(buf: &mut &[u8], max_len: usize) -> alloy_rlp::Result<String> {
+    // Only a peek at the header, the String decoder reads it again.
+    let header = Header::decode(&mut &buf[..])?;
+    if !header.list && header.payload_length > max_len {
+        return Err(alloy_rlp::Error::Custom(Hello::CLIENT_VERSION_TOO_LONG));
+    }
+    Decodable::decode(buf)
+}
+
+fn decode_capabilities(buf: &mut &[u8], max_capabilities: usize) -> alloy_rlp::Result<Vec<Capability>> {
+    let mut payload = Header::decode_bytes(buf, true)?;
+    let mut capabilities = Vec::new();
+    while !payload.is_empty() {
+        if capabilities.len() >= max_capabilities {
+            return Err(alloy_rlp::Error::Custom(Hello::TOO_MANY_CAPABILITIES));
+        }
+        let capability = Capability::decode(&mut payload)?;
+        if capability.name.is_empty() {
+            return Err(alloy_rlp::Error::Custom(Hello::EMPTY_CAPABILITY_NAME));
+        }
+        capabilities.push(capability);
+    }
+    Ok(capabilities)
+}
+
+// devp2p lets newer clients append fields to a message, and older ones must
+// ignore whatever they don't know about. The derived decoders reject any extra
+// list items, hence the hand written ones for Hello and Status.
+impl Decodable for Hello {
+    fn decode(buf: &mut &[u8]) -> alloy_rlp::Result<Self> {
+        Self::decode_limited(buf, usize::MAX, usize::MAX)
+    }
+}
+
+// Field order matters, the derived Ord sorts by name and then version, which is
+// the canonical order devp2p lays capabilities out in.
+#[derive(Clone, Debug, RlpEncodable, RlpDecodable, PartialEq, Eq, PartialOrd, Ord)]
 pub struct Capability {
     pub name: String,
     pub version: usize,
 }
 
-#[derive(Debug, RlpEncodable, RlpDecodable, PartialEq, Eq)]
+impl Capability {
+    // How many message ids the capability takes up, needed to place the ones
+    // after it. None for anything we don't know the layout of.
+    pub fn message_count(&self) -> Option<u8> {
+        match (self.name.as_str(), self.version) {
+            ("eth", 66..=68) => Some(17),
+            ("snap", 1) => Some(8),
+            ("les", 2) => Some(22),
+            ("les", 3 | 4) => Some(24),
+            // NewWitness, NewWitnessHashes, GetWitness, Witness.
+            ("wit", 0) => Some(4),
+            _ => None,
+        }
+    }
+
+    // The codes within the capability that we can decode or encode, by name.
+    pub fn known_messages(&self) -> &'static [(u8, &'static str)] {
+        match self.name.as_str() {
+            "eth" => &[
+                (Status::CODE, "eth/Status"),
+                (NewBlockHashes::CODE, "eth/NewBlockHashes"),
+                (Transactions::CODE, "eth/Transactions"),
+                (NewBlock::CODE, "eth/NewBlock"),
+                (PooledTransactionHashes::CODE, "eth/NewPooledTransactionHashes"),
+                (GetPooledTransactions::CODE, "eth/GetPooledTransactions"),
+                (PooledTransactions::CODE, "eth/PooledTransactions"),
+            ],
+            "les" => &[(Les::STATUS_CODE, "les/Status"), (Les::ANNOUNCE_CODE, "les/Announce")],
+            _ => &[],
+        }
+    }
+}
+
+// The p2p base protocol messages, whatever got negotiated on top.
+pub const BASE_PROTOCOL_MESSAGES: [(u8, &str); 4] =
+    [(Hello::ID, "Hello"), (Disconnect::ID, "Disconnect"), (Ping::ID, "Ping"), (Pong::ID, "Pong")];
+
+// name/version, e.g. eth/68
+impl FromStr for Capability {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, version) = s.split_once('/').ok_or("Invalid capability format! ")?;
+        let capability = Capability {
+            name: name.to_string(),
+            version: version.parse().map_err(|_| "Invalid capability version! ")?,
+        };
+        capability.message_count().ok_or("Unsupported capability! ")?;
+        Ok(capability)
+    }
+}
+
+// A capability both sides speak, with the first message id it was given.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SharedCapability {
+    pub capability: Capability,
+    pub offset: u8,
+}
+
+impl SharedCapability {
+    // The absolute message id for one of the capability's own message codes.
+    pub fn message_id(&self, code: u8) -> Option<u8> {
+        if code >= self.capability.message_count()? {
+            return None;
+        }
+        self.offset.checked_add(code)
+    }
+
+    pub fn owns(&self, message_id: u8) -> bool {
+        message_id
+            .checked_sub(self.offset)
+            .and_then(|code| self.message_id(code))
+            .is_some()
+    }
+}
+
+// Per devp2p, every name both sides know is matched at the highest version
+// both support. The matches are then laid out in name order, starting right
+// after the base protocol's message ids.
+pub fn negotiate_capabilities(ours: &[Capability], theirs: &[Capability]) -> Result<Vec<SharedCapability>, &'static str> {
+    let mut shared: Vec<&Capability> = Vec::new();
+    // Only versions we know the layout of get matched, which leaves out a peer
+    // claiming eth/0 say. wit is the one that really does start at 0.
+    for capability in ours
+        .iter()
+        .filter(|capability| capability.message_count().is_some() && theirs.contains(capability))
+    {
+        match shared.iter_mut().find(|known| known.name == capability.name) {
+            Some(known) if known.version < capability.version => *known = capability,
+            Some(_) => {}
+            None => shared.push(capability),
+        }
+    }
+    shared.sort();
+
+    let mut offset = BASE_PROTOCOL_IDS;
+    let shared: Vec<SharedCapability> = shared
+        .into_iter()
+        .filter_map(|capability| {
+            let count = capability.message_count()?;
+            let shared = SharedCapability {
+                capability: capability.clone(),
+                offset,
+            };
+            offset = offset.checked_add(count)?;
+            Some(shared)
+        })
+        .collect();
+    check_offsets(&shared)?;
+    Ok(shared)
+}
+
+// Ids below BASE_PROTOCOL_IDS are p2p's own, and every message id has to
+// route to exactly one capability. A layout that breaks either would send
+// frames to the wrong handler without anything failing, so it's refused
+// outright. It can only come from a bug in the layout above.
+pub fn check_offsets(shared: &[SharedCapability]) -> Result<(), &'static str> {
+    let mut next_free = BASE_PROTOCOL_IDS;
+    for capability in shared {
+        if capability.offset < next_free {
+            return Err(if next_free == BASE_PROTOCOL_IDS {
+                "Capability offset overlaps the p2p message ids"
+            } else {
+                "Capability offsets overlap"
+            });
+        }
+        let count = capability.capability.message_count().ok_or("Shared capability we don't know")?;
+        next_free = capability.offset.checked_add(count).ok_or("Capability offsets past the last message id")?;
+    }
+    Ok(())
+}
+
+#[derive(Debug, RlpEncodable, PartialEq, Eq)]
 pub struct Disconnect {
     pub reason: usize,
 }
 
+// The spec wraps the reason in a list, but some clients send it bare and some
+// send an empty list or no body at all. Anything without a reason counts as
+// 0x00, disconnect requested.
+impl Decodable for Disconnect {
+    fn decode(buf: &mut &[u8]) -> alloy_rlp::Result<Self> {
+        if buf.is_empty() {
+            return Ok(Self {
+                reason: Self::REQUESTED,
+            });
+        }
+        if buf.first().is_some_and(|byte| *byte >= alloy_rlp::EMPTY_LIST_CODE) {
+            let mut payload = Header::decode_bytes(buf, true)?;
+            let reason = if payload.is_empty() { Self::REQUESTED } else { Decodable::decode(&mut payload)? };
+            return Ok(Self { reason });
+        }
+        Ok(Self {
+            reason: Decodable::decode(buf)?,
+        })
+    }
+}
+
 impl Disconnect {
     pub const ID: u8 = 0x1;
+    pub const REQUESTED: Reason = 0x00;
+    pub const PROTOCOL_BREACH: Reason = 0x02;
+    pub const USELESS_PEER: Reason = 0x03;
+    pub const TOO_MANY_PEERS: Reason = 0x04;
+    pub const ALREADY_CONNECTED: Reason = 0x05;
+    pub const CLIENT_QUITTING: Reason = 0x08;
+    pub const SUBPROTOCOL_REASON: Reason = 0x10;
 }
 
 #[derive(Debug, RlpEncodable, RlpDecodable, PartialEq, Eq)]
@@ -54,13 +329,15 @@ impl Pong {
     pub const ID: u8 = 0x3;
 }
 
-#[derive(Debug, RlpEncodable, RlpDecodable, PartialEq, Eq)]
+#[derive(Clone, Debug, RlpEncodable, RlpDecodable, PartialEq, Eq)]
 pub struct ForkId {
-    hash: u32,
-    next: u64,
+    // CRC32 of the genesis hash and every fork passed, sent as a 4 byte string.
+    pub hash: [u8; 4],
+    // The next fork we know of, 0 if none.
+    pub next: u64,
 }
 
-#[derive(Debug, RlpEncodable, RlpDecodable, PartialEq, Eq)]
+#[derive(Clone, Debug, RlpEncodable, PartialEq, Eq)]
 pub struct Status {
     pub version: u8,
     pub networkid: u64,
@@ -71,5 +348,514 @@ pub struct Status {
 }
 
 impl Status {
-    pub const ID: u8 = 16;
+    pub const CAPABILITY: &'static str = "eth";
+    // Relative to wherever eth ended up in the negotiated layout.
+    pub const CODE: u8 = 0x00;
+}
+
+impl Decodable for Status {
+    fn decode(buf: &mut &[u8]) -> alloy_rlp::Result<Self> {
+        let mut payload = Header::decode_bytes(buf, true)?;
+        Ok(Self {
+            version: Decodable::decode(&mut payload)?,
+            networkid: Decodable::decode(&mut payload)?,
+            td: Decodable::decode(&mut payload)?,
+            blockhash: Decodable::decode(&mut payload)?,
+            genesis: Decodable::decode(&mut payload)?,
+            forkid: Decodable::decode(&mut payload)?,
+        })
+    }
+}
+
+// eth Transactions, kept down to the hashes of the transactions it carries.
+pub struct Transactions;
+
+impl Transactions {
+    pub const CAPABILITY: &'static str = "eth";
+    pub const CODE: u8 = 0x02;
+
+    pub fn decode_hashes(buf: &mut &[u8]) -> alloy_rlp::Result<Vec<TxHash>> {
+        transaction_list(buf)?.into_iter().map(transaction_hash).collect()
+    }
+}
+
+// The transactions of a list, each as it went over the wire.
+fn transaction_list<'a>(buf: &mut &'a [u8]) -> alloy_rlp::Result<Vec<&'a [u8]>> {
+    let PayloadView::List(transactions) = Header::decode_raw(buf)? else {
+        return Err(alloy_rlp::Error::UnexpectedString);
+    };
+    Ok(transactions)
+}
+
+fn owned(transactions: Vec<&[u8]>) -> Vec<Bytes> {
+    transactions.into_iter().map(Bytes::copy_from_slice).collect()
+}
+
+// Legacy transactions come as their RLP list and hash as such, typed ones
+// come as a string wrapping type || payload and hash without the wrapping.
+pub fn transaction_hash(mut transaction: &[u8]) -> alloy_rlp::Result<TxHash> {
+    if transaction.first().is_some_and(|byte| *byte >= alloy_rlp::EMPTY_LIST_CODE) {
+        Ok(keccak256(transaction))
+    } else {
+        Header::decode_bytes(&mut transaction, false).map(keccak256)
+    }
+}
+
+// eth NewBlockHashes, blocks announced by hash and number only. Gone from
+// eth/69 on, with the merge blocks stopped being gossiped.
+pub struct NewBlockHashes;
+
+impl NewBlockHashes {
+    pub const CAPABILITY: &'static str = "eth";
+    pub const CODE: u8 = 0x01;
+
+    pub fn decode(buf: &mut &[u8]) -> alloy_rlp::Result<Vec<BlockHashNumber>> {
+        Decodable::decode(buf)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, RlpEncodable, RlpDecodable)]
+pub struct BlockHashNumber {
+    pub hash: B256,
+    pub number: u64,
+}
+
+// eth NewBlock, a whole block pushed to us along with the chain's total
+// difficulty. Only what it takes to tell which block it is and when it was
+// made is picked out, nothing is validated. Gone from eth/69 on as well.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct NewBlock {
+    // keccak256 of the header as it went over the wire.
+    pub hash: B256,
+    pub parent_hash: B256,
+    pub number: u64,
+    pub timestamp: u64,
+    pub transactions: Vec<TxHash>,
+    pub td: u128,
+}
+
+impl NewBlock {
+    pub const CAPABILITY: &'static str = "eth";
+    pub const CODE: u8 = 0x07;
+
+    // [[header, transactions, ommers, ...], td]
+    pub fn decode(buf: &mut &[u8]) -> alloy_rlp::Result<Self> {
+        let mut payload = Header::decode_bytes(buf, true)?;
+        let mut block = Header::decode_bytes(&mut payload, true)?;
+
+        let raw_header = block;
+        let mut header = Header::decode_bytes(&mut block, true)?;
+        let hash = keccak256(&raw_header[..raw_header.len() - block.len()]);
+
+        // header = [parent-hash, ommers-hash, coinbase, state-root, txs-root,
+        //           receipts-root, bloom, difficulty, number, gas-limit,
+        //           gas-used, time, ...]
+        let parent_hash = B256::decode(&mut header)?;
+        skip_items(&mut header, 7)?;
+        let number = u64::decode(&mut header)?;
+        skip_items(&mut header, 2)?;
+        let timestamp = u64::decode(&mut header)?;
+
+        let transactions = transaction_list(&mut block)?
+            .into_iter()
+            .map(transaction_hash)
+            .collect::<alloy_rlp::Result<_>>()?;
+        Ok(Self {
+            hash,
+            parent_hash,
+            number,
+            timestamp,
+            transactions,
+            td: Decodable::decode(&mut payload)?,
+        })
+    }
+}
+
+// Steps over count items of whatever kind, without decoding them.
+fn skip_items(buf: &mut &[u8], count: usize) -> alloy_rlp::Result<()> {
+    for _ in 0..count {
+        let header = Header::decode(buf)?;
+        if buf.len() < header.payload_length {
+            return Err(alloy_rlp::Error::InputTooShort);
+        }
+        *buf = &buf[header.payload_length..];
+    }
+    Ok(())
+}
+
+// eth NewPooledTransactionHashes. Before eth/68 it's a bare list of hashes,
+// from then on each hash also comes with its transaction's type and size.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PooledTransactionHashes {
+    pub types: Vec<u8>,
+    pub sizes: Vec<u64>,
+    pub hashes: Vec<TxHash>,
+}
+
+#[derive(RlpDecodable)]
+struct PooledTransactionHashes68 {
+    types: Bytes,
+    sizes: Vec<u64>,
+    hashes: Vec<TxHash>,
+}
+
+impl PooledTransactionHashes {
+    pub const CAPABILITY: &'static str = "eth";
+    pub const CODE: u8 = 0x08;
+
+    pub fn decode(buf: &mut &[u8], eth_version: usize) -> alloy_rlp::Result<Self> {
+        if eth_version < 68 {
+            return Ok(Self {
+                hashes: Decodable::decode(buf)?,
+                ..Default::default()
+            });
+        }
+        let announcement = PooledTransactionHashes68::decode(buf)?;
+        if announcement.types.len() != announcement.hashes.len()
+            || announcement.sizes.len() != announcement.hashes.len()
+        {
+            return Err(alloy_rlp::Error::Custom("announcement lists differ in length"));
+        }
+        Ok(Self {
+            types: announcement.types.to_vec(),
+            sizes: announcement.sizes,
+            hashes: announcement.hashes,
+        })
+    }
+}
+
+// eth GetPooledTransactions. Since eth/66 requests carry an id the response
+// echoes back, older versions send the bare list of hashes.
+#[derive(Clone, Debug, Default, PartialEq, Eq, RlpEncodable, RlpDecodable)]
+pub struct GetPooledTransactions {
+    pub request_id: u64,
+    pub hashes: Vec<TxHash>,
+}
+
+impl GetPooledTransactions {
+    pub const CAPABILITY: &'static str = "eth";
+    pub const CODE: u8 = 0x09;
+
+    pub fn decode(buf: &mut &[u8], eth_version: usize) -> alloy_rlp::Result<Self> {
+        if eth_version < REQUEST_ID_ETH_VERSION {
+            return Ok(Self {
+                hashes: Decodable::decode(buf)?,
+                ..Default::default()
+            });
+        }
+        <Self as Decodable>::decode(buf)
+    }
+}
+
+// eth PooledTransactions, the answer to GetPooledTransactions. The
+// transactions are kept as they went over the wire.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PooledTransactions {
+    pub request_id: u64,
+    pub transactions: Vec<Bytes>,
+}
+
+impl PooledTransactions {
+    pub const CAPABILITY: &'static str = "eth";
+    pub const CODE: u8 = 0x0a;
+
+    pub fn decode(buf: &mut &[u8], eth_version: usize) -> alloy_rlp::Result<Self> {
+        if eth_version < REQUEST_ID_ETH_VERSION {
+            return Ok(Self {
+                transactions: owned(transaction_list(buf)?),
+                ..Default::default()
+            });
+        }
+        let mut payload = Header::decode_bytes(buf, true)?;
+        let request_id = u64::decode(&mut payload)?;
+        Ok(Self {
+            request_id,
+            transactions: owned(transaction_list(&mut payload)?),
+        })
+    }
+
+    pub fn hashes(&self) -> alloy_rlp::Result<Vec<TxHash>> {
+        self.transactions.iter().map(|transaction| pooled_transaction_hash(transaction)).collect()
+    }
+}
+
+// EIP-4844 blob transactions.
+const BLOB_TX_TYPE: u8 = 0x03;
+
+// Blob transactions are pooled in their network form, with the sidecar:
+// 0x03 || rlp([tx_payload_body, blobs, commitments, proofs]). They hash as
+// 0x03 || rlp(tx_payload_body) all the same, as they do in a block. Told
+// apart from the form without the sidecar by tx_payload_body being a list,
+// where that one starts with the chain id.
+fn pooled_transaction_hash(mut transaction: &[u8]) -> alloy_rlp::Result<TxHash> {
+    if transaction.first().is_some_and(|byte| *byte >= alloy_rlp::EMPTY_LIST_CODE) {
+        return Ok(keccak256(transaction));
+    }
+    let typed = Header::decode_bytes(&mut transaction, false)?;
+    let Some(mut wrapper) = typed.strip_prefix(&[BLOB_TX_TYPE]) else {
+        return Ok(keccak256(typed));
+    };
+    let PayloadView::List(fields) = Header::decode_raw(&mut wrapper)? else {
+        return Err(alloy_rlp::Error::UnexpectedString);
+    };
+    match fields.first() {
+        Some(body) if body.first().is_some_and(|byte| *byte >= alloy_rlp::EMPTY_LIST_CODE) => {
+            Ok(keccak256([&[BLOB_TX_TYPE][..], body].concat()))
+        }
+        _ => Ok(keccak256(typed)),
+    }
+}
+
+// First eth version whose requests and responses carry a request id.
+pub const REQUEST_ID_ETH_VERSION: usize = 66;
+
+// The light client protocol. Only enough of it to route its messages, the
+// bodies aren't decoded.
+pub struct Les;
+
+impl Les {
+    pub const CAPABILITY: &'static str = "les";
+    pub const STATUS_CODE: u8 = 0x00;
+    pub const ANNOUNCE_CODE: u8 = 0x01;
+}
+
+// Block witnesses for stateless clients. Its messages are only routed so far,
+// they come out as RLPx_Message::Unknown.
+pub struct Wit;
+
+impl Wit {
+    pub const CAPABILITY: &'static str = "wit";
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloy_primitives::{hex, Address};
+    use alloy_rlp::Encodable;
+
+    // The string an eth message wraps a typed transaction in.
+    fn typed(transaction: &[u8]) -> Bytes {
+        let mut wrapped = Vec::new();
+        Header { list: false, payload_length: transaction.len() }.encode(&mut wrapped);
+        wrapped.extend_from_slice(transaction);
+        wrapped.into()
+    }
+
+    #[test]
+    fn pooled_blob_transaction_hashes_without_its_sidecar() {
+        // chain id, nonce and so on, enough to stand in for tx_payload_body.
+        let body = alloy_rlp::encode(vec![1_u64, 7, 1_000, 21_000]);
+        let sidecar = [
+            alloy_rlp::encode(vec![Bytes::from(vec![0xbb; 128])]),
+            alloy_rlp::encode(vec![Bytes::from(vec![0xcc; 48])]),
+            alloy_rlp::encode(vec![Bytes::from(vec![0xdd; 48])]),
+        ]
+        .concat();
+        let mut network = vec![BLOB_TX_TYPE];
+        Header { list: true, payload_length: body.len() + sidecar.len() }.encode(&mut network);
+        network.extend_from_slice(&body);
+        network.extend_from_slice(&sidecar);
+        let canonical = [&[BLOB_TX_TYPE][..], &body].concat();
+
+        let legacy = alloy_rlp::encode(vec![0_u64, 1, 2]);
+        let response = PooledTransactions {
+            request_id: 5,
+            transactions: vec![typed(&network), legacy.clone().into(), typed(&canonical)],
+        };
+        assert_eq!(
+            response.hashes().unwrap(),
+            vec![keccak256(&canonical), keccak256(&legacy), keccak256(&canonical)]
+        );
+    }
+
+    #[test]
+    fn eth_69_is_not_offered() {
+        // Its Status has another layout, which we don't speak.
+        assert!("eth/69".parse::<Capability>().is_err());
+        let eth69 = vec![Capability { name: "eth".to_string(), version: 69 }];
+        assert_eq!(negotiate_capabilities(&eth69, &eth69), Ok(Vec::new()));
+    }
+
+    // The encoded list with one more item on the end, as a newer client sends it.
+    fn with_extra_field(encoded: &[u8], extra: &[u8]) -> Vec<u8> {
+        let mut payload = Header::decode_bytes(&mut &encoded[..], true).unwrap().to_vec();
+        payload.extend_from_slice(extra);
+        let mut extended = Vec::new();
+        Header { list: true, payload_length: payload.len() }.encode(&mut extended);
+        extended.extend_from_slice(&payload);
+        extended
+    }
+
+    #[test]
+    fn hello_and_status_with_trailing_fields_decode() {
+        let hello = Hello {
+            protocol_version: 5,
+            client_version: "geth/v1.14".to_string(),
+            capabilities: vec![Capability { name: "eth".to_string(), version: 68 }],
+            port: 30303,
+            id: [7; 64],
+        };
+        let extended = with_extra_field(&alloy_rlp::encode(&hello), &alloy_rlp::encode("future"));
+        assert_eq!(Hello::decode(&mut &extended[..]).unwrap(), hello);
+
+        let status = Status {
+            version: 68,
+            networkid: 1,
+            td: 17,
+            blockhash: [1; 32],
+            genesis: [2; 32],
+            forkid: ForkId { hash: [3; 4], next: 0 },
+        };
+        let extended = with_extra_field(&alloy_rlp::encode(&status), &alloy_rlp::encode(42_u64));
+        assert_eq!(Status::decode(&mut &extended[..]).unwrap(), status);
+    }
+
+    #[test]
+    fn disconnect_reason_decodes_wrapped_bare_or_missing() {
+        let decode = |body: &[u8]| Disconnect::decode(&mut &body[..]).unwrap().reason;
+        // geth and most others: [reason].
+        assert_eq!(decode(&[0xc1, 0x04]), Disconnect::TOO_MANY_PEERS);
+        // Some clients send the reason on its own.
+        assert_eq!(decode(&[0x04]), Disconnect::TOO_MANY_PEERS);
+        assert_eq!(decode(&[0x10]), Disconnect::SUBPROTOCOL_REASON);
+        // No reason at all is a plain request.
+        assert_eq!(decode(&[0xc0]), Disconnect::REQUESTED);
+        assert_eq!(decode(&[]), Disconnect::REQUESTED);
+    }
+
+    #[test]
+    fn capability_list_past_the_cap_is_refused_without_reading_the_rest() {
+        let hello = |count: usize| Hello {
+            protocol_version: 5,
+            client_version: "geth/v1.14".to_string(),
+            capabilities: (0..count).map(|n| Capability { name: format!("c{}", n), version: 1 }).collect(),
+            port: 30303,
+            id: [7; 64],
+        };
+        let decode = |encoded: &[u8]| Hello::decode_limited(&mut &encoded[..], 64, 1024);
+        assert_eq!(
+            decode(&alloy_rlp::encode(hello(5000))),
+            Err(alloy_rlp::Error::Custom(Hello::TOO_MANY_CAPABILITIES))
+        );
+        // Right at the cap is fine.
+        assert_eq!(decode(&alloy_rlp::encode(hello(64))), Ok(hello(64)));
+
+        // 64 good ones and then a megabyte that isn't RLP at all: it never gets
+        // as far as finding out.
+        let at_cap = hello(64);
+        let mut capabilities = Vec::new();
+        at_cap.capabilities.iter().for_each(|capability| capability.encode(&mut capabilities));
+        capabilities.extend_from_slice(&[0xff; 1 << 20]);
+        let mut payload = Vec::new();
+        at_cap.protocol_version.encode(&mut payload);
+        at_cap.client_version.encode(&mut payload);
+        Header { list: true, payload_length: capabilities.len() }.encode(&mut payload);
+        payload.extend_from_slice(&capabilities);
+        at_cap.port.encode(&mut payload);
+        at_cap.id.encode(&mut payload);
+        let mut encoded = Vec::new();
+        Header { list: true, payload_length: payload.len() }.encode(&mut encoded);
+        encoded.extend_from_slice(&payload);
+        assert_eq!(decode(&encoded), Err(alloy_rlp::Error::Custom(Hello::TOO_MANY_CAPABILITIES)));
+    }
+
+    // Items already RLP encoded, wrapped up as a list.
+    fn list(items: &[Vec<u8>]) -> Vec<u8> {
+        let payload = items.concat();
+        let mut encoded = Vec::new();
+        Header { list: true, payload_length: payload.len() }.encode(&mut encoded);
+        encoded.extend_from_slice(&payload);
+        encoded
+    }
+
+    #[test]
+    fn new_block_picks_out_the_mainnet_genesis() {
+        let zero_hash = alloy_rlp::encode(B256::ZERO);
+        let empty_trie = alloy_rlp::encode(B256::from(hex!("56e81f171bcc55a6ff8345e692c0f86e5b48e01b996cadc001622fb5e363b421")));
+        let header = list(&[
+            zero_hash.clone(),
+            alloy_rlp::encode(B256::from(hex!("1dcc4de8dec75d7aab85b567b6ccd41ad312451b948a7413f0a142fd40d49347"))),
+            alloy_rlp::encode(Address::ZERO),
+            alloy_rlp::encode(B256::from(hex!("d7f8974fb5ac78d9ac099b9ad5018bedc2ce0a72dad1827a1709da30580f0544"))),
+            empty_trie.clone(),
+            empty_trie,
+            alloy_rlp::encode(Bytes::from(vec![0; 256])),
+            alloy_rlp::encode(0x4_0000_0000_u64),
+            alloy_rlp::encode(0_u64),
+            alloy_rlp::encode(5000_u64),
+            alloy_rlp::encode(0_u64),
+            alloy_rlp::encode(0_u64),
+            alloy_rlp::encode(Bytes::from(hex!("11bbe8db4e347b4e8c937c1c8370e4b5ed33adb3db69cbdb7a38e1e50b1b82fa"))),
+            zero_hash,
+            alloy_rlp::encode(Bytes::from(hex!("0000000000000042"))),
+        ]);
+        // One legacy and one typed transaction, a made up block around the real header.
+        let legacy = list(&[alloy_rlp::encode(1_u64), alloy_rlp::encode(2_u64)]);
+        let typed = [0x02, 0xc1, 0x07];
+        let transactions = list(&[legacy.clone(), alloy_rlp::encode(Bytes::copy_from_slice(&typed))]);
+        let message = list(&[list(&[header, transactions, list(&[])]), alloy_rlp::encode(0x4_0000_0000_u128)]);
+
+        let block = NewBlock::decode(&mut &message[..]).unwrap();
+        assert_eq!(block.hash, B256::from(hex!("d4e56740f876aef8c010b86a40d5f56745a118d0906a34e69aec8c0db1cb8fa3")));
+        assert_eq!((block.parent_hash, block.number, block.timestamp), (B256::ZERO, 0, 0));
+        assert_eq!(block.transactions, vec![keccak256(&legacy), keccak256(typed)]);
+        assert_eq!(block.td, 0x4_0000_0000);
+    }
+
+    #[test]
+    fn new_block_hashes_round_trip() {
+        let announced = vec![
+            BlockHashNumber { hash: B256::repeat_byte(1), number: 19_000_000 },
+            BlockHashNumber { hash: B256::repeat_byte(2), number: 19_000_001 },
+        ];
+        let encoded = alloy_rlp::encode(&announced);
+        assert_eq!(NewBlockHashes::decode(&mut &encoded[..]).unwrap(), announced);
+        // A hash without its number is no announcement.
+        let truncated = list(&[list(&[alloy_rlp::encode(B256::repeat_byte(1))])]);
+        assert!(NewBlockHashes::decode(&mut &truncated[..]).is_err());
+    }
+
+    #[test]
+    fn version_zero_capabilities_are_never_shared() {
+        let eth = |version| Capability { name: "eth".to_string(), version };
+        assert_eq!(negotiate_capabilities(&[eth(0)], &[eth(0)]), Ok(Vec::new()));
+        let shared = negotiate_capabilities(&[eth(0), eth(68)], &[eth(0), eth(68)]);
+        assert_eq!(shared, Ok(vec![SharedCapability { capability: eth(68), offset: 0x10 }]));
+    }
+
+    #[test]
+    fn capability_layouts_that_misroute_ids_are_refused() {
+        let at = |capability: &str, offset: u8| SharedCapability { capability: capability.parse().unwrap(), offset };
+        let eth_count = at("eth/68", 0).capability.message_count().unwrap();
+
+        assert_eq!(check_offsets(&[at("eth/68", 0x10), at("les/4", 0x10 + eth_count)]), Ok(()));
+        assert_eq!(check_offsets(&[at("eth/68", 0x0f)]), Err("Capability offset overlaps the p2p message ids"));
+        assert_eq!(
+            check_offsets(&[at("eth/68", 0x10), at("les/4", 0x10 + eth_count - 1)]),
+            Err("Capability offsets overlap")
+        );
+        assert_eq!(check_offsets(&[at("eth/68", 0xfa)]), Err("Capability offsets past the last message id"));
+        // What negotiation lays out passes its own check.
+        let ours: Vec<Capability> = ["eth/66", "eth/68", "les/4", "snap/1"].iter().map(|c| c.parse().unwrap()).collect();
+        assert!(check_offsets(&negotiate_capabilities(&ours, &ours).unwrap()).is_ok());
+    }
+
+    #[test]
+    fn client_version_past_the_limit_is_refused_before_it_is_copied() {
+        let hello = |client_version: String| Hello {
+            protocol_version: 5,
+            client_version,
+            capabilities: vec![Capability { name: "eth".to_string(), version: 68 }],
+            port: 30303,
+            id: [7; 64],
+        };
+        let decode = |hello: Hello| Hello::decode_limited(&mut &alloy_rlp::encode(hello)[..], 64, 256);
+        assert!(decode(hello("g".repeat(256))).is_ok());
+        for len in [257, 1 << 20] {
+            assert_eq!(
+                decode(hello("g".repeat(len))),
+                Err(alloy_rlp::Error::Custom(Hello::CLIENT_VERSION_TOO_LONG))
+            );
+        }
+    }
 }
@@ -0,0 +1,228 @@
+use std::net::SocketAddr;
+
+use log::{debug, warn};
+use secp256k1::PublicKey;
+
+use crate::redact;
+
+const ENODE_PREFIX: &str = "enode://";
+
+// Node keys turn up in three shapes: the bare 64 byte X || Y of an enode,
+// the 65 byte uncompressed SEC1 form with its 04 tag, or the 33 byte
+// compressed form ENRs carry. Only the bare form is missing its tag.
+pub fn node_pubkey_from_bytes(key: &[u8]) -> Result<PublicKey, &'static str> {
+    match (key.len(), key.first()) {
+        (64, _) => {
+            let mut buf = [4_u8; 65];
+            buf[1..].copy_from_slice(key);
+            PublicKey::from_slice(&buf)
+        }
+        (65, Some(4)) | (33, Some(2 | 3)) => PublicKey::from_slice(key),
+        _ => return Err("Node public key has an unknown length or prefix! "),
+    }
+    .map_err(|_| "Node public key is not a point on secp256k1 ")
+}
+
+// Same as node_pubkey_from_bytes, for keys written out in hex. Enodes copied
+// from block explorers and some clients' logs come in upper or mixed case.
+pub fn parse_node_pubkey(key: &str) -> Result<PublicKey, &'static str> {
+    let key = hex::decode(key.trim().to_ascii_lowercase()).map_err(|_| "Node public key is not valid hex! ")?;
+    node_pubkey_from_bytes(&key)
+}
+
+// enode://<hex node id>@<host:port> into the key and the still unresolved
+// address, resolving is up to the caller.
+pub fn split_enode(enode: &str) -> Result<(PublicKey, &str), &'static str> {
+    let (enode_prefix, enode_data) = enode
+        .split_once(ENODE_PREFIX)
+        .ok_or("Invalid enode prefix! ")?;
+
+    if !enode_prefix.is_empty() {
+        return Err("Invalid enode prefix location! ");
+    }
+
+    let (enode_key_string, socket_address_string) =
+        enode_data.rsplit_once("@").ok_or("Invalid ip delimiter")?;
+
+    let enode_public_key = parse_node_pubkey(enode_key_string)
+        .inspect_err(|err| debug!("The enode public key error is {:?}.", err))?;
+
+    Ok((enode_public_key, socket_address_string))
+}
+
+// Happy eyeballs order (RFC 8305): alternate between the families, starting
+// with whichever the resolver put first, so a broken IPv6 path only costs one
+// attempt before IPv4 gets its turn.
+pub fn interleave_families(addresses: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let Some(first) = addresses.first() else {
+        return addresses;
+    };
+    let prefer_ipv6 = first.is_ipv6();
+    let (mut preferred, mut other): (Vec<_>, Vec<_>) =
+        addresses.into_iter().partition(|address| address.is_ipv6() == prefer_ipv6);
+    let mut ordered = Vec::with_capacity(preferred.len() + other.len());
+    preferred.reverse();
+    other.reverse();
+    while let Some(address) = preferred.pop() {
+        ordered.push(address);
+        ordered.extend(other.pop());
+    }
+    ordered.extend(other.into_iter().rev());
+    ordered
+}
+
+// A peer is its node id, not its address: nodes sharing an IP on different
+// ports are all kept (the subnet rate limit still spaces them out), while a
+// node listed at several addresses ends up as one peer with all of them, in
+// the order they came. Repeats of the same address, which is what
+// concatenated peer files end up with, are dropped.
+pub fn dedup_peers(peers: Vec<(PublicKey, SocketAddr)>) -> Vec<(PublicKey, Vec<SocketAddr>)> {
+    let mut nodes: Vec<(PublicKey, Vec<SocketAddr>)> = Vec::new();
+    for (public_key, address) in peers {
+        match nodes.iter_mut().find(|(known, _)| *known == public_key) {
+            Some((_, addresses)) if addresses.contains(&address) => {
+                warn!("Dropping duplicate peer {} at {}", redact::public_key(&public_key), address)
+            }
+            Some((_, addresses)) => addresses.push(address),
+            None => nodes.push((public_key, vec![address])),
+        }
+    }
+    nodes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::{DialConfig, MappedIpv4},
+        testing::random_key,
+    };
+    use secp256k1::SECP256K1;
+
+    fn node() -> PublicKey {
+        PublicKey::from_secret_key(SECP256K1, &random_key())
+    }
+
+    fn address(address: &str) -> SocketAddr {
+        address.parse().unwrap()
+    }
+
+    #[test]
+    fn repeated_enode_at_the_same_address_is_dropped() {
+        let (a, b) = (node(), node());
+        let peers = vec![
+            (a, address("192.0.2.1:30303")),
+            (b, address("192.0.2.2:30303")),
+            (a, address("192.0.2.1:30303")),
+        ];
+        assert_eq!(
+            dedup_peers(peers),
+            vec![(a, vec![address("192.0.2.1:30303")]), (b, vec![address("192.0.2.2:30303")])]
+        );
+    }
+
+    #[test]
+    fn same_key_at_other_addresses_is_one_peer_with_all_of_them() {
+        let (a, b) = (node(), node());
+        let peers = vec![
+            (a, address("192.0.2.1:30303")),
+            (b, address("192.0.2.1:30304")),
+            (a, address("[2001:db8::1]:30303")),
+            (a, address("192.0.2.1:30305")),
+        ];
+        assert_eq!(
+            dedup_peers(peers),
+            vec![
+                (
+                    a,
+                    vec![
+                        address("192.0.2.1:30303"),
+                        address("[2001:db8::1]:30303"),
+                        address("192.0.2.1:30305"),
+                    ]
+                ),
+                // Same IP, different node: kept.
+                (b, vec![address("192.0.2.1:30304")]),
+            ]
+        );
+    }
+
+    #[test]
+    fn families_alternate_starting_with_the_first_one() {
+        let v4 = |n: u8| address(&format!("192.0.2.{}:30303", n));
+        let v6 = |n: u8| address(&format!("[2001:db8::{}]:30303", n));
+
+        assert_eq!(
+            interleave_families(vec![v6(1), v6(2), v6(3), v4(1), v4(2)]),
+            vec![v6(1), v4(1), v6(2), v4(2), v6(3)]
+        );
+        // The leftovers of the longer family keep their order at the end.
+        assert_eq!(
+            interleave_families(vec![v4(1), v6(1), v6(2), v6(3)]),
+            vec![v4(1), v6(1), v6(2), v6(3)]
+        );
+        assert_eq!(interleave_families(vec![v4(2), v4(1)]), vec![v4(2), v4(1)]);
+        assert_eq!(interleave_families(Vec::new()), Vec::new());
+    }
+
+    #[test]
+    fn enode_keys_off_the_curve_are_refused() {
+        let enode = |key: &str| format!("enode://{}@192.0.2.1:30303", key);
+        // All zeros is what the point at infinity would look like, and (1, 1)
+        // is simply not on the curve. Both are well-formed hex of the right length.
+        for key in ["00".repeat(64), format!("{}{}", "00".repeat(31) + "01", "00".repeat(31) + "01")] {
+            assert_eq!(split_enode(&enode(&key)).err(), Some("Node public key is not a point on secp256k1 "));
+        }
+        // A tag that's not 04 on the uncompressed form.
+        let valid = hex::encode(&node().serialize_uncompressed()[1..]);
+        assert_eq!(
+            split_enode(&enode(&format!("05{}", valid))).err(),
+            Some("Node public key has an unknown length or prefix! ")
+        );
+        assert!(split_enode(&enode(&valid)).is_ok());
+    }
+
+    #[test]
+    fn uppercase_and_mixed_case_enode_keys_are_the_same_node() {
+        let key = node();
+        let lower = hex::encode(&key.serialize_uncompressed()[1..]);
+        // Every other character upper, the way a hand-edited list might end up.
+        let mixed: String = lower
+            .chars()
+            .enumerate()
+            .map(|(i, c)| if i % 2 == 0 { c.to_ascii_uppercase() } else { c })
+            .collect();
+        for hex_key in [lower.to_ascii_uppercase(), mixed] {
+            let enode = format!("enode://{}@192.0.2.1:30303", hex_key);
+            assert_eq!(split_enode(&enode), Ok((key, "192.0.2.1:30303")));
+        }
+    }
+
+    #[test]
+    fn nodes_behind_one_ip_on_different_ports_are_both_dialed() {
+        let (a, b) = (node(), node());
+        let peers = vec![(a, address("1.2.3.4:30303")), (b, address("1.2.3.4:30304"))];
+        assert_eq!(
+            dedup_peers(peers),
+            vec![(a, vec![address("1.2.3.4:30303")]), (b, vec![address("1.2.3.4:30304")])]
+        );
+    }
+
+    #[test]
+    fn mapped_ipv4_is_dialed_as_configured_and_deduplicated_as_such() {
+        let mapped = address("[::ffff:1.2.3.4]:30303");
+        let v4 = DialConfig::default();
+        let v6 = DialConfig {
+            mapped_ipv4: MappedIpv4::V6,
+            ..DialConfig::default()
+        };
+        assert_eq!(v4.normalize(mapped), address("1.2.3.4:30303"));
+        assert_eq!(v6.normalize(mapped), mapped);
+        assert_eq!(v4.normalize(address("[2001:db8::1]:30303")), address("[2001:db8::1]:30303"));
+
+        // Once normalized it's the plain IPv4 entry, listed twice.
+        let a = node();
+        let peers = vec![(a, address("1.2.3.4:30303")), (a, v4.normalize(mapped))];
+        assert_eq!(dedup_peers(peers), vec![(a, vec![address("1.2.3.4:30303")])]);
+    }
+}
@@ -0,0 +1,53 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::Mutex,
+    time::Duration,
+};
+
+use log::debug;
+use tokio::time::{sleep_until, Instant};
+
+// Spaces out dials to peers sharing a /24 (IPv4) or /48 (IPv6), so walking a
+// clustered peer list doesn't look like a port scan to the hosting provider.
+pub struct SubnetRateLimiter {
+    interval: Duration,
+    next_slot: Mutex<HashMap<Vec<u8>, Instant>>,
+}
+
+impl SubnetRateLimiter {
+    pub fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            next_slot: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Waits for this subnet's next free dial slot. Concurrent callers each get
+    // their own slot, one interval apart.
+    pub async fn wait(&self, ip: IpAddr) {
+        let now = Instant::now();
+        let slot = {
+            let Ok(mut next_slot) = self.next_slot.lock() else {
+                return;
+            };
+            let next = next_slot.entry(Self::subnet(ip)).or_insert(now);
+            let slot = (*next).max(now);
+            *next = slot + self.interval;
+            slot
+        };
+
+        if slot > now {
+            debug!("Delaying dial to {} by {:?}", ip, slot - now);
+            sleep_until(slot).await;
+        }
+    }
+
+    // An IPv4 address written as ::ffff:a.b.c.d shares its /24 with the plain one.
+    fn subnet(ip: IpAddr) -> Vec<u8> {
+        match ip.to_canonical() {
+            IpAddr::V4(ip) => ip.octets()[..3].to_vec(),
+            IpAddr::V6(ip) => ip.octets()[..6].to_vec(),
+        }
+    }
+}
@@ -0,0 +1,38 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use alloy_primitives::keccak256;
+use secp256k1::PublicKey;
+
+// Set once from --redact-node-ids, before anything gets logged.
+static REDACT_NODE_IDS: AtomicBool = AtomicBool::new(false);
+
+pub fn set_redact_node_ids(redact: bool) {
+    REDACT_NODE_IDS.store(redact, Ordering::Relaxed);
+}
+
+// A node id the way the logs show it: in full, or with redaction on as the
+// first 4 bytes of its keccak256. That's still enough to follow one peer
+// through a log, but not to dial it or look it up anywhere.
+pub fn node_id(id: &[u8]) -> String {
+    if REDACT_NODE_IDS.load(Ordering::Relaxed) {
+        format!("node#{}", hex::encode(&keccak256(id)[..4]))
+    } else {
+        hex::encode(id)
+    }
+}
+
+pub fn public_key(public_key: &PublicKey) -> String {
+    node_id(&public_key.serialize_uncompressed()[1..])
+}
+
+// enode://<node id>@host:port with only the node id swapped out. Anything
+// that doesn't look like an enode is left alone.
+pub fn enode(enode: &str) -> String {
+    let Some((id, address)) = enode.strip_prefix("enode://").and_then(|rest| rest.split_once('@')) else {
+        return enode.to_string();
+    };
+    match hex::decode(id) {
+        Ok(id) => format!("enode://{}@{}", node_id(&id), address),
+        Err(_) => enode.to_string(),
+    }
+}
@@ -0,0 +1,400 @@
+// `check --record <file>` writes down everything that went over the socket,
+// both ways and with timing, so `replay <file>` can later play the peer's
+// side back into a fresh codec without the peer being around:
+//   # comments
+//   args <the command line, as a JSON array>
+//   key <our private key, hex>
+//   seed <what our handshake randomness was seeded with>
+//   peer <enode>
+//   <secs.µs since connect> in <hex bytes>     one line per socket read
+//   <secs.µs since connect> out <hex bytes>    one line per socket write
+//   <secs.µs since connect> eof                the peer closed the connection
+//   end <codec state> <check status>
+//
+// The key is a throwaway one `check` makes up per run, but together with the
+// seed it's everything needed to decrypt the session. A recording is as
+// secret as the session it holds.
+use std::{
+    collections::VecDeque,
+    fs::{self, File, OpenOptions},
+    future::Future,
+    io::{self, Write},
+    net::SocketAddr,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{ready, Context, Poll},
+    time::Duration,
+};
+#[cfg(unix)]
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+
+use log::{info, warn};
+use secp256k1::{PublicKey, SecretKey};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    time::{sleep_until, Instant, Sleep},
+};
+
+use crate::{
+    check::{check_session, CheckStatus},
+    cli::Options,
+    ecies::EciesRng,
+    nodekey::split_enode,
+    rplx::{RLPx, RlpxState},
+    session::Session,
+};
+
+// Process exit codes of `replay`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReplayStatus {
+    Reproduced = 0,
+    Diverged = 1,
+    Unreadable = 2,
+}
+
+#[derive(Clone)]
+pub struct Recorder {
+    file: Arc<Mutex<File>>,
+    start: Instant,
+}
+
+impl Recorder {
+    pub fn create(
+        path: &str,
+        args: &[String],
+        private_key: SecretKey,
+        seed: u64,
+        peer_public_key: PublicKey,
+        address: SocketAddr,
+    ) -> Result<Self, &'static str> {
+        let mut options = OpenOptions::new();
+        options.create(true).write(true).truncate(true);
+        // It holds the key, so it's ours alone to read.
+        #[cfg(unix)]
+        options.mode(0o600);
+        let mut file = options.open(path).map_err(|_| "Could not open the recording file! ")?;
+        // The mode only applies to a new file, one we overwrite keeps its own.
+        #[cfg(unix)]
+        file.set_permissions(fs::Permissions::from_mode(0o600))
+            .map_err(|_| "Could not restrict the recording file! ")?;
+        let header = format!(
+            "# Handshake recording, play it back with `replay {}`\nargs {}\nkey {}\nseed {}\npeer enode://{}@{}\n",
+            path,
+            serde_json::Value::from(args),
+            hex::encode(private_key.secret_bytes()),
+            seed,
+            hex::encode(&peer_public_key.serialize_uncompressed()[1..]),
+            address
+        );
+        file.write_all(header.as_bytes())
+            .map_err(|_| "Could not write the recording file! ")?;
+        Ok(Self {
+            file: Arc::new(Mutex::new(file)),
+            start: Instant::now(),
+        })
+    }
+
+    fn record(&self, event: &str, data: &[u8]) {
+        let offset = self.start.elapsed();
+        let line = if data.is_empty() {
+            format!("{}.{:06} {}\n", offset.as_secs(), offset.subsec_micros(), event)
+        } else {
+            format!("{}.{:06} {} {}\n", offset.as_secs(), offset.subsec_micros(), event, hex::encode(data))
+        };
+        self.write(&line);
+    }
+
+    pub fn finish(&self, state: RlpxState, status: CheckStatus) {
+        self.write(&format!("end {:?} {:?}\n", state, status));
+    }
+
+    fn write(&self, line: &str) {
+        let Ok(mut file) = self.file.lock() else {
+            return;
+        };
+        if let Err(err) = file.write_all(line.as_bytes()) {
+            warn!("Writing the recording failed: {:?}", err);
+        }
+    }
+}
+
+// Passes everything through to the socket, noting it down on the way.
+pub struct RecordingStream<T> {
+    inner: T,
+    recorder: Recorder,
+}
+
+impl<T> RecordingStream<T> {
+    pub fn new(inner: T, recorder: Recorder) -> Self {
+        Self { inner, recorder }
+    }
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for RecordingStream<T> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let filled = buf.filled().len();
+        ready!(Pin::new(&mut self.inner).poll_read(cx, buf))?;
+        match &buf.filled()[filled..] {
+            [] => self.recorder.record("eof", &[]),
+            data => self.recorder.record("in", data),
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for RecordingStream<T> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let written = ready!(Pin::new(&mut self.inner).poll_write(cx, buf))?;
+        self.recorder.record("out", &buf[..written]);
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+struct Recording {
+    args: Vec<String>,
+    private_key: SecretKey,
+    seed: u64,
+    peer_public_key: PublicKey,
+    address: SocketAddr,
+    inbound: VecDeque<(Duration, Vec<u8>)>,
+    outbound: Vec<u8>,
+    peer_closed: bool,
+    end: String,
+}
+
+impl Recording {
+    fn parse(contents: &str) -> Result<Self, &'static str> {
+        let mut args = None;
+        let mut private_key = None;
+        let mut seed = None;
+        let mut peer = None;
+        let mut inbound = VecDeque::new();
+        let mut outbound = Vec::new();
+        let mut peer_closed = false;
+        let mut end = None;
+        for line in contents.lines().filter(|line| !line.is_empty() && !line.starts_with('#')) {
+            let (field, rest) = line.split_once(' ').unwrap_or((line, ""));
+            match field {
+                "args" => args = Some(serde_json::from_str(rest).map_err(|_| "Invalid args in the recording! ")?),
+                "key" => {
+                    let key = hex::decode(rest).map_err(|_| "Invalid key in the recording! ")?;
+                    private_key = Some(SecretKey::from_slice(&key).map_err(|_| "Invalid key in the recording! ")?);
+                }
+                "seed" => seed = Some(rest.parse().map_err(|_| "Invalid seed in the recording! ")?),
+                "peer" => {
+                    let (public_key, address) = split_enode(rest)?;
+                    let address: SocketAddr = address.parse().map_err(|_| "Invalid peer address in the recording! ")?;
+                    peer = Some((public_key, address));
+                }
+                "end" => end = Some(rest.to_string()),
+                offset => {
+                    let offset = parse_offset(offset).ok_or("Invalid line in the recording! ")?;
+                    let (event, data) = rest.split_once(' ').unwrap_or((rest, ""));
+                    let data = hex::decode(data).map_err(|_| "Invalid bytes in the recording! ")?;
+                    match event {
+                        "in" => inbound.push_back((offset, data)),
+                        "out" => outbound.extend_from_slice(&data),
+                        "eof" => peer_closed = true,
+                        _ => return Err("Invalid line in the recording! "),
+                    }
+                }
+            }
+        }
+        let (peer_public_key, address) = peer.ok_or("No peer in the recording! ")?;
+        Ok(Self {
+            args: args.ok_or("No args in the recording! ")?,
+            private_key: private_key.ok_or("No key in the recording! ")?,
+            seed: seed.ok_or("No seed in the recording! ")?,
+            peer_public_key,
+            address,
+            inbound,
+            outbound,
+            peer_closed,
+            end: end.ok_or("The recording never finished! ")?,
+        })
+    }
+}
+
+// secs.µs, the way Recorder writes offsets.
+fn parse_offset(offset: &str) -> Option<Duration> {
+    let (secs, micros) = offset.split_once('.')?;
+    Some(Duration::from_secs(secs.parse().ok()?) + Duration::from_micros(micros.parse().ok()?))
+}
+
+// Plays the peer back: each read gets the next recorded chunk, no sooner than
+// it came in the first time, so timeouts land as they did. Whatever we write
+// is checked against what we wrote back then. Once the chunks run out, reads
+// end the stream if the peer closed it, and otherwise never return.
+struct ReplayStream {
+    start: Instant,
+    inbound: VecDeque<(Duration, Vec<u8>)>,
+    peer_closed: bool,
+    outbound: Vec<u8>,
+    written: usize,
+    diverged: bool,
+    next_read: Option<Pin<Box<Sleep>>>,
+}
+
+impl AsyncRead for ReplayStream {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let Some(&(offset, _)) = self.inbound.front() else {
+            return if self.peer_closed { Poll::Ready(Ok(())) } else { Poll::Pending };
+        };
+        let due = self.start + offset;
+        let next_read = self.next_read.get_or_insert_with(|| Box::pin(sleep_until(due)));
+        ready!(next_read.as_mut().poll(cx));
+        self.next_read = None;
+
+        let Some((_, data)) = self.inbound.front_mut() else {
+            return Poll::Pending;
+        };
+        let size = data.len().min(buf.remaining());
+        buf.put_slice(&data[..size]);
+        data.drain(..size);
+        if data.is_empty() {
+            self.inbound.pop_front();
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for ReplayStream {
+    fn poll_write(mut self: Pin<&mut Self>, _cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let written = self.written;
+        let recorded = self.outbound.get(written..).unwrap_or_default();
+        if !self.diverged && !recorded.starts_with(buf) {
+            let at = written + recorded.iter().zip(buf).take_while(|(a, b)| a == b).count();
+            warn!("We wrote something else than in the recording, from byte {} on", at);
+            self.diverged = true;
+        }
+        self.written += buf.len();
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+// Runs `check` again against the recorded peer and compares where it ends up.
+#[tokio::main(flavor = "current_thread")]
+pub async fn run(path: &str) -> ReplayStatus {
+    let recording = match fs::read_to_string(path)
+        .map_err(|_| "Could not read the recording! ")
+        .and_then(|contents| Recording::parse(&contents))
+    {
+        Ok(recording) => recording,
+        Err(err) => {
+            eprintln!("{}", err);
+            return ReplayStatus::Unreadable;
+        }
+    };
+    let options = match Options::from_args(recording.args.into_iter()) {
+        Ok(options) => options,
+        Err(err) => {
+            eprintln!("The recorded arguments don't parse: {}", err);
+            return ReplayStatus::Unreadable;
+        }
+    };
+    let mut config = options.rlpx_config();
+    config.ecies_rng = EciesRng::seeded(recording.seed);
+    let codec = RLPx::new(recording.private_key, recording.peer_public_key, config);
+    let stream = ReplayStream {
+        start: Instant::now(),
+        inbound: recording.inbound,
+        peer_closed: recording.peer_closed,
+        outbound: recording.outbound,
+        written: 0,
+        diverged: false,
+        next_read: None,
+    };
+
+    let (status, state) = check_session(Session::new(stream, codec), recording.address).await;
+    let end = format!("{:?} {:?}", state, status);
+    if end == recording.end {
+        info!("Replay ended up where the recording did: {}", end);
+        println!("Reproduced: {}", end);
+        ReplayStatus::Reproduced
+    } else {
+        println!("Diverged: recorded {}, replayed {}", recording.end, end);
+        ReplayStatus::Diverged
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::RLPxConfig;
+    use secp256k1::SECP256K1;
+    use tokio::io::duplex;
+
+    // Runs check against a listener over a pipe, recorded into path the way
+    // `check --record` does it.
+    fn record(path: &str) -> CheckStatus {
+        let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+        runtime.block_on(async {
+            let our_key = SecretKey::new(&mut secp256k1::rand::thread_rng());
+            let their_key = SecretKey::new(&mut secp256k1::rand::thread_rng());
+            let their_public_key = PublicKey::from_secret_key(SECP256K1, &their_key);
+            let address: SocketAddr = "127.0.0.1:30303".parse().unwrap();
+            let enode = format!("enode://{}@{}", hex::encode(&their_public_key.serialize_uncompressed()[1..]), address);
+            let options = Options::from_args(["check".to_string(), enode].into_iter()).unwrap();
+
+            let seed = 42;
+            let mut config = options.rlpx_config();
+            config.ecies_rng = EciesRng::seeded(seed);
+            let recorder = Recorder::create(path, &options.args, our_key, seed, their_public_key, address).unwrap();
+            let (ours, theirs) = duplex(64 * 1024);
+            let session = Session::new(RecordingStream::new(ours, recorder.clone()), RLPx::new(our_key, their_public_key, config));
+            let mut peer = Session::new(theirs, RLPx::new_incoming(their_key, RLPxConfig::default()));
+
+            let ((status, state), _) = tokio::join!(check_session(session, address), peer.handshake());
+            recorder.finish(state, status);
+            status
+        })
+    }
+
+    #[test]
+    fn recorded_check_replays_to_the_same_end_and_a_tampered_one_does_not() {
+        let path = std::env::temp_dir().join(format!("reth-handshake-replay-{}.txt", std::process::id()));
+        let path = path.to_str().unwrap();
+        assert_eq!(record(path), CheckStatus::Active);
+        #[cfg(unix)]
+        assert_eq!(fs::metadata(path).unwrap().permissions().mode() & 0o777, 0o600);
+        // No peer on the other end this time, it's all in the file.
+        assert_eq!(run(path), ReplayStatus::Reproduced);
+
+        // One bit off in the first thing the peer sent, its ack.
+        let mut tampered = Vec::new();
+        let mut flipped = false;
+        for line in fs::read_to_string(path).unwrap().lines() {
+            match line.split_once(" in ") {
+                Some((offset, data)) if !flipped => {
+                    let mut bytes = hex::decode(data).unwrap();
+                    *bytes.last_mut().unwrap() ^= 0x01;
+                    tampered.push(format!("{} in {}", offset, hex::encode(bytes)));
+                    flipped = true;
+                }
+                _ => tampered.push(line.to_string()),
+            }
+        }
+        fs::write(path, tampered.join("\n")).unwrap();
+        assert_eq!(run(path), ReplayStatus::Diverged);
+
+        fs::write(path, "not a recording").unwrap();
+        assert_eq!(run(path), ReplayStatus::Unreadable);
+        fs::remove_file(path).unwrap();
+    }
+}
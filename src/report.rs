@@ -0,0 +1,139 @@
+use std::net::SocketAddr;
+
+use log::info;
+use secp256k1::PublicKey;
+use serde_json::json;
+
+use crate::{redact, rplx::ByteStats};
+
+// What happened with one peer, printed once all dials are done.
+#[derive(Debug)]
+pub struct PeerReport {
+    pub address: SocketAddr,
+    // Not known for peers that dialed us and dropped off before their auth.
+    pub public_key: Option<PublicKey>,
+    pub inbound: bool,
+    pub client_version: Option<String>,
+    // Where the peer can be dialed back, if its Hello said it listens at all.
+    pub enode: Option<String>,
+    pub handshake_complete: bool,
+    // Why we hung up on the peer ourselves, or never dialed it, if so.
+    pub rejected: Option<&'static str>,
+    pub error: Option<String>,
+    pub byte_stats: ByteStats,
+}
+
+impl PeerReport {
+    pub fn new(public_key: Option<PublicKey>, address: SocketAddr) -> Self {
+        Self {
+            address,
+            public_key,
+            inbound: false,
+            client_version: None,
+            enode: None,
+            handshake_complete: false,
+            rejected: None,
+            error: None,
+            byte_stats: ByteStats::default(),
+        }
+    }
+
+    // Redacted like the log with --redact-node-ids, the report ends up in
+    // the same places.
+    fn node_id(&self) -> Option<String> {
+        self.public_key.as_ref().map(redact::public_key)
+    }
+
+    fn direction(&self) -> &'static str {
+        if self.inbound { "inbound" } else { "outbound" }
+    }
+
+    fn to_json(&self) -> serde_json::Value {
+        json!({
+            "address": self.address.to_string(),
+            "node_id": self.node_id(),
+            "direction": self.direction(),
+            "client_version": self.client_version,
+            "enode": self.enode.as_deref().map(redact::enode),
+            "handshake_complete": self.handshake_complete,
+            "rejected": self.rejected,
+            "error": self.error,
+            "bytes_read": self.byte_stats.read,
+            "bytes_written": self.byte_stats.written,
+        })
+    }
+}
+
+pub fn print_summary(reports: &[PeerReport], as_json: bool) {
+    if as_json {
+        for report in reports {
+            println!("{}", report.to_json());
+        }
+        return;
+    }
+    for line in summary(reports) {
+        info!("{}", line);
+    }
+}
+
+fn summary(reports: &[PeerReport]) -> Vec<String> {
+    let complete = reports.iter().filter(|report| report.handshake_complete).count();
+    let mut lines = vec![format!("Handshakes completed with {} out of {} peers", complete, reports.len())];
+    for report in reports {
+        lines.push(format!(
+            "{} {} {} client: {:?} handshake: {} rejected: {:?} error: {:?} read: {} written: {}",
+            report.direction(),
+            report.address,
+            report.node_id().as_deref().unwrap_or("-"),
+            report.client_version.as_deref().unwrap_or("-"),
+            if report.handshake_complete { "ok" } else { "failed" },
+            report.rejected,
+            report.error,
+            report.byte_stats.read,
+            report.byte_stats.written,
+        ));
+    }
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::RLPxConfig,
+        testing::{auth_ack, codecs, hellos},
+    };
+
+    #[test]
+    fn redaction_keeps_full_ids_out_of_the_log_and_the_report() {
+        // A peer that listens, so its Hello gives an enode.
+        let listening = RLPxConfig {
+            listen_port: 30303,
+            ..RLPxConfig::default()
+        };
+        let (mut initiator, mut responder) = codecs(RLPxConfig::default(), listening);
+        auth_ack(&mut initiator, &mut responder);
+        hellos(&mut initiator, &mut responder);
+        let peer_info = initiator.peer_info().unwrap();
+        let public_key = initiator.peer_public_key().unwrap();
+        let full_id = hex::encode(&public_key.serialize_uncompressed()[1..]);
+        let address: SocketAddr = "192.0.2.1:30303".parse().unwrap();
+        let enode = format!("enode://{}@{}", full_id, address);
+
+        let mut report = PeerReport::new(Some(public_key), address);
+        report.handshake_complete = true;
+        report.enode = Some(enode.clone());
+
+        redact::set_redact_node_ids(true);
+        let mut output = summary(std::slice::from_ref(&report));
+        output.push(report.to_json().to_string());
+        output.push(format!("Peer listens on {}", redact::enode(&enode)));
+        output.push(redact::enode(&peer_info.enode(address.ip()).unwrap()));
+        redact::set_redact_node_ids(false);
+
+        for line in output {
+            assert!(!line.contains(&full_id), "{}", line);
+            assert!(line.contains("node#") || line.starts_with("Handshakes"), "{}", line);
+        }
+    }
+}
@@ -1,33 +1,270 @@
-use std::{io::{Error, ErrorKind}, process};
+use std::{
+    borrow::Cow,
+    collections::HashSet,
+    io::{Read, Write},
+    net::{IpAddr, SocketAddr},
+    time::{Duration, Instant},
+};
 use crate::{
+    config::RLPxConfig,
     ecies::{ECIESDirection, HandshakeSecrets, ECIES},
-    messages::{Capability, Disconnect, Hello, Ping, Pong, RLPx_Message, Status},
+    framelog::{FrameDirection, FrameKind, FrameLog},
+    keccak_mac::KeccakMac,
+    messages::{
+        negotiate_capabilities, Capability, BASE_PROTOCOL_IDS, BASE_PROTOCOL_MESSAGES, Disconnect, GetPooledTransactions, Hello, Les, NewBlock, NewBlockHashes, Ping, Pong, PooledTransactionHashes,
+        PooledTransactions, RLPx_Message, Reason, REQUEST_ID_ETH_VERSION,
+        SharedCapability, Status, Transactions, Wit,
+    },
+    redact,
+    statelog::StateLog,
 };
-use aes::cipher::{generic_array::GenericArray, BlockDecrypt, BlockEncrypt, KeyInit};
+use aes::cipher::{generic_array::GenericArray, BlockEncrypt};
 use alloy_primitives::B512;
-use alloy_rlp::{Buf, BytesMut, Encodable,Decodable};
-use ctr::cipher::KeyIvInit;
-use ctr::cipher::StreamCipher;
-use ethereum_types::{H128, H256};
-use log::{debug, error, info, warn};
-use rlp::RlpStream;
+use alloy_rlp::{Buf, BufMut, BytesMut, Encodable, Decodable, RlpDecodable, RlpEncodable};
+use ctr::cipher::{StreamCipher, StreamCipherSeek};
+use log::{debug, error, info, trace, warn};
 use secp256k1::{PublicKey, SecretKey, SECP256K1};
-use sha2::{Digest, Sha256};
-use sha3::Keccak256;
-use snap::raw::Decoder as SnapDecoder;
 use tokio_util::codec::{Decoder, Encoder};
+use zeroize::Zeroize;
 
 #[derive(Clone, Copy, Debug, PartialEq)]
 pub enum RlpxState {
     ExpectingConnection,
     AuthSent,
     AuthAckRecieved,
+    // The same steps from the listening side.
+    ExpectingAuth,
+    AuthRecieved,
+    AuthAckSent,
     HelloSent,
     HelloRecieved,
+    // The p2p layer is up, both Hellos are through.
     Active,
+    // We sent our eth Status and are waiting on the peer's.
+    StatusSent,
+    EthStatusExchanged,
     Disconnected,
 }
 
+impl RlpxState {
+    // Whether frames can flow, whatever the eth Status exchange is up to.
+    pub fn is_active(&self) -> bool {
+        matches!(self, RlpxState::Active | RlpxState::StatusSent | RlpxState::EthStatusExchanged)
+    }
+}
+
+// What we learned about the peer from its Hello.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PeerInfo {
+    pub id: [u8; 64],
+    pub client_version: String,
+    pub protocol_version: usize,
+    // The lower of the peer's version and ours, what the session runs at. A
+    // peer ahead of us is expected to fall back to ours, as the spec has it.
+    pub negotiated_protocol_version: usize,
+    pub capabilities: Vec<Capability>,
+    // What we ended up agreeing on, in message id order.
+    pub shared_capabilities: Vec<SharedCapability>,
+    // The port the peer says it listens on. None when it sent 0, which is how
+    // a peer that doesn't take inbound connections says so.
+    pub listen_port: Option<u16>,
+}
+
+impl PeerInfo {
+    // The peer speaks a newer p2p version than we do and may try things we
+    // don't understand.
+    pub fn ahead_of_us(&self) -> bool {
+        self.protocol_version > PROTOCOL_VERSION
+    }
+
+    // Where the peer can be dialed, given the IP we saw it at. Nothing for a
+    // peer that doesn't listen. The id is in full since it's for dialing and
+    // the crawl file, anything shown goes through redact::enode.
+    pub fn enode(&self, ip: IpAddr) -> Option<String> {
+        let port = self.listen_port?;
+        Some(format!("enode://{}@{}", hex::encode(self.id), SocketAddr::new(ip, port)))
+    }
+}
+
+impl From<Hello> for PeerInfo {
+    fn from(hello: Hello) -> Self {
+        Self {
+            id: hello.id,
+            client_version: hello.client_version,
+            protocol_version: hello.protocol_version,
+            negotiated_protocol_version: hello.protocol_version.min(PROTOCOL_VERSION),
+            capabilities: hello.capabilities,
+            shared_capabilities: Vec::new(),
+            listen_port: (hello.port != 0).then_some(hello.port),
+        }
+    }
+}
+
+// Everything the codec can fail with.
+#[derive(Debug)]
+pub enum RLPxError {
+    Io(std::io::Error),
+    // ECIES auth/ack handling failed.
+    Ecies(&'static str),
+    // Frame body was malformed.
+    Frame(&'static str),
+    // A header or frame MAC didn't verify. Both MAC ratchets have already taken
+    // the bad frame in, so they're out of step with the peer for good and
+    // nothing after it can be verified either. The only way on is a new session.
+    FrameMacMismatch,
+    // The decrypted frame didn't hold a message we could decode.
+    Decode(&'static str),
+    // Tried to send for a capability the peer didn't agree to.
+    Capability(&'static str),
+    Compression(&'static str),
+    // A frame body that should have been snappy compressed wasn't valid snappy.
+    DecompressionFailed,
+    UnexpectedMessage,
+    InvalidState(RlpxState),
+    // The peer went away in the middle of a frame.
+    Truncated,
+    // The auth-ack size prefix was over the configured limit.
+    AuthMessageTooLarge(usize),
+    // A frame header declared a zero frame-size.
+    EmptyFrame,
+    // The peer broke the protocol badly enough to be disconnected for it.
+    ProtocolBreach(&'static str),
+    // The session would hold more than max_session_memory bytes.
+    MemoryBudgetExceeded(usize),
+    // Something wanted a frame before auth and ack gave us the secrets for it.
+    SecretsNotEstablished,
+}
+
+impl std::fmt::Display for RLPxError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RLPxError::Io(err) => write!(f, "I/O error: {}", err),
+            RLPxError::Ecies(err) => write!(f, "ECIES error: {}", err),
+            RLPxError::Frame(err) => write!(f, "Frame error: {}", err),
+            RLPxError::FrameMacMismatch => write!(f, "Frame MAC mismatch"),
+            RLPxError::Decode(err) => write!(f, "Message decode error: {}", err),
+            RLPxError::Compression(err) => write!(f, "Snappy error: {}", err),
+            RLPxError::DecompressionFailed => write!(f, "Snappy decompression failed"),
+            RLPxError::Capability(err) => write!(f, "Capability error: {}", err),
+            RLPxError::UnexpectedMessage => write!(f, "Unexpected message"),
+            RLPxError::InvalidState(state) => write!(f, "Invalid codec state {:?}", state),
+            RLPxError::Truncated => write!(f, "Connection closed in the middle of a frame"),
+            RLPxError::AuthMessageTooLarge(size) => write!(f, "Auth message of {} bytes is too large", size),
+            RLPxError::EmptyFrame => write!(f, "Frame header declared an empty frame"),
+            RLPxError::ProtocolBreach(err) => write!(f, "Protocol breach: {}", err),
+            RLPxError::MemoryBudgetExceeded(usage) => write!(f, "Session would hold {} bytes, over its memory budget", usage),
+            RLPxError::SecretsNotEstablished => write!(f, "No frame secrets yet, the ECIES handshake isn't done"),
+        }
+    }
+}
+
+impl From<std::io::Error> for RLPxError {
+    fn from(err: std::io::Error) -> Self {
+        RLPxError::Io(err)
+    }
+}
+
+#[derive(Debug)]
+pub enum HandshakeError {
+    Io(std::io::Error),
+    Codec(RLPxError),
+    UnexpectedMessage,
+    PeerClosed,
+    // The peer sent Disconnect instead of finishing the handshake.
+    Disconnected(Reason),
+    // Which phase ran out of time.
+    Timeout(&'static str),
+    // The session's own deadline passed, whatever it was doing.
+    SessionDeadlineExceeded,
+}
+
+impl std::fmt::Display for HandshakeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HandshakeError::Io(err) => write!(f, "I/O error: {}", err),
+            HandshakeError::Codec(err) => write!(f, "{}", err),
+            HandshakeError::UnexpectedMessage => write!(f, "Unexpected message during handshake"),
+            HandshakeError::PeerClosed => write!(f, "Peer closed socket connection"),
+            HandshakeError::Disconnected(reason) => write!(f, "Peer disconnected during handshake, reason {:#x}", reason),
+            HandshakeError::Timeout(phase) => write!(f, "Timed out during {}", phase),
+            HandshakeError::SessionDeadlineExceeded => write!(f, "Session deadline exceeded"),
+        }
+    }
+}
+
+impl From<std::io::Error> for HandshakeError {
+    fn from(err: std::io::Error) -> Self {
+        HandshakeError::Io(err)
+    }
+}
+
+impl From<RLPxError> for HandshakeError {
+    fn from(err: RLPxError) -> Self {
+        HandshakeError::Codec(err)
+    }
+}
+
+// header-data = [capability-id, context-id]
+// Base protocol frames use capability-id 0, subprotocols count from 1 in the
+// negotiated order. We don't multiplex, so context-id stays 0.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, RlpEncodable, RlpDecodable)]
+pub struct HeaderData {
+    pub capability_id: u8,
+    pub context_id: u16,
+}
+
+impl HeaderData {
+    pub fn for_message(message_id: u8, shared_capabilities: &[SharedCapability]) -> Self {
+        let capability_id = shared_capabilities
+            .iter()
+            .position(|shared| shared.owns(message_id))
+            .map_or(0, |index| index as u8 + 1);
+        Self {
+            capability_id,
+            context_id: 0,
+        }
+    }
+}
+
+// Bytes that went through the codec in each direction, handshake included.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ByteStats {
+    pub read: u64,
+    pub written: u64,
+    // Messages still in the session's outbound queue, the codec leaves it at 0.
+    pub queued: usize,
+    // Bytes held against max_session_memory, the session fills it in.
+    pub memory: usize,
+}
+
+// Why the last decode came back without a message. Tells a peer that's slow
+// to finish what it started from one that sent nothing at all.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DecodeWait {
+    // Not a single byte to go on.
+    EmptyBuffer,
+    // The peer got in ahead of our auth, these wait until we expect an ack.
+    AheadOfOurAuth { have: usize },
+    // Part of an auth or ack. How much it takes is only known once the size
+    // prefix, or enough of a pre-EIP-8 message to tell, is in.
+    NeedMoreForHandshake { have: usize, need: Option<usize> },
+    NeedMoreForHeader { have: usize },
+    // The header is through, the frame body and frame-mac aren't yet.
+    NeedMoreForFrame { have: usize, need: usize },
+}
+
+// What the last ingress frame header decoded to. It's known before the frame
+// MAC is checked, so when that fails this tells whether the size field made
+// sense in the first place.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct FrameHeaderInfo {
+    // frame-data size straight from the header.
+    pub payload_size: usize,
+    // What we then wait for: the ciphertext, padded to 16, and the frame-mac.
+    pub frame_size: usize,
+    pub header_data: HeaderData,
+}
+
 #[derive(Clone, Copy, Debug, PartialEq)]
 pub enum FrameState {
     DecodingHeader,
@@ -38,45 +275,318 @@ pub enum FrameState {
 pub struct RLPx {
     rlpx_state: RlpxState,
     direction: ECIESDirection,
-    auth_request: BytesMut,
     ecies: ECIES,
     public_key: PublicKey,
     frame_state: FrameState,
     secrets: Option<HandshakeSecrets>,
+    config: RLPxConfig,
+    peer_info: Option<PeerInfo>,
+    // The peer's eth Status, once it sent one.
+    peer_status: Option<Status>,
+    // p2p v5 compresses everything after Hello, once both sides speak it.
+    snappy: bool,
+    frame_log: Option<FrameLog>,
+    state_log: Option<StateLog>,
+    // header-data of the frame currently being decoded.
+    ingress_header_data: HeaderData,
+    // Raw bytes of the frame being decoded, kept around only for the frame log
+    // since everything gets decrypted in place as it comes in.
+    raw_frame: Vec<u8>,
+    // frame-data (padding included) decrypted so far. The ciphertext is taken
+    // out of the read buffer block by block as it arrives, so a large frame
+    // only ever sits in memory once, here.
+    ingress_frame: BytesMut,
+    byte_stats: ByteStats,
+    // keccak256.digest(mac)[:16] as it stood after each side's last frame-mac,
+    // which is exactly what the next header starts from. Saves a clone and
+    // finalize of the Keccak state per frame.
+    egress_mac_digest: Option<[u8; 16]>,
+    ingress_mac_digest: Option<[u8; 16]>,
+    // Ciphertext bytes we've moved past in the read buffer. The ingress
+    // keystream must sit exactly here whenever we start on the next chunk, or
+    // some bytes got decrypted twice (or not at all).
+    ingress_ciphertext_consumed: u64,
+    // Ids of the eth requests we sent that haven't been answered yet.
+    pending_requests: HashSet<u64>,
+    last_ingress_header: Option<FrameHeaderInfo>,
+    // The capability mismatch hook asked to keep a peer we share nothing with.
+    keep_useless_peer: bool,
+    // Start of the current one second Ping window and the Pings seen in it.
+    ping_window: Option<(Instant, u32)>,
+    // What the session has waiting to go out, as it last told us.
+    outbound_bytes: usize,
+    last_decode_wait: Option<DecodeWait>,
+    // The frame begin_frame_stream started, while its pieces go out.
+    egress_stream: Option<EgressStream>,
 }
 
-pub const PROTOCOL_VERSION: usize = 5;
-const ZERO_HEADER: &[u8; 16] = &[0, 0, 148, 194, 128, 128, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]; // Lifted from geth
+// How far along a streamed frame is. Its raw bytes are only kept with a frame
+// log, which wants the whole frame.
+#[derive(Clone, Debug)]
+struct EgressStream {
+    remaining: usize,
+    padding: usize,
+    raw_frame: Vec<u8>,
+}
 
+// p2p v5 only adds snappy compression, so a build without it stays at v4 and
+// peers then leave their frames uncompressed too.
+pub const PROTOCOL_VERSION: usize = if cfg!(feature = "snappy") { 5 } else { 4 };
 
 const FRAME_HEADER_CIPHERTEXT_SIZE: usize = 16;
 const FRAME_MAC_SIZE: usize = 16;
+const FRAME_HEADER_SIZE: usize = FRAME_HEADER_CIPHERTEXT_SIZE + FRAME_MAC_SIZE;
+// Largest frame-data the header's 3 byte frame-size can describe.
+const MAX_FRAME_SIZE: usize = 0xff_ffff;
 
 impl RLPx {
-    pub fn new(our_private_key: SecretKey, peer_public_key: PublicKey) -> Self {
+    pub fn new(our_private_key: SecretKey, peer_public_key: PublicKey, config: RLPxConfig) -> Self {
+        Self::with_ecies(our_private_key, ECIES::new(our_private_key, peer_public_key, config.ecies_rng.clone()), config)
+    }
+
+    // Codec for a connection the peer dialed, it starts out waiting for auth.
+    pub fn new_incoming(our_private_key: SecretKey, config: RLPxConfig) -> Self {
+        let mut rlpx = Self::with_ecies(our_private_key, ECIES::new_incoming(our_private_key, config.ecies_rng.clone()), config);
+        rlpx.direction = ECIESDirection::Incoming;
+        rlpx.rlpx_state = RlpxState::ExpectingAuth;
+        rlpx
+    }
+
+    fn with_ecies(our_private_key: SecretKey, ecies: ECIES, config: RLPxConfig) -> Self {
         let public_key = PublicKey::from_secret_key(SECP256K1, &our_private_key);
         Self {
             rlpx_state: RlpxState::ExpectingConnection,
             direction: ECIESDirection::Outgoing,
-            auth_request: BytesMut::new(), // todo
-            ecies: ECIES::new(our_private_key, peer_public_key),
-            public_key: public_key,
+            ecies,
+            public_key,
             frame_state: FrameState::DecodingHeader,
             secrets: None,
+            config,
+            peer_info: None,
+            peer_status: None,
+            snappy: false,
+            frame_log: None,
+            state_log: None,
+            ingress_header_data: HeaderData::default(),
+            raw_frame: Vec::new(),
+            ingress_frame: BytesMut::new(),
+            byte_stats: ByteStats::default(),
+            egress_mac_digest: None,
+            ingress_mac_digest: None,
+            ingress_ciphertext_consumed: 0,
+            pending_requests: HashSet::new(),
+            last_ingress_header: None,
+            ping_window: None,
+            keep_useless_peer: false,
+            outbound_bytes: 0,
+            last_decode_wait: None,
+            egress_stream: None,
+        }
+    }
+
+    pub fn set_frame_log(&mut self, frame_log: FrameLog) {
+        self.frame_log = Some(frame_log);
+    }
+
+    pub fn set_state_log(&mut self, state_log: StateLog) {
+        self.state_log = Some(state_log);
+    }
+
+    // Tears down everything keyed to this connection once it's of no more use,
+    // after a decode error say, so nothing lingers until the codec is dropped.
+    // The frame ciphers and MAC states zeroize themselves when dropped. The
+    // codec ends up Disconnected for good.
+    pub fn reset(&mut self) {
+        if self.secrets.is_none() && self.rlpx_state == RlpxState::Disconnected {
+            return;
+        }
+        debug!("Releasing the session secrets");
+        self.secrets = None;
+        self.egress_mac_digest = None;
+        self.ingress_mac_digest = None;
+        self.ecies.wipe();
+        self.raw_frame.zeroize();
+        self.ingress_frame.zeroize();
+        self.ingress_frame.clear();
+        if let Some(mut stream) = self.egress_stream.take() {
+            stream.raw_frame.zeroize();
+        }
+        self.frame_state = FrameState::DecodingHeader;
+        self.pending_requests.clear();
+        if self.rlpx_state != RlpxState::Disconnected {
+            self.set_state(RlpxState::Disconnected, "Codec reset");
         }
     }
 
-    fn write_frame(&mut self, data: &[u8]) -> BytesMut {
+    // Every state change goes through here so the state log sees all of them.
+    fn set_state(&mut self, state: RlpxState, event: &str) {
+        debug!("State {:?} -> {:?}: {}", self.rlpx_state, state, event);
+        if let Some(state_log) = &self.state_log {
+            state_log.record(self.peer_public_key().as_ref(), self.rlpx_state, state, event);
+        }
+        self.rlpx_state = state;
+    }
+
+    fn log_frame(&self, direction: FrameDirection, kind: FrameKind, data: &[u8]) {
+        if let Some(frame_log) = &self.frame_log {
+            frame_log.record(direction, kind, data);
+        }
+    }
+
+    fn write_frame(&mut self, header_data: HeaderData, data: &[u8]) -> BytesMut {
+        let mut out = BytesMut::new();
+        self.write_frame_into(header_data, data, &mut out);
+        out
+    }
+
+    // Frames payload as-is: no msg-id, no snappy and no check that it's anything
+    // the peer will understand. The egress MAC still moves on, so the frame has
+    // to actually be sent. Refused with the same checks as a streamed frame,
+    // and while one of those is going out.
+    pub fn write_raw_frame(&mut self, payload: &[u8], dst: &mut BytesMut) -> Result<(), RLPxError> {
+        if self.secrets.is_none() {
+            return Err(RLPxError::SecretsNotEstablished);
+        }
+        if self.egress_stream.is_some() {
+            return Err(RLPxError::Frame("A streamed frame is going out, finish it first"));
+        }
+        if payload.is_empty() {
+            return Err(RLPxError::EmptyFrame);
+        }
+        if payload.len() > MAX_FRAME_SIZE {
+            return Err(RLPxError::Frame("Frame too large for the 3 byte frame-size "));
+        }
+        let buffered = dst.len();
+        self.write_frame_into(HeaderData::default(), payload, dst);
+        // It never goes through encode(), so count it here.
+        self.byte_stats.written += (dst.len() - buffered) as u64;
+        Ok(())
+    }
+
+    // write_raw_frame in pieces, for frames too big to want a second copy of
+    // in the write buffer. begin_frame_stream hands back the header, then all
+    // of the payload goes through encrypt_frame_stream, in as many pieces as
+    // suits, and finish_frame_stream gives the padding and frame-mac. Each
+    // piece has to be sent as it comes back and before anything else is
+    // framed, the egress MAC has already moved on past it.
+    pub fn begin_frame_stream(&mut self, payload_len: usize) -> Result<[u8; FRAME_HEADER_SIZE], RLPxError> {
+        if self.secrets.is_none() {
+            return Err(RLPxError::SecretsNotEstablished);
+        }
+        if self.egress_stream.is_some() {
+            return Err(RLPxError::Frame("A streamed frame is already going out"));
+        }
+        if payload_len == 0 {
+            return Err(RLPxError::EmptyFrame);
+        }
+        if payload_len > MAX_FRAME_SIZE {
+            return Err(RLPxError::Frame("Frame too large for the 3 byte frame-size "));
+        }
+        let header = self.egress_header(HeaderData::default(), payload_len);
+        let mut raw_frame = Vec::new();
+        if self.frame_log.is_some() {
+            raw_frame.extend_from_slice(&header);
+        }
+        self.egress_stream = Some(EgressStream {
+            remaining: payload_len,
+            padding: payload_len.next_multiple_of(16) - payload_len,
+            raw_frame,
+        });
+        self.byte_stats.written += FRAME_HEADER_SIZE as u64;
+        Ok(header)
+    }
+
+    // Encrypts the next piece of the payload in place.
+    pub fn encrypt_frame_stream(&mut self, piece: &mut [u8]) -> Result<(), RLPxError> {
+        let stream = self.egress_stream.as_mut().ok_or(RLPxError::Frame("No streamed frame going out"))?;
+        stream.remaining = stream
+            .remaining
+            .checked_sub(piece.len())
+            .ok_or(RLPxError::Frame("More payload than the frame header announced"))?;
+        self.egress_frame_ciphertext(piece);
+        if self.frame_log.is_some() {
+            if let Some(stream) = &mut self.egress_stream {
+                stream.raw_frame.extend_from_slice(piece);
+            }
+        }
+        self.byte_stats.written += piece.len() as u64;
+        Ok(())
+    }
+
+    // The encrypted padding and the frame-mac, once all of the payload is through.
+    pub fn finish_frame_stream(&mut self) -> Result<Vec<u8>, RLPxError> {
+        match &self.egress_stream {
+            Some(stream) if stream.remaining == 0 => {}
+            Some(_) => return Err(RLPxError::Frame("Less payload than the frame header announced")),
+            None => return Err(RLPxError::Frame("No streamed frame going out")),
+        }
+        let mut stream = self.egress_stream.take().unwrap();
+        let mut tail = vec![0; stream.padding];
+        self.egress_frame_ciphertext(&mut tail);
+        tail.extend_from_slice(&self.egress_frame_mac());
+        if self.frame_log.is_some() {
+            stream.raw_frame.extend_from_slice(&tail);
+            self.log_frame(FrameDirection::Outbound, FrameKind::Frame, &stream.raw_frame);
+        }
+        self.byte_stats.written += tail.len() as u64;
+        Ok(tail)
+    }
+
+    // Encodes the messages onto dst one after the other, in the order given,
+    // and returns how many made it. Stops at the first one that can't be
+    // encoded, what's already in dst has moved the egress MAC on and still
+    // has to be sent.
+    pub fn write_messages(
+        &mut self,
+        messages: impl IntoIterator<Item = RLPx_Message>,
+        dst: &mut BytesMut,
+    ) -> Result<usize, RLPxError> {
+        let mut written = 0;
+        for message in messages {
+            self.encode(message, dst)?;
+            written += 1;
+        }
+        Ok(written)
+    }
+
+    // Appends the frame straight onto dst and encrypts it in place there, so the
+    // codec's write buffer is the only copy of the frame we ever make.
+    //
+    // Every frame's header-mac starts from the egress-mac as the previous
+    // frame-mac left it, so frames have to hit the wire in exactly the order
+    // they went through here. That's why this only ever appends to the buffer
+    // that gets sent, rather than handing frames back to be placed by the
+    // caller: nothing can get between the MAC moving on and the bytes taking
+    // their place in line.
+    fn write_frame_into(&mut self, header_data: HeaderData, data: &[u8], dst: &mut BytesMut) {
         // frame = header-ciphertext || header-mac || frame-ciphertext || frame-mac
-        // header = frame-size || header-data || header-padding
-        // header-data = [capability-id, context-id]
+        let start = dst.len();
+        let padded_len = data.len().next_multiple_of(16);
+        dst.reserve(FRAME_HEADER_SIZE + padded_len + FRAME_MAC_SIZE);
+
+        let header = self.egress_header(header_data, data.len());
+        dst.extend_from_slice(&header);
+
+        let frame_start = dst.len();
+        dst.extend_from_slice(data);
+        dst.resize(frame_start + padded_len, 0);
+        self.egress_frame_ciphertext(&mut dst[frame_start..]);
+
+        let frame_mac = self.egress_frame_mac();
+        dst.extend_from_slice(&frame_mac);
+
+        self.log_frame(FrameDirection::Outbound, FrameKind::Frame, &dst[start..]);
+    }
 
+    // header-ciphertext || header-mac for a frame carrying data_len bytes.
+    fn egress_header(&mut self, header_data: HeaderData, data_len: usize) -> [u8; FRAME_HEADER_SIZE] {
         // header = frame-size || header-data || header-padding
+        // header-data = [capability-id, context-id]
+        // frame-size is 3 bytes big endian, the top one at position 0.
         let mut header_buf = BytesMut::new();
-        header_buf.extend_from_slice(ZERO_HEADER);
-        // We're missing a byte from the length here.
-        let x: u16 = data.len() as u16;
-        header_buf[1..3].copy_from_slice(&x.to_be_bytes());
+        header_buf.extend_from_slice(&(data_len as u32).to_be_bytes()[1..]);
+        header_data.encode(&mut header_buf);
+        header_buf.resize(16, 0);
 
         let secrets = self.secrets.as_mut().unwrap();
 
@@ -85,9 +595,10 @@ impl RLPx {
             .aes_keystream_egress
             .apply_keystream(header_buf.as_mut());
         // header-mac-seed = aes(mac-secret, keccak256.digest(egress-mac)[:16]) ^ header-ciphertext
-        let egress_mac = &secrets.egress_mac.clone().finalize();
-        let mut egress_mac_digest: [u8; 16] = [0; 16];
-        egress_mac_digest.copy_from_slice(&egress_mac[..16]);
+        let mut egress_mac_digest = self
+            .egress_mac_digest
+            .take()
+            .unwrap_or_else(|| mac_digest(&secrets.egress_mac));
         secrets
             .mac_secret
             .encrypt_block(GenericArray::from_mut_slice(egress_mac_digest.as_mut()));
@@ -102,32 +613,27 @@ impl RLPx {
         secrets.egress_mac.update(header_mac_seed);
         let header_mac = &secrets.egress_mac.clone().finalize()[..16];
 
-        let mut out = BytesMut::default();
-        out.reserve(32);
-        out.extend_from_slice(&header_buf);
-        out.extend_from_slice(header_mac);
-
-        let mut len = data.len();
-        if len % 16 > 0 {
-            len = (len / 16 + 1) * 16;
-        }
-
-        let old_len = out.len();
-        out.resize(old_len + len, 0);
-
-        let encrypted = &mut out[old_len..old_len + len];
-        encrypted[..data.len()].copy_from_slice(data);
+        let mut header = [0; FRAME_HEADER_SIZE];
+        header[..FRAME_HEADER_CIPHERTEXT_SIZE].copy_from_slice(&header_buf);
+        header[FRAME_HEADER_CIPHERTEXT_SIZE..].copy_from_slice(header_mac);
+        header
+    }
 
+    // Encrypts the padded frame-data in place and feeds it to the egress MAC.
+    fn egress_frame_ciphertext(&mut self, frame: &mut [u8]) {
+        let secrets = self.secrets.as_mut().unwrap();
         //frame-ciphertext = aes(aes-secret, frame-data || frame-padding)
-        secrets.aes_keystream_egress.apply_keystream(encrypted);
+        secrets.aes_keystream_egress.apply_keystream(frame);
         // egress-mac = keccak256.update(egress-mac, frame-ciphertext)
-        secrets.egress_mac.update(encrypted);
+        secrets.egress_mac.update(&*frame);
+    }
+
+    fn egress_frame_mac(&mut self) -> [u8; FRAME_MAC_SIZE] {
+        let secrets = self.secrets.as_mut().unwrap();
         // frame-mac-seed = aes(mac-secret, keccak256.digest(egress-mac)[:16]) ^ keccak256.digest(egress-mac)[:16]
         // keccak256.digest(egress-mac)[:16])
-        let egress_mac = &secrets.egress_mac.clone().finalize();
-        let mut egress_mac_digest: [u8; 16] = [0; 16];
-        egress_mac_digest.copy_from_slice(&egress_mac[0..16]);
-        let mut egress_mac_aes = egress_mac_digest.clone();
+        let egress_mac_digest = mac_digest(&secrets.egress_mac);
+        let mut egress_mac_aes = egress_mac_digest;
         // This is done in block encryption mode
         //aes(mac-secret, keccak256.digest(egress-mac)[:16])
         secrets
@@ -142,26 +648,20 @@ impl RLPx {
         secrets.egress_mac.update(frame_mac_seed);
 
         // frame-mac = keccak256.digest(egress-mac)[:16]
-        let frame_mac = &secrets.egress_mac.clone().finalize()[..16];
-
-        out.extend_from_slice(frame_mac);
-
-        out
+        let frame_mac = mac_digest(&secrets.egress_mac);
+        self.egress_mac_digest = Some(frame_mac);
+        frame_mac
     }
 
-    pub fn decode_frame_header<'a>(
+    // Takes exactly one header's worth of bytes, the caller waits until it has them.
+    pub fn decode_frame_header(
         &mut self,
-        data_in: &'a mut [u8],
-    ) -> Result<usize, &'static str> {
+        data_in: &mut [u8; FRAME_HEADER_SIZE],
+    ) -> Result<(usize, HeaderData), RLPxError> {
 
         // frame = header-ciphertext || header-mac || frame-ciphertext || frame-mac
-        let (header_ciphertext, rest) = data_in
-            .split_at_mut_checked(FRAME_HEADER_CIPHERTEXT_SIZE)
-            .ok_or("No header ciphertext! ")?;
-
-        let (header_mac, rest) = rest
-            .split_at_mut_checked(FRAME_MAC_SIZE)
-            .ok_or("No header MAC ")?;
+        let (header_ciphertext, header_mac) = data_in.split_at_mut(FRAME_HEADER_CIPHERTEXT_SIZE);
+        let verify_mac = self.verify_macs();
 
         // Get a local reference so it's simpler and I don't have to unwrap it every time.
         let secrets = self.secrets.as_mut().unwrap();
@@ -170,12 +670,10 @@ impl RLPx {
         // header-mac-seed = aes(mac-secret, keccak256.digest(egress-mac)[:16]) ^ header-ciphertext
         // egress-mac = keccak256.update(egress-mac, header-mac-seed)
         // header-mac = keccak256.digest(egress-mac)[:16]
-        let ingress_mac = &secrets.ingress_mac.clone().finalize();
-        // debug!("ingress_mac: {:?}", ingress_mac);
-
-        let mut ingress_mac_digest: [u8; 16] = [0; 16];
-
-        ingress_mac_digest.copy_from_slice(&ingress_mac[..16]);
+        let mut ingress_mac_digest = self
+            .ingress_mac_digest
+            .take()
+            .unwrap_or_else(|| mac_digest(&secrets.ingress_mac));
         // debug!("ingress_mac_digest: {:?}", ingress_mac_digest);
 
         secrets
@@ -198,44 +696,133 @@ impl RLPx {
         // debug!("header_ciphertext: {:?}", header_ciphertext);
         // debug!("header_mac_computed: {:?}", header_mac_computed);
         // debug!("header_mac:  {:?}", header_mac);
-        if header_mac_computed != header_mac {
-            return Err("Header MAC mismatch!");
+        if verify_mac && header_mac_computed != header_mac {
+            #[cfg(feature = "mac-debug")]
+            log_mac_mismatch("Header", header_mac_computed, header_mac, secrets);
+            return Err(RLPxError::FrameMacMismatch);
         }
 
         secrets
             .aes_keystream_ingress
             .apply_keystream(header_ciphertext);
 
-        let mut payload_size = u32::from_be_bytes([0, header_ciphertext[0], header_ciphertext[1], header_ciphertext[2]]) as usize;
+        // This is the frame-data size, the ciphertext is padded up to 16 bytes on top.
+        let payload_size = u32::from_be_bytes([0, header_ciphertext[0], header_ciphertext[1], header_ciphertext[2]]) as usize;
 
-        if (payload_size % 16) !=0 { 
-            payload_size = ((payload_size / 16) +1)*16;
+        // frame-data always holds at least the msg-id, so an empty frame is
+        // nothing a well behaved peer sends.
+        if payload_size == 0 {
+            return Err(RLPxError::EmptyFrame);
         }
-        Ok(payload_size)
 
+        // Plenty of clients just send zeroes (or nothing sensible) here, so a
+        // header-data we can't parse isn't worth killing the session over.
+        let header_data = HeaderData::decode(&mut &header_ciphertext[3..])
+            .inspect_err(|err| debug!("Could not decode header-data: {:?}", err))
+            .unwrap_or_default();
+
+        let header_info = FrameHeaderInfo {
+            payload_size,
+            frame_size: payload_size.next_multiple_of(16) + FRAME_MAC_SIZE,
+            header_data,
+        };
+        trace!("Frame header decoded: {:?}", header_info);
+        self.last_ingress_header = Some(header_info);
+
+        Ok((payload_size, header_data))
+
+    }
+
+    // False only with unsafe-no-mac and the config asking for it. The MACs
+    // still move along either way, only the comparison is skipped.
+    fn verify_macs(&self) -> bool {
+        #[cfg(any(test, feature = "unsafe-no-mac"))]
+        if self.config.unsafe_skip_mac_verification {
+            return false;
+        }
+        true
+    }
+
+    // The frame secrets, from the ack on until the codec is reset.
+    pub fn secrets(&self) -> Option<&HandshakeSecrets> {
+        self.secrets.as_ref()
+    }
+
+    // Takes over a session whose handshake was done somewhere else, from the
+    // secrets exported there. Frames flow straight away, the Hellos aren't
+    // redone, so whether they get snappy compressed has to come along too.
+    pub fn resume(&mut self, secrets: HandshakeSecrets, snappy: bool) {
+        self.ingress_ciphertext_consumed = secrets.aes_keystream_ingress.current_pos();
+        self.secrets = Some(secrets);
+        self.egress_mac_digest = None;
+        self.ingress_mac_digest = None;
+        self.ingress_frame.clear();
+        self.frame_state = FrameState::DecodingHeader;
+        self.snappy = snappy;
+        self.set_state(RlpxState::Active, "Resumed from exported secrets");
+    }
+
+    // None until the first frame header after the handshake.
+    pub fn last_ingress_header(&self) -> Option<FrameHeaderInfo> {
+        self.last_ingress_header
+    }
+
+    // None when the last decode returned a message or an error, or when
+    // nothing has come in to decode yet.
+    pub fn last_decode_reason(&self) -> Option<DecodeWait> {
+        self.last_decode_wait
+    }
+
+    fn wait_for(&mut self, wait: DecodeWait) {
+        trace!("Decode waiting: {:?}", wait);
+        self.last_decode_wait = Some(wait);
     }
 
+    // A whole frame-ciphertext || frame-mac in one go. Decoding feeds the
+    // pieces through decrypt_frame_ciphertext and check_frame_mac instead.
     pub fn decode_frame_ciphertext<'a>(
         &mut self,
         data_in: &'a mut [u8],
-    ) -> Result<&'a mut [u8], &'static str> {
-        const FRAME_MAC_SIZE: usize = 16;
+    ) -> Result<&'a mut [u8], RLPxError> {
+        let ciphertext_size = data_in
+            .len()
+            .checked_sub(FRAME_MAC_SIZE)
+            .ok_or(RLPxError::Frame("No frame MAC, invalid frame length "))?;
+        // Same as a zero frame-size in the header: there's always a msg-id, so
+        // a frame with nothing in front of its MAC isn't one.
+        if ciphertext_size == 0 {
+            return Err(RLPxError::EmptyFrame);
+        }
+        let (frame_ciphertext, frame_mac) = data_in.split_at_mut(ciphertext_size);
+        self.decrypt_frame_ciphertext(frame_ciphertext);
+        self.check_frame_mac(frame_mac)?;
+        Ok(frame_ciphertext)
+    }
 
-        let (frame_ciphertext, frame_mac) = data_in
-            .split_at_mut_checked((data_in.len() - FRAME_MAC_SIZE))
-            .ok_or("No frame MAC, invalid frame length ")?;
+    // Runs the next stretch of frame-ciphertext through the ingress MAC and
+    // decrypts it in place. Any split of the ciphertext gives the same MAC
+    // and keystream as doing it all at once, so it can be fed in as it
+    // arrives. Nothing decrypted here is to be trusted until check_frame_mac
+    // has seen the frame-mac that follows.
+    fn decrypt_frame_ciphertext(&mut self, frame_ciphertext: &mut [u8]) {
+        let secrets = self.secrets.as_mut().unwrap();
+        // egress-mac = keccak256.update(egress-mac, frame-ciphertext)
+        secrets.ingress_mac.update(&frame_ciphertext);
+        secrets
+            .aes_keystream_ingress
+            .apply_keystream(frame_ciphertext);
+    }
 
+    // Once all of the frame-ciphertext went through decrypt_frame_ciphertext.
+    fn check_frame_mac(&mut self, frame_mac: &[u8]) -> Result<(), RLPxError> {
+        let verify_mac = self.verify_macs();
         // Get a local reference so it's simpler and I don't have to unwrap it every time.
         let secrets = self.secrets.as_mut().unwrap();
 
-        // egress-mac = keccak256.update(egress-mac, frame-ciphertext)
-        secrets.ingress_mac.update(&frame_ciphertext);
         // frame-mac-seed = aes(mac-secret, keccak256.digest(egress-mac)[:16]) ^ keccak256.digest(egress-mac)[:16]
         // keccak256.digest(egress-mac)[:16])
-        let ingress_mac = &secrets.ingress_mac.clone().finalize();
-        let mut ingress_mac_digest: [u8; 16] = [0; 16];
-        ingress_mac_digest.copy_from_slice(&ingress_mac[0..16]);
-        let mut ingress_mac_aes = ingress_mac_digest.clone();
+        let ingress_mac_digest = mac_digest(&secrets.ingress_mac);
+        let mut ingress_mac_aes = ingress_mac_digest;
         // This is done in block encryption mode
         //aes(mac-secret, keccak256.digest(egress-mac)[:16])
         secrets
@@ -250,33 +837,190 @@ impl RLPx {
         secrets.ingress_mac.update(frame_mac_seed);
 
         // frame-mac = keccak256.digest(egress-mac)[:16]
-        let frame_mac_computed = &secrets.ingress_mac.clone().finalize()[..16];
+        let frame_mac_computed = mac_digest(&secrets.ingress_mac);
 
-        if frame_mac_computed != frame_mac {
+        if verify_mac && frame_mac_computed != frame_mac {
             debug!("RX Frame MAC mismatch!");
-            return Err("Frame MAC mismatch!");
+            #[cfg(feature = "mac-debug")]
+            log_mac_mismatch("Frame", &frame_mac_computed, frame_mac, secrets);
+            return Err(RLPxError::FrameMacMismatch);
         }
 
-        secrets
-            .aes_keystream_ingress
-            .apply_keystream(frame_ciphertext);
+        self.ingress_mac_digest = Some(frame_mac_computed);
+        Ok(())
+    }
 
-        Ok(frame_ciphertext)
+    // See ECIES::set_ephemeral and set_envelope, both have to happen before
+    // auth/ack is written.
+    #[cfg(any(test, feature = "test-vectors"))]
+    pub fn set_ephemeral(&mut self, ephemeral_priv_key: SecretKey, nonce: ethereum_types::H256) {
+        self.ecies.set_ephemeral(ephemeral_priv_key, nonce);
+    }
+
+    #[cfg(any(test, feature = "test-vectors"))]
+    pub fn set_envelope(&mut self, envelope_key: SecretKey, iv: ethereum_types::H128) {
+        self.ecies.set_envelope(envelope_key, iv);
+    }
+
+    // Known up front when we dial, and from the auth message on when we listen.
+    pub fn peer_public_key(&self) -> Option<PublicKey> {
+        self.ecies.peer_public_key()
+    }
+
+    #[cfg(any(test, feature = "handshake-bytes"))]
+    pub fn ecies(&self) -> &ECIES {
+        &self.ecies
+    }
+
+    pub fn direction(&self) -> ECIESDirection {
+        self.direction
+    }
+
+    pub fn byte_stats(&self) -> ByteStats {
+        self.byte_stats
+    }
+
+    pub fn set_outbound_bytes(&mut self, outbound_bytes: usize) {
+        self.outbound_bytes = outbound_bytes;
+    }
+
+    // Everything the session holds on to, given what's in the read buffer.
+    pub fn memory_usage(&self, inbound_bytes: usize) -> usize {
+        inbound_bytes + self.ingress_frame.len() + self.outbound_bytes + self.pending_requests.len() * size_of::<u64>()
+    }
+
+    pub fn check_memory(&self, inbound_bytes: usize) -> Result<(), RLPxError> {
+        let usage = self.memory_usage(inbound_bytes);
+        match self.config.max_session_memory {
+            Some(max_session_memory) if usage > max_session_memory => Err(RLPxError::MemoryBudgetExceeded(usage)),
+            _ => Ok(()),
+        }
     }
 
     pub fn get_state(&self) -> RlpxState {
         self.rlpx_state
     }
 
-    pub fn hello_msg(&mut self) -> BytesMut {
+    pub fn peer_info(&self) -> Option<&PeerInfo> {
+        self.peer_info.as_ref()
+    }
+
+    // The Status we'll open eth with, if we're configured to and eth got
+    // negotiated. It carries the negotiated eth version.
+    pub fn eth_status(&self) -> Option<Status> {
+        if !self.config.eth_status {
+            return None;
+        }
+        let eth = self
+            .shared_capabilities()
+            .iter()
+            .find(|shared| shared.capability.name == Status::CAPABILITY)?;
+        Some(Status {
+            version: eth.capability.version as u8,
+            networkid: self.config.network_id,
+            td: self.config.total_difficulty,
+            blockhash: self.config.best_block_hash,
+            genesis: self.config.genesis_hash,
+            forkid: self.config.fork_id.clone(),
+        })
+    }
+
+    // Hands out the configured initial messages, only the first call gets them.
+    pub fn take_initial_messages(&mut self) -> Vec<RLPx_Message> {
+        std::mem::take(&mut self.config.initial_messages)
+    }
+
+    // Past Hello with nothing in common, and no hook that wants the peer anyway.
+    pub fn is_useless_peer(&self) -> bool {
+        !self.keep_useless_peer
+            && self
+                .peer_info
+                .as_ref()
+                .is_some_and(|peer_info| peer_info.shared_capabilities.is_empty())
+    }
+
+    // Done with the handshake: p2p is up and, where eth calls for it, both
+    // Status messages are through.
+    pub fn is_ready(&self) -> bool {
+        match self.rlpx_state {
+            RlpxState::Active => self.eth_status().is_none(),
+            RlpxState::EthStatusExchanged => true,
+            _ => false,
+        }
+    }
+
+    // Same handshake as the Framed path, but driven over a blocking socket for
+    // callers that don't run a tokio runtime. Dialing only, and it stops at
+    // the Hellos: unlike Session::handshake there's no eth Status exchange,
+    // and a peer sharing no capability with us isn't sent USELESS_PEER.
+    // Callers that care have to check peer_info themselves.
+    pub fn handshake_blocking(&mut self, stream: &mut (impl Read + Write)) -> Result<PeerInfo, HandshakeError> {
+        let mut write_buf = BytesMut::new();
+        let mut read_buf = BytesMut::new();
+
+        debug!("We're sending Auth!");
+        self.encode(RLPx_Message::Auth, &mut write_buf)?;
+        stream.write_all(&write_buf)?;
+
+        debug!("We're recieving ack!");
+        match self.read_message_blocking(stream, &mut read_buf)? {
+            RLPx_Message::AuthAck => {}
+            _ => return Err(HandshakeError::UnexpectedMessage),
+        }
+
+        debug!("We're sending Hello!");
+        write_buf.clear();
+        self.encode(RLPx_Message::Hello, &mut write_buf)?;
+        stream.write_all(&write_buf)?;
+
+        debug!("We're waiting Hello!");
+        match self.read_message_blocking(stream, &mut read_buf)? {
+            RLPx_Message::Hello => {}
+            RLPx_Message::Disconnect(reason) => return Err(HandshakeError::Disconnected(reason)),
+            _ => return Err(HandshakeError::UnexpectedMessage),
+        }
+
+        self.peer_info.clone().ok_or(HandshakeError::UnexpectedMessage)
+    }
+
+    fn read_message_blocking(
+        &mut self,
+        stream: &mut impl Read,
+        read_buf: &mut BytesMut,
+    ) -> Result<RLPx_Message, HandshakeError> {
+        let mut chunk = [0_u8; 4096];
+        loop {
+            if let Some(message) = self.decode(read_buf)? {
+                return Ok(message);
+            }
+            let read = stream.read(&mut chunk)?;
+            if read == 0 {
+                if let Some(message) = self.decode_eof(read_buf)? {
+                    return Ok(message);
+                }
+                return Err(HandshakeError::PeerClosed);
+            }
+            read_buf.extend_from_slice(&chunk[..read]);
+        }
+    }
+
+    // Hello is framed like everything after it, so it needs the secrets from
+    // auth and ack first.
+    pub fn hello_msg(&mut self) -> Result<BytesMut, RLPxError> {
+        if self.secrets.is_none() {
+            return Err(RLPxError::SecretsNotEstablished);
+        }
+        // The peer sorts what we send before laying out message ids, so send
+        // them in that order too.
+        let mut capabilities = self.config.capabilities.clone();
+        capabilities.sort();
+        capabilities.dedup();
+
         let msg = Hello {
             protocol_version: PROTOCOL_VERSION,
-            client_version: "reth_hello".to_string(),
-            capabilities: vec![Capability {
-                version: 68,
-                name: "eth".to_string(),
-            }],
-            port: 0,
+            client_version: self.config.client_version.pick(),
+            capabilities,
+            port: self.config.listen_port,
             id: *B512::from_slice(&self.public_key.serialize_uncompressed()[1..]),
         };
 
@@ -284,106 +1028,655 @@ impl RLPx {
         Hello::ID.encode(&mut encoded_hello);
         msg.encode(&mut encoded_hello);
 
-        self.write_frame(&encoded_hello)
+        Ok(self.write_frame(HeaderData::default(), &encoded_hello))
     }
 
-    fn decode_frame(&mut self, src: &mut BytesMut) -> Result<Option<RLPx_Message>, std::io::Error> {
-        
-        if self.frame_state == FrameState::DecodingHeader{
-            if src.len() >= FRAME_HEADER_CIPHERTEXT_SIZE+FRAME_MAC_SIZE {
-                let frame_ciphertext_size = self.decode_frame_header(src).map_err(|err|{
-                    error!("Error decoding header: {:?} ", err);
-                    Error::from(ErrorKind::Other)})?;
+    // frame-data = msg-id || msg-data, with msg-data snappy compressed when enabled.
+    fn write_message<T: Encodable>(&mut self, message_id: u8, message: &T, dst: &mut BytesMut) -> Result<(), RLPxError> {
+        let mut frame_data = BytesMut::default();
+        message_id.encode(&mut frame_data);
+
+        let mut message_data = Vec::new();
+        message.encode(&mut message_data);
+        if self.snappy {
+            message_data = snappy_compress(&message_data)?;
+        }
+        frame_data.extend_from_slice(&message_data);
+        if frame_data.len() > MAX_FRAME_SIZE {
+            return Err(RLPxError::Frame("Frame too large for the 3 byte frame-size "));
+        }
+
+        let header_data = HeaderData::for_message(message_id, self.shared_capabilities());
+        self.write_frame_into(header_data, &frame_data, dst);
+        Ok(())
+    }
 
-                self.frame_state = FrameState::DecodingFrame(frame_ciphertext_size);
-                src.advance(FRAME_HEADER_CIPHERTEXT_SIZE+FRAME_MAC_SIZE);
+    // Everything after Hello is snappy compressed once both sides speak p2p v5.
+    fn decompress<'a>(&self, message_data: &'a [u8]) -> Result<Cow<'a, [u8]>, RLPxError> {
+        if !self.snappy {
+            return Ok(Cow::Borrowed(message_data));
+        }
+        snappy_decompress(message_data).map(Cow::Owned)
+    }
+
+    fn shared_capabilities(&self) -> &[SharedCapability] {
+        self.peer_info
+            .as_ref()
+            .map_or(&[], |peer_info| &peer_info.shared_capabilities)
+    }
+
+    // Every message id this session can carry and we know how to handle, with
+    // its name. Subprotocol ones only show up once Hello placed them.
+    pub fn supported_message_ids(&self) -> Vec<(u8, &'static str)> {
+        let mut ids = BASE_PROTOCOL_MESSAGES.to_vec();
+        for shared in self.shared_capabilities() {
+            ids.extend(
+                shared
+                    .capability
+                    .known_messages()
+                    .iter()
+                    .filter_map(|&(code, name)| Some((shared.message_id(code)?, name))),
+            );
+        }
+        ids
+    }
+
+    // Maps a capability's own message code onto the id it has on this connection.
+    fn subprotocol_message_id(&self, capability: &str, code: u8) -> Result<u8, RLPxError> {
+        self.shared_capabilities()
+            .iter()
+            .find(|shared| shared.capability.name == capability)
+            .ok_or(RLPxError::Capability("Capability was not negotiated with the peer"))?
+            .message_id(code)
+            .ok_or(RLPxError::Capability("Message code out of the capability's range"))
+    }
+
+    fn negotiated_version(&self, capability: &str) -> Option<usize> {
+        self.shared_capabilities()
+            .iter()
+            .find(|shared| shared.capability.name == capability)
+            .map(|shared| shared.capability.version)
+    }
+
+    // The other way around, which capability a message id belongs to and its
+    // code within it.
+    fn subprotocol_code(&self, message_id: u8) -> Option<(&Capability, u8)> {
+        self.shared_capabilities()
+            .iter()
+            .find(|shared| shared.owns(message_id))
+            .map(|shared| (&shared.capability, message_id - shared.offset))
+    }
+
+    // Hands a size prefixed auth or ack to ECIES once all of it is in, returns
+    // whether it was. Pre-EIP-8 peers send theirs without the prefix.
+    fn decode_handshake_message(&mut self, src: &mut BytesMut, kind: FrameKind) -> Result<bool, RLPxError> {
+        // A legacy message starts with the uncompressed key's 0x04 tag. An EIP-8
+        // prefix starting with it would claim over 1KB, more than a legacy
+        // message, so it's safe to wait for that much either way.
+        if src.first() == Some(&0x04) {
+            let legacy_size = self.ecies.legacy_message_size();
+            if src.len() < legacy_size {
+                self.wait_for(DecodeWait::NeedMoreForHandshake { have: src.len(), need: None });
+                return Ok(false);
             }
-            else {
+            let raw_message = self.frame_log.as_ref().map(|_| src[..legacy_size].to_vec());
+            match self.ecies.decrypt_legacy(&mut src[..legacy_size]) {
+                Ok(()) => {
+                    debug!("Peer sent a pre-EIP-8 {:?}", kind);
+                    if let Some(raw_message) = raw_message {
+                        self.log_frame(FrameDirection::Inbound, kind, &raw_message);
+                    }
+                    src.advance(legacy_size);
+                    return Ok(true);
+                }
+                Err(e) => debug!("Not a pre-EIP-8 {:?} ({}), trying EIP-8", kind, e),
+            }
+        }
+
+        // The message is prefixed by its 2 byte size, wait until we have all of it.
+        if src.len() < 2 {
+            self.wait_for(DecodeWait::NeedMoreForHandshake { have: src.len(), need: None });
+            return Ok(false);
+        }
+        let message_size = u16::from_be_bytes([src[0], src[1]]) as usize + 2;
+        // Don't sit around waiting for whatever the prefix claims.
+        if message_size > self.config.max_auth_message_size {
+            debug!("{:?} size prefix of {} is over the limit", kind, message_size);
+            return Err(RLPxError::AuthMessageTooLarge(message_size));
+        }
+        if src.len() < message_size {
+            self.wait_for(DecodeWait::NeedMoreForHandshake { have: src.len(), need: Some(message_size) });
+            return Ok(false);
+        }
+
+        // Decryption happens in place, keep the wire bytes for the frame log.
+        let raw_message = self.frame_log.as_ref().map(|_| src[..message_size].to_vec());
+        // Peers commonly pipeline their Hello right behind the ack, so only
+        // hand the message itself to ECIES and leave the rest in the buffer.
+        let (_decrypted, frame_size) = self
+            .ecies
+            .decrypt(&mut src[..message_size])
+            .map_err(|e| {debug!("Frame decrypt Error: {:?}", e); RLPxError::Ecies(e)})?;
+
+        if let Some(raw_message) = raw_message {
+            self.log_frame(FrameDirection::Inbound, kind, &raw_message);
+        }
+
+        src.advance(frame_size);
+        if !src.is_empty() {
+            // Framed calls us again with these before reading the socket.
+            debug!("{} bytes pipelined after the {:?}, keeping them for the next frame", src.len(), kind);
+        }
+        Ok(true)
+    }
+
+    fn check_ingress_keystream(&self) -> Result<(), RLPxError> {
+        let Some(secrets) = &self.secrets else {
+            return Err(RLPxError::InvalidState(self.rlpx_state));
+        };
+        let position: u64 = secrets.aes_keystream_ingress.current_pos();
+        if position != self.ingress_ciphertext_consumed {
+            error!(
+                "Ingress keystream at {} but {} ciphertext bytes consumed",
+                position, self.ingress_ciphertext_consumed
+            );
+            return Err(RLPxError::Frame("Ingress keystream out of step with the stream"));
+        }
+        Ok(())
+    }
+
+    // A body that doesn't decompress means the peer is sending something we
+    // can't make sense of, so we're done with it rather than guess.
+    fn poison_on_decompression_failure(&mut self, err: RLPxError) -> RLPxError {
+        if matches!(err, RLPxError::DecompressionFailed) {
+            self.set_state(RlpxState::Disconnected, "Snappy decompression failed");
+            self.reset();
+        }
+        err
+    }
+
+    // Same goes for a keystream that's out of step, everything after is garbage.
+    fn poison_on_keystream_misalignment(&mut self, err: RLPxError) -> RLPxError {
+        self.set_state(RlpxState::Disconnected, "Ingress keystream misaligned");
+        self.reset();
+        err
+    }
+
+    // There's no skipping a frame that failed its MAC, so make sure the codec
+    // refuses anything that comes after it rather than decode garbage.
+    fn poison_on_mac_mismatch(&mut self, err: RLPxError) -> RLPxError {
+        if matches!(err, RLPxError::FrameMacMismatch) {
+            self.set_state(RlpxState::Disconnected, "Frame MAC mismatch");
+            self.reset();
+        }
+        err
+    }
+
+    fn decode_frame(&mut self, src: &mut BytesMut) -> Result<Option<RLPx_Message>, RLPxError> {
+        
+        if self.frame_state == FrameState::DecodingHeader{
+            let Some((header, _)) = src.split_first_chunk_mut::<FRAME_HEADER_SIZE>() else {
                 // Call us back until we get a full header. 
+                self.wait_for(DecodeWait::NeedMoreForHeader { have: src.len() });
                 return Ok(None);
+            };
+            if self.frame_log.is_some() {
+                self.raw_frame.clear();
+                self.raw_frame.extend_from_slice(header);
             }
+            self.check_ingress_keystream()
+                .map_err(|err| self.poison_on_keystream_misalignment(err))?;
+            let (frame_size, header_data) = self
+                .decode_frame_header(header)
+                .inspect_err(|err| error!("Error decoding header: {:?} ", err))
+                .map_err(|err| self.poison_on_mac_mismatch(err))?;
+            self.ingress_header_data = header_data;
+            // The whole frame has to be in memory before we can do anything
+            // with it, so refuse it up front rather than once it's in.
+            let buffered = src.len().max(FRAME_HEADER_SIZE + frame_size.next_multiple_of(16) + FRAME_MAC_SIZE);
+            self.check_memory(buffered)?;
+
+            self.frame_state = FrameState::DecodingFrame(frame_size);
+            src.advance(FRAME_HEADER_SIZE);
+            self.ingress_ciphertext_consumed += FRAME_HEADER_CIPHERTEXT_SIZE as u64;
+            self.ingress_frame.clear();
+            self.ingress_frame.reserve(frame_size.next_multiple_of(16));
         }
-        return match self.frame_state {
-            FrameState::DecodingFrame(frame_ciphertext_size) => {
+        match self.frame_state {
+            FrameState::DecodingFrame(frame_size) => {
+                let frame_ciphertext_size = frame_size.next_multiple_of(16);
+
+                // Decrypt whatever whole blocks of frame-ciphertext are in,
+                // rather than leave them in the read buffer until the rest
+                // shows up. The header is done with, it's never gone over again.
+                let missing = frame_ciphertext_size - self.ingress_frame.len();
+                let blocks = missing.min(src.len() - src.len() % 16);
+                if blocks > 0 {
+                    self.check_ingress_keystream()
+                        .map_err(|err| self.poison_on_keystream_misalignment(err))?;
+                    // Out of the read buffer before decrypting, so the same bytes
+                    // can't be around to be decrypted again on the next call.
+                    let mut ciphertext = src.split_to(blocks);
+                    if self.frame_log.is_some() {
+                        self.raw_frame.extend_from_slice(&ciphertext);
+                    }
+                    self.decrypt_frame_ciphertext(&mut ciphertext);
+                    self.ingress_ciphertext_consumed += blocks as u64;
+                    self.ingress_frame.extend_from_slice(&ciphertext);
+                }
 
-                if src.len() >= frame_ciphertext_size {
+                if self.ingress_frame.len() == frame_ciphertext_size && src.len() >= FRAME_MAC_SIZE {
+                    let frame_mac = src.split_to(FRAME_MAC_SIZE);
+                    if self.frame_log.is_some() {
+                        let mut raw_frame = std::mem::take(&mut self.raw_frame);
+                        raw_frame.extend_from_slice(&frame_mac);
+                        self.log_frame(FrameDirection::Inbound, FrameKind::Frame, &raw_frame);
+                    }
 
-                    let decrypted_frame = self.decode_frame_ciphertext(&mut src[..frame_ciphertext_size + FRAME_MAC_SIZE]).map_err(|err|{
-                        error!("Error decrypting frame: {:?} ", err);
-                        Error::from(ErrorKind::Other)})?;
-                        
-                    let message_id =  self.decode_frame_data(decrypted_frame).unwrap();
-                    src.advance(frame_ciphertext_size+FRAME_MAC_SIZE);
                     self.frame_state = FrameState::DecodingHeader;
+                    let decrypted_frame = std::mem::take(&mut self.ingress_frame);
+                    if let Err(err) = self.check_frame_mac(&frame_mac) {
+                        error!(
+                            "Error decrypting frame: {:?}, header said {:?} ",
+                            err, self.last_ingress_header
+                        );
+                        return Err(self.poison_on_mac_mismatch(err));
+                    }
+
+                    // Leave the padding out, snappy won't have it.
+                    let message_id = self
+                        .decode_frame_data(&decrypted_frame[..frame_size])
+                        .inspect_err(|err| error!("Error decoding frame data: {:?} ", err))
+                        .map_err(|err| self.poison_on_decompression_failure(err))?;
 
                     Ok(Some(message_id))
                 }
                 else {
-                    // Call us back until we get a full header. 
+                    // Call us back until we get the whole frame.
+                    self.wait_for(DecodeWait::NeedMoreForFrame {
+                        have: self.ingress_frame.len() + src.len(),
+                        need: frame_ciphertext_size + FRAME_MAC_SIZE,
+                    });
                     Ok(None)
                 }
 
             },
             _ => {
                 error!(" Unexpected state! We should not have gotten in this situation! ");
-                Err(Error::from(ErrorKind::Other)) }
+                Err(RLPxError::InvalidState(self.rlpx_state)) }
         }
     }
 
-    fn decode_frame_data(&mut self, frame: &[u8]) -> Result<RLPx_Message,  &'static str> {
+    fn decode_frame_data(&mut self, frame: &[u8]) -> Result<RLPx_Message, RLPxError> {
 
         let (message_id, message) = frame.split_at(1);
-        let message_id = u8::decode(&mut &message_id[..]).map_err(|_|" RLP stream decode error! ")?;
+        let message_id = u8::decode(&mut &message_id[..]).map_err(|_| RLPxError::Decode(" RLP stream decode error! "))?;
 
         debug!("Message ID received: {}", message_id);
 
+        // The message id is what actually routes the frame, since most peers
+        // zero out header-data. Only flag frames that claim a different capability.
+        let header_data = self.ingress_header_data;
+        if header_data != HeaderData::default() && header_data != HeaderData::for_message(message_id, self.shared_capabilities()) {
+            debug!("Frame header-data {:?} does not match message id {}", header_data, message_id);
+        }
+
         match message_id{
+            // Hello only ever opens a session, another one once we're past it
+            // means the peer has lost track of where we are.
+            Hello::ID if self.rlpx_state.is_active() => {
+                info!("Peer sent Hello again after the handshake");
+                Err(RLPxError::ProtocolBreach("Hello after the handshake"))
+            },
+
             Hello::ID => {
-                let hello = Hello::decode(&mut &message[..]);
-                info!("Hello message recieved from target node: {:?}", hello.unwrap());
-                return Ok(RLPx_Message::Hello);
+                let hello = Hello::decode_limited(
+                    &mut &message[..],
+                    self.config.max_peer_capabilities,
+                    self.config.max_client_version_len,
+                )
+                .map_err(|err| match err {
+                    alloy_rlp::Error::Custom(Hello::TOO_MANY_CAPABILITIES) => RLPxError::ProtocolBreach(Hello::TOO_MANY_CAPABILITIES),
+                    alloy_rlp::Error::Custom(Hello::EMPTY_CAPABILITY_NAME) => RLPxError::ProtocolBreach(Hello::EMPTY_CAPABILITY_NAME),
+                    alloy_rlp::Error::Custom(Hello::CLIENT_VERSION_TOO_LONG) => RLPxError::ProtocolBreach(Hello::CLIENT_VERSION_TOO_LONG),
+                    _ => RLPxError::Decode(" Hello decode error! "),
+                })?;
+                info!(
+                    "Hello message recieved from target node {}: client {:?}, p2p v{}, capabilities {:?}, port {}",
+                    redact::node_id(&hello.id),
+                    hello.client_version,
+                    hello.protocol_version,
+                    hello.capabilities,
+                    hello.port
+                );
+                let mut peer_info = PeerInfo::from(hello);
+                if peer_info.ahead_of_us() {
+                    warn!(
+                        "Peer speaks p2p version {}, we only know {}, carrying on at ours",
+                        peer_info.protocol_version, PROTOCOL_VERSION
+                    );
+                }
+                self.snappy = peer_info.negotiated_protocol_version >= 5;
+                peer_info.shared_capabilities = negotiate_capabilities(&self.config.capabilities, &peer_info.capabilities)
+                    .inspect_err(|err| error!("Capability negotiation went wrong: {}", err))
+                    .map_err(RLPxError::Capability)?;
+                if peer_info.shared_capabilities.is_empty() {
+                    if let Some(hook) = &self.config.on_capability_mismatch {
+                        self.keep_useless_peer = hook.call(&self.config.capabilities, &peer_info.capabilities);
+                    }
+                }
+                self.peer_info = Some(peer_info);
+                Ok(RLPx_Message::Hello)
+            },
+
+            Disconnect::ID => {
+                // A peer that balks at our Hello hangs up before it ever turned
+                // snappy on, so take the Disconnect as it is if it won't inflate.
+                let message = self.decompress(message).unwrap_or(Cow::Borrowed(message));
+                let disconnect = Disconnect::decode(&mut &message[..]).map_err(|_| RLPxError::Decode(" Disconnect decode error! "))?;
+                info!("Peer disconnected us, reason {:#x}", disconnect.reason);
+                self.set_state(RlpxState::Disconnected, "Disconnect received");
+                Ok(RLPx_Message::Disconnect(disconnect.reason))
             },
 
+            // Base protocol keepalives, not to be mistaken for unknown ids below.
+            Ping::ID => {
+                self.count_ping()?;
+                Ok(RLPx_Message::Ping)
+            }
+            Pong::ID => Ok(RLPx_Message::Pong),
+
+            _ if message_id < BASE_PROTOCOL_IDS => {
+                let body = self.decompress(message)?.into_owned();
+                debug!("Peer sent p2p message {:#x} we don't model, {} bytes", message_id, body.len());
+                Ok(RLPx_Message::P2p { id: message_id, body })
+            }
+
             _ => {
-                info!("We probably got back the eth capabily status message, we can't handle it currently, just exit.");
-                process::exit(0);
-                return Err("TODO")
+                let message = self.decode_subprotocol_message(message_id, message)?;
+                if let RLPx_Message::PooledTransactions(response) = &message {
+                    if !self.pending_requests.remove(&response.request_id) {
+                        return Err(RLPxError::Decode(" PooledTransactions answers no request of ours! "));
+                    }
+                }
+                Ok(message)
+            }
+        }
+    }
+
+    // Every Ping costs us a Pong, so a peer flooding them gets disconnected.
+    fn count_ping(&mut self) -> Result<(), RLPxError> {
+        let now = Instant::now();
+        let pings = match &mut self.ping_window {
+            Some((start, pings)) if now.duration_since(*start) < Duration::from_secs(1) => {
+                *pings += 1;
+                *pings
+            }
+            window => {
+                *window = Some((now, 1));
+                1
+            }
+        };
+        if pings > self.config.max_pings_per_second {
+            return Err(RLPxError::ProtocolBreach("Ping flood"));
+        }
+        Ok(())
+    }
+
+    fn decode_subprotocol_message(&self, message_id: u8, message: &[u8]) -> Result<RLPx_Message, RLPxError> {
+        let Some((capability, code)) = self.subprotocol_code(message_id) else {
+            info!("Peer sent message id {:#x}, which no negotiated capability owns", message_id);
+            return Err(RLPxError::ProtocolBreach("Message id outside the negotiated capabilities"));
+        };
+        match (capability.name.as_str(), code) {
+            (Status::CAPABILITY, Status::CODE) => {
+                let message = self.decompress(message)?;
+                let status = Status::decode(&mut &message[..]).map_err(|_| RLPxError::Decode(" Status decode error! "))?;
+                info!("Status message recieved from target node: {:?}", status);
+                Ok(RLPx_Message::Status(status))
+            }
+            (Transactions::CAPABILITY, Transactions::CODE) => {
+                let message = self.decompress(message)?;
+                let hashes = Transactions::decode_hashes(&mut &message[..])
+                    .map_err(|_| RLPxError::Decode(" Transactions decode error! "))?;
+                debug!("Peer sent {} transactions", hashes.len());
+                Ok(RLPx_Message::Transactions(hashes))
+            }
+            (PooledTransactionHashes::CAPABILITY, PooledTransactionHashes::CODE) => {
+                let message = self.decompress(message)?;
+                let announcement = PooledTransactionHashes::decode(&mut &message[..], capability.version)
+                    .map_err(|_| RLPxError::Decode(" NewPooledTransactionHashes decode error! "))?;
+                debug!("Peer announced {} pooled transactions", announcement.hashes.len());
+                Ok(RLPx_Message::NewPooledTransactionHashes(announcement))
+            }
+            (GetPooledTransactions::CAPABILITY, GetPooledTransactions::CODE) => {
+                let message = self.decompress(message)?;
+                let request = GetPooledTransactions::decode(&mut &message[..], capability.version)
+                    .map_err(|_| RLPxError::Decode(" GetPooledTransactions decode error! "))?;
+                debug!("Peer asked for {} pooled transactions", request.hashes.len());
+                Ok(RLPx_Message::GetPooledTransactions(request))
+            }
+            (PooledTransactions::CAPABILITY, PooledTransactions::CODE) => {
+                let message = self.decompress(message)?;
+                let response = PooledTransactions::decode(&mut &message[..], capability.version)
+                    .map_err(|_| RLPxError::Decode(" PooledTransactions decode error! "))?;
+                debug!("Peer sent {} pooled transactions", response.transactions.len());
+                Ok(RLPx_Message::PooledTransactions(response))
+            }
+            (NewBlockHashes::CAPABILITY, NewBlockHashes::CODE) => {
+                let message = self.decompress(message)?;
+                let announced = NewBlockHashes::decode(&mut &message[..])
+                    .map_err(|_| RLPxError::Decode(" NewBlockHashes decode error! "))?;
+                debug!("Peer announced {} blocks", announced.len());
+                Ok(RLPx_Message::NewBlockHashes(announced))
+            }
+            (NewBlock::CAPABILITY, NewBlock::CODE) => {
+                let message = self.decompress(message)?;
+                let block = NewBlock::decode(&mut &message[..]).map_err(|_| RLPxError::Decode(" NewBlock decode error! "))?;
+                debug!("Peer sent block {} with {} transactions", block.number, block.transactions.len());
+                Ok(RLPx_Message::NewBlock(block))
+            }
+            (Les::CAPABILITY, Les::STATUS_CODE) => {
+                let message = self.decompress(message)?;
+                Ok(RLPx_Message::LesStatus(message.into_owned()))
+            }
+            (Les::CAPABILITY, Les::ANNOUNCE_CODE) => {
+                let message = self.decompress(message)?;
+                Ok(RLPx_Message::LesAnnounce(message.into_owned()))
+            }
+            (Wit::CAPABILITY, code) => {
+                let message = self.decompress(message)?;
+                debug!("Peer sent wit message {:#x}, {} bytes", code, message.len());
+                Ok(RLPx_Message::Unknown {
+                    capability: capability.name.clone(),
+                    code,
+                    body: message.into_owned(),
+                })
+            }
+            // Everything else a negotiated capability may send, les requests,
+            // snap, eth requests we don't answer, goes up as it is rather than
+            // ending the session.
+            _ => {
+                let message = self.decompress(message)?;
+                debug!(
+                    "Peer sent {}/{} message {:#x} we don't model, {} bytes",
+                    capability.name, capability.version, code, message.len()
+                );
+                Ok(RLPx_Message::Unknown {
+                    capability: capability.name.clone(),
+                    code,
+                    body: message.into_owned(),
+                })
             }
         }
     }
 }
 
+// A message body we only pass along, already RLP encoded.
+struct RawBody<'a>(&'a [u8]);
+
+impl Encodable for RawBody<'_> {
+    fn encode(&self, out: &mut dyn BufMut) {
+        out.put_slice(self.0);
+    }
+
+    fn length(&self) -> usize {
+        self.0.len()
+    }
+}
+
+#[cfg(feature = "snappy")]
+fn snappy_compress(data: &[u8]) -> Result<Vec<u8>, RLPxError> {
+    snap::raw::Encoder::new().compress_vec(data).map_err(|err| {
+        error!("Snappy compression failed: {:?} ", err);
+        RLPxError::Compression("Compression failed")
+    })
+}
+
+#[cfg(feature = "snappy")]
+fn snappy_decompress(data: &[u8]) -> Result<Vec<u8>, RLPxError> {
+    snap::raw::Decoder::new().decompress_vec(data).map_err(|err| {
+        debug!("Snappy decompression failed: {:?} ", err);
+        RLPxError::DecompressionFailed
+    })
+}
+
+// Never turned on without the feature, we don't advertise v5 then.
+#[cfg(not(feature = "snappy"))]
+fn snappy_compress(_data: &[u8]) -> Result<Vec<u8>, RLPxError> {
+    Err(RLPxError::Compression("Built without snappy"))
+}
+
+#[cfg(not(feature = "snappy"))]
+fn snappy_decompress(_data: &[u8]) -> Result<Vec<u8>, RLPxError> {
+    Err(RLPxError::DecompressionFailed)
+}
+
+// keccak256.digest(mac)[:16], without disturbing the running state.
+pub fn mac_digest(mac: &KeccakMac) -> [u8; 16] {
+    let mut digest = [0; 16];
+    digest.copy_from_slice(&mac.clone().finalize()[..16]);
+    digest
+}
+
+// Dumps everything needed to figure out on which side the MAC chain went off.
+#[cfg(feature = "mac-debug")]
+fn log_mac_mismatch(what: &str, computed: &[u8], received: &[u8], secrets: &HandshakeSecrets) {
+    error!(
+        "{} MAC mismatch! computed: {} received: {} ingress-mac digest: {} egress-mac digest: {}",
+        what,
+        hex::encode(computed),
+        hex::encode(received),
+        hex::encode(secrets.ingress_mac.clone().finalize()),
+        hex::encode(secrets.egress_mac.clone().finalize()),
+    );
+}
+
 impl Encoder<RLPx_Message> for RLPx {
-    type Error = std::io::Error;
+    type Error = RLPxError;
 
     fn encode(&mut self, item: RLPx_Message, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        // It would land in the middle of the streamed frame's MAC chain.
+        if self.egress_stream.is_some() {
+            return Err(RLPxError::Frame("A streamed frame is still going out"));
+        }
+        let buffered = dst.len();
+        let result = self.encode_message(item, dst);
+        self.byte_stats.written += dst.len().saturating_sub(buffered) as u64;
+        result
+    }
+}
+
+impl RLPx {
+    fn encode_message(&mut self, item: RLPx_Message, dst: &mut BytesMut) -> Result<(), RLPxError> {
         debug!("Encoding message {:?}!", item);
         match item {
             RLPx_Message::Auth => {
                 dst.clear();
 
                 dst.extend_from_slice(self.ecies.get_auth_request());
+                self.log_frame(FrameDirection::Outbound, FrameKind::Auth, dst);
 
-                self.rlpx_state = RlpxState::AuthSent;
+                self.set_state(RlpxState::AuthSent, "Auth sent");
             }
             RLPx_Message::AuthAck => {
-                // Implement AuthAck encoding here
-                todo!()
+                dst.extend_from_slice(self.ecies.get_auth_ack().map_err(RLPxError::Ecies)?);
+                self.log_frame(FrameDirection::Outbound, FrameKind::AuthAck, dst);
+
+                // The secrets cover the ack too, so they only exist once it's written.
+                self.secrets = Some(self.ecies.get_secrets());
+                self.ecies.release_handshake_messages();
+                self.frame_state = FrameState::DecodingHeader;
+                self.set_state(RlpxState::AuthAckSent, "Ack sent");
             }
             RLPx_Message::Hello => {
-                dst.extend_from_slice(&self.hello_msg());
+                dst.extend_from_slice(&self.hello_msg()?);
+                self.set_state(RlpxState::HelloSent, "Hello sent");
             }
             RLPx_Message::Disconnect(reason) => {
-                todo!()
+                // Any state with secrets can frame it, Hello phase included.
+                // Before the ack there's no way to reach the peer but hanging up.
+                if self.secrets.is_none() {
+                    return Err(RLPxError::InvalidState(self.rlpx_state));
+                }
+                self.write_message(Disconnect::ID, &Disconnect { reason }, dst)?;
+                self.set_state(RlpxState::Disconnected, "Disconnect sent");
             }
             RLPx_Message::Ping => {
-                todo!()
+                self.write_message(Ping::ID, &Ping {}, dst)?;
             }
             RLPx_Message::Pong => {
-                todo!()
+                self.write_message(Pong::ID, &Pong {}, dst)?;
+            }
+            RLPx_Message::Status(status) => {
+                let message_id = self.subprotocol_message_id(Status::CAPABILITY, Status::CODE)?;
+                self.write_message(message_id, &status, dst)?;
+                if self.rlpx_state == RlpxState::Active {
+                    let state = if self.peer_status.is_some() {
+                        RlpxState::EthStatusExchanged
+                    } else {
+                        RlpxState::StatusSent
+                    };
+                    self.set_state(state, "Status sent");
+                }
+            }
+            RLPx_Message::GetPooledTransactions(request) => {
+                let message_id = self.subprotocol_message_id(GetPooledTransactions::CAPABILITY, GetPooledTransactions::CODE)?;
+                if self.negotiated_version(GetPooledTransactions::CAPABILITY) < Some(REQUEST_ID_ETH_VERSION) {
+                    self.write_message(message_id, &request.hashes, dst)?;
+                } else {
+                    self.write_message(message_id, &request, dst)?;
+                    self.pending_requests.insert(request.request_id);
+                }
+            }
+            // Only the hashes are kept on the way in, and we hold no pool to
+            // answer from, so there's nothing to send.
+            RLPx_Message::Transactions(_)
+            | RLPx_Message::NewPooledTransactionHashes(_)
+            | RLPx_Message::PooledTransactions(_)
+            | RLPx_Message::NewBlockHashes(_)
+            | RLPx_Message::NewBlock(_) => {
+                return Err(RLPxError::UnexpectedMessage);
+            }
+            RLPx_Message::LesStatus(body) => {
+                let message_id = self.subprotocol_message_id(Les::CAPABILITY, Les::STATUS_CODE)?;
+                self.write_message(message_id, &RawBody(&body), dst)?;
+            }
+            RLPx_Message::LesAnnounce(body) => {
+                let message_id = self.subprotocol_message_id(Les::CAPABILITY, Les::ANNOUNCE_CODE)?;
+                self.write_message(message_id, &RawBody(&body), dst)?;
+            }
+            RLPx_Message::Unknown { capability, code, body } => {
+                let message_id = self.subprotocol_message_id(&capability, code)?;
+                self.write_message(message_id, &RawBody(&body), dst)?;
             }
-            RLPx_Message::Status(msg) => {
-                todo!()
+            // Goes out as given, even under an id we do model. Only the range
+            // is checked, a subprotocol id has to go through its capability.
+            RLPx_Message::P2p { id, body } => {
+                if id >= BASE_PROTOCOL_IDS {
+                    return Err(RLPxError::Capability("Not a p2p message id"));
+                }
+                self.write_message(id, &RawBody(&body), dst)?;
             }
         }
         Ok(())
@@ -392,9 +1685,32 @@ impl Encoder<RLPx_Message> for RLPx {
 
 impl Decoder for RLPx {
     type Item = RLPx_Message;
-    type Error = std::io::Error;
+    type Error = RLPxError;
 
     fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        let buffered = src.len();
+        self.last_decode_wait = None;
+        let result = self.decode_message(src);
+        self.byte_stats.read += buffered.saturating_sub(src.len()) as u64;
+        result
+    }
+
+    fn decode_eof(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        if let Some(message) = self.decode(src)? {
+            return Ok(Some(message));
+        }
+        // Anything still buffered, or a header whose frame never showed up, means
+        // the peer hung up halfway through sending us something.
+        if !src.is_empty() || self.frame_state != FrameState::DecodingHeader {
+            debug!("Connection closed with {} undecoded bytes", src.len());
+            return Err(RLPxError::Truncated);
+        }
+        Ok(None)
+    }
+}
+
+impl RLPx {
+    fn decode_message(&mut self, src: &mut BytesMut) -> Result<Option<RLPx_Message>, RLPxError> {
         // See example here:
         // https://docs.rs/tokio-util/latest/tokio_util/codec/index.html
         //   It seems we need to validate full frame and clear only the frame
@@ -403,53 +1719,690 @@ impl Decoder for RLPx {
         //   We proooobably need to process header before frame data in order to
         // insure frame integrity
         if src.is_empty() {
+            self.wait_for(DecodeWait::EmptyBuffer);
             return Ok(None);
         }
         match self.rlpx_state {
+            // Our auth isn't out yet, but the peer needn't wait for it. Whatever
+            // it sent stays buffered until we're expecting an ack to read it as.
+            RlpxState::ExpectingConnection => {
+                if src.len() > self.config.max_auth_message_size {
+                    return Err(RLPxError::AuthMessageTooLarge(src.len()));
+                }
+                debug!("{} bytes from the peer before our auth went out, keeping them", src.len());
+                self.wait_for(DecodeWait::AheadOfOurAuth { have: src.len() });
+                Ok(None)
+            }
+            RlpxState::ExpectingAuth => {
+                debug!("We're decoding auth... ");
+
+                if !self.decode_handshake_message(src, FrameKind::Auth)? {
+                    return Ok(None);
+                }
+                self.set_state(RlpxState::AuthRecieved, "Auth received");
+
+                Ok(Some(RLPx_Message::Auth))
+            }
             RlpxState::AuthSent => {
                 debug!("We're decoding authAck... ");
 
-                // debug!("We're decoding !! Raw Data is: {:?} ", src);
-                let (_decrypted, frame_size) = self
-                    .ecies
-                    .decrypt(src)
-                    .map_err(|e| {debug!("Frame decrypt Error: {:?}", e); Error::from(ErrorKind::Other)})?;
-
+                if !self.decode_handshake_message(src, FrameKind::AuthAck)? {
+                    return Ok(None);
+                }
                 self.secrets = Some(self.ecies.get_secrets());
-                self.rlpx_state = RlpxState::AuthAckRecieved;
-                // debug!("Raw data after Ack rx buffer is:  {:?} ", src.as_mut());
-                src.advance(frame_size);
-
+                self.ecies.release_handshake_messages();
+                self.set_state(RlpxState::AuthAckRecieved, "Ack received");
                 self.frame_state = FrameState::DecodingHeader;
 
-                return Ok(Some(RLPx_Message::AuthAck));
+                Ok(Some(RLPx_Message::AuthAck))
             }
-            RlpxState::AuthAckRecieved => {
+            RlpxState::AuthAckRecieved | RlpxState::HelloSent => {
                 debug!("We're decoding a Hello frame... ");
 
-                return match self.decode_frame(src) {
+                match self.decode_frame(src) {
                     Ok(Some(RLPx_Message::Hello)) =>  {
-                        self.rlpx_state = RlpxState::Active;
+                        self.set_state(RlpxState::Active, "Hello received");
                         Ok(Some(RLPx_Message::Hello))
                     },
                     Ok(None) => {Ok(None)}
+                    // Peers at capacity say so instead of sending their Hello.
+                    Ok(Some(RLPx_Message::Disconnect(reason))) => Ok(Some(RLPx_Message::Disconnect(reason))),
+                    Err(err) => Err(err),
                     _ => {
                         error!(" Unexpected message! We should have gotten a Hello!! ");
-                        Err(Error::from(ErrorKind::Other))
+                        Err(RLPxError::UnexpectedMessage)
                     },
                 }
             
             }
-            RlpxState::Active => {
+            RlpxState::Active | RlpxState::StatusSent | RlpxState::EthStatusExchanged => {
                 debug!("We're decoding a protocol frame... ");
 
-                return self.decode_frame(src)
-
+                let message = self.decode_frame(src)?;
+                // Peers may well send their Status before ours is out.
+                if let Some(RLPx_Message::Status(status)) = &message {
+                    self.peer_status = Some(status.clone());
+                    if self.rlpx_state == RlpxState::StatusSent {
+                        self.set_state(RlpxState::EthStatusExchanged, "Status received");
+                    }
+                }
+                Ok(message)
             }
             _ => {
                 debug!("Invalid frame!! ");
-                return Err(Error::from(ErrorKind::Other));
+                Err(RLPxError::InvalidState(self.rlpx_state))
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::DEFAULT_MAX_AUTH_MESSAGE_SIZE,
+        messages::BlockHashNumber,
+        testing::{active_codecs, auth_ack, codecs, decode_all, hellos, p2p_frame_data, random_key},
+    };
+    use alloy_primitives::{hex, keccak256, Bytes, B256};
+    use alloy_rlp::Header;
+    use std::{os::unix::net::UnixStream, thread};
+
+    fn capabilities(list: &[&str]) -> Vec<Capability> {
+        list.iter().map(|capability| capability.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn unsorted_capabilities_go_out_sorted_and_both_sides_agree_on_offsets() {
+        let unsorted = RLPxConfig {
+            capabilities: capabilities(&["snap/1", "eth/68", "les/4", "eth/66", "eth/68"]),
+            ..RLPxConfig::default()
+        };
+        let sorted = RLPxConfig {
+            capabilities: capabilities(&["eth/66", "eth/68", "snap/1"]),
+            ..RLPxConfig::default()
+        };
+        let (mut initiator, mut responder) = codecs(unsorted, sorted);
+        auth_ack(&mut initiator, &mut responder);
+        hellos(&mut initiator, &mut responder);
+
+        // The capability list as it came over the wire, duplicates gone.
+        let received = &responder.peer_info().unwrap().capabilities;
+        assert_eq!(*received, capabilities(&["eth/66", "eth/68", "les/4", "snap/1"]));
+
+        let expected = vec![
+            SharedCapability { capability: capabilities(&["eth/68"]).remove(0), offset: 0x10 },
+            SharedCapability { capability: capabilities(&["snap/1"]).remove(0), offset: 0x21 },
+        ];
+        assert_eq!(initiator.peer_info().unwrap().shared_capabilities, expected);
+        assert_eq!(responder.peer_info().unwrap().shared_capabilities, expected);
+    }
+
+    #[test]
+    fn streamed_frame_matches_the_buffered_one() {
+        let (mut buffered, mut receiver) = active_codecs();
+        let mut streamed = buffered.clone();
+        let payload = p2p_frame_data(0x0f, &[0x5a; 1000]);
+
+        let mut expected = BytesMut::new();
+        buffered.write_raw_frame(&payload, &mut expected).unwrap();
+
+        let mut wire = BytesMut::new();
+        wire.extend_from_slice(&streamed.begin_frame_stream(payload.len()).unwrap());
+        for piece in payload.chunks(333) {
+            let mut piece = piece.to_vec();
+            streamed.encrypt_frame_stream(&mut piece).unwrap();
+            wire.extend_from_slice(&piece);
+        }
+        wire.extend_from_slice(&streamed.finish_frame_stream().unwrap());
+        assert_eq!(wire, expected);
+        assert_eq!(streamed.byte_stats().written, buffered.byte_stats().written);
+
+        let messages = decode_all(&mut receiver, &mut wire);
+        assert!(matches!(&messages[..], [RLPx_Message::P2p { id: 0x0f, body }] if body[..] == [0x5a; 1000]));
+    }
+
+    #[test]
+    fn streamed_frame_refuses_a_payload_other_than_announced() {
+        let (mut codec, _) = active_codecs();
+        codec.begin_frame_stream(4).unwrap();
+        assert!(matches!(codec.encrypt_frame_stream(&mut [0; 5]), Err(RLPxError::Frame(_))));
+        codec.encrypt_frame_stream(&mut [0; 3]).unwrap();
+        assert!(matches!(codec.finish_frame_stream(), Err(RLPxError::Frame(_))));
+        // Nothing else gets framed into the middle of it.
+        assert!(matches!(codec.encode(RLPx_Message::Ping, &mut BytesMut::new()), Err(RLPxError::Frame(_))));
+    }
+
+    // Flips a bit of a Ping frame at the offset picked from its length.
+    fn flip_mac_and_decode(at: impl Fn(usize) -> usize) {
+        let (mut sender, mut receiver) = active_codecs();
+        let mut wire = BytesMut::new();
+        sender.encode(RLPx_Message::Ping, &mut wire).unwrap();
+        let at = at(wire.len());
+        wire[at] ^= 0x01;
+
+        assert!(matches!(receiver.decode(&mut wire), Err(RLPxError::FrameMacMismatch)));
+        assert_eq!(receiver.get_state(), RlpxState::Disconnected);
+        assert!(receiver.secrets().is_none());
+
+        // The next frame is perfectly good, but there's no telling any more.
+        let mut wire = BytesMut::new();
+        sender.encode(RLPx_Message::Ping, &mut wire).unwrap();
+        assert!(matches!(receiver.decode(&mut wire), Err(RLPxError::InvalidState(RlpxState::Disconnected))));
+    }
+
+    #[test]
+    fn header_mac_mismatch_resets_the_codec() {
+        flip_mac_and_decode(|_| FRAME_HEADER_CIPHERTEXT_SIZE);
+    }
+
+    #[test]
+    fn frame_mac_mismatch_resets_the_codec() {
+        flip_mac_and_decode(|frame_len| frame_len - 1);
+    }
+
+    #[test]
+    fn blocking_handshake_over_a_socket_pair() {
+        let (mut initiator, mut responder) = codecs(RLPxConfig::default(), RLPxConfig::default());
+        let (mut ours, mut theirs) = UnixStream::pair().unwrap();
+        // The peer's side, driven by hand on a thread of its own. It sends its
+        // Hello right behind the ack, before reading ours.
+        let peer = thread::spawn(move || {
+            let mut read_buf = BytesMut::new();
+            let auth = responder.read_message_blocking(&mut theirs, &mut read_buf).unwrap();
+            assert!(matches!(auth, RLPx_Message::Auth));
+            let mut write_buf = BytesMut::new();
+            responder.encode(RLPx_Message::AuthAck, &mut write_buf).unwrap();
+            responder.encode(RLPx_Message::Hello, &mut write_buf).unwrap();
+            theirs.write_all(&write_buf).unwrap();
+            let hello = responder.read_message_blocking(&mut theirs, &mut read_buf).unwrap();
+            assert!(matches!(hello, RLPx_Message::Hello));
+            (responder, theirs)
+        });
+
+        let peer_info = initiator.handshake_blocking(&mut ours).unwrap();
+        let (mut responder, mut theirs) = peer.join().unwrap();
+        let responder_id = initiator.peer_public_key().unwrap().serialize_uncompressed();
+        assert_eq!(peer_info.id[..], responder_id[1..]);
+        assert_eq!(initiator.get_state(), RlpxState::Active);
+        assert_eq!(responder.get_state(), RlpxState::Active);
+
+        // Framing carries on from where the blocking handshake left it.
+        let mut wire = BytesMut::new();
+        initiator.encode(RLPx_Message::Ping, &mut wire).unwrap();
+        ours.write_all(&wire).unwrap();
+        let ping = responder.read_message_blocking(&mut theirs, &mut BytesMut::new()).unwrap();
+        assert!(matches!(ping, RLPx_Message::Ping));
+    }
+
+    #[test]
+    fn hello_pipelined_behind_the_ack_is_decoded_next() {
+        let (mut initiator, mut responder) = codecs(RLPxConfig::default(), RLPxConfig::default());
+        let mut wire = BytesMut::new();
+        initiator.encode(RLPx_Message::Auth, &mut wire).unwrap();
+        assert!(matches!(decode_all(&mut responder, &mut wire)[..], [RLPx_Message::Auth]));
+
+        // Ack and Hello in one buffer, as a peer that doesn't wait for ours sends them.
+        responder.encode(RLPx_Message::AuthAck, &mut wire).unwrap();
+        responder.encode(RLPx_Message::Hello, &mut wire).unwrap();
+        assert!(matches!(initiator.decode(&mut wire), Ok(Some(RLPx_Message::AuthAck))));
+        assert!(!wire.is_empty());
+        initiator.encode(RLPx_Message::Hello, &mut BytesMut::new()).unwrap();
+        assert!(matches!(initiator.decode(&mut wire), Ok(Some(RLPx_Message::Hello))));
+        assert!(wire.is_empty());
+        assert_eq!(initiator.get_state(), RlpxState::Active);
+    }
+
+    #[test]
+    fn frame_cut_at_the_header_and_mac_boundaries_waits_for_more() {
+        let (mut sender, mut receiver) = active_codecs();
+        let mut wire = BytesMut::new();
+        sender.encode(RLPx_Message::Ping, &mut wire).unwrap();
+        let body_size = wire.len() - FRAME_HEADER_SIZE - FRAME_MAC_SIZE;
+
+        // One short of a header, a header exactly, and one short of the frame.
+        let mut src = BytesMut::from(&wire[..FRAME_HEADER_SIZE - 1]);
+        assert!(receiver.decode(&mut src).unwrap().is_none());
+        assert_eq!(
+            receiver.last_decode_reason(),
+            Some(DecodeWait::NeedMoreForHeader { have: FRAME_HEADER_SIZE - 1 })
+        );
+        src.extend_from_slice(&wire[FRAME_HEADER_SIZE - 1..FRAME_HEADER_SIZE]);
+        assert!(receiver.decode(&mut src).unwrap().is_none());
+        assert_eq!(
+            receiver.last_decode_reason(),
+            Some(DecodeWait::NeedMoreForFrame { have: 0, need: body_size + FRAME_MAC_SIZE })
+        );
+        src.extend_from_slice(&wire[FRAME_HEADER_SIZE..wire.len() - 1]);
+        assert!(receiver.decode(&mut src).unwrap().is_none());
+        src.extend_from_slice(&wire[wire.len() - 1..]);
+        assert!(matches!(receiver.decode(&mut src), Ok(Some(RLPx_Message::Ping))));
+        assert!(src.is_empty());
+    }
+
+    #[test]
+    fn oversized_auth_and_ack_prefixes_are_refused_before_waiting_on_them() {
+        let (mut initiator, mut responder) = codecs(RLPxConfig::default(), RLPxConfig::default());
+        let limit = RLPxConfig::default().max_auth_message_size;
+        let prefix = |size: usize| BytesMut::from(&u16::try_from(size - 2).unwrap().to_be_bytes()[..]);
+
+        // Prefix and all, one byte over the limit is refused with just the prefix in.
+        assert!(matches!(
+            responder.decode(&mut prefix(limit + 1)),
+            Err(RLPxError::AuthMessageTooLarge(size)) if size == limit + 1
+        ));
+        let (_, mut responder) = codecs(RLPxConfig::default(), RLPxConfig::default());
+        assert!(responder.decode(&mut prefix(limit)).unwrap().is_none());
+
+        initiator.encode(RLPx_Message::Auth, &mut BytesMut::new()).unwrap();
+        assert!(matches!(
+            initiator.decode(&mut prefix(0xffff + 2)),
+            Err(RLPxError::AuthMessageTooLarge(0x10001))
+        ));
+    }
+
+    #[test]
+    fn zero_frame_size_is_refused_at_the_header() {
+        let (mut sender, mut receiver) = active_codecs();
+        // A header declaring no frame-data, properly MACed, with just the
+        // frame-mac behind it.
+        let mut wire = BytesMut::new();
+        sender.write_frame_into(HeaderData::default(), &[], &mut wire);
+        assert_eq!(wire.len(), FRAME_HEADER_SIZE + FRAME_MAC_SIZE);
+        assert!(matches!(receiver.decode(&mut wire), Err(RLPxError::EmptyFrame)));
+
+        // Same for a frame-mac with no frame-ciphertext in front of it.
+        let (_, mut receiver) = active_codecs();
+        assert!(matches!(receiver.decode_frame_ciphertext(&mut [0; FRAME_MAC_SIZE]), Err(RLPxError::EmptyFrame)));
+    }
+
+    #[test]
+    fn disconnect_frames_decode_in_either_reason_encoding() {
+        // A Disconnect ends the session, so each one gets a pair of its own.
+        for body in [&[0xc1, 0x04][..], &[0x04]] {
+            let (mut sender, mut receiver) = active_codecs();
+            let mut wire = BytesMut::new();
+            sender.write_raw_frame(&p2p_frame_data(Disconnect::ID, body), &mut wire).unwrap();
+            assert!(matches!(
+                receiver.decode(&mut wire),
+                Ok(Some(RLPx_Message::Disconnect(Disconnect::TOO_MANY_PEERS)))
+            ));
+        }
+    }
+
+    // EIP-155's example, a legacy transfer signed for chain id 1.
+    const LEGACY_TX: [u8; 110] = hex!(
+        "f86c098504a817c800825208943535353535353535353535353535353535353535880de0b6b3a76400008025a028ef61340bd939bc2195"
+        "fe537567866003e1a15d3c71ff63e1590620aa636276a067cbe9d8997f761aecb703304b3800ccf555c9f3dc64214b297fb1966a3b6d83"
+    );
+    const LEGACY_TX_HASH: B256 = B256::new(hex!("33469b22e9f636356c4160a87eb19df52b7412e8eac32a4a55ffe88ea8350788"));
+    // An EIP-1559 transfer mined on mainnet, type || payload.
+    const DYNAMIC_FEE_TX: [u8; 117] = hex!(
+        "02f872018307910d808507204d2cb1827d0094388c818ca8b9251b393131c08a736a67ccb19297880320d04823e2701c80c001a0cf024f"
+        "4815304df2867a1a74e9d2707b6abda0337d2d54a4438d453f4160f190a07ac0e6b3bc9395b5b9c8b9e6d77204a236577a5b18467b9175"
+        "c01de4faa208d9"
+    );
+    const DYNAMIC_FEE_TX_HASH: B256 = B256::new(hex!("86718885c4b4218c6af87d3d0b0d83e3cc465df2a05c048aa4db9f1a6f9de91f"));
+
+    #[test]
+    fn transaction_announcements_come_out_as_hashes() {
+        let (mut sender, mut receiver) = active_codecs();
+        let eth = |code: u8| BASE_PROTOCOL_IDS + code;
+        let list = |items: &[&[u8]]| {
+            let mut list = Vec::new();
+            Header { list: true, payload_length: items.iter().map(|item| item.len()).sum() }.encode(&mut list);
+            items.iter().for_each(|item| list.extend_from_slice(item));
+            list
+        };
+
+        // A legacy transaction goes in as its RLP list, a typed one as a
+        // string wrapping type || payload.
+        let transactions = list(&[&LEGACY_TX, &alloy_rlp::encode(&DYNAMIC_FEE_TX[..])]);
+        let mut wire = BytesMut::new();
+        sender.write_raw_frame(&p2p_frame_data(eth(Transactions::CODE), &transactions), &mut wire).unwrap();
+        assert!(matches!(
+            &decode_all(&mut receiver, &mut wire)[..],
+            [RLPx_Message::Transactions(hashes)] if *hashes == [LEGACY_TX_HASH, DYNAMIC_FEE_TX_HASH]
+        ));
+
+        // eth/68 announces the same two with their types and encoded sizes.
+        let sizes = vec![LEGACY_TX.len() as u64, DYNAMIC_FEE_TX.len() as u64];
+        let announcement = list(&[
+            &alloy_rlp::encode(Bytes::from(vec![0x00, 0x02])),
+            &alloy_rlp::encode(&sizes),
+            &alloy_rlp::encode(vec![LEGACY_TX_HASH, DYNAMIC_FEE_TX_HASH]),
+        ]);
+        let mut wire = BytesMut::new();
+        sender.write_raw_frame(&p2p_frame_data(eth(PooledTransactionHashes::CODE), &announcement), &mut wire).unwrap();
+        assert!(matches!(
+            &decode_all(&mut receiver, &mut wire)[..],
+            [RLPx_Message::NewPooledTransactionHashes(announced)]
+                if announced.hashes == [LEGACY_TX_HASH, DYNAMIC_FEE_TX_HASH]
+                    && announced.types == [0x00, 0x02]
+                    && announced.sizes == [110, 117]
+        ));
+    }
+
+    #[test]
+    fn keystream_stays_in_step_through_partial_reads_and_bad_frames() {
+        let (mut sender, mut receiver) = active_codecs();
+        let mut wire = BytesMut::new();
+        sender.encode(RLPx_Message::Ping, &mut wire).unwrap();
+        // Decrypts fine but isn't a Disconnect, the list header runs past the body.
+        sender.write_raw_frame(&p2p_frame_data(0x01, &[0xc5]), &mut wire).unwrap();
+        sender.encode(RLPx_Message::Pong, &mut wire).unwrap();
+
+        // A byte at a time, so the header and the frame each get asked for
+        // over and over before they're all there.
+        let mut received = BytesMut::new();
+        let mut messages = Vec::new();
+        let mut errors = 0;
+        for byte in wire.iter() {
+            received.extend_from_slice(&[*byte]);
+            loop {
+                match receiver.decode(&mut received) {
+                    Ok(Some(message)) => messages.push(message),
+                    Ok(None) => break,
+                    Err(RLPxError::Decode(_)) => errors += 1,
+                    Err(err) => panic!("keystream lost step: {:?}", err),
+                }
+            }
+        }
+        assert_eq!(errors, 1);
+        assert!(matches!(messages[..], [RLPx_Message::Ping, RLPx_Message::Pong]));
+        assert!(received.is_empty());
+        let position: u64 = receiver.secrets().unwrap().aes_keystream_ingress.current_pos();
+        assert_eq!(position, receiver.ingress_ciphertext_consumed);
+    }
+
+    #[test]
+    fn small_frame_header_carries_size_then_header_data() {
+        let (mut sender, mut receiver) = active_codecs();
+        let mut wire = BytesMut::new();
+        sender.encode(RLPx_Message::Ping, &mut wire).unwrap();
+
+        let mut header: [u8; FRAME_HEADER_SIZE] = wire[..FRAME_HEADER_SIZE].try_into().unwrap();
+        let (frame_size, header_data) = receiver.decode_frame_header(&mut header).unwrap();
+        assert_eq!(frame_size, p2p_frame_data(0x02, &[0xc0]).len());
+        assert_eq!(header_data, HeaderData::default());
+        // frame-size || rlp([0, 0]) || zero padding, decrypted in place.
+        assert_eq!(header[..3], [0, 0, frame_size as u8]);
+        assert_eq!(header[3..6], [0xc2, 0x80, 0x80]);
+        assert!(header[6..FRAME_HEADER_CIPHERTEXT_SIZE].iter().all(|byte| *byte == 0));
+    }
+
+    #[test]
+    fn frames_past_sixteen_bits_keep_their_top_size_byte() {
+        let (mut sender, mut receiver) = active_codecs();
+        // Hashes so snappy, where it's on, can't bring it back under 64 KiB.
+        let body: Vec<u8> = (0..2200u32).flat_map(|i| keccak256(i.to_be_bytes()).0).collect();
+        let payload = p2p_frame_data(0x0f, &body);
+        assert!(payload.len() > 0xffff);
+
+        let mut wire = BytesMut::new();
+        sender.write_raw_frame(&payload, &mut wire).unwrap();
+        let messages = decode_all(&mut receiver, &mut wire);
+        assert!(matches!(&messages[..], [RLPx_Message::P2p { id: 0x0f, body: received }] if received[..] == body[..]));
+    }
+
+    #[cfg(feature = "snappy")]
+    #[test]
+    fn garbage_snappy_body_fails_decompression_and_drops_the_peer() {
+        let (mut sender, mut receiver) = active_codecs();
+        let mut wire = BytesMut::new();
+        // A p2p message whose body claims a length snappy's varint can't hold.
+        sender.write_raw_frame(&[0x0f, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff], &mut wire).unwrap();
+        assert!(matches!(receiver.decode(&mut wire), Err(RLPxError::DecompressionFailed)));
+        assert_eq!(receiver.get_state(), RlpxState::Disconnected);
+
+        let mut wire = BytesMut::new();
+        sender.encode(RLPx_Message::Ping, &mut wire).unwrap();
+        assert!(matches!(receiver.decode(&mut wire), Err(RLPxError::InvalidState(RlpxState::Disconnected))));
+    }
+
+    #[test]
+    fn hello_with_thousands_of_capabilities_is_a_protocol_breach() {
+        // Made up ones, our own capability parsing wouldn't take them.
+        let flooding = RLPxConfig {
+            capabilities: (0..3000).map(|n| Capability { name: format!("c{}", n), version: 1 }).collect(),
+            ..RLPxConfig::default()
+        };
+        let (mut initiator, mut responder) = codecs(flooding, RLPxConfig::default());
+        auth_ack(&mut initiator, &mut responder);
+        // Ours goes out first, as it does in a session.
+        responder.encode(RLPx_Message::Hello, &mut BytesMut::new()).unwrap();
+        let mut wire = BytesMut::new();
+        initiator.encode(RLPx_Message::Hello, &mut wire).unwrap();
+        assert!(matches!(
+            responder.decode(&mut wire),
+            Err(RLPxError::ProtocolBreach(Hello::TOO_MANY_CAPABILITIES))
+        ));
+    }
+
+    #[test]
+    fn disconnect_goes_out_in_the_hello_phase_but_not_before_the_ack() {
+        let eth = RLPxConfig { capabilities: capabilities(&["eth/68"]), ..RLPxConfig::default() };
+        let les = RLPxConfig { capabilities: capabilities(&["les/4"]), ..RLPxConfig::default() };
+        let (mut initiator, mut responder) = codecs(eth, les);
+        // No secrets yet, nothing to frame it with.
+        assert!(matches!(
+            initiator.encode(RLPx_Message::Disconnect(Disconnect::USELESS_PEER), &mut BytesMut::new()),
+            Err(RLPxError::InvalidState(_))
+        ));
+        auth_ack(&mut initiator, &mut responder);
+
+        let mut to_initiator = BytesMut::new();
+        let mut to_responder = BytesMut::new();
+        responder.encode(RLPx_Message::Hello, &mut to_initiator).unwrap();
+        initiator.encode(RLPx_Message::Hello, &mut to_responder).unwrap();
+        assert!(matches!(decode_all(&mut responder, &mut to_responder)[..], [RLPx_Message::Hello]));
+        // Nothing in common with this one, so it's turned away before Active.
+        assert!(responder.peer_info().unwrap().shared_capabilities.is_empty());
+        responder.encode(RLPx_Message::Disconnect(Disconnect::USELESS_PEER), &mut to_initiator).unwrap();
+        assert_eq!(responder.get_state(), RlpxState::Disconnected);
+
+        // Still waiting on the Hello, and the Disconnect right behind it decodes too.
+        assert_eq!(initiator.get_state(), RlpxState::HelloSent);
+        assert!(matches!(
+            decode_all(&mut initiator, &mut to_initiator)[..],
+            [RLPx_Message::Hello, RLPx_Message::Disconnect(Disconnect::USELESS_PEER)]
+        ));
+    }
+
+    #[test]
+    fn peer_on_p2p_version_99_is_met_at_ours() {
+        let (mut initiator, mut responder) = codecs(RLPxConfig::default(), RLPxConfig::default());
+        auth_ack(&mut initiator, &mut responder);
+        let mut to_initiator = BytesMut::new();
+        responder.encode(RLPx_Message::Hello, &mut to_initiator).unwrap();
+
+        // The initiator's Hello, claiming a version from the far future.
+        let hello = Hello {
+            protocol_version: 99,
+            client_version: "future/v99".to_string(),
+            capabilities: capabilities(&["eth/68"]),
+            port: 0,
+            id: [7; 64],
+        };
+        let mut frame_data = vec![0x80];
+        frame_data.extend_from_slice(&alloy_rlp::encode(&hello));
+        let mut to_responder = BytesMut::new();
+        initiator.write_raw_frame(&frame_data, &mut to_responder).unwrap();
+        assert!(matches!(decode_all(&mut responder, &mut to_responder)[..], [RLPx_Message::Hello]));
+
+        let peer_info = responder.peer_info().unwrap();
+        assert_eq!(peer_info.protocol_version, 99);
+        assert_eq!(peer_info.negotiated_protocol_version, PROTOCOL_VERSION);
+        assert!(peer_info.ahead_of_us());
+        assert_eq!(responder.get_state(), RlpxState::Active);
+
+        // And the session carries on at our version.
+        assert!(matches!(decode_all(&mut initiator, &mut to_initiator)[..], [RLPx_Message::Hello]));
+        assert!(!initiator.peer_info().unwrap().ahead_of_us());
+        let mut wire = BytesMut::new();
+        responder.encode(RLPx_Message::Ping, &mut wire).unwrap();
+        assert!(matches!(decode_all(&mut initiator, &mut wire)[..], [RLPx_Message::Ping]));
+    }
+
+    #[test]
+    fn frame_of_just_a_mac_is_refused_without_touching_the_ingress_mac() {
+        let (mut sender, mut receiver) = active_codecs();
+        let ingress_mac = mac_digest(&receiver.secrets().unwrap().ingress_mac);
+
+        assert!(matches!(receiver.decode_frame_ciphertext(&mut [0; FRAME_MAC_SIZE]), Err(RLPxError::EmptyFrame)));
+        assert!(matches!(receiver.decode_frame_ciphertext(&mut [0; FRAME_MAC_SIZE - 1]), Err(RLPxError::Frame(_))));
+        assert!(matches!(receiver.decode_frame_ciphertext(&mut []), Err(RLPxError::Frame(_))));
+        assert_eq!(mac_digest(&receiver.secrets().unwrap().ingress_mac), ingress_mac);
+
+        // So the next real frame checks out as usual.
+        let mut wire = BytesMut::new();
+        sender.encode(RLPx_Message::Ping, &mut wire).unwrap();
+        assert!(matches!(decode_all(&mut receiver, &mut wire)[..], [RLPx_Message::Ping]));
+    }
+
+    #[test]
+    fn peer_auth_arriving_around_ours_is_kept_and_named_for_what_it_is() {
+        // Both dial each other at once.
+        let (our_key, their_key) = (random_key(), random_key());
+        let mut ours = RLPx::new(our_key, PublicKey::from_secret_key(SECP256K1, &their_key), RLPxConfig::default());
+        let mut theirs = RLPx::new(their_key, PublicKey::from_secret_key(SECP256K1, &our_key), RLPxConfig::default());
+        let mut from_them = BytesMut::new();
+        theirs.encode(RLPx_Message::Auth, &mut from_them).unwrap();
+
+        // Half of theirs is in before ours has gone out, and waits.
+        let mut read_buf = from_them.split_to(from_them.len() / 2);
+        assert!(matches!(ours.decode(&mut read_buf), Ok(None)));
+        assert_eq!(ours.get_state(), RlpxState::ExpectingConnection);
+        ours.encode(RLPx_Message::Auth, &mut BytesMut::new()).unwrap();
+        assert!(matches!(ours.decode(&mut read_buf), Ok(None)));
+
+        // The rest comes in once we're expecting an ack, and it's not one.
+        read_buf.extend_from_slice(&from_them);
+        assert!(matches!(
+            ours.decode(&mut read_buf),
+            Err(RLPxError::Ecies("Got an auth where the ack should be, the peer dialed us as well!"))
+        ));
+    }
+
+    #[test]
+    fn early_bytes_past_the_auth_size_limit_are_refused() {
+        let (mut initiator, _) = codecs(RLPxConfig::default(), RLPxConfig::default());
+        let mut read_buf = BytesMut::from(&[0_u8; DEFAULT_MAX_AUTH_MESSAGE_SIZE + 1][..]);
+        assert!(matches!(initiator.decode(&mut read_buf), Err(RLPxError::AuthMessageTooLarge(_))));
+    }
+
+    #[test]
+    fn block_announcements_decode_at_the_eth_offset() {
+        let (mut sender, mut receiver) = active_codecs();
+        let announced = vec![BlockHashNumber { hash: B256::repeat_byte(1), number: 19_000_000 }];
+        let mut wire = BytesMut::new();
+        sender.write_raw_frame(&p2p_frame_data(0x10 + NewBlockHashes::CODE, &alloy_rlp::encode(&announced)), &mut wire).unwrap();
+        assert!(matches!(&decode_all(&mut receiver, &mut wire)[..], [RLPx_Message::NewBlockHashes(received)] if *received == announced));
+    }
+
+    #[test]
+    fn hello_advertises_v5_only_when_built_with_snappy() {
+        let (initiator, responder) = active_codecs();
+        let expected = if cfg!(feature = "snappy") { 5 } else { 4 };
+        assert_eq!(responder.peer_info().unwrap().protocol_version, expected);
+        assert_eq!(initiator.snappy, cfg!(feature = "snappy"));
+        assert_eq!(responder.snappy, cfg!(feature = "snappy"));
+    }
+
+    #[cfg(not(feature = "snappy"))]
+    #[test]
+    fn v5_peer_is_met_at_v4_and_read_uncompressed() {
+        let (mut initiator, mut responder) = codecs(RLPxConfig::default(), RLPxConfig::default());
+        auth_ack(&mut initiator, &mut responder);
+        responder.encode(RLPx_Message::Hello, &mut BytesMut::new()).unwrap();
+
+        // The peer's Hello, from a build that has snappy.
+        let hello = Hello {
+            protocol_version: 5,
+            client_version: "geth/v1.14".to_string(),
+            capabilities: capabilities(&["eth/68"]),
+            port: 0,
+            id: [7; 64],
+        };
+        let mut frame_data = vec![0x80];
+        frame_data.extend_from_slice(&alloy_rlp::encode(&hello));
+        let mut wire = BytesMut::new();
+        initiator.write_raw_frame(&frame_data, &mut wire).unwrap();
+        // Which then goes on uncompressed, as v4 asks of it.
+        initiator.write_raw_frame(&[0x0f, 0xc3, 0x01, 0x02, 0x03], &mut wire).unwrap();
+
+        let messages = decode_all(&mut responder, &mut wire);
+        assert_eq!(responder.peer_info().unwrap().negotiated_protocol_version, 4);
+        assert!(!responder.snappy);
+        assert!(matches!(&messages[..], [RLPx_Message::Hello, RLPx_Message::P2p { id: 0x0f, body }] if body[..] == [0xc3, 0x01, 0x02, 0x03]));
+    }
+
+    #[test]
+    fn hello_before_the_secrets_is_an_error_not_a_panic() {
+        let (mut initiator, mut responder) = codecs(RLPxConfig::default(), RLPxConfig::default());
+        for codec in [&mut initiator, &mut responder] {
+            let state = codec.get_state();
+            let mut wire = BytesMut::new();
+            assert!(matches!(codec.encode(RLPx_Message::Hello, &mut wire), Err(RLPxError::SecretsNotEstablished)));
+            assert!(wire.is_empty());
+            assert_eq!(codec.get_state(), state);
+        }
+        // Once auth and ack are through it goes out as usual.
+        auth_ack(&mut initiator, &mut responder);
+        hellos(&mut initiator, &mut responder);
+    }
+
+    #[test]
+    fn raw_frames_round_trip_and_are_refused_where_encode_would_be() {
+        let (mut initiator, mut responder) = codecs(RLPxConfig::default(), RLPxConfig::default());
+        // Before the handshake there's nothing to frame it with.
+        let mut wire = BytesMut::new();
+        assert!(matches!(initiator.write_raw_frame(&[0x0f, 0xc0], &mut wire), Err(RLPxError::SecretsNotEstablished)));
+        assert!(wire.is_empty());
+
+        auth_ack(&mut initiator, &mut responder);
+        hellos(&mut initiator, &mut responder);
+        let payload = p2p_frame_data(0x0f, &[0xc3, 0x01, 0x02, 0x03]);
+        initiator.write_raw_frame(&payload, &mut wire).unwrap();
+        assert!(matches!(&decode_all(&mut responder, &mut wire)[..], [RLPx_Message::P2p { id: 0x0f, body }] if body[..] == [0xc3, 0x01, 0x02, 0x03]));
+
+        // None of these touch the egress MAC, so the frame after them still checks out.
+        assert!(matches!(initiator.write_raw_frame(&[], &mut wire), Err(RLPxError::EmptyFrame)));
+        assert!(matches!(initiator.write_raw_frame(&vec![0; MAX_FRAME_SIZE + 1], &mut wire), Err(RLPxError::Frame(_))));
+        let header = initiator.begin_frame_stream(payload.len()).unwrap();
+        assert!(matches!(initiator.write_raw_frame(&payload, &mut wire), Err(RLPxError::Frame(_))));
+        assert!(wire.is_empty());
+        wire.extend_from_slice(&header);
+        let mut streamed = payload.clone();
+        initiator.encrypt_frame_stream(&mut streamed).unwrap();
+        wire.extend_from_slice(&streamed);
+        wire.extend_from_slice(&initiator.finish_frame_stream().unwrap());
+        initiator.write_raw_frame(&payload, &mut wire).unwrap();
+        assert_eq!(decode_all(&mut responder, &mut wire).len(), 2);
+    }
+
+    #[test]
+    fn unmodelled_messages_of_a_negotiated_capability_come_up_as_unknown() {
+        let config = RLPxConfig { capabilities: capabilities(&["eth/68", "les/4"]), ..RLPxConfig::default() };
+        let (mut sender, mut receiver) = codecs(config.clone(), config);
+        auth_ack(&mut sender, &mut receiver);
+        hellos(&mut sender, &mut receiver);
+        let les = receiver.peer_info().unwrap().shared_capabilities[1].offset;
+
+        let mut wire = BytesMut::new();
+        // les GetBlockHeaders and eth GetBlockHeaders, neither of which we model.
+        sender.write_raw_frame(&p2p_frame_data(les + 0x02, &[0xc2, 0x01, 0x02]), &mut wire).unwrap();
+        sender.write_raw_frame(&p2p_frame_data(0x10 + 0x03, &[0xc1, 0x07]), &mut wire).unwrap();
+        sender.encode(RLPx_Message::Ping, &mut wire).unwrap();
+        let messages = decode_all(&mut receiver, &mut wire);
+        assert!(matches!(
+            &messages[..],
+            [
+                RLPx_Message::Unknown { capability: first, code: 0x02, body: first_body },
+                RLPx_Message::Unknown { capability: second, code: 0x03, body: second_body },
+                RLPx_Message::Ping,
+            ] if first == "les" && first_body[..] == [0xc2, 0x01, 0x02] && second == "eth" && second_body[..] == [0xc1, 0x07]
+        ));
+    }
+
+}
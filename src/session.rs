@@ -0,0 +1,893 @@
+use std::{collections::VecDeque, io, net::SocketAddr};
+
+use alloy_primitives::TxHash;
+use futures::{stream, SinkExt, Stream, StreamExt};
+use log::{debug, info};
+use secp256k1::{PublicKey, SecretKey};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, AsyncWriteExt},
+    net::TcpStream,
+    time::{sleep, timeout, timeout_at, Instant},
+};
+use tokio_util::codec::{Decoder, Framed};
+
+use crate::{
+    config::{DialConfig, RLPxConfig},
+    ecies::{ECIESDirection, HandshakeSecrets},
+    messages::{Disconnect, GetPooledTransactions, RLPx_Message},
+    rplx::{ByteStats, DecodeWait, FrameHeaderInfo, HandshakeError, PeerInfo, RLPx, RLPxError, RlpxState},
+};
+
+// How much of a streamed frame is encrypted and written at a time.
+const STREAM_PIECE_SIZE: usize = 16 * 1024;
+
+// A connection to one peer, framed by the RLPx codec. Anything that reads
+// and writes bytes will do as the transport, a tokio::io::duplex pipe say.
+pub struct Session<T = TcpStream> {
+    framed: Framed<T, RLPx>,
+    // Messages waiting for flush_queue. p2p control messages go out ahead of
+    // subprotocol data, so a keepalive isn't held up behind a bulk request.
+    control_queue: VecDeque<RLPx_Message>,
+    data_queue: VecDeque<RLPx_Message>,
+    next_request_id: u64,
+    flush_policy: FlushPolicy,
+    // When the session is over no matter what, if ever.
+    deadline: Option<Instant>,
+}
+
+// When send writes to the socket. Coalescing saves writes, and with them
+// syscalls and TCP segments, at the price of latency.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum FlushPolicy {
+    // Every message is written out as soon as it's sent.
+    Immediate,
+    // Messages are only buffered until a flush or a full write buffer.
+    Coalesce,
+    // Control messages right away, subprotocol data coalesced.
+    #[default]
+    ControlImmediate,
+}
+
+impl Session {
+    // Only opens the TCP connection, the handshake is left to the caller so it
+    // can still look at the session when the handshake fails. Each connect
+    // attempt gets its own timeout, failed ones are retried a few times.
+    pub async fn dial(socket_address: SocketAddr, codec: RLPx, dial: &DialConfig) -> Result<Self, HandshakeError> {
+        Ok(Self::new(Self::connect(socket_address, dial).await?, codec))
+    }
+
+    // Just the TCP connection, for callers that wrap the socket before
+    // handing it to Session::new.
+    pub async fn connect(socket_address: SocketAddr, dial: &DialConfig) -> Result<TcpStream, HandshakeError> {
+        // Addresses from discovery or a crawl haven't been through get_peers.
+        let socket_address = dial.normalize(socket_address);
+        let mut attempt = 1;
+        let stream = loop {
+            let err = match timeout(dial.connect_timeout, TcpStream::connect(&socket_address)).await {
+                Ok(Ok(stream)) => break stream,
+                Ok(Err(err)) => HandshakeError::Io(err),
+                Err(_) => HandshakeError::Timeout("TCP connect"),
+            };
+            info!(
+                "TCP connection to {:?} failed, attempt {}/{}! Error {} ",
+                socket_address.to_string(),
+                attempt,
+                dial.connect_attempts,
+                err
+            );
+            if attempt >= dial.connect_attempts {
+                return Err(err);
+            }
+            attempt += 1;
+            sleep(dial.connect_retry_delay).await;
+        };
+        info!(
+            "TCP connection to {:?} established! ",
+            socket_address.to_string()
+        );
+
+        Ok(stream)
+    }
+
+    // Where the socket actually ended up connected, as the OS sees it.
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.framed.get_ref().peer_addr()
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.framed.get_ref().local_addr()
+    }
+
+}
+
+impl<T: AsyncRead + AsyncWrite + Unpin> Session<T> {
+    // Wraps a connection that's already open, like one we just accepted.
+    pub fn new(stream: T, codec: RLPx) -> Self {
+        Self {
+            framed: Framed::new(stream, codec),
+            control_queue: VecDeque::new(),
+            data_queue: VecDeque::new(),
+            next_request_id: 0,
+            flush_policy: FlushPolicy::default(),
+            deadline: None,
+        }
+    }
+
+    // For embedders that bring their own socket, from their accept loop or a
+    // proxy say: builds the codec for the given side and runs the handshake.
+    // Only the side that dialed knows the peer key up front.
+    pub async fn from_stream(
+        stream: T,
+        secret_key: SecretKey,
+        peer_public_key: Option<PublicKey>,
+        direction: ECIESDirection,
+        config: RLPxConfig,
+    ) -> Result<Self, HandshakeError> {
+        let codec = match (direction, peer_public_key) {
+            (ECIESDirection::Outgoing, Some(peer_public_key)) => RLPx::new(secret_key, peer_public_key, config),
+            (ECIESDirection::Outgoing, None) => {
+                return Err(RLPxError::Ecies("Dialing out needs the peer's public key").into())
+            }
+            (ECIESDirection::Incoming, _) => RLPx::new_incoming(secret_key, config),
+        };
+        let mut session = Self::new(stream, codec);
+        session.handshake().await?;
+        Ok(session)
+    }
+
+    // Runs until the peer is ready for subprotocol messages, which for eth
+    // means past the Status exchange, then sends the configured initial
+    // messages in one flush.
+    pub async fn handshake(&mut self) -> Result<(), HandshakeError> {
+        while !self.is_ready() {
+            self.handshake_step_before_deadline().await?;
+        }
+        let initial_messages = self.framed.codec_mut().take_initial_messages();
+        if !initial_messages.is_empty() {
+            debug!("We're sending {} initial messages!", initial_messages.len());
+            for message in initial_messages {
+                self.framed.feed(message).await?;
+            }
+            self.framed.flush().await?;
+        }
+        Ok(())
+    }
+
+    // Moves the session along until the codec is in the given state: through
+    // the handshake while it isn't done, by reading messages after that. The
+    // messages read along the way are dropped. Fails if the session ends up
+    // Disconnected first (unless that's what we're waiting for), if the peer
+    // goes away, or for a state it's already past. Once the handshake is done
+    // reading only gets it to Disconnected, so that's all there is to wait for.
+    pub async fn wait_for_state(&mut self, state: RlpxState) -> Result<(), HandshakeError> {
+        loop {
+            let current = self.state();
+            if current == state {
+                return Ok(());
+            }
+            // Active, StatusSent and EthStatusExchanged only go forward, and
+            // never back into the handshake.
+            let passed = match state {
+                RlpxState::StatusSent => current == RlpxState::EthStatusExchanged,
+                RlpxState::EthStatusExchanged | RlpxState::Disconnected => false,
+                _ => current.is_active(),
+            };
+            if passed || current == RlpxState::Disconnected {
+                return Err(RLPxError::InvalidState(current).into());
+            }
+            if !self.is_ready() {
+                self.handshake_step_before_deadline().await?;
+                continue;
+            }
+            if state != RlpxState::Disconnected {
+                return Err(RLPxError::InvalidState(current).into());
+            }
+            let message = match self.deadline {
+                Some(deadline) => timeout_at(deadline, self.recv())
+                    .await
+                    .map_err(|_| HandshakeError::SessionDeadlineExceeded)?,
+                None => self.recv().await,
+            };
+            match message {
+                Some(Ok(RLPx_Message::Disconnect(reason))) if state != RlpxState::Disconnected => {
+                    return Err(HandshakeError::Disconnected(reason))
+                }
+                Some(Ok(_)) => {}
+                Some(Err(err)) => return Err(err.into()),
+                None => return Err(HandshakeError::PeerClosed),
+            }
+        }
+    }
+
+    // Only auth and ack, stopping short of Hello: the secrets are derived but
+    // nothing has been framed with them yet. Tells an ECIES problem with a
+    // peer apart from a p2p framing one.
+    pub async fn ecies_handshake(&mut self) -> Result<&HandshakeSecrets, HandshakeError> {
+        loop {
+            match self.state() {
+                RlpxState::AuthAckRecieved | RlpxState::AuthAckSent => break,
+                RlpxState::ExpectingConnection
+                | RlpxState::AuthSent
+                | RlpxState::ExpectingAuth
+                | RlpxState::AuthRecieved => self.handshake_step_before_deadline().await?,
+                state => return Err(RLPxError::InvalidState(state).into()),
+            };
+        }
+        self.framed
+            .codec()
+            .secrets()
+            .ok_or_else(|| RLPxError::InvalidState(self.state()).into())
+    }
+
+    async fn handshake_step_before_deadline(&mut self) -> Result<RlpxState, HandshakeError> {
+        match self.deadline {
+            Some(deadline) => timeout_at(deadline, self.handshake_step()).await.map_err(|_| {
+                info!(
+                    "Handshake stalled in {:?}, last decode waiting on {:?}",
+                    self.state(),
+                    self.last_decode_reason()
+                );
+                HandshakeError::SessionDeadlineExceeded
+            })?,
+            None => self.handshake_step().await,
+        }
+    }
+
+    // Moves the handshake along by one message and returns the state we ended up
+    // in, so callers can show progress between steps.
+    pub async fn handshake_step(&mut self) -> Result<RlpxState, HandshakeError> {
+        match self.state() {
+            RlpxState::ExpectingConnection => {
+                debug!("We're sending Auth!");
+                self.framed.send(RLPx_Message::Auth).await?;
+            }
+            RlpxState::AuthSent => {
+                debug!("We're recieving ack!");
+                self.expect(|message| matches!(message, RLPx_Message::AuthAck)).await?;
+            }
+            RlpxState::ExpectingAuth => {
+                debug!("We're recieving auth!");
+                self.expect(|message| matches!(message, RLPx_Message::Auth)).await?;
+            }
+            RlpxState::AuthRecieved => {
+                debug!("We're sending ack!");
+                self.framed.send(RLPx_Message::AuthAck).await?;
+            }
+            RlpxState::AuthAckRecieved | RlpxState::AuthAckSent => {
+                debug!("We're sending Hello!");
+                self.framed.send(RLPx_Message::Hello).await?;
+            }
+            RlpxState::HelloSent => {
+                debug!("We're waiting Hello!");
+                self.expect(|message| matches!(message, RLPx_Message::Hello)).await?;
+                if self.framed.codec().is_useless_peer() {
+                    info!("Peer shares no capability with us, disconnecting");
+                    self.send(RLPx_Message::Disconnect(Disconnect::USELESS_PEER)).await?;
+                    return Err(RLPxError::Capability("No capability in common with the peer").into());
+                }
+            }
+            RlpxState::Active => {
+                if let Some(status) = self.framed.codec().eth_status() {
+                    debug!("We're sending Status!");
+                    self.framed.send(RLPx_Message::Status(status)).await?;
+                }
+            }
+            RlpxState::StatusSent => {
+                debug!("We're waiting Status!");
+                self.expect(|message| matches!(message, RLPx_Message::Status(_))).await?;
+            }
+            RlpxState::EthStatusExchanged => {}
+            _ => return Err(HandshakeError::UnexpectedMessage),
+        }
+        Ok(self.state())
+    }
+
+    async fn expect(&mut self, expected: fn(&RLPx_Message) -> bool) -> Result<(), HandshakeError> {
+        let message = match self.decode_buffered() {
+            Ok(Some(message)) => Some(Ok(message)),
+            Ok(None) => self.recv().await,
+            Err(err) => Some(Err(err)),
+        };
+        match message {
+            Some(Ok(message)) if expected(&message) => Ok(()),
+            Some(Ok(RLPx_Message::Disconnect(reason))) => Err(HandshakeError::Disconnected(reason)),
+            Some(Ok(_)) => Err(HandshakeError::UnexpectedMessage),
+            Some(Err(err)) => Err(err.into()),
+            None => Err(HandshakeError::PeerClosed),
+        }
+    }
+
+    // Framed only decodes again once more bytes come in. Whatever the peer got
+    // in before the codec was ready for it, before our auth went out say,
+    // would otherwise sit in the read buffer until the peer sends more, which
+    // it may well be waiting on us for.
+    fn decode_buffered(&mut self) -> Result<Option<RLPx_Message>, RLPxError> {
+        if self.framed.read_buffer().is_empty() {
+            return Ok(None);
+        }
+        let mut buffered = std::mem::take(self.framed.read_buffer_mut());
+        let message = self.framed.codec_mut().decode(&mut buffered);
+        *self.framed.read_buffer_mut() = buffered;
+        message
+    }
+
+    // Whether the message goes out now or waits for more company depends on
+    // the flush policy. Anything held back is written on flush, on the next
+    // recv, or once enough has piled up, whichever comes first.
+    // Holding back more than the memory budget allows flushes right away
+    // instead.
+    pub async fn send(&mut self, message: RLPx_Message) -> Result<(), RLPxError> {
+        let immediate = match self.flush_policy {
+            FlushPolicy::Immediate => true,
+            FlushPolicy::Coalesce => false,
+            FlushPolicy::ControlImmediate => message.is_control(),
+        } || self.check_memory(message.approx_size()).is_err();
+        let sent = if immediate {
+            self.framed.send(message).await
+        } else {
+            self.framed.feed(message).await
+        };
+        self.update_outbound_bytes();
+        sent
+    }
+
+    pub async fn flush(&mut self) -> Result<(), RLPxError> {
+        let flushed = self.framed.flush().await;
+        self.update_outbound_bytes();
+        flushed
+    }
+
+    // Caps the whole session, handshake and all, as opposed to the per stage
+    // timeouts. The handshake gives up on its own once it passes, after that
+    // it's up to whoever drives recv to watch deadline().
+    pub fn set_deadline(&mut self, deadline: Instant) {
+        self.deadline = Some(deadline);
+    }
+
+    pub fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    pub fn set_flush_policy(&mut self, flush_policy: FlushPolicy) {
+        self.flush_policy = flush_policy;
+    }
+
+    // Asks the peer for the full transactions behind the hashes. The answer
+    // comes in through recv as PooledTransactions carrying the returned id.
+    pub async fn request_pooled_transactions(&mut self, hashes: Vec<TxHash>) -> Result<u64, RLPxError> {
+        let request_id = self.next_request_id;
+        self.next_request_id += 1;
+        self.send(RLPx_Message::GetPooledTransactions(GetPooledTransactions { request_id, hashes }))
+            .await?;
+        Ok(request_id)
+    }
+
+    // Holds the message back until the next flush_queue. Refused if that
+    // would take the session over its memory budget, flush_queue first.
+    pub fn queue(&mut self, message: RLPx_Message) -> Result<(), RLPxError> {
+        self.check_memory(message.approx_size())?;
+        if message.is_control() {
+            self.control_queue.push_back(message)
+        } else {
+            self.data_queue.push_back(message)
+        }
+        self.update_outbound_bytes();
+        Ok(())
+    }
+
+    // Sends everything queued, control messages first, in one flush. Whatever
+    // is still queued behind a Disconnect is dropped. The order is settled
+    // before anything is framed, and the whole batch is framed in that order
+    // straight onto the write buffer, so the egress MAC chain follows the wire.
+    pub async fn flush_queue(&mut self) -> Result<(), RLPxError> {
+        let mut batch = Vec::with_capacity(self.control_queue.len() + self.data_queue.len());
+        while let Some(message) = self.control_queue.pop_front().or_else(|| self.data_queue.pop_front()) {
+            let disconnect = matches!(message, RLPx_Message::Disconnect(_));
+            batch.push(message);
+            if disconnect {
+                self.control_queue.clear();
+                self.data_queue.clear();
+            }
+        }
+        let mut write_buffer = std::mem::take(self.framed.write_buffer_mut());
+        let written = self.framed.codec_mut().write_messages(batch, &mut write_buffer);
+        *self.framed.write_buffer_mut() = write_buffer;
+        written?;
+        let flushed = self.framed.flush().await;
+        self.update_outbound_bytes();
+        flushed
+    }
+
+    // What's queued and what's framed but not written out yet, which the
+    // codec counts against the budget along with the frame it's reading.
+    fn update_outbound_bytes(&mut self) {
+        let queued: usize = self
+            .control_queue
+            .iter()
+            .chain(&self.data_queue)
+            .map(RLPx_Message::approx_size)
+            .sum();
+        let outbound_bytes = queued + self.framed.write_buffer().len();
+        self.framed.codec_mut().set_outbound_bytes(outbound_bytes);
+    }
+
+    // Whether the session can take on extra bytes and stay within budget.
+    fn check_memory(&self, extra: usize) -> Result<(), RLPxError> {
+        self.framed.codec().check_memory(self.framed.read_buffer().len() + extra)
+    }
+
+    // A p2p message by id, with an already RLP encoded body. It's snappy
+    // compressed like any other once that's on, and counts as control
+    // traffic. Ids from BASE_PROTOCOL_IDS up are refused.
+    pub async fn send_p2p(&mut self, msg_id: u8, rlp_body: &[u8]) -> Result<(), RLPxError> {
+        self.send(RLPx_Message::P2p {
+            id: msg_id,
+            body: rlp_body.to_vec(),
+        })
+        .await
+    }
+
+    // Sends payload as a frame of its own, bypassing RLPx_Message and with it
+    // any message id validation. Meant for conformance testing and for
+    // subprotocols the enum doesn't model.
+    pub async fn send_frame_bytes(&mut self, payload: &[u8]) -> Result<(), RLPxError> {
+        if !self.state().is_active() {
+            return Err(RLPxError::InvalidState(self.state()));
+        }
+        let mut write_buffer = std::mem::take(self.framed.write_buffer_mut());
+        let framed = self.framed.codec_mut().write_raw_frame(payload, &mut write_buffer);
+        *self.framed.write_buffer_mut() = write_buffer;
+        framed?;
+        self.framed.flush().await
+    }
+
+    // send_frame_bytes without the frame ever sitting whole in the write
+    // buffer: the payload is encrypted a piece at a time and each piece goes
+    // straight to the transport. Whatever was framed before goes out first. A
+    // failed write leaves the frame half sent, so the session is done then.
+    pub async fn stream_frame_bytes(&mut self, payload: &[u8]) -> Result<(), RLPxError> {
+        if !self.state().is_active() {
+            return Err(RLPxError::InvalidState(self.state()));
+        }
+        self.framed.flush().await?;
+        let streamed = self.write_frame_stream(payload).await;
+        if streamed.is_err() {
+            self.framed.codec_mut().reset();
+        }
+        self.update_outbound_bytes();
+        streamed
+    }
+
+    async fn write_frame_stream(&mut self, payload: &[u8]) -> Result<(), RLPxError> {
+        let header = self.framed.codec_mut().begin_frame_stream(payload.len())?;
+        self.framed.get_mut().write_all(&header).await?;
+        let mut piece = Vec::with_capacity(STREAM_PIECE_SIZE.min(payload.len()));
+        for chunk in payload.chunks(STREAM_PIECE_SIZE) {
+            piece.clear();
+            piece.extend_from_slice(chunk);
+            self.framed.codec_mut().encrypt_frame_stream(&mut piece)?;
+            self.framed.get_mut().write_all(&piece).await?;
+        }
+        let tail = self.framed.codec_mut().finish_frame_stream()?;
+        self.framed.get_mut().write_all(&tail).await?;
+        self.framed.get_mut().flush().await?;
+        Ok(())
+    }
+
+    pub async fn recv(&mut self) -> Option<Result<RLPx_Message, RLPxError>> {
+        // Whatever we held back may well be what the peer is waiting on.
+        if let Err(err) = self.flush().await {
+            return Some(Err(err));
+        }
+        let message = self.framed.next().await;
+        if let Some(Err(RLPxError::ProtocolBreach(err))) = &message {
+            // Tell the peer why before hanging up, it's all we still owe it.
+            info!("Disconnecting peer for a protocol breach: {}", err);
+            let _ = self.send(RLPx_Message::Disconnect(Disconnect::PROTOCOL_BREACH)).await;
+        }
+        if let Some(Err(_)) = &message {
+            // Framed is done after an error, so are the secrets.
+            self.framed.codec_mut().reset();
+        }
+        message
+    }
+
+    // Hands over the incoming messages as a stream. It reads through recv, so
+    // a protocol breach still gets its Disconnect, and ends after the peer's
+    // Disconnect, an error or when the connection closes, whichever comes
+    // first. The session stays with the stream, our end is closed when it's
+    // dropped.
+    pub fn into_stream(self) -> impl Stream<Item = Result<RLPx_Message, RLPxError>> {
+        stream::unfold((self, false), |(mut session, done)| async move {
+            if done {
+                return None;
+            }
+            let message = session.recv().await?;
+            let done = !matches!(message, Ok(ref message) if !matches!(message, RLPx_Message::Disconnect(_)));
+            Some((message, (session, done)))
+        })
+    }
+
+    pub fn byte_stats(&self) -> ByteStats {
+        let codec = self.framed.codec();
+        ByteStats {
+            queued: self.control_queue.len() + self.data_queue.len(),
+            memory: codec.memory_usage(self.framed.read_buffer().len()),
+            ..codec.byte_stats()
+        }
+    }
+
+    pub fn peer_public_key(&self) -> Option<PublicKey> {
+        self.framed.codec().peer_public_key()
+    }
+
+    pub fn direction(&self) -> ECIESDirection {
+        self.framed.codec().direction()
+    }
+
+    // Past the handshake, Status exchange included where eth needs one.
+    pub fn is_ready(&self) -> bool {
+        self.framed.codec().is_ready()
+    }
+
+    pub fn state(&self) -> RlpxState {
+        self.framed.codec().get_state()
+    }
+
+    pub fn supported_message_ids(&self) -> Vec<(u8, &'static str)> {
+        self.framed.codec().supported_message_ids()
+    }
+
+    // For chasing framing issues, see FrameHeaderInfo.
+    pub fn last_ingress_header(&self) -> Option<FrameHeaderInfo> {
+        self.framed.codec().last_ingress_header()
+    }
+
+    // What the codec is still waiting on, when a session seems stuck.
+    pub fn last_decode_reason(&self) -> Option<DecodeWait> {
+        self.framed.codec().last_decode_reason()
+    }
+
+    // What the peer told us in its Hello, available once the session is Active.
+    pub fn peer_info(&self) -> Option<&PeerInfo> {
+        if !self.state().is_active() {
+            return None;
+        }
+        self.framed.codec().peer_info()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::{ClientVersion, DEFAULT_MAX_PINGS_PER_SECOND},
+        messages::{Capability, Hello},
+        testing::{codecs, p2p_frame_data, sessions, PIPE_CAPACITY},
+    };
+    use alloy_rlp::BytesMut;
+    use std::{
+        pin::Pin,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        task::{Context, Poll},
+        time::Duration,
+    };
+    use tokio::io::{duplex, DuplexStream, ReadBuf};
+    use tokio_util::codec::Encoder;
+
+    // The pipe, with a count of the writes that reach it.
+    struct CountingWrites {
+        pipe: DuplexStream,
+        writes: Arc<AtomicUsize>,
+    }
+
+    impl AsyncRead for CountingWrites {
+        fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.pipe).poll_read(cx, buf)
+        }
+    }
+
+    impl AsyncWrite for CountingWrites {
+        fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+            let written = Pin::new(&mut self.pipe).poll_write(cx, buf);
+            if matches!(written, Poll::Ready(Ok(_))) {
+                self.writes.fetch_add(1, Ordering::Relaxed);
+            }
+            written
+        }
+
+        fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.pipe).poll_flush(cx)
+        }
+
+        fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.pipe).poll_shutdown(cx)
+        }
+    }
+
+    // Like testing::sessions, with the initiator's writes counted from
+    // after the handshake on.
+    async fn counted_sessions(
+        flush_policy: FlushPolicy,
+    ) -> (Session<CountingWrites>, Session<DuplexStream>, Arc<AtomicUsize>) {
+        let (initiator_codec, responder_codec) = codecs(RLPxConfig::default(), RLPxConfig::default());
+        let (initiator_pipe, responder_pipe) = duplex(PIPE_CAPACITY);
+        let writes = Arc::new(AtomicUsize::new(0));
+        let counting = CountingWrites {
+            pipe: initiator_pipe,
+            writes: writes.clone(),
+        };
+        let mut initiator = Session::new(counting, initiator_codec);
+        let mut responder = Session::new(responder_pipe, responder_codec);
+        let (initiated, responded) = tokio::join!(initiator.handshake(), responder.handshake());
+        initiated.unwrap();
+        responded.unwrap();
+        initiator.set_flush_policy(flush_policy);
+        writes.store(0, Ordering::Relaxed);
+        (initiator, responder, writes)
+    }
+
+    // Subprotocol data, which is what the policies treat differently.
+    fn data(id: u8) -> RLPx_Message {
+        RLPx_Message::Unknown {
+            capability: "eth".to_string(),
+            code: id,
+            body: vec![id; 100],
+        }
+    }
+
+    #[tokio::test]
+    async fn immediate_policy_writes_each_message_on_its_own() {
+        let (mut initiator, mut responder, writes) = counted_sessions(FlushPolicy::Immediate).await;
+        initiator.send(RLPx_Message::Ping).await.unwrap();
+        initiator.send(data(0x03)).await.unwrap();
+        initiator.send(data(0x04)).await.unwrap();
+        assert_eq!(writes.load(Ordering::Relaxed), 3);
+
+        initiator.flush().await.unwrap();
+        assert_eq!(writes.load(Ordering::Relaxed), 3);
+        assert!(matches!(responder.recv().await, Some(Ok(RLPx_Message::Ping))));
+    }
+
+    #[tokio::test]
+    async fn batched_messages_go_out_in_one_write_per_flush() {
+        let (mut initiator, mut responder, writes) = counted_sessions(FlushPolicy::Coalesce).await;
+        initiator.send(RLPx_Message::Ping).await.unwrap();
+        initiator.send(data(0x03)).await.unwrap();
+        initiator.send(data(0x04)).await.unwrap();
+        assert_eq!(writes.load(Ordering::Relaxed), 0);
+        initiator.flush().await.unwrap();
+        assert_eq!(writes.load(Ordering::Relaxed), 1);
+
+        // The queue goes out the same way, whatever the policy.
+        initiator.queue(data(0x05)).unwrap();
+        initiator.queue(RLPx_Message::Pong).unwrap();
+        assert_eq!(writes.load(Ordering::Relaxed), 1);
+        initiator.flush_queue().await.unwrap();
+        assert_eq!(writes.load(Ordering::Relaxed), 2);
+
+        assert!(matches!(responder.recv().await, Some(Ok(RLPx_Message::Ping))));
+    }
+
+    #[tokio::test]
+    async fn streamed_frame_arrives_and_the_mac_chain_carries_on() {
+        let (mut initiator, mut responder) = sessions(RLPxConfig::default(), RLPxConfig::default()).await;
+        let body: Vec<u8> = (0..100_000).map(|i| (i * 7 % 251) as u8).collect();
+        let payload = p2p_frame_data(0x0f, &body);
+
+        let (streamed, received) = tokio::join!(initiator.stream_frame_bytes(&payload), responder.recv());
+        streamed.unwrap();
+        assert!(matches!(received, Some(Ok(RLPx_Message::P2p { id: 0x0f, body: received })) if received == body));
+
+        // The next frame starts from the MAC state the streamed one left.
+        initiator.send(RLPx_Message::Ping).await.unwrap();
+        assert!(matches!(responder.recv().await, Some(Ok(RLPx_Message::Ping))));
+    }
+
+    #[tokio::test]
+    async fn streamed_frame_goes_out_after_what_was_already_framed() {
+        let (mut initiator, mut responder) = sessions(RLPxConfig::default(), RLPxConfig::default()).await;
+        initiator.set_flush_policy(FlushPolicy::Coalesce);
+        initiator.send(RLPx_Message::Ping).await.unwrap();
+        initiator.stream_frame_bytes(&p2p_frame_data(0x0f, b"after")).await.unwrap();
+
+        assert!(matches!(responder.recv().await, Some(Ok(RLPx_Message::Ping))));
+        assert!(matches!(responder.recv().await, Some(Ok(RLPx_Message::P2p { id: 0x0f, body })) if body == b"after"));
+    }
+
+    #[tokio::test]
+    async fn peer_hanging_up_halfway_through_a_frame_is_an_error() {
+        let (mut initiator, mut responder) = sessions(RLPxConfig::default(), RLPxConfig::default()).await;
+        let mut frame = BytesMut::new();
+        initiator.framed.codec_mut().encode(RLPx_Message::Ping, &mut frame).unwrap();
+        initiator.framed.get_mut().write_all(&frame[..frame.len() / 2]).await.unwrap();
+        drop(initiator);
+        assert!(matches!(responder.recv().await, Some(Err(RLPxError::Truncated))));
+    }
+
+    #[tokio::test]
+    async fn peer_hanging_up_between_frames_just_ends_the_stream() {
+        let (mut initiator, mut responder) = sessions(RLPxConfig::default(), RLPxConfig::default()).await;
+        initiator.send(RLPx_Message::Ping).await.unwrap();
+        drop(initiator);
+        assert!(matches!(responder.recv().await, Some(Ok(RLPx_Message::Ping))));
+        assert!(responder.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn hello_again_once_active_gets_the_peer_disconnected() {
+        let (mut initiator, mut responder) = sessions(RLPxConfig::default(), RLPxConfig::default()).await;
+        let hello = Hello {
+            protocol_version: 5,
+            client_version: "geth/v1.14".to_string(),
+            capabilities: vec!["eth/68".parse().unwrap()],
+            port: 30303,
+            id: [7; 64],
+        };
+        // msg-id 0 is RLP 0x80, and Hello is never compressed.
+        let mut frame_data = vec![0x80];
+        frame_data.extend_from_slice(&alloy_rlp::encode(&hello));
+        initiator.send_frame_bytes(&frame_data).await.unwrap();
+
+        assert!(matches!(
+            responder.recv().await,
+            Some(Err(RLPxError::ProtocolBreach("Hello after the handshake")))
+        ));
+        assert_eq!(responder.state(), RlpxState::Disconnected);
+        assert!(matches!(
+            initiator.recv().await,
+            Some(Ok(RLPx_Message::Disconnect(Disconnect::PROTOCOL_BREACH)))
+        ));
+    }
+
+    #[tokio::test]
+    async fn peers_without_a_shared_capability_part_at_the_hello() {
+        let eth = RLPxConfig { capabilities: vec!["eth/68".parse().unwrap()], ..RLPxConfig::default() };
+        let les = RLPxConfig { capabilities: vec!["les/4".parse().unwrap()], ..RLPxConfig::default() };
+        let (initiator_codec, responder_codec) = codecs(eth, les);
+        let (initiator_pipe, responder_pipe) = duplex(PIPE_CAPACITY);
+        let mut initiator = Session::new(initiator_pipe, initiator_codec);
+        let mut responder = Session::new(responder_pipe, responder_codec);
+        let (initiated, responded) = tokio::join!(initiator.handshake(), responder.handshake());
+        // Each side sends its Disconnect as soon as it has the other's Hello.
+        for (result, state) in [(initiated, initiator.state()), (responded, responder.state())] {
+            assert!(matches!(result, Err(HandshakeError::Codec(RLPxError::Capability(_)))));
+            assert_eq!(state, RlpxState::Disconnected);
+        }
+    }
+
+    #[tokio::test]
+    async fn ping_flood_gets_the_peer_disconnected() {
+        let (mut initiator, mut responder) = sessions(RLPxConfig::default(), RLPxConfig::default()).await;
+        for _ in 0..10 {
+            initiator.send(RLPx_Message::Ping).await.unwrap();
+        }
+
+        // Up to the limit they're just Pings, the one after is a breach.
+        for _ in 0..DEFAULT_MAX_PINGS_PER_SECOND {
+            assert!(matches!(responder.recv().await, Some(Ok(RLPx_Message::Ping))));
+        }
+        assert!(matches!(responder.recv().await, Some(Err(RLPxError::ProtocolBreach("Ping flood")))));
+        assert!(matches!(
+            initiator.recv().await,
+            Some(Ok(RLPx_Message::Disconnect(Disconnect::PROTOCOL_BREACH)))
+        ));
+    }
+
+    #[tokio::test]
+    async fn hello_with_a_nameless_capability_is_refused_with_a_protocol_breach() {
+        let nameless = RLPxConfig {
+            capabilities: vec![Capability { name: String::new(), version: 1 }, "eth/68".parse().unwrap()],
+            ..RLPxConfig::default()
+        };
+        let (initiator_codec, responder_codec) = codecs(nameless, RLPxConfig::default());
+        let (initiator_pipe, responder_pipe) = duplex(PIPE_CAPACITY);
+        let mut initiator = Session::new(initiator_pipe, initiator_codec);
+        let mut responder = Session::new(responder_pipe, responder_codec);
+        let (initiated, responded) = tokio::join!(initiator.handshake(), responder.handshake());
+        assert!(matches!(
+            responded,
+            Err(HandshakeError::Codec(RLPxError::ProtocolBreach(Hello::EMPTY_CAPABILITY_NAME)))
+        ));
+        assert!(matches!(initiated, Err(HandshakeError::Disconnected(Disconnect::PROTOCOL_BREACH))));
+    }
+
+    #[tokio::test]
+    async fn megabyte_client_version_is_refused_with_a_protocol_breach() {
+        let bloated = RLPxConfig {
+            client_version: ClientVersion::Fixed("g".repeat(1 << 20)),
+            ..RLPxConfig::default()
+        };
+        let (initiator_codec, responder_codec) = codecs(bloated, RLPxConfig::default());
+        let (initiator_pipe, responder_pipe) = duplex(4 << 20);
+        let mut initiator = Session::new(initiator_pipe, initiator_codec);
+        let mut responder = Session::new(responder_pipe, responder_codec);
+        let (initiated, responded) = tokio::join!(initiator.handshake(), responder.handshake());
+        assert!(matches!(
+            responded,
+            Err(HandshakeError::Codec(RLPxError::ProtocolBreach(Hello::CLIENT_VERSION_TOO_LONG)))
+        ));
+        assert!(matches!(initiated, Err(HandshakeError::Disconnected(Disconnect::PROTOCOL_BREACH))));
+        // It never got as far as the peer info, or the log line that goes with it.
+        assert!(responder.peer_info().is_none());
+    }
+
+    // The peer says nothing, so anything that waits on it would hang.
+    async fn fails_at_once(session: &mut Session<DuplexStream>, state: RlpxState) -> RlpxState {
+        match timeout(Duration::from_millis(100), session.wait_for_state(state)).await {
+            Ok(Err(HandshakeError::Codec(RLPxError::InvalidState(current)))) => current,
+            other => panic!("Waiting for {:?} gave {:?}", state, other),
+        }
+    }
+
+    #[tokio::test]
+    async fn waiting_for_a_state_goes_through_the_handshake_and_stops_past_it() {
+        let (initiator_codec, responder_codec) = codecs(RLPxConfig::default(), RLPxConfig::default());
+        let (initiator_pipe, responder_pipe) = duplex(PIPE_CAPACITY);
+        let mut initiator = Session::new(initiator_pipe, initiator_codec);
+        let mut responder = Session::new(responder_pipe, responder_codec);
+
+        // The initiator sends its Status on the way, the responder stops short of it.
+        let (initiated, responded) = tokio::join!(
+            initiator.wait_for_state(RlpxState::StatusSent),
+            responder.wait_for_state(RlpxState::Active)
+        );
+        initiated.unwrap();
+        responded.unwrap();
+        assert_eq!(fails_at_once(&mut initiator, RlpxState::Active).await, RlpxState::StatusSent);
+        assert_eq!(fails_at_once(&mut initiator, RlpxState::HelloSent).await, RlpxState::StatusSent);
+
+        let (initiated, responded) = tokio::join!(
+            initiator.wait_for_state(RlpxState::EthStatusExchanged),
+            responder.wait_for_state(RlpxState::EthStatusExchanged)
+        );
+        initiated.unwrap();
+        responded.unwrap();
+        assert_eq!(fails_at_once(&mut initiator, RlpxState::Active).await, RlpxState::EthStatusExchanged);
+        assert_eq!(fails_at_once(&mut initiator, RlpxState::StatusSent).await, RlpxState::EthStatusExchanged);
+    }
+
+    #[tokio::test]
+    async fn waiting_on_a_disconnected_session_fails_unless_for_disconnected() {
+        let (mut initiator, mut responder) = sessions(RLPxConfig::default(), RLPxConfig::default()).await;
+        initiator.send(RLPx_Message::Disconnect(Disconnect::REQUESTED)).await.unwrap();
+        initiator.wait_for_state(RlpxState::Disconnected).await.unwrap();
+        assert_eq!(fails_at_once(&mut initiator, RlpxState::Active).await, RlpxState::Disconnected);
+        assert_eq!(fails_at_once(&mut initiator, RlpxState::EthStatusExchanged).await, RlpxState::Disconnected);
+
+        // The other side gets there by reading the Disconnect.
+        responder.wait_for_state(RlpxState::Disconnected).await.unwrap();
+        assert_eq!(fails_at_once(&mut responder, RlpxState::StatusSent).await, RlpxState::Disconnected);
+    }
+
+    #[tokio::test]
+    async fn message_stream_ends_after_a_disconnect_a_breach_and_at_eof() {
+        let (initiator, mut responder) = sessions(RLPxConfig::default(), RLPxConfig::default()).await;
+        responder.send(RLPx_Message::Ping).await.unwrap();
+        responder.send(RLPx_Message::Disconnect(Disconnect::REQUESTED)).await.unwrap();
+        let messages: Vec<_> = initiator.into_stream().collect().await;
+        assert!(matches!(
+            messages[..],
+            [Ok(RLPx_Message::Ping), Ok(RLPx_Message::Disconnect(Disconnect::REQUESTED))]
+        ));
+
+        let (initiator, mut responder) = sessions(RLPxConfig::default(), RLPxConfig::default()).await;
+        responder.send(RLPx_Message::Ping).await.unwrap();
+        drop(responder);
+        let messages: Vec<_> = initiator.into_stream().collect().await;
+        assert!(matches!(messages[..], [Ok(RLPx_Message::Ping)]));
+
+        // A breach ends it too, with the peer told why.
+        let (initiator, mut responder) = sessions(RLPxConfig::default(), RLPxConfig::default()).await;
+        responder.send_frame_bytes(&p2p_frame_data(0x7f, &[0xc0])).await.unwrap();
+        let messages: Vec<_> = initiator.into_stream().collect().await;
+        assert!(matches!(messages[..], [Err(RLPxError::ProtocolBreach(_))]));
+        assert!(matches!(
+            responder.recv().await,
+            Some(Ok(RLPx_Message::Disconnect(Disconnect::PROTOCOL_BREACH)))
+        ));
+    }
+}
@@ -0,0 +1,333 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Mutex,
+    },
+};
+
+use log::info;
+use secp256k1::{PublicKey, SecretKey, SECP256K1};
+use tokio::sync::{mpsc, Notify};
+
+use crate::{
+    ecies::ECIESDirection,
+    messages::{Disconnect, RLPx_Message, Reason},
+    redact,
+};
+
+// What the manager can ask of a registered session.
+#[derive(Debug)]
+pub enum SessionCommand {
+    Send(RLPx_Message),
+    // Send a Disconnect with the reason and end the session, noting why.
+    Close(Reason, &'static str),
+}
+
+// The active sessions by node id. Sessions register once their handshake is
+// done and are reached through their command channel, since each one is
+// driven by its own task. It's also what keeps a node that dials us while we
+// dial it down to one connection instead of two.
+pub struct SessionManager {
+    our_id: [u8; 64],
+    // Cap on sessions with peers that dialed us, our own dials aren't counted.
+    // Handshakes still in flight with such peers count towards it too.
+    max_inbound: Option<usize>,
+    inbound_handshakes: AtomicUsize,
+    active: Mutex<HashMap<[u8; 64], SessionHandle>>,
+    next_id: AtomicU64,
+    // Nodes we're dialing and haven't registered a session with yet. The same
+    // node can be listed at several addresses, only one gets dialed at a time.
+    handshaking: Mutex<HashSet<[u8; 64]>>,
+    handshake_done: Notify,
+}
+
+// How to reach one registered session.
+#[derive(Clone, Debug)]
+pub struct SessionHandle {
+    id: u64,
+    pub direction: ECIESDirection,
+    commands: mpsc::UnboundedSender<SessionCommand>,
+}
+
+impl SessionHandle {
+    // False once the session has ended.
+    pub fn send(&self, command: SessionCommand) -> bool {
+        self.commands.send(command).is_ok()
+    }
+}
+
+// Why a session couldn't be added, and what to tell the peer.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Rejection {
+    pub reason: Reason,
+    pub why: &'static str,
+}
+
+impl Rejection {
+    fn new(reason: Reason, why: &'static str) -> Self {
+        Self { reason, why }
+    }
+}
+
+// Held while dialing a node, until its session is registered or has failed.
+// A peer that dialed us holds one of the inbound slots instead, its node id
+// isn't known until the handshake is done.
+pub struct HandshakeGuard<'a> {
+    manager: &'a SessionManager,
+    node_id: Option<[u8; 64]>,
+}
+
+// Held by a session for as long as it's the one we keep with a node.
+pub struct Registration<'a> {
+    manager: &'a SessionManager,
+    node_id: [u8; 64],
+    id: u64,
+    pub commands: mpsc::UnboundedReceiver<SessionCommand>,
+}
+
+impl SessionManager {
+    pub fn new(private_key: &SecretKey, max_inbound: Option<usize>) -> Self {
+        Self {
+            our_id: node_id(&PublicKey::from_secret_key(SECP256K1, private_key)),
+            max_inbound,
+            inbound_handshakes: AtomicUsize::new(0),
+            active: Mutex::new(HashMap::new()),
+            next_id: AtomicU64::new(0),
+            handshaking: Mutex::new(HashSet::new()),
+            handshake_done: Notify::new(),
+        }
+    }
+
+    // When both sides dial at once, the connection dialed by the node with
+    // the lower id is kept. Both ends compare the same two ids, so they agree
+    // on which one that is without talking it over.
+    pub fn add(&self, peer: &PublicKey, direction: ECIESDirection) -> Result<Registration<'_>, Rejection> {
+        let node_id = node_id(peer);
+        let Ok(mut active) = self.active.lock() else {
+            return Err(Rejection::new(Disconnect::REQUESTED, "Session table poisoned"));
+        };
+
+        if let Some(existing) = active.get(&node_id) {
+            if existing.direction == direction {
+                return Err(Rejection::new(Disconnect::ALREADY_CONNECTED, "Already connected to this node"));
+            }
+            let kept = if self.our_id < node_id {
+                ECIESDirection::Outgoing
+            } else {
+                ECIESDirection::Incoming
+            };
+            if direction != kept {
+                info!("Simultaneous open with {}, keeping the {:?} connection", redact::node_id(&node_id), kept);
+                return Err(Rejection::new(
+                    Disconnect::ALREADY_CONNECTED,
+                    "Simultaneous open, kept the other connection",
+                ));
+            }
+            info!("Simultaneous open with {}, replacing the {:?} connection", redact::node_id(&node_id), existing.direction);
+            existing.send(SessionCommand::Close(
+                Disconnect::ALREADY_CONNECTED,
+                "Replaced by a simultaneous connection",
+            ));
+        } else if direction == ECIESDirection::Incoming
+            && self.max_inbound.is_some_and(|max_inbound| {
+                active.values().filter(|handle| handle.direction == ECIESDirection::Incoming).count() >= max_inbound
+            })
+        {
+            return Err(Rejection::new(Disconnect::TOO_MANY_PEERS, "Too many peers"));
+        }
+
+        let (commands_tx, commands) = mpsc::unbounded_channel();
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        active.insert(
+            node_id,
+            SessionHandle {
+                id,
+                direction,
+                commands: commands_tx,
+            },
+        );
+        Ok(Registration {
+            manager: self,
+            node_id,
+            id,
+            commands,
+        })
+    }
+
+    // Waits out any handshake with the node that is already in flight. Check
+    // for a session with it afterwards, the other dial may have got one.
+    pub async fn begin_handshake(&self, peer: &PublicKey) -> HandshakeGuard<'_> {
+        let node_id = node_id(peer);
+        loop {
+            // Created before the check, so a guard dropped in between still wakes us.
+            let done = self.handshake_done.notified();
+            let started = match self.handshaking.lock() {
+                Ok(mut handshaking) => handshaking.insert(node_id),
+                Err(_) => true,
+            };
+            if started {
+                return HandshakeGuard {
+                    manager: self,
+                    node_id: Some(node_id),
+                };
+            }
+            info!("Handshake with {} already in flight, waiting for it", redact::node_id(&node_id));
+            done.await;
+        }
+    }
+
+    // Takes an inbound slot for a peer that just connected, if one is free.
+    // Sessions and handshakes with peers that dialed us both hold one.
+    pub fn begin_inbound(&self) -> Result<HandshakeGuard<'_>, Rejection> {
+        let Ok(active) = self.active.lock() else {
+            return Err(Rejection::new(Disconnect::REQUESTED, "Session table poisoned"));
+        };
+        // Only changed with the table locked, so the count can't go past the cap.
+        let handshakes = self.inbound_handshakes.load(Ordering::Relaxed);
+        if self.max_inbound.is_some_and(|max_inbound| {
+            active.values().filter(|handle| handle.direction == ECIESDirection::Incoming).count() + handshakes >= max_inbound
+        }) {
+            return Err(Rejection::new(Disconnect::TOO_MANY_PEERS, "Too many peers"));
+        }
+        self.inbound_handshakes.store(handshakes + 1, Ordering::Relaxed);
+        Ok(HandshakeGuard {
+            manager: self,
+            node_id: None,
+        })
+    }
+
+    // Asks the session with the node to disconnect and forgets about it.
+    pub fn remove(&self, peer: &PublicKey, reason: Reason) -> bool {
+        let Ok(mut active) = self.active.lock() else {
+            return false;
+        };
+        active
+            .remove(&node_id(peer))
+            .is_some_and(|handle| handle.send(SessionCommand::Close(reason, "Removed by the session manager")))
+    }
+
+    pub fn get(&self, peer: &PublicKey) -> Option<SessionHandle> {
+        self.active.lock().ok()?.get(&node_id(peer)).cloned()
+    }
+
+    // Queues the message on every active session, returns how many took it.
+    pub fn broadcast(&self, message: RLPx_Message) -> usize {
+        self.for_each(|| SessionCommand::Send(message.clone()))
+    }
+
+    // Disconnects every active session with the same reason.
+    pub fn close_all(&self, reason: Reason, why: &'static str) -> usize {
+        self.for_each(|| SessionCommand::Close(reason, why))
+    }
+
+    fn for_each(&self, command: impl Fn() -> SessionCommand) -> usize {
+        let Ok(active) = self.active.lock() else {
+            return 0;
+        };
+        active.values().filter(|handle| handle.send(command())).count()
+    }
+}
+
+impl Drop for Registration<'_> {
+    fn drop(&mut self) {
+        let Ok(mut active) = self.manager.active.lock() else {
+            return;
+        };
+        // Only clear the entry if a simultaneous connection hasn't taken it over.
+        if active.get(&self.node_id).is_some_and(|handle| handle.id == self.id) {
+            active.remove(&self.node_id);
+        }
+    }
+}
+
+impl Drop for HandshakeGuard<'_> {
+    fn drop(&mut self) {
+        let Some(node_id) = self.node_id else {
+            // Under the table lock, like the check in begin_inbound.
+            let _active = self.manager.active.lock();
+            self.manager.inbound_handshakes.fetch_sub(1, Ordering::Relaxed);
+            return;
+        };
+        if let Ok(mut handshaking) = self.manager.handshaking.lock() {
+            handshaking.remove(&node_id);
+        }
+        self.manager.handshake_done.notify_waiters();
+    }
+}
+
+fn node_id(public_key: &PublicKey) -> [u8; 64] {
+    let mut id = [0; 64];
+    id.copy_from_slice(&public_key.serialize_uncompressed()[1..]);
+    id
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::RLPxConfig,
+        testing::{random_key, sessions},
+    };
+    use std::time::Duration;
+    use tokio::time::timeout;
+
+    #[tokio::test]
+    async fn inbound_handshakes_in_flight_count_towards_max_peers() {
+        let manager = SessionManager::new(&random_key(), Some(2));
+
+        // Two peers dial us, neither has a session registered yet.
+        let mut slots = Vec::new();
+        for _ in 0..2 {
+            slots.push(manager.begin_inbound().unwrap());
+        }
+
+        // A third gets no slot. What it's told on the wire is up to the
+        // listener, see its tests.
+        let rejection = manager.begin_inbound().map(|_| ()).unwrap_err();
+        assert_eq!(rejection.reason, Disconnect::TOO_MANY_PEERS);
+
+        // A slot carries over to the registered session, and is freed with it.
+        let (_initiator, responder) = sessions(RLPxConfig::default(), RLPxConfig::default()).await;
+        let slot = slots.pop().unwrap();
+        let registration = manager.add(&responder.peer_public_key().unwrap(), responder.direction()).unwrap();
+        drop(slot);
+        assert!(manager.begin_inbound().is_err());
+        drop(registration);
+        assert!(manager.begin_inbound().is_ok());
+    }
+
+    #[tokio::test]
+    async fn our_own_dials_take_no_inbound_slot() {
+        let manager = SessionManager::new(&random_key(), Some(1));
+        let (initiator, _responder) = sessions(RLPxConfig::default(), RLPxConfig::default()).await;
+        let peer = initiator.peer_public_key().unwrap();
+
+        let handshake = manager.begin_handshake(&peer).await;
+        let _registration = manager.add(&peer, initiator.direction()).unwrap();
+        drop(handshake);
+        assert!(manager.begin_inbound().is_ok());
+    }
+
+    #[tokio::test]
+    async fn one_node_at_two_addresses_gets_one_handshake_at_a_time() {
+        let manager = SessionManager::new(&random_key(), None);
+        let (initiator, _responder) = sessions(RLPxConfig::default(), RLPxConfig::default()).await;
+        let peer = initiator.peer_public_key().unwrap();
+
+        // Dialing the node at its first address.
+        let first = manager.begin_handshake(&peer).await;
+        // The dial to its second address waits, another node's doesn't.
+        let second = manager.begin_handshake(&peer);
+        tokio::pin!(second);
+        assert!(timeout(Duration::from_millis(50), &mut second).await.is_err());
+        let other = PublicKey::from_secret_key(SECP256K1, &random_key());
+        assert!(timeout(Duration::from_millis(50), manager.begin_handshake(&other)).await.is_ok());
+
+        // The first one gets its session, the second finds it once it's let through.
+        let _registration = manager.add(&peer, initiator.direction()).unwrap();
+        drop(first);
+        let _second = timeout(Duration::from_secs(1), &mut second).await.unwrap();
+        assert!(manager.get(&peer).is_some());
+    }
+}
@@ -0,0 +1,72 @@
+// Session state transitions, one line each:
+//   <unix time, µs precision> <peer> <node id> <old state> <new state> <event>
+//
+// Appended to across runs and sessions, for working out afterwards at which
+// stage particular peers keep failing.
+use std::{
+    fs::{File, OpenOptions},
+    io::Write,
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use log::warn;
+use secp256k1::PublicKey;
+
+use crate::{redact, rplx::RlpxState};
+
+#[derive(Clone)]
+pub struct StateLog {
+    file: Arc<Mutex<File>>,
+    peer: String,
+}
+
+impl StateLog {
+    pub fn create(path: &str) -> Result<Self, &'static str> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|_| "Could not open state log file! ")?;
+
+        Ok(Self {
+            file: Arc::new(Mutex::new(file)),
+            peer: String::new(),
+        })
+    }
+
+    // Same file, tagged with the peer so concurrent sessions can be told apart.
+    pub fn for_peer(&self, peer: String) -> Self {
+        Self {
+            file: self.file.clone(),
+            peer,
+        }
+    }
+
+    // The node id is only known up front when we dialed, inbound peers get
+    // theirs once their auth is in.
+    pub fn record(&self, node: Option<&PublicKey>, old: RlpxState, new: RlpxState, event: &str) {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let node_id = node.map_or_else(|| "-".to_string(), redact::public_key);
+
+        let line = format!(
+            "{}.{:06} {} {} {:?} {:?} {}\n",
+            timestamp.as_secs(),
+            timestamp.subsec_micros(),
+            self.peer,
+            node_id,
+            old,
+            new,
+            event
+        );
+
+        let Ok(mut file) = self.file.lock() else {
+            return;
+        };
+        if let Err(err) = file.write_all(line.as_bytes()) {
+            warn!("Writing the state log failed: {:?}", err);
+        }
+    }
+}
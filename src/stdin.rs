@@ -0,0 +1,74 @@
+use std::{collections::HashSet, net::SocketAddr};
+
+use futures::{stream, Stream};
+use log::{info, warn};
+use secp256k1::PublicKey;
+use tokio::{
+    io::{self, AsyncBufReadExt, BufReader, Lines, Stdin},
+    net::lookup_host,
+};
+
+use crate::{
+    config::DialConfig,
+    nodekey::{interleave_families, split_enode},
+    redact,
+};
+
+struct Reader {
+    lines: Lines<BufReader<Stdin>>,
+    seen: HashSet<PublicKey>,
+    dial: DialConfig,
+}
+
+// --stdin: enodes one per line, handed out as they come in. The stream is only
+// polled as the dialer frees up a slot, so a long list waits in the pipe
+// rather than in memory. Blank lines and # comments are skipped, and so is a
+// line that doesn't parse or resolve rather than ending the whole run.
+pub fn peers(dial: DialConfig) -> impl Stream<Item = (PublicKey, Vec<SocketAddr>)> {
+    let reader = Reader {
+        lines: BufReader::new(io::stdin()).lines(),
+        seen: HashSet::new(),
+        dial,
+    };
+    stream::unfold(reader, |mut reader| async move {
+        loop {
+            let line = match reader.lines.next_line().await {
+                Ok(Some(line)) => line,
+                Ok(None) => {
+                    info!("Standard input closed, {} peers read from it", reader.seen.len());
+                    return None;
+                }
+                Err(err) => {
+                    warn!("Reading peers from standard input failed! {}", err);
+                    return None;
+                }
+            };
+            let enode = line.trim();
+            if enode.is_empty() || enode.starts_with('#') {
+                continue;
+            }
+            match resolve(enode, &reader.dial).await {
+                Ok((public_key, _)) if !reader.seen.insert(public_key) => {
+                    warn!("Dropping duplicate peer {} from standard input", redact::public_key(&public_key))
+                }
+                Ok(peer) => return Some((peer, reader)),
+                Err(err) => warn!("Skipping {:?} from standard input: {}", redact::enode(enode), err),
+            }
+        }
+    })
+}
+
+// Same as for enode arguments, except resolving doesn't hold up the sessions
+// already running.
+async fn resolve(enode: &str, dial: &DialConfig) -> Result<(PublicKey, Vec<SocketAddr>), &'static str> {
+    let (public_key, socket_address_string) = split_enode(enode)?;
+    let addresses: Vec<SocketAddr> = lookup_host(socket_address_string)
+        .await
+        .map_err(|_| " Invalid IP address format! ")?
+        .map(|address| dial.normalize(address))
+        .collect();
+    if addresses.is_empty() {
+        return Err("Invalid IP address! ");
+    }
+    Ok((public_key, interleave_families(addresses)))
+}
@@ -0,0 +1,129 @@
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::Duration,
+};
+
+use log::debug;
+use secp256k1::rand::{thread_rng, RngCore};
+use tokio::{
+    net::{lookup_host, UdpSocket},
+    time::timeout,
+};
+
+// RFC 5389, just enough of it to ask a server which address our packets
+// come from.
+const BINDING_REQUEST: u16 = 0x0001;
+const BINDING_SUCCESS: u16 = 0x0101;
+const MAGIC_COOKIE: u32 = 0x2112_a442;
+const HEADER_SIZE: usize = 20;
+const MAPPED_ADDRESS: u16 = 0x0001;
+const XOR_MAPPED_ADDRESS: u16 = 0x0020;
+const FAMILY_IPV4: u8 = 0x01;
+const FAMILY_IPV6: u8 = 0x02;
+// A lost datagram isn't retried, there's a fallback for when this fails.
+const STUN_TIMEOUT: Duration = Duration::from_secs(3);
+
+// Our address as the STUN server at host:port sees it, which behind a NAT is
+// the NAT's outside address rather than any of our own interfaces.
+pub async fn external_ip(server: &str) -> Result<IpAddr, &'static str> {
+    let server_address = lookup_host(server)
+        .await
+        .map_err(|_| "Could not resolve the STUN server")?
+        .next()
+        .ok_or("Could not resolve the STUN server")?;
+    let local_address: SocketAddr = if server_address.is_ipv4() {
+        (Ipv4Addr::UNSPECIFIED, 0).into()
+    } else {
+        (Ipv6Addr::UNSPECIFIED, 0).into()
+    };
+    let socket = UdpSocket::bind(local_address)
+        .await
+        .map_err(|_| "Could not open a UDP socket for STUN")?;
+
+    let mut transaction_id = [0; 12];
+    thread_rng().fill_bytes(&mut transaction_id);
+    let mut request = Vec::with_capacity(HEADER_SIZE);
+    request.extend_from_slice(&BINDING_REQUEST.to_be_bytes());
+    request.extend_from_slice(&0u16.to_be_bytes());
+    request.extend_from_slice(&MAGIC_COOKIE.to_be_bytes());
+    request.extend_from_slice(&transaction_id);
+    socket
+        .send_to(&request, server_address)
+        .await
+        .map_err(|_| "Could not send the STUN request")?;
+
+    let mut response = [0; 512];
+    loop {
+        let (size, from) = timeout(STUN_TIMEOUT, socket.recv_from(&mut response))
+            .await
+            .map_err(|_| "STUN server didn't answer in time")?
+            .map_err(|_| "Could not read the STUN response")?;
+        // Anything else landing on the socket isn't our answer.
+        if from != server_address {
+            debug!("Ignoring a datagram from {} while waiting on STUN", from);
+            continue;
+        }
+        return parse_binding_response(&response[..size], &transaction_id);
+    }
+}
+
+fn parse_binding_response(response: &[u8], transaction_id: &[u8; 12]) -> Result<IpAddr, &'static str> {
+    if response.len() < HEADER_SIZE
+        || response[4..8] != MAGIC_COOKIE.to_be_bytes()
+        || response[8..HEADER_SIZE] != transaction_id[..]
+    {
+        return Err("Not a response to our STUN request");
+    }
+    if u16::from_be_bytes([response[0], response[1]]) != BINDING_SUCCESS {
+        return Err("STUN server refused the binding request");
+    }
+    let length = u16::from_be_bytes([response[2], response[3]]) as usize;
+    let mut attributes = response.get(HEADER_SIZE..HEADER_SIZE + length).ok_or("STUN response cut short")?;
+
+    // Servers send XOR-MAPPED-ADDRESS, some old ones only MAPPED-ADDRESS.
+    let mut mapped = None;
+    while attributes.len() >= 4 {
+        let kind = u16::from_be_bytes([attributes[0], attributes[1]]);
+        let value_length = u16::from_be_bytes([attributes[2], attributes[3]]) as usize;
+        let value = attributes.get(4..4 + value_length).ok_or("STUN attribute cut short")?;
+        match kind {
+            XOR_MAPPED_ADDRESS => return mapped_ip(value, Some(transaction_id)),
+            MAPPED_ADDRESS => mapped = Some(mapped_ip(value, None)?),
+            _ => {}
+        }
+        // Attributes are padded to a multiple of 4 bytes.
+        let padded_length = 4 + value_length.next_multiple_of(4);
+        attributes = attributes.get(padded_length..).unwrap_or_default();
+    }
+    mapped.ok_or("STUN response has no mapped address")
+}
+
+// value = reserved || family || port || address, with the address XORed
+// against the magic cookie (and for IPv6 the transaction id) when it's the
+// XOR variant.
+fn mapped_ip(value: &[u8], xor_transaction_id: Option<&[u8; 12]>) -> Result<IpAddr, &'static str> {
+    let family = *value.get(1).ok_or("STUN address attribute cut short")?;
+    let address = value.get(4..).unwrap_or_default();
+    let mut mask = [0; 16];
+    if let Some(transaction_id) = xor_transaction_id {
+        mask[..4].copy_from_slice(&MAGIC_COOKIE.to_be_bytes());
+        mask[4..].copy_from_slice(transaction_id);
+    }
+    match (family, address.len()) {
+        (FAMILY_IPV4, 4) => {
+            let mut octets = [0; 4];
+            for (i, octet) in octets.iter_mut().enumerate() {
+                *octet = address[i] ^ mask[i];
+            }
+            Ok(IpAddr::from(octets))
+        }
+        (FAMILY_IPV6, 16) => {
+            let mut octets = [0; 16];
+            for (i, octet) in octets.iter_mut().enumerate() {
+                *octet = address[i] ^ mask[i];
+            }
+            Ok(IpAddr::from(octets))
+        }
+        _ => Err("STUN address attribute is malformed"),
+    }
+}
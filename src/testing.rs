@@ -0,0 +1,94 @@
+// Paired codecs and sessions for the unit tests, one dialing the other.
+use alloy_rlp::BytesMut;
+use secp256k1::{PublicKey, SecretKey, SECP256K1};
+use tokio::io::{duplex, DuplexStream};
+use tokio_util::codec::{Decoder, Encoder};
+
+use crate::{
+    config::RLPxConfig,
+    messages::RLPx_Message,
+    rplx::RLPx,
+    session::Session,
+};
+
+// Enough for a whole auth or ack, so neither side blocks on the other reading.
+pub const PIPE_CAPACITY: usize = 64 * 1024;
+
+pub fn random_key() -> SecretKey {
+    SecretKey::new(&mut secp256k1::rand::thread_rng())
+}
+
+// A dialing codec and the listening one it dials, fresh.
+pub fn codecs(initiator_config: RLPxConfig, responder_config: RLPxConfig) -> (RLPx, RLPx) {
+    let responder_key = random_key();
+    let responder_id = PublicKey::from_secret_key(SECP256K1, &responder_key);
+    (
+        RLPx::new(random_key(), responder_id, initiator_config),
+        RLPx::new_incoming(responder_key, responder_config),
+    )
+}
+
+// Everything one codec encodes, decoded by the other until it has no more.
+pub fn deliver(from: &mut RLPx, to: &mut RLPx, message: RLPx_Message) -> Vec<RLPx_Message> {
+    let mut wire = BytesMut::new();
+    from.encode(message, &mut wire).unwrap();
+    decode_all(to, &mut wire)
+}
+
+pub fn decode_all(to: &mut RLPx, wire: &mut BytesMut) -> Vec<RLPx_Message> {
+    let mut messages = Vec::new();
+    while let Some(message) = to.decode(wire).unwrap() {
+        messages.push(message);
+    }
+    messages
+}
+
+// Auth one way and ack the other, both sides then hold the frame secrets.
+pub fn auth_ack(initiator: &mut RLPx, responder: &mut RLPx) {
+    assert!(matches!(deliver(initiator, responder, RLPx_Message::Auth)[..], [RLPx_Message::Auth]));
+    assert!(matches!(deliver(responder, initiator, RLPx_Message::AuthAck)[..], [RLPx_Message::AuthAck]));
+}
+
+// Both send their Hello before reading the other's, as sessions do.
+pub fn hellos(initiator: &mut RLPx, responder: &mut RLPx) {
+    let mut to_responder = BytesMut::new();
+    let mut to_initiator = BytesMut::new();
+    initiator.encode(RLPx_Message::Hello, &mut to_responder).unwrap();
+    responder.encode(RLPx_Message::Hello, &mut to_initiator).unwrap();
+    assert!(matches!(decode_all(responder, &mut to_responder)[..], [RLPx_Message::Hello]));
+    assert!(matches!(decode_all(initiator, &mut to_initiator)[..], [RLPx_Message::Hello]));
+}
+
+// Through auth, ack and both Hellos, with the default config on both sides.
+pub fn active_codecs() -> (RLPx, RLPx) {
+    let (mut initiator, mut responder) = codecs(RLPxConfig::default(), RLPxConfig::default());
+    auth_ack(&mut initiator, &mut responder);
+    hellos(&mut initiator, &mut responder);
+    (initiator, responder)
+}
+
+// Two sessions over an in-memory pipe, both through their handshake.
+pub async fn sessions(
+    initiator_config: RLPxConfig,
+    responder_config: RLPxConfig,
+) -> (Session<DuplexStream>, Session<DuplexStream>) {
+    let (initiator_codec, responder_codec) = codecs(initiator_config, responder_config);
+    let (initiator_pipe, responder_pipe) = duplex(PIPE_CAPACITY);
+    let mut initiator = Session::new(initiator_pipe, initiator_codec);
+    let mut responder = Session::new(responder_pipe, responder_codec);
+    let (initiated, responded) = tokio::join!(initiator.handshake(), responder.handshake());
+    initiated.unwrap();
+    responded.unwrap();
+    (initiator, responder)
+}
+
+// The frame-data a P2p message with this id and body goes out as, once
+// snappy is on if the build has it.
+pub fn p2p_frame_data(id: u8, body: &[u8]) -> Vec<u8> {
+    let mut frame_data = vec![id];
+    #[cfg(feature = "snappy")]
+    frame_data.extend_from_slice(&snap::raw::Encoder::new().compress_vec(body).unwrap());
+    #[cfg(not(feature = "snappy"))]
+    frame_data.extend_from_slice(body);
+    frame_data
+}